@@ -0,0 +1,202 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Alignment files usually name the aligner which made them (and its command) in a @PG header
+// line. Each aligner needs different settings to give Polypolish all of a read's alignments, so
+// knowing the aligner lets Polypolish point out the specific setting which was missed.
+
+use std::io;
+use std::path::Path;
+
+use crate::alignment::SamLines;
+use crate::run::RunContext;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aligner {
+    Bwa,
+    BwaMem2,
+    Minimap2,
+    Bbmap,
+}
+
+impl Aligner {
+    fn from_name(name: &str) -> Option<Aligner> {
+        match name.to_ascii_lowercase().as_str() {
+            "bwa"      => Some(Aligner::Bwa),
+            "bwa-mem2" => Some(Aligner::BwaMem2),
+            "minimap2" => Some(Aligner::Minimap2),
+            "bbmap"    => Some(Aligner::Bbmap),
+            _          => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aligner::Bwa      => "bwa",
+            Aligner::BwaMem2  => "bwa-mem2",
+            Aligner::Minimap2 => "minimap2",
+            Aligner::Bbmap    => "BBMap",
+        }
+    }
+
+    /// How to run this aligner so it gives all of each read's alignments.
+    pub fn all_alignments_hint(&self) -> &'static str {
+        match self {
+            Aligner::Bwa      => "use bwa mem -a, e.g. bwa mem -a draft.fasta reads_1.fastq.gz > \
+                                  alignments_1.sam",
+            Aligner::BwaMem2  => "use bwa-mem2 mem -a, e.g. bwa-mem2 mem -a draft.fasta \
+                                  reads_1.fastq.gz > alignments_1.sam",
+            Aligner::Minimap2 => "don't use --secondary=no and raise -N, e.g. minimap2 -ax sr \
+                                  -N 100 draft.fasta reads_1.fastq.gz > alignments_1.sam",
+            Aligner::Bbmap    => "use ambiguous=all, e.g. bbmap.sh ref=draft.fasta \
+                                  in=reads_1.fastq.gz out=alignments_1.sam ambiguous=all",
+        }
+    }
+}
+
+
+/// The aligner which made an alignment file, as given in its @PG header line.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub aligner: Aligner,
+    pub version: Option<String>,
+    pub command_line: Option<String>,
+}
+
+impl Program {
+    /// If the aligner's command shows that it only gave each read's best alignment, this returns
+    /// the setting which was missed.
+    pub fn single_best_reason(&self) -> Option<&'static str> {
+        let args = self.args();
+        match self.aligner {
+            Aligner::Bwa | Aligner::BwaMem2 => {
+                let all_alignments = args.iter()
+                    .any(|a| a.len() > 1 && a.starts_with('-') && !a.starts_with("--") &&
+                             a[1..].chars().all(|c| c.is_ascii_alphabetic()) && a.contains('a'));
+                (args.contains(&"mem") && !all_alignments).then_some("bwa mem was run without -a")
+            },
+            Aligner::Minimap2 => {
+                args.contains(&"--secondary=no").then_some("minimap2 was run with --secondary=no")
+            },
+            Aligner::Bbmap => {
+                let ambiguous_all = args.iter()
+                    .any(|a| *a == "ambiguous=all" || *a == "ambig=all");
+                (!args.is_empty() && !ambiguous_all)
+                    .then_some("BBMap was run without ambiguous=all")
+            },
+        }
+    }
+
+    /// Other known problems with how the aligner was run.
+    pub fn other_problems(&self) -> Vec<&'static str> {
+        let args = self.args();
+        let mut problems = Vec::new();
+        match self.aligner {
+            Aligner::Bwa | Aligner::BwaMem2 => {
+                if !args.is_empty() && !args.contains(&"mem") {
+                    problems.push("only bwa mem alignments are supported (not bwa aln or bwasw)");
+                }
+            },
+            Aligner::Minimap2 => {
+                if !args.is_empty() && !args.iter().any(|a| a.starts_with("-N")) {
+                    problems.push("minimap2 gives at most 5 secondary alignments per read by \
+                                   default, so reads in high-copy repeats may be missing \
+                                   alignments (use -N to raise this)");
+                }
+            },
+            Aligner::Bbmap => {},
+        }
+        problems
+    }
+
+    fn args(&self) -> Vec<&str> {
+        self.command_line.as_deref().map_or(Vec::new(), |c| c.split_whitespace().collect())
+    }
+}
+
+
+/// Looks through the file's header for the first @PG line from a known aligner. Later @PG lines
+/// are usually from other tools (e.g. samtools sort), so they're skipped if unrecognised.
+pub fn detect_aligner(filename: &Path, run: &RunContext) -> io::Result<Option<Program>> {
+    for line in SamLines::open(filename, run)? {
+        let line = line?;
+        if !line.starts_with('@') {
+            break;
+        }
+        if let Some(program) = parse_pg_line(&line) {
+            return Ok(Some(program));
+        }
+    }
+    Ok(None)
+}
+
+
+fn parse_pg_line(line: &str) -> Option<Program> {
+    let fields = line.strip_prefix("@PG\t")?;
+    let (mut id, mut name, mut version, mut command_line) = (None, None, None, None);
+    for field in fields.split('\t') {
+        if let Some(value) = field.strip_prefix("ID:") {
+            id = Some(value);
+        } else if let Some(value) = field.strip_prefix("PN:") {
+            name = Some(value);
+        } else if let Some(value) = field.strip_prefix("VN:") {
+            version = Some(value.to_string());
+        } else if let Some(value) = field.strip_prefix("CL:") {
+            command_line = Some(value.to_string());
+        }
+    }
+    let aligner = name.and_then(Aligner::from_name).or_else(|| id.and_then(Aligner::from_name))?;
+    Some(Program { aligner, version, command_line })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(line: &str) -> Program {
+        parse_pg_line(line).unwrap()
+    }
+
+    #[test]
+    fn test_parse_pg_line() {
+        let p = program("@PG\tID:bwa\tPN:bwa\tVN:0.7.17-r1188\tCL:bwa mem -a draft.fasta r1.fq");
+        assert_eq!(p.aligner, Aligner::Bwa);
+        assert_eq!(p.version.as_deref(), Some("0.7.17-r1188"));
+        assert_eq!(p.command_line.as_deref(), Some("bwa mem -a draft.fasta r1.fq"));
+        assert_eq!(program("@PG\tID:BBMap\tPN:BBMap\tVN:39.01").aligner, Aligner::Bbmap);
+        assert_eq!(program("@PG\tID:minimap2\tVN:2.26").aligner, Aligner::Minimap2);
+        assert!(parse_pg_line("@PG\tID:samtools\tPN:samtools\tVN:1.17").is_none());
+        assert!(parse_pg_line("@SQ\tSN:bwa\tLN:100").is_none());
+    }
+
+    #[test]
+    fn test_single_best_reason() {
+        let bwa = |cl: &str| program(&format!("@PG\tID:bwa\tPN:bwa\tCL:{}", cl));
+        assert_eq!(bwa("bwa mem -t 16 -a draft.fasta r1.fq").single_best_reason(), None);
+        assert_eq!(bwa("bwa mem -aM draft.fasta r1.fq").single_best_reason(), None);
+        assert!(bwa("bwa mem -t 16 draft.fasta r1.fq").single_best_reason().is_some());
+        assert!(bwa("bwa mem draft.fasta reads.fq").single_best_reason().is_some());
+        assert_eq!(program("@PG\tID:bwa\tPN:bwa").single_best_reason(), None);
+
+        let mm2 = |cl: &str| program(&format!("@PG\tID:minimap2\tPN:minimap2\tCL:{}", cl));
+        assert!(mm2("minimap2 -ax sr --secondary=no a.fa r.fq").single_best_reason().is_some());
+        assert_eq!(mm2("minimap2 -ax sr -N 50 a.fa r.fq").single_best_reason(), None);
+        assert!(mm2("minimap2 -ax sr a.fa r.fq").other_problems().len() == 1);
+        assert!(mm2("minimap2 -ax sr -N 50 a.fa r.fq").other_problems().is_empty());
+
+        let bbmap = |cl: &str| program(&format!("@PG\tID:BBMap\tPN:BBMap\tCL:{}", cl));
+        assert!(bbmap("java align2.BBMap in=r.fq ref=a.fa").single_best_reason().is_some());
+        assert_eq!(bbmap("java align2.BBMap ambig=all in=r.fq").single_best_reason(), None);
+        assert!(bbmap("java align2.BBMap ambig=all in=r.fq").other_problems().is_empty());
+    }
+}
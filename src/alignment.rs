@@ -9,155 +9,470 @@
 // Public License for more details. You should have received a copy of the GNU General Public
 // License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
 
-use lazy_static::lazy_static;
-use regex::Regex;
-
-use crate::misc::{quit_with_error, reverse_complement};
+use crate::bam;
+use crate::decompress::ThreadedGzReader;
+use crate::downsample::Downsampler;
+use crate::external_sort::{ExternalSorter, SortedFile};
+use crate::log;
+use crate::misc;
+use crate::misc::{quit_with_error, reverse_complement, Error, ErrorType, FastHashMap,
+                  FastHashSet};
+use crate::mmap::MappedLines;
+use crate::paf;
 use crate::pileup::Pileup;
+use crate::placement;
+use crate::placement::{Placement, PlacementShares, PlacementWeights};
+use crate::progress::{Progress, Unit};
+use crate::run::RunContext;
+use crate::source::{AlignmentSource, SamSource};
+
+use serde::de::{Deserializer, Error as _};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::{prelude::*, BufReader};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// The tag polypolish filter adds to alignments which aren't part of a good read pair.
+pub const DEFAULT_FAIL_TAG: &str = "ZP:Z:fail";
+
+/// Alignments with this error rate or lower get the full weight with --identity-weighting, as a
+/// read from a correct sequence still differs wherever the assembly has an error.
+const FULL_WEIGHT_ERROR_RATE: f64 = 0.02;
+
+
+/// Alignments with any of the fail tags (matched case-insensitively) were failed by a pre-filter
+/// and aren't used for polishing. When there are none, DEFAULT_FAIL_TAG is used.
+fn is_fail_tag(tag: &str, fail_tags: &[String]) -> bool {
+    if fail_tags.is_empty() {
+        tag.eq_ignore_ascii_case(DEFAULT_FAIL_TAG)
+    } else {
+        fail_tags.iter().any(|t| tag.eq_ignore_ascii_case(t))
+    }
+}
 
 
-lazy_static! {
-    static ref RE: Regex = regex::Regex::new(r"\d+[MIDNSHP=X]").unwrap();
+/// Fail tags are complete SAM tags (TAG:TYPE:VALUE), e.g. ZP:Z:fail.
+pub fn is_valid_tag(tag: &str) -> bool {
+    let parts: Vec<&str> = tag.splitn(3, ':').collect();
+    parts.len() == 3 && parts[0].len() == 2 &&
+        parts[0].starts_with(|c: char| c.is_ascii_alphabetic()) &&
+        parts[0].chars().all(|c| c.is_ascii_alphanumeric()) &&
+        ["A", "i", "f", "Z", "H", "B"].contains(&parts[1]) && !parts[2].is_empty()
 }
 
 
 #[derive(Debug)]
 pub struct Alignment {
-    pub read_name: String,
+    pub read_name: Arc<str>,
     pub ref_name: String,
     sam_flags: u32,
     pub ref_start: usize,
+    mapq: u8,                      // 255 if unavailable
     cigar: String,
-    expanded_cigar: String,
+    cigar_ops: Vec<(u32, CigarOp)>,
     pub read_seq: String,
+    read_qual: Option<Arc<[u8]>>,  // Phred scores (None for "*"), shared by a read's alignments
     mismatches: u32,
-    pass_qc: bool,
+    alignment_score: Option<i32>,  // from the AS tag
+    failed: bool,                  // has a fail tag or was failed by the read pair filter
+    has_alt_hits: bool,            // other alignments are only listed in an XA tag
+    count_weight: f64,             // set when the alignment is accepted for polishing
 }
 
 impl Alignment {
 
     /// This is the full constructor for an Alignment object. It stores the read sequence and
-    /// expanded CIGAR string.
-    pub fn new(sam_line: &str) -> Result<Alignment, &str> {
+    /// parsed CIGAR operations, and checks the optional fields for the fail tags (as in
+    /// AlignmentFilter). Malformed lines (e.g. a line cut short in a truncated file) give an
+    /// error.
+    pub fn new(sam_line: &str, fail_tags: &[String]) -> Result<Alignment, &'static str> {
         let parts = sam_line.split('\t').collect::<Vec<&str>>();
         if parts.len() < 11 {
             return Err("too few columns");
         }
 
         let read_name = parts[0];
-        let sam_flags = parts[1].parse::<u32>().unwrap();
+        let sam_flags = parts[1].parse::<u32>().map_err(|_| "invalid flag")?;
         let ref_name = parts[2];
-        let mut ref_start = parts[3].parse::<usize>().unwrap();
-        if ref_start > 0 {
-            ref_start -= 1;
-        }
+        let ref_start = parts[3].parse::<usize>().map_err(|_| "invalid position")?
+            .saturating_sub(1);
+        let mapq = parts[4].parse::<u8>().map_err(|_| "invalid MAPQ")?;
         let cigar = parts[5];
         let read_seq = parts[9];
+        let read_qual = parts[10];
 
         let mut mismatches = u32::MAX;
-        let mut pass_qc = true;
+        let mut alignment_score = None;
+        let mut has_alt_hits = false;
+        let mut failed = false;
         for p in &parts[11..] {
-            if p.starts_with("NM:i:") {
-                let nm = p[5..].to_string();
-                mismatches = nm.parse::<u32>().unwrap();
+            if let Some(nm) = p.strip_prefix("NM:i:") {
+                mismatches = nm.parse::<u32>().map_err(|_| "invalid NM tag")?;
+            }
+            if let Some(score) = p.strip_prefix("AS:i:") {
+                alignment_score = score.parse::<i32>().ok();
             }
-            if p.eq_ignore_ascii_case("ZP:Z:fail") {
-                pass_qc = false;
+            if p.starts_with("XA:Z:") {
+                has_alt_hits = true;
+            }
+            if is_fail_tag(p, fail_tags) {
+                failed = true;
             }
         }
         if mismatches == u32::MAX && sam_flags & 4 == 0 {
             return Err("missing NM tag");
         }
-        let expanded_cigar_result = get_expanded_cigar(&cigar, read_seq.len());
-        match expanded_cigar_result {
-            Ok(_)  => (),
-            Err(_) => quit_with_error(&format!("encountered an invalid CIGAR string for read {}: \
-                                                {:?}", read_name, cigar)),
-        };
-        let expanded_cigar = expanded_cigar_result.unwrap();
+        let cigar_ops = parse_cigar(cigar).map_err(|_| "invalid CIGAR string")?;
+        if read_seq != "*" && !cigar_ops.is_empty() {
+            let cigar_read_length: usize = cigar_ops.iter().filter(|(_, op)| op.consumes_read())
+                .map(|(num, _)| *num as usize).sum();
+            if cigar_read_length != read_seq.len() {
+                return Err("CIGAR and sequence lengths differ");
+            }
+        }
 
         Ok(Alignment {
-            read_name: read_name.to_string(),
+            read_name: Arc::from(read_name),
             ref_name: ref_name.to_string(),
-            sam_flags: sam_flags,
-            ref_start: ref_start,
+            sam_flags,
+            ref_start,
+            mapq,
             cigar: cigar.to_string(),
-            expanded_cigar: expanded_cigar,
+            cigar_ops,
             read_seq: read_seq.to_ascii_uppercase(),
-            mismatches: mismatches,
-            pass_qc: pass_qc,
+            read_qual: parse_quals(read_qual),
+            mismatches,
+            alignment_score,
+            failed,
+            has_alt_hits,
+            count_weight: 1.0,
         })
     }
 
     /// This is the quick constructor for an Alignment object. It stores less than Alignment::new
-    /// and is used by filter.rs where read_seq and expanded_cigar aren't needed.
-    pub fn new_quick(sam_line: &str) -> Result<Alignment, &str> {
+    /// and is used by filter.rs where read_seq and cigar_ops aren't needed.
+    pub fn new_quick(sam_line: &str) -> Result<Alignment, &'static str> {
         let parts = sam_line.split('\t').collect::<Vec<&str>>();
         if parts.len() < 11 {
             return Err("too few columns");
         }
 
         let read_name = parts[0];
-        let sam_flags = parts[1].parse::<u32>().unwrap();
+        let sam_flags = parts[1].parse::<u32>().map_err(|_| "invalid flag")?;
         let ref_name = parts[2];
-        let mut ref_start = parts[3].parse::<usize>().unwrap();
-        if ref_start > 0 {
-            ref_start -= 1;
-        }
+        let ref_start = parts[3].parse::<usize>().map_err(|_| "invalid position")?
+            .saturating_sub(1);
+        let mapq = parts[4].parse::<u8>().map_err(|_| "invalid MAPQ")?;
         let cigar = parts[5];
 
         Ok(Alignment {
-            read_name: read_name.to_string(),
+            read_name: Arc::from(read_name),
             ref_name: ref_name.to_string(),
-            sam_flags: sam_flags,
-            ref_start: ref_start,
+            sam_flags,
+            ref_start,
+            mapq,
             cigar: cigar.to_string(),
-            expanded_cigar: String::new(),
+            cigar_ops: Vec::new(),
             read_seq: String::new(),
+            read_qual: None,
             mismatches: 0,
-            pass_qc: true,
+            alignment_score: None,
+            failed: false,
+            has_alt_hits: false,
+            count_weight: 1.0,
         })
     }
 
+    /// Returns the approximate number of bytes of memory used by this alignment, including its
+    /// heap-allocated strings. The read name and qualities aren't included, as they can be shared
+    /// with other alignments.
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Alignment>() + self.ref_name.capacity() + self.cigar.capacity() +
+            self.read_seq.capacity() +
+            self.cigar_ops.capacity() * std::mem::size_of::<(u32, CigarOp)>()
+    }
+
     pub fn is_aligned(&self) -> bool {
         (self.sam_flags & 4) == 0
     }
 
-    fn get_strand(&self) -> i8 {
+    /// Marks the alignment as failed by the read pair filter, as if it had a fail tag.
+    pub fn fail_filter(&mut self) {
+        self.failed = true;
+    }
+
+    /// Returns true if the alignment was failed by a pre-filter: it had one of the fail tags when
+    /// it was parsed or was marked with fail_filter.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn get_strand(&self) -> i8 {
         if self.is_on_forward_strand() { 1 } else { -1 }
     }
 
     pub fn get_ref_end(&self) -> usize {
         let mut ref_end = self.ref_start;
-        for m in RE.find_iter(&self.cigar) {
-            let num: usize = self.cigar[m.start()..m.end()-1].parse().unwrap();
-            let letter = &self.cigar[m.end()-1..m.end()].chars().next().unwrap();
-            match letter {
-                'M' | 'D' | 'N' | '=' | 'X' => ref_end += num,
-                _ => {}
+        for (num, op) in CigarOps::new(&self.cigar).map_while(Result::ok) {
+            if op.consumes_ref() {
+                ref_end += num as usize;
             }
         }
         ref_end
     }
 
+    /// Returns false for secondary and supplementary alignments.
+    pub fn is_primary(&self) -> bool {
+        (self.sam_flags & (256 | 2048)) == 0
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        (self.sam_flags & 256) != 0
+    }
+
+    /// The mapping quality, or None if it's unavailable (255).
+    pub fn mapq(&self) -> Option<u8> {
+        (self.mapq != 255).then_some(self.mapq)
+    }
+
+    /// The values used to share its read's depth with --placement-weights.
+    pub fn placement(&self) -> Placement {
+        (self.mapq(), self.alignment_score)
+    }
+
     pub fn is_on_forward_strand(&self) -> bool {
         (self.sam_flags & 16) == 0
     }
 
-    fn starts_and_ends_with_match(&self) -> bool {
-        self.expanded_cigar.chars().next().unwrap() == 'M' &&
-            self.expanded_cigar.chars().last().unwrap() == 'M'
+    /// Returns true if this alignment can be used for polishing: end-to-end, not too many errors
+    /// and not failed by a pre-filter. The best alignment score for the read is only needed
+    /// for --max-score-diff.
+    pub fn is_usable(&self, filter: &AlignmentFilter, best_score: Option<i32>) -> bool {
+        self.discard_reason(filter, best_score).is_none()
+    }
+
+    /// Returns why this alignment can't be used for polishing, or None if it can be. This quits
+    /// if the filter needs an alignment score the alignment doesn't have (see check_score).
+    pub fn discard_reason(&self, filter: &AlignmentFilter,
+                          best_score: Option<i32>) -> Option<DiscardReason> {
+        if let Err(e) = self.check_score(filter) {
+            e.quit();
+        }
+        if let Some(reason) = self.end_to_end_reason() {
+            Some(reason)
+        } else if self.is_failed() {
+            Some(DiscardReason::FailedFilter)
+        } else if filter.exclude_qcfail && self.sam_flags & 512 != 0 {
+            Some(DiscardReason::QcFail)
+        } else if filter.exclude_alt_hits && self.has_alt_hits {
+            Some(DiscardReason::AltHits)
+        } else if filter.max_errors.is_some_and(|m| self.mismatches > m) {
+            Some(DiscardReason::MaxErrors)
+        } else if filter.max_error_rate.is_some_and(|r| self.error_rate() > r) {
+            Some(DiscardReason::MaxErrorRate)
+        } else if filter.min_identity.is_some_and(|m| self.identity() < m) {
+            Some(DiscardReason::MinIdentity)
+        } else if filter.min_alignment_score.is_some_and(|m| self.alignment_score < Some(m)) {
+            Some(DiscardReason::MinAlignmentScore)
+        } else if filter.too_far_below_best(self.alignment_score, best_score) {
+            Some(DiscardReason::MaxScoreDiff)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an error if the filter needs alignment scores and this alignment has none.
+    pub fn check_score(&self, filter: &AlignmentFilter) -> Result<(), Error> {
+        if filter.uses_scores() && self.alignment_score.is_none() {
+            return Err(Error::new(ErrorType::Input,
+                                  format!("alignment for read {} has no AS tag, which is needed \
+                                           for --min-alignment-score and --max-score-diff",
+                                          self.read_name)));
+        }
+        Ok(())
+    }
+
+    /// The number of mismatches and indels (NM) divided by the alignment length (matches,
+    /// insertions and deletions).
+    pub fn error_rate(&self) -> f64 {
+        let length: u32 = self.cigar_ops.iter()
+            .filter(|(_, op)| matches!(op, CigarOp::Match | CigarOp::Insertion |
+                                           CigarOp::Deletion))
+            .map(|(num, _)| num).sum();
+        if length == 0 {
+            return 0.0;
+        }
+        self.mismatches as f64 / length as f64
+    }
+
+    /// The fraction of the alignment length without a mismatch or indel, as reported by minimap2
+    /// and BBMap.
+    pub fn identity(&self) -> f64 {
+        (1.0 - self.error_rate()).max(0.0)
+    }
+
+    /// The weight of this alignment's sequences in the pileup: one, unless it was accepted by a
+    /// filter with --identity-weighting.
+    pub fn count_weight(&self) -> f64 {
+        self.count_weight
+    }
+
+    /// Sets the alignment's count weight from the filter which accepted it.
+    fn set_count_weight(&mut self, filter: &AlignmentFilter) {
+        self.count_weight = if filter.identity_weighting {
+            identity_weight(self.error_rate())
+        } else {
+            1.0
+        };
+    }
+
+    /// The NM tag: the number of mismatches and indel bases, as given by the aligner.
+    pub fn nm(&self) -> u32 {
+        self.mismatches
+    }
+
+    /// Counts the alignment's mismatches and indel bases from its CIGAR, read sequence and
+    /// reference sequence (for --validate-nm). Returns None if the read has no sequence or the
+    /// alignment goes past the end of the reference.
+    pub fn recompute_nm(&self, pileup: &Pileup) -> Option<(u32, u32)> {
+        if self.read_seq == "*" {
+            return None;
+        }
+        let read_seq = self.read_seq.as_bytes();
+        let (mut read_pos, mut ref_pos) = (0, self.ref_start);
+        let (mut mismatches, mut indel_bases) = (0, 0);
+        for &(num, op) in &self.cigar_ops {
+            let num_usize = num as usize;
+            match op {
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch => {
+                    for i in 0..num_usize {
+                        let ref_base = pileup.bases.get(ref_pos + i)?.original();
+                        if !ref_base.eq_ignore_ascii_case(&(*read_seq.get(read_pos + i)? as char)) {
+                            mismatches += 1;
+                        }
+                    }
+                    read_pos += num_usize;
+                    ref_pos += num_usize;
+                },
+                CigarOp::Insertion => { indel_bases += num; read_pos += num_usize; },
+                CigarOp::Deletion  => { indel_bases += num; ref_pos += num_usize; },
+                CigarOp::Skip      => ref_pos += num_usize,
+                CigarOp::SoftClip  => read_pos += num_usize,
+                CigarOp::HardClip | CigarOp::Padding => (),
+            }
+        }
+        (ref_pos <= pileup.bases.len()).then_some((mismatches, indel_bases))
+    }
+
+    pub fn summary(&self) -> AlignmentSummary {
+        AlignmentSummary { read_name: self.read_name.to_string(), ref_name: self.ref_name.clone(),
+                           flags: self.sam_flags, ref_start: self.ref_start,
+                           ref_end: self.get_ref_end(), mapq: self.mapq(),
+                           cigar: self.cigar.clone(),
+                           mismatches: self.mismatches, alignment_score: self.alignment_score,
+                           pass_qc: !self.failed }
+    }
+
+    /// Removes any clipping from the alignment, so it only covers the aligned part of the read.
+    /// This is used for long reads, which are rarely aligned end-to-end. Indels left at the ends
+    /// (e.g. 2S2I500M or 500M3D2S) are removed too, as a pileup needs an alignment to start and
+    /// end with a match.
+    pub fn strip_clips(&mut self) {
+        let mut start = 0;
+        while let Some(&(num, op)) = self.cigar_ops.first() {
+            match op {
+                CigarOp::SoftClip | CigarOp::Insertion => start += num as usize,
+                CigarOp::Deletion                      => self.ref_start += num as usize,
+                CigarOp::HardClip                      => {},
+                _                                      => break,
+            }
+            self.cigar_ops.remove(0);
+        }
+        let mut end = self.read_seq.len();
+        while let Some(&(num, op)) = self.cigar_ops.last() {
+            match op {
+                CigarOp::SoftClip | CigarOp::Insertion => end = end.saturating_sub(num as usize),
+                CigarOp::Deletion | CigarOp::HardClip  => {},
+                _                                      => break,
+            }
+            self.cigar_ops.pop();
+        }
+        if start <= end {
+            self.read_qual = self.quals().map(|q| Arc::from(&q[start..end]));
+            self.read_seq = self.read_seq[start..end].to_string();
+        }
+        self.cigar = self.cigar_ops.iter().map(|(num, op)| format!("{}{}", num, op.to_char()))
+            .collect();
+    }
+
+    /// Returns true if the alignment starts and ends with a match and has only M, I and D
+    /// operations, so its bases can be added to a pileup.
+    pub fn has_simple_cigar(&self) -> bool {
+        self.end_to_end_reason().is_none() &&
+            self.cigar_ops.iter().all(|(_, op)| matches!(op, CigarOp::Match |
+                                                          CigarOp::Insertion | CigarOp::Deletion))
+    }
+
+    /// Returns the index in the read sequence of the base aligned to a reference position (or the
+    /// next read base, for a deleted position). Positions beyond the ends of the alignment are
+    /// carried into its soft clips, and None is returned if the position is outside the read.
+    pub fn read_pos_at(&self, ref_pos: usize) -> Option<usize> {
+        let mut ops = self.cigar_ops.iter().filter(|(_, op)| *op != CigarOp::HardClip).peekable();
+        let mut read_i = match ops.peek() {
+            Some(&&(num, CigarOp::SoftClip)) => { ops.next(); num as usize },
+            _                                => 0,
+        };
+        if ref_pos < self.ref_start {
+            return read_i.checked_sub(self.ref_start - ref_pos);
+        }
+        let mut ref_i = self.ref_start;
+        for &(num, op) in ops {
+            let num = num as usize;
+            match op {
+                CigarOp::SoftClip => break,
+                _ if op.consumes_ref() && ref_pos < ref_i + num => {
+                    return Some(if op.consumes_read() { read_i + ref_pos - ref_i }
+                                else { read_i });
+                },
+                _ => {},
+            }
+            if op.consumes_ref() {
+                ref_i += num;
+            }
+            if op.consumes_read() {
+                read_i += num;
+            }
+        }
+        Some(read_i + ref_pos - ref_i).filter(|&i| i <= self.read_seq.len())
+    }
+
+    /// Alignments must start and end with a match. This gives the reason for those that don't:
+    /// hard clipping (the outermost operation), soft clipping or anything else (an indel).
+    fn end_to_end_reason(&self) -> Option<DiscardReason> {
+        let ends = [self.cigar_ops.first(), self.cigar_ops.last()];
+        if ends.iter().all(|op| matches!(op, Some((_, CigarOp::Match)))) {
+            None
+        } else if ends.iter().any(|op| matches!(op, Some((_, CigarOp::HardClip)))) {
+            Some(DiscardReason::HardClipped)
+        } else if ends.iter().any(|op| matches!(op, Some((_, CigarOp::SoftClip)))) {
+            Some(DiscardReason::SoftClipped)
+        } else {
+            Some(DiscardReason::NotEndToEnd)
+        }
     }
 
-    fn add_read_seq(&mut self, read_seq: &str, strand: i8) {
+    pub fn add_read_seq(&mut self, read_seq: &str, strand: i8) {
         if self.get_strand() == strand {
             self.read_seq = read_seq.to_string();
         } else {
@@ -165,6 +480,57 @@ impl Alignment {
         }
     }
 
+    /// Qualities from an alignment on the same strand are shared rather than copied.
+    pub fn add_read_qual(&mut self, read_qual: Option<&Arc<[u8]>>, strand: i8) {
+        self.read_qual = if self.get_strand() == strand {
+            read_qual.cloned()
+        } else {
+            read_qual.map(|q| q.iter().rev().copied().collect())
+        };
+    }
+
+    /// Returns the base qualities (Phred scores), or None if the read has none or they don't
+    /// match the read sequence.
+    fn quals(&self) -> Option<&[u8]> {
+        self.read_qual.as_deref().filter(|q| q.len() == self.read_seq.len())
+    }
+
+    /// Returns the read sequence for one of the ranges given by
+    /// get_read_bases_for_each_target_base, with "-" for a deletion.
+    pub fn read_seq_for_range(&self, (start, end): (usize, usize)) -> &str {
+        if start == end { "-" } else { &self.read_seq[start..end] }
+    }
+
+    /// Returns the mean base quality for one of the ranges given by
+    /// get_read_bases_for_each_target_base. Deletions use the bases on either side. If the read
+    /// has no qualities, this is zero.
+    pub fn mean_qual_for_range(&self, (start, end): (usize, usize)) -> f64 {
+        let Some(quals) = self.quals() else { return 0.0; };
+        let (start, end) = if start == end {
+            (start.saturating_sub(1), (end + 1).min(quals.len()))
+        } else {
+            (start, end)
+        };
+        let quals = &quals[start..end];
+        if quals.is_empty() {
+            return 0.0;
+        }
+        quals.iter().map(|&q| q as f64).sum::<f64>() / quals.len() as f64
+    }
+
+    /// Returns the base quality at a read position, or None if the read has no qualities.
+    pub fn base_qual(&self, read_pos: usize) -> Option<u8> {
+        self.quals()?.get(read_pos).copied()
+    }
+
+    /// Returns true if this alignment and the other are on opposite strands of the same reference
+    /// sequence with overlapping reference ranges, as the two mates of a short-insert pair are.
+    pub fn overlaps_mate(&self, other: &Alignment) -> bool {
+        self.ref_name == other.ref_name &&
+            self.is_on_forward_strand() != other.is_on_forward_strand() &&
+            self.ref_start < other.get_ref_end() && other.ref_start < self.get_ref_end()
+    }
+
     /// This function returns a vector giving the read base(s) for each position of the target
     /// sequence. Instead of returning these as strings (which would involve a lot of allocation
     /// of new strings to memory which is slow), it returns them as start/end indices of the read
@@ -173,29 +539,39 @@ impl Alignment {
     /// zero-length ranges (e.g. 5,5).
     pub fn get_read_bases_for_each_target_base(&self) -> Vec<(usize, usize)> {
         let mut i = 0;
-        let mut read_bases = Vec::with_capacity(self.expanded_cigar.len());
-        for c in self.expanded_cigar.chars() {
-            if c == 'M' {
-                read_bases.push((i, i+1));
-                i += 1;
-            } else if c == 'I' {
-                read_bases.last_mut().unwrap().1 = i+1;
-                i += 1;
-            } else if c == 'D' {
-                read_bases.push((i, i));
-            } else {
-                // Since non-end-to-end alignments have already been filtered out, the only CIGAR
-                // operations we should encounter here are M, I and D.
-                quit_with_error(&format!("unexpected character (other than M, I or D) in CIGAR \
-                                          string for read {}: {:?} - did you use BWA MEM to \
-                                          generate your alignments?", self.read_name, self.cigar));
+        let ref_len = self.cigar_ops.iter().filter(|(_, op)| op.consumes_ref())
+            .map(|&(num, _)| num as usize).sum();
+        let mut read_bases = Vec::with_capacity(ref_len);
+        for &(num, op) in &self.cigar_ops {
+            let num = num as usize;
+            match op {
+                CigarOp::Match => {
+                    read_bases.extend((i..i+num).map(|j| (j, j+1)));
+                    i += num;
+                },
+                CigarOp::Insertion => {
+                    read_bases.last_mut().unwrap().1 = i+num;
+                    i += num;
+                },
+                CigarOp::Deletion => {
+                    read_bases.extend(std::iter::repeat((i, i)).take(num));
+                },
+                _ => {
+                    // Since non-end-to-end alignments have already been filtered out, the only
+                    // CIGAR operations we should encounter here are M, I and D.
+                    quit_with_error(ErrorType::Input,
+                                    &format!("unexpected character (other than M, I or D) in \
+                                              CIGAR string for read {}: {:?} - did you use BWA \
+                                              MEM to generate your alignments?",
+                                             self.read_name, self.cigar));
+                },
             }
         }
         if i != self.read_seq.len() {
-            quit_with_error(&format!("CIGAR string for read {} does not match read sequence",
+            quit_with_error(ErrorType::Input,
+                            &format!("CIGAR string for read {} does not match read sequence",
                                      self.read_name));
         }
-        trim_bases_for_homopolymers(&mut read_bases, &self.read_seq);
         read_bases
     }
 }
@@ -210,212 +586,1845 @@ impl fmt::Display for Alignment {
 }
 
 
-pub fn process_sam(filename: &PathBuf, pileups: &mut HashMap<String, Pileup>,
-                   max_errors: u32, careful: bool) -> (usize, usize, usize) {
-    let result = add_to_pileup(filename, pileups, max_errors, careful);
-    match result {
-        Ok((_,_,_)) => (),
-        Err(_)      => quit_with_error(&format!("unable to load alignments from {:?}", filename)),
-    }
-    result.unwrap()
+/// The lines of an alignment file, which can be SAM, BAM or PAF, possibly gzipped (BAM and PAF
+/// records are converted to SAM lines). Compressed files are decompressed on a separate thread.
+/// It also keeps track of how many bytes of the file have been read, for progress reporting.
+/// Lines can be taken as Strings (as an Iterator) or borrowed with next_line, which saves copying
+/// them out of a memory-mapped file (--mmap).
+pub struct SamLines {
+    lines: Lines,
+    line: String,         // the last line taken, unless it's in the map
+    filename: PathBuf,
+    pub run: RunContext,  // for --mmap and --skip-bad-records
+    line_count: usize,
+    bytes_read: Arc<AtomicU64>,
+    pub file_size: u64,
+    failed: Vec<u64>,     // lines which failed the read pair filter (polish --paired)
+    fail_tag: String,     // added to the failed lines
+    returned_count: usize,
 }
 
+impl SamLines {
+    pub fn open(filename: &Path, run: &RunContext) -> io::Result<SamLines> {
+        let file = File::open(filename)?;
+        let file_size = file.metadata()?.len();
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let is_bam = bam::is_bam(filename);
+        let is_gzipped = !is_bam && file_size >= 2 && misc::is_file_gzipped(filename);
+        let is_paf = !is_bam && paf::is_paf(filename);
+        let lines = if run.use_mmap && !is_bam && !is_gzipped && !is_paf {
+            Lines::Mapped(MappedLines::new(&file, bytes_read.clone())?)
+        } else {
+            let reader = CountingReader { inner: file, bytes_read: bytes_read.clone() };
+            let mut lines: Box<dyn Iterator<Item = io::Result<String>>> = if is_bam {
+                Box::new(bam::BamLines::new(ThreadedGzReader::new(reader))?)
+            } else if is_gzipped {
+                Box::new(BufReader::new(ThreadedGzReader::new(reader)).lines())
+            } else {
+                Box::new(BufReader::new(reader).lines())
+            };
+            if is_paf {
+                lines = Box::new(paf::PafLines::new(lines));
+            }
+            Lines::Owned(lines)
+        };
+        Ok(SamLines { lines, line: String::new(), filename: filename.to_path_buf(),
+                      run: run.clone(), line_count: 0, bytes_read, file_size, failed: Vec::new(),
+                      fail_tag: String::new(), returned_count: 0 })
+    }
 
-pub fn add_to_pileup(filename: &PathBuf, pileups: &mut HashMap<String, Pileup>,
-                     max_errors: u32, careful: bool) -> io::Result<(usize, usize, usize)> {
-    let file = File::open(&filename)?;
-    let reader = BufReader::new(file);
+    /// Lines from a stream rather than a file, so there's no size for progress reporting.
+    pub fn from_lines(name: &Path, lines: Box<dyn Iterator<Item = io::Result<String>>>,
+                      run: &RunContext) -> SamLines {
+        SamLines { lines: Lines::Owned(lines), line: String::new(), filename: name.to_path_buf(),
+                   run: run.clone(), line_count: 0, bytes_read: Arc::new(AtomicU64::new(0)),
+                   file_size: 0, failed: Vec::new(), fail_tag: String::new(), returned_count: 0 }
+    }
 
-    let mut current_read_name = String::new();
-    let mut current_read_alignments = Vec::new();
+    /// Tags the lines which failed the read pair filter (one bit per returned line) with
+    /// a fail tag, as polypolish filter would, so they aren't used for polishing.
+    pub fn with_failures(mut self, failed: Vec<u64>, fail_tag: &str) -> SamLines {
+        self.failed = failed;
+        self.fail_tag = format!("\t{}", fail_tag);
+        self
+    }
 
-    let mut line_count: usize = 0;
-    let mut alignment_count: usize = 0;
-    let mut used_count: usize = 0;
-    let mut read_count: usize = 0;
+    /// The number of bytes read from the file (compressed bytes, if the file is compressed).
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
 
-    for line in reader.lines() {
-        line_count += 1;
-        let sam_line = line?;
-        if sam_line.len() == 0 {continue;}
-        if sam_line.starts_with('@') {continue;}
+    /// Returns the next line, borrowed from the memory map if the file is mapped and the line
+    /// doesn't need a fail tag.
+    pub fn next_line(&mut self) -> Option<io::Result<&str>> {
+        match self.advance()? {
+            Ok(Some(range)) => Some(Ok(self.lines.mapped_line(range))),
+            Ok(None)        => Some(Ok(&self.line)),
+            Err(e)          => Some(Err(e)),
+        }
+    }
 
-        let alignment_result = Alignment::new(&sam_line);
-        match alignment_result {
-            Ok(_)  => (),
-            Err(e) => quit_with_error(&format!("{} in {:?} (line {})", e, filename, line_count)),
+    /// Takes the next line, leaving it in the map (returning its range) or in self.line. With
+    /// --skip-bad-records, a line which can't be read (e.g. a malformed BAM record) is skipped,
+    /// and a file which can't be read any further (e.g. a truncated gzip) is treated as ending
+    /// there.
+    fn advance(&mut self) -> Option<io::Result<Option<Range<usize>>>> {
+        let range = loop {
+            let line = match &mut self.lines {
+                Lines::Mapped(lines) => lines.next_range()?.map(Some),
+                Lines::Owned(lines)  => lines.next()?.map(|line| { self.line = line; None }),
+            };
+            self.line_count += 1;
+            match line {
+                Ok(range) => break range,
+                Err(e) if self.run.skipping_bad_records() => {
+                    let ended = e.kind() != io::ErrorKind::InvalidData;
+                    let error = if ended { "unreadable data (file may be truncated)" }
+                                else { "invalid record" };
+                    self.run.bad_record(error, &self.filename, self.line_count, "");
+                    if ended {
+                        return None;
+                    }
+                },
+                Err(e) => return Some(Err(e)),
+            }
+        };
+        let i = self.returned_count;
+        self.returned_count += 1;
+        if !self.failed.get(i / 64).is_some_and(|bits| bits & (1 << (i % 64)) != 0) {
+            return Some(Ok(range));
         }
-        let alignment = alignment_result.unwrap();
-        if !alignment.is_aligned() {continue;}
+        if let Some(range) = range {
+            self.line = self.lines.mapped_line(range).to_string();
+        }
+        self.line.push_str(&self.fail_tag);
+        Some(Ok(None))
+    }
+}
 
-        alignment_count += 1;
-        let read_name = alignment.read_name.clone();
+impl Iterator for SamLines {
+    type Item = io::Result<String>;
 
-        if current_read_name.is_empty() || current_read_name == alignment.read_name {
-            current_read_alignments.push(alignment);
-        } else {
-            used_count += process_one_read(current_read_alignments, pileups, max_errors, careful);
-            read_count += 1;
-            current_read_alignments = Vec::new();
-            current_read_alignments.push(alignment);
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance()? {
+            Ok(Some(range)) => Some(Ok(self.lines.mapped_line(range).to_string())),
+            Ok(None)        => Some(Ok(std::mem::take(&mut self.line))),
+            Err(e)          => Some(Err(e)),
         }
-        current_read_name = read_name;
     }
-    used_count += process_one_read(current_read_alignments, pileups, max_errors, careful);
-    read_count += 1;
+}
 
-    if alignment_count == 0 {
-        quit_with_error(&format!("no alignments in {:?}", filename))
+
+enum Lines {
+    Mapped(MappedLines),
+    Owned(Box<dyn Iterator<Item = io::Result<String>>>),
+}
+
+impl Lines {
+    fn mapped_line(&self, range: Range<usize>) -> &str {
+        match self {
+            Lines::Mapped(lines) => lines.line(range),
+            Lines::Owned(_)      => unreachable!(),
+        }
     }
-    Ok((alignment_count, used_count, read_count))
 }
 
 
-fn process_one_read(alignments: Vec<Alignment>, pileups: &mut HashMap<String, Pileup>,
-                    max_errors: u32, careful: bool) -> usize {
-    if careful && alignments.len() > 1 {
-        return 0;
+struct CountingReader<R: Read> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(count as u64, Ordering::Relaxed);
+        Ok(count)
     }
-    let (read_seq, strand) = get_read_seq_from_alignments(&alignments);
+}
 
-    let mut good_alignments = Vec::new();
-    for a in alignments {
-        if a.starts_and_ends_with_match() && a.mismatches <= max_errors && a.pass_qc{
-            good_alignments.push(a);
-        }
+
+/// How many alignments are looked at when checking whether a file is sorted by position.
+const SORT_CHECK_SAMPLE_SIZE: usize = 10000;
+
+/// How many alignments are looked at when checking for XA tags.
+const ALT_HIT_CHECK_SAMPLE_SIZE: usize = 10000;
+
+/// How many alignments are held in memory at once when sorting a file by read name.
+const SORT_CHUNK_SIZE: usize = 200000;
+
+
+/// An alignment's metadata (everything but the read's sequence and qualities), for reports and
+/// downstream tools.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlignmentSummary {
+    pub read_name: String,
+    pub ref_name: String,
+    pub flags: u32,
+    pub ref_start: usize,  // 0-based
+    pub ref_end: usize,    // exclusive
+    pub mapq: Option<u8>,
+    pub cigar: String,
+    pub mismatches: u32,   // from the NM tag (u32::MAX if it had none)
+    pub alignment_score: Option<i32>,
+    pub pass_qc: bool,     // false if failed by a pre-filter
+}
+
+
+/// The limits on an alignment's errors and score for it to be used for polishing, and how a read's
+/// depth is shared between its usable alignments. The absolute error limit (--max_errors) is
+/// replaced by the rate limit (--max-error-rate) or the identity limit (--min-identity) when
+/// either is given.
+#[derive(Clone, Debug, Default)]
+pub struct AlignmentFilter {
+    pub max_errors: Option<u32>,
+    pub max_error_rate: Option<f64>,
+    pub min_identity: Option<f64>,
+    pub min_alignment_score: Option<i32>,
+    pub max_score_diff: Option<i32>,  // relative to the best alignment score for the read
+    pub careful: bool,                // skip reads with multiple alignments
+    pub exclude_qcfail: bool,         // alignments with the QCFAIL flag (0x200)
+    pub exclude_alt_hits: bool,       // alignments whose other placements are in an XA tag
+    pub max_hits: Option<usize>,      // reads with more alignments than this are skipped
+    pub placement_weights: PlacementWeights,
+    pub identity_weighting: bool,     // count alignments' sequences by their error rate
+    pub fail_tags: Vec<String>,       // failed by a pre-filter (DEFAULT_FAIL_TAG if empty)
+
+    /// Alignments to these sequences are skipped when they have no pileup, instead of being an
+    /// error: with --batch-size, the sequences in other batches, and for a shard from polypolish
+    /// split, the other sequences in its header.
+    pub skippable_seqs: FastHashSet<String>,
+
+    /// The run's shared settings for reading files and its records of skipped records, NM checks
+    /// and warnings.
+    pub run: RunContext,
+}
+
+impl AlignmentFilter {
+    /// The tag used to mark alignments failed by the read pair filter while loading (polish
+    /// --paired), so they are recognised like those from polypolish filter.
+    pub fn fail_tag(&self) -> &str {
+        self.fail_tags.first().map_or(DEFAULT_FAIL_TAG, String::as_str)
     }
-    let depth_contribution = 1.0 / good_alignments.len() as f64;
 
-    for a in &mut good_alignments {
-        let needs_length = a.read_seq == "*";
-        if needs_length {
-            a.add_read_seq(&read_seq, strand);
-        }
+    fn uses_scores(&self) -> bool {
+        self.min_alignment_score.is_some() || self.max_score_diff.is_some()
     }
 
-    for a in &good_alignments {
-        if !pileups.contains_key(&a.ref_name) {
-            quit_with_error(&format!("query name {} in SAM but not in assembly", a.ref_name))
+    /// Whether an alignment's score is further below its read's best score than --max-score-diff
+    /// allows.
+    fn too_far_below_best(&self, score: Option<i32>, best_score: Option<i32>) -> bool {
+        match (self.max_score_diff, score, best_score) {
+            (Some(diff), Some(score), Some(best)) => best - score > diff,
+            _                                     => false,
         }
-        let pileup = pileups.get_mut(&a.ref_name).unwrap();
-        pileup.add_alignment(a, depth_contribution);
     }
-    good_alignments.len()
 }
 
 
-/// This function takes a vector of all the alignments for one read. At least one of these
-/// alignments should have the read seq included (i.e. not just "*"). This function will return
-/// that sequence and its strand.
-fn get_read_seq_from_alignments(alignments: &Vec<Alignment>) -> (String, i8) {
-    for a in alignments {
-        if a.read_seq == "*" {
-            continue;
-        } else {
-            return (a.read_seq.clone(), a.get_strand());
+/// Why an alignment wasn't used for polishing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscardReason {
+    Careful,            // from a read with multiple alignments (--careful)
+    MaxHits,            // from a read with more alignments than --max-hits
+    SoftClipped,        // soft-clipped at either end
+    HardClipped,        // hard-clipped at either end
+    NotEndToEnd,        // starts or ends with something other than a match (e.g. an indel)
+    FailedFilter,       // failed by a pre-filter (e.g. ZP:Z:fail from Polypolish filter)
+    QcFail,             // has the QCFAIL flag (--exclude-qcfail)
+    AltHits,            // other placements only in an XA tag (--exclude-xa)
+    MaxErrors,          // more mismatches and indels than --max_errors
+    MaxErrorRate,       // higher error rate than --max-error-rate
+    MinIdentity,        // lower identity than --min-identity
+    MinAlignmentScore,  // lower AS than --min-alignment-score
+    MaxScoreDiff,       // AS further below the read's best than --max-score-diff
+    BadRecord,          // from a read with a malformed record (--skip-bad-records)
+}
+
+impl DiscardReason {
+    pub const ALL: [DiscardReason; 14] = [DiscardReason::Careful, DiscardReason::MaxHits,
+                                          DiscardReason::SoftClipped, DiscardReason::HardClipped,
+                                          DiscardReason::NotEndToEnd, DiscardReason::FailedFilter,
+                                          DiscardReason::QcFail, DiscardReason::AltHits,
+                                          DiscardReason::MaxErrors, DiscardReason::MaxErrorRate,
+                                          DiscardReason::MinIdentity,
+                                          DiscardReason::MinAlignmentScore,
+                                          DiscardReason::MaxScoreDiff, DiscardReason::BadRecord];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DiscardReason::Careful           => "careful",
+            DiscardReason::MaxHits           => "max_hits",
+            DiscardReason::SoftClipped       => "soft_clipped",
+            DiscardReason::HardClipped       => "hard_clipped",
+            DiscardReason::NotEndToEnd       => "not_end_to_end",
+            DiscardReason::FailedFilter      => "failed_filter",
+            DiscardReason::QcFail            => "qc_fail",
+            DiscardReason::AltHits           => "alt_hits",
+            DiscardReason::MaxErrors         => "max_errors",
+            DiscardReason::MaxErrorRate      => "max_error_rate",
+            DiscardReason::MinIdentity       => "min_identity",
+            DiscardReason::MinAlignmentScore => "min_alignment_score",
+            DiscardReason::MaxScoreDiff      => "max_score_diff",
+            DiscardReason::BadRecord         => "bad_record",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            DiscardReason::Careful           => "from reads with multiple alignments",
+            DiscardReason::MaxHits           => "from reads with more than --max-hits alignments",
+            DiscardReason::SoftClipped       => "soft-clipped at an end",
+            DiscardReason::HardClipped       => "hard-clipped at an end",
+            DiscardReason::NotEndToEnd       => "starting or ending with an indel",
+            DiscardReason::FailedFilter      => "failed by a pre-filter (fail tag)",
+            DiscardReason::QcFail            => "QCFAIL flag",
+            DiscardReason::AltHits           => "other placements only in an XA tag",
+            DiscardReason::MaxErrors         => "more than --max_errors",
+            DiscardReason::MaxErrorRate      => "above --max-error-rate",
+            DiscardReason::MinIdentity       => "below --min-identity",
+            DiscardReason::MinAlignmentScore => "below --min-alignment-score",
+            DiscardReason::MaxScoreDiff      => "more than --max-score-diff below the read's best",
+            DiscardReason::BadRecord         => "from reads with a skipped malformed record",
         }
     }
-    let read_name = &alignments.first().unwrap().read_name;
-    quit_with_error(&format!("no alignments for read {} contain sequence", read_name));
-    unreachable!();
 }
 
 
-fn get_expanded_cigar(cigar: &str, read_seq_len: usize) -> Result<String, ()> {
-    if cigar == "*" {
-        return Ok("".to_string());
+/// The number of discarded alignments for each reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiscardCounts([usize; DiscardReason::ALL.len()]);
+
+impl DiscardCounts {
+    pub fn add(&mut self, reason: DiscardReason) {
+        self.add_many(reason, 1);
     }
-    let mut expanded_cigar = String::with_capacity(read_seq_len);
-    let mut total_len = 0;
-    for m in RE.find_iter(cigar) {
-        let num: u32 = cigar[m.start()..m.end()-1].parse().unwrap();
-        let letter = &cigar[m.end()-1..m.end()];
-        for _ in 0..num {
-            expanded_cigar.push_str(letter);
+
+    pub fn add_many(&mut self, reason: DiscardReason, count: usize) {
+        self.0[reason as usize] += count;
+    }
+
+    pub fn merge(&mut self, other: &DiscardCounts) {
+        for (count, other_count) in self.0.iter_mut().zip(other.0) {
+            *count += other_count;
         }
-        total_len += m.end() - m.start();
     }
-    // As a sanity check, we make sure that the total length of the regex-extracted pieces matches
-    // the length of the original CIGAR string. If not, that means part of the CIGAR wasn't grabbed
-    // by the regex, and therefore the CIGAR isn't valid.
-    if cigar.len() != total_len {
-        return Err(());
+
+    pub fn get(&self, reason: DiscardReason) -> usize {
+        self.0[reason as usize]
     }
-    Ok(expanded_cigar)
 }
 
-
-/// Alignments that end in a homopolymer can cause trouble, as they can align cleanly
-/// (without an indel) even when an indel is needed.
-///
-/// For example, an alignment should look like this:
-///   read: ... T G A G T A C AG G G G G A A G T
-///   ref:  ... T G A G T A C A  G G G G A A G T C C A G T ...
-///
-/// But if the read ends in the homopolymer, it could look like this:
-///   read: ... T G A G T A C A G G
-///   ref:  ... T G A G T A C A G G G G A A G T C C A G T ...
-///
-/// Which results in a clean alignment on the 'A' that should be 'AG'. To avoid this, we
-/// trim off the last couple unique bases of the alignment, so the example becomes:
-///   read: ... T G A G T A C
-///   ref:  ... T G A G T A C A G G G G A A G T C C A G T ...
-fn trim_bases_for_homopolymers(read_bases: &mut Vec<(usize, usize)>, read_seq: &str) {
-    let (last_start, last_end) = *read_bases.last().unwrap();
-    let last_base = &read_seq[last_start..last_end];
-    while read_bases.len() > 0 {
-        let (current_last_start, current_last_end) = *read_bases.last().unwrap();
-        let current_last_base = &read_seq[current_last_start..current_last_end];
-        if current_last_base != last_base {
-            break;
+/// Serialised as a map from each reason's name to its count.
+impl Serialize for DiscardCounts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(DiscardReason::ALL.len()))?;
+        for reason in DiscardReason::ALL {
+            map.serialize_entry(reason.name(), &self.get(reason))?;
         }
-        read_bases.pop();
+        map.end()
     }
-    if read_bases.len() > 0 {
-        read_bases.pop();
+}
+
+impl<'de> Deserialize<'de> for DiscardCounts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = FastHashMap::<String, usize>::deserialize(deserializer)?;
+        let mut counts = DiscardCounts::default();
+        for reason in DiscardReason::ALL {
+            // Clipped alignments were once counted as not end-to-end and --max-hits is newer, so
+            // older checkpoints don't have them.
+            let optional = matches!(reason, DiscardReason::MaxHits | DiscardReason::SoftClipped |
+                                            DiscardReason::HardClipped);
+            let Some(&count) = map.get(reason.name()).or(optional.then_some(&0)) else {
+                return Err(D::Error::missing_field(reason.name()));
+            };
+            counts.add_many(reason, count);
+        }
+        Ok(counts)
     }
 }
 
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// What we need to know about a read with multiple alignments when its alignments aren't next to
+/// each other in the file (e.g. when it's sorted by position). This is gathered in a scan before
+/// the alignments are used.
+#[derive(Default)]
+pub struct MultiAlignedRead {
+    total: u32,                       // number of alignments
+    usable: u32,                      // number of alignments usable for polishing
+    seq: Option<(String, i8)>,        // read sequence and strand (secondaries may lack it)
+    best_score: Option<i32>,          // highest alignment score (AS) of any alignment
+    placements: Vec<Placement>,       // only used during the scan, for usable and shares
+    shares: PlacementShares,          // depth shares of the usable alignments (--placement-weights)
+}
 
-    #[test]
-    fn test_get_expanded_cigar_good() {
-        assert_eq!(get_expanded_cigar("10M", 10).unwrap(), "MMMMMMMMMM");
-        assert_eq!(get_expanded_cigar("3M1I7M", 11).unwrap(), "MMMIMMMMMMM");
-        assert_eq!(get_expanded_cigar("5M2D4M", 9).unwrap(), "MMMMMDDMMMM");
-        assert_eq!(get_expanded_cigar("*", 1).unwrap(), "");
+
+/// The alignment, used alignment and read counts for one alignment file.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SamCounts {
+    pub alignments: usize,
+    pub used: usize,
+    pub reads: usize,
+    pub multi_aligned_reads: usize,  // reads with more than one alignment
+    #[serde(default)]
+    pub unmapped: usize,             // unmapped records, which are skipped
+}
+
+
+pub fn process_sam(filename: &Path, pileups: &mut FastHashMap<String, Pileup>,
+                   filter: &AlignmentFilter, auto_sort: bool,
+                   downsampler: &Downsampler, discarded: &mut DiscardCounts) -> SamCounts {
+    let result = load_sam(filename, pileups, filter, auto_sort, downsampler, discarded);
+    result.unwrap_or_else(|e| e.quit())
+}
+
+
+/// Adds a file's alignments to the pileup for polish --paired, with the alignments which failed
+/// the read pair filter tagged as they are read. The file must be grouped by read, as the failed
+/// alignments are identified by their line.
+pub fn process_paired_sam(filename: &Path, failed: Vec<u64>,
+                          pileups: &mut FastHashMap<String, Pileup>, filter: &AlignmentFilter,
+                          downsampler: &Downsampler, discarded: &mut DiscardCounts) -> SamCounts {
+    let result = SamLines::open(filename, &filter.run).map_err(|e| load_error(e, filename))
+        .and_then(|lines| {
+            let source = SamSource::new(filename, lines.with_failures(failed, filter.fail_tag()))
+                .with_fail_tags(&filter.fail_tags).downsampled(*downsampler);
+            add_to_pileup(source, pileups, filter, discarded)
+        });
+    result.unwrap_or_else(|e| e.quit())
+}
+
+
+/// Adds a file's alignments to the pileup. Files which aren't grouped by read are either sorted
+/// by read name first (with --auto-sort) or, if they are sorted by position, regrouped.
+fn load_sam(filename: &Path, pileups: &mut FastHashMap<String, Pileup>,
+            filter: &AlignmentFilter, auto_sort: bool, downsampler: &Downsampler,
+            discarded: &mut DiscardCounts) -> Result<SamCounts, Error> {
+    let io_error = |e| load_error(e, filename);
+    let sorted_by_position = is_sorted_by_position(filename, &filter.run).map_err(io_error)?;
+    let needs_sort = auto_sort &&
+        (sorted_by_position || !is_grouped_by_read(filename, &filter.run).map_err(io_error)?);
+    if needs_sort {
+        log::text!("{} is not grouped by read, so it will be sorted by read name",
+                   filename.display());
+        log::event("sam_sorted", json!({"file": filename}));
+        let sorted = sort_by_read_name(filename, &filter.run).map_err(io_error)?;
+        let lines = SamLines::open(sorted.path(), &filter.run).map_err(io_error)?;
+        let source = SamSource::new(filename, lines).with_fail_tags(&filter.fail_tags)
+            .downsampled(*downsampler);
+        add_to_pileup(source, pileups, filter, discarded)
+    } else if sorted_by_position {
+        log::text!("{} is sorted by position, so its alignments will be regrouped by read",
+                   filename.display());
+        log::event("sam_regrouped", json!({"file": filename}));
+        add_to_pileup_regrouped(filename, pileups, filter, downsampler, discarded)
+            .map_err(io_error)
+    } else {
+        let source = SamSource::open(filename, &filter.run).map_err(io_error)?
+            .with_fail_tags(&filter.fail_tags).downsampled(*downsampler);
+        add_to_pileup(source, pileups, filter, discarded)
     }
+}
 
-    #[test]
-    fn test_get_expanded_cigar_bad() {
-        assert!(get_expanded_cigar("10Q", 10).is_err());        // 'Q' isn't a CIGAR operator
-        assert!(get_expanded_cigar("10MM1I10M", 11).is_err());  // can't have consecutive letters
-        assert!(get_expanded_cigar("100M5", 9).is_err());       // can't end on a number
+
+/// Turns an error from reading alignments into one which ends the run: an Error carried by the
+/// io::Error (e.g. a bad record which can't be skipped) is returned as it is, and anything else
+/// means the alignments couldn't be read.
+fn load_error(error: io::Error, filename: &Path) -> Error {
+    match error.into_inner().map(|e| e.downcast::<Error>()) {
+        Some(Ok(error)) => *error,
+        _               => Error::new(ErrorType::Io,
+                                      format!("unable to load alignments from {:?}", filename)),
     }
+}
 
-    #[test]
-    fn test_get_ref_positions() {
-        let a_str = format!("r_1\t0\tx\t{}\t60\t4M\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
-        let alignment = Alignment::new(&a_str).unwrap();
-        assert_eq!(alignment.ref_start, 999);
-        assert_eq!(alignment.get_ref_end(), 1003);
 
-        let a_str = format!("r_1\t0\tx\t{}\t60\t2=1X1=\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
-        let alignment = Alignment::new(&a_str).unwrap();
-        assert_eq!(alignment.ref_start, 999);
-        assert_eq!(alignment.get_ref_end(), 1003);
+/// Adds a source's alignments to the pileup, one read at a time.
+pub fn add_to_pileup(mut source: impl AlignmentSource,
+                     pileups: &mut FastHashMap<String, Pileup>, filter: &AlignmentFilter,
+                     discarded: &mut DiscardCounts) -> Result<SamCounts, Error> {
+    let filename = source.name().to_path_buf();
+    let filename = filename.as_path();
+    let mut progress = Progress::new(&filename.display().to_string(), Unit::Bytes, source.size(),
+                                     "alignments");
 
-        let a_str = format!("r_1\t0\tx\t{}\t60\t2M1I1M\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
-        let alignment = Alignment::new(&a_str).unwrap();
-        assert_eq!(alignment.ref_start, 999);
-        assert_eq!(alignment.get_ref_end(), 1002);
+    let mut current_read_name: Arc<str> = Arc::from("");
+    let mut current_read_alignments = Vec::new();
 
-        let a_str = format!("r_1\t0\tx\t{}\t60\t2M1D1M\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
-        let alignment = Alignment::new(&a_str).unwrap();
-        assert_eq!(alignment.ref_start, 999);
-        assert_eq!(alignment.get_ref_end(), 1003);
+    let mut alignment_count: usize = 0;
+    let mut used_count: usize = 0;
+    let mut read_count: usize = 0;
+    let mut multi_aligned_count: usize = 0;
+    let mut unmapped_count: usize = 0;
+
+    while let Some(alignment) = source.next() {
+        let alignment = alignment.map_err(|e| load_error(e, filename))?;
+        progress.update(source.position(), alignment_count as u64);
+        if !alignment.is_aligned() {
+            unmapped_count += 1;
+            continue;
+        }
+        filter.run.check_nm(filename, &alignment, pileups);
+
+        alignment_count += 1;
+        let read_name = alignment.read_name.clone();
+
+        if current_read_name.is_empty() || current_read_name == alignment.read_name {
+            current_read_alignments.push(alignment);
+        } else {
+            if current_read_alignments.len() > 1 {
+                multi_aligned_count += 1;
+            }
+            if check_read_is_grouped(&current_read_alignments, filename, &filter.run,
+                                     discarded)? {
+                used_count += process_one_read(current_read_alignments, pileups, filter,
+                                               discarded)?;
+            }
+            read_count += 1;
+            current_read_alignments = vec![alignment];
+        }
+        current_read_name = read_name;
+    }
+    if current_read_alignments.len() > 1 {
+        multi_aligned_count += 1;
+    }
+    if check_read_is_grouped(&current_read_alignments, filename, &filter.run, discarded)? {
+        used_count += process_one_read(current_read_alignments, pileups, filter, discarded)?;
+    }
+    read_count += 1;
+    progress.finish();
+
+    if alignment_count == 0 {
+        let unmapped = if unmapped_count > 0 {
+            format!(" (all {} records are unmapped)", unmapped_count)
+        } else {
+            String::new()
+        };
+        return Err(Error::new(ErrorType::NoAlignments,
+                              format!("no alignments in {:?}{}", filename, unmapped)));
+    }
+    Ok(SamCounts { alignments: alignment_count, used: used_count, reads: read_count,
+                   multi_aligned_reads: multi_aligned_count, unmapped: unmapped_count })
+}
+
+
+/// Every read has exactly one primary alignment, so a group of alignments without one means that
+/// the read's alignments aren't all together in the file. Depth contributions would then be wrong,
+/// so this returns an error. An empty group (a file with no alignments) is fine here. If the
+/// missing primary alignment was skipped as a bad record, this returns false and the read's other
+/// alignments are discarded.
+fn check_read_is_grouped(alignments: &[Alignment], filename: &Path, run: &RunContext,
+                         discarded: &mut DiscardCounts) -> Result<bool, Error> {
+    if alignments.is_empty() || alignments.iter().any(|a| a.is_primary()) {
+        return Ok(true);
+    }
+    if run.read_has_bad_record(&alignments[0].read_name) {
+        discarded.add_many(DiscardReason::BadRecord, alignments.len());
+        return Ok(false);
+    }
+    Err(Error::new(ErrorType::Input,
+                   format!("the alignments for read {} are not together in {:?} - Polypolish \
+                            needs each read's alignments to be grouped (as bwa mem outputs \
+                            them), so please sort the file by read name (e.g. samtools sort -n) \
+                            or use --auto-sort", alignments[0].read_name, filename)))
+}
+
+
+/// Returns true if each read's alignments are together in the file. Every read has exactly one
+/// primary alignment, so a group of alignments without one means that a read's alignments are
+/// split up.
+pub fn is_grouped_by_read(filename: &Path, run: &RunContext) -> io::Result<bool> {
+    let mut lines = SamLines::open(filename, run)?;
+    let mut progress = Progress::new(&format!("Checking {}", filename.display()), Unit::Bytes,
+                                     lines.file_size, "");
+    let mut current_read_name = String::new();
+    let mut has_primary = true;
+    while let Some(line) = lines.next() {
+        let sam_line = line?;
+        progress.update(lines.bytes_read(), 0);
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        let Ok(alignment) = Alignment::new_quick(&sam_line) else { continue; };
+        if !alignment.is_aligned() {continue;}
+        if *alignment.read_name != current_read_name {
+            if !has_primary {
+                break;
+            }
+            current_read_name = alignment.read_name.to_string();
+            has_primary = false;
+        }
+        has_primary |= alignment.is_primary();
+    }
+    progress.finish();
+    Ok(has_primary)
+}
+
+
+/// Writes a file's alignments to a temporary file, sorted by read name. The sort is stable, so
+/// each read's alignments stay in their original order.
+fn sort_by_read_name(filename: &Path, run: &RunContext) -> io::Result<SortedFile> {
+    let mut lines = SamLines::open(filename, run)?;
+    let mut progress = Progress::new(&format!("Sorting {}", filename.display()), Unit::Bytes,
+                                     lines.file_size, "alignments");
+    let mut sorter = ExternalSorter::new(SORT_CHUNK_SIZE, read_name_key)?;
+    let mut alignment_count: u64 = 0;
+    while let Some(line) = lines.next() {
+        let sam_line = line?;
+        progress.update(lines.bytes_read(), alignment_count);
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        sorter.push(sam_line)?;
+        alignment_count += 1;
+    }
+    progress.finish();
+    sorter.finish()
+}
+
+
+pub fn read_name_key(sam_line: &str) -> &str {
+    sam_line.split('\t').next().unwrap()
+}
+
+
+pub fn process_sam_pair(filenames: [&Path; 2], pileups: &mut FastHashMap<String, Pileup>,
+                        filter: &AlignmentFilter, auto_sort: bool, downsampler: &Downsampler,
+                        discarded: &mut DiscardCounts) -> ([SamCounts; 2], usize) {
+    let result = add_pair_to_pileup(filenames, pileups, filter, auto_sort, downsampler,
+                                    discarded);
+    match result {
+        Ok(counts) => counts,
+        Err(_)     => quit_with_error(ErrorType::Io,
+                                      &format!("unable to load alignments from {:?} and {:?}",
+                                               filenames[0], filenames[1])),
+    }
+}
+
+
+/// Adds alignments to the pileup from the two files of a read pair (first mates and second
+/// mates), which must have the same reads in the same order. This lets overlapping mates be
+/// counted once. Along with the alignment, used and read counts for each file, this returns the
+/// number of overlapping mate pairs.
+fn add_pair_to_pileup(filenames: [&Path; 2], pileups: &mut FastHashMap<String, Pileup>,
+                      filter: &AlignmentFilter, auto_sort: bool,
+                      downsampler: &Downsampler, discarded: &mut DiscardCounts)
+        -> io::Result<([SamCounts; 2], usize)> {
+    let mut sorted_files = Vec::new();  // keeps the temporary sorted files until we're done
+    let mut groups = Vec::new();
+    for filename in filenames {
+        let sorted_by_position = is_sorted_by_position(filename, &filter.run)?;
+        let needs_sort = auto_sort &&
+            (sorted_by_position || !is_grouped_by_read(filename, &filter.run)?);
+        let lines = if needs_sort {
+            log::text!("{} is not grouped by read, so it will be sorted by read name",
+                       filename.display());
+            log::event("sam_sorted", json!({"file": filename}));
+            let sorted = sort_by_read_name(filename, &filter.run)?;
+            let lines = SamLines::open(sorted.path(), &filter.run)?;
+            sorted_files.push(sorted);
+            lines
+        } else if sorted_by_position {
+            quit_with_error(ErrorType::Input,
+                            &format!("{:?} is sorted by position - --merge-overlaps needs \
+                                      alignments grouped by read, so please sort the file by \
+                                      read name (e.g. samtools sort -n) or use --auto-sort",
+                                     filename));
+        } else {
+            SamLines::open(filename, &filter.run)?
+        };
+        groups.push(ReadGroups { lines, line_count: 0, next: None, filename, downsampler,
+                                 fail_tags: &filter.fail_tags });
+    }
+    let total_size = groups.iter().map(|g| g.lines.file_size).sum();
+    let mut progress = Progress::new(&format!("{} and {}", filenames[0].display(),
+                                              filenames[1].display()),
+                                     Unit::Bytes, total_size, "alignments");
+
+    let mut counts = [SamCounts::default(); 2];
+    let mut overlap_count = 0;
+    while let Some(mates) = next_read_pair(&mut groups, filenames, "--merge-overlaps")? {
+        let mut good = [Vec::new(), Vec::new()];
+        for (i, mate) in mates.into_iter().enumerate() {
+            let aligned = aligned_mate(mate, filenames[i], &mut counts[i], &filter.run, discarded);
+            good[i] = get_usable_alignments(aligned, filter, discarded)
+                .unwrap_or_else(|e| e.quit());
+            counts[i].used += good[i].len();
+        }
+        overlap_count += add_pair_alignments(good, filter, pileups);
+        let bytes_read = groups.iter().map(|g| g.lines.bytes_read()).sum();
+        progress.update(bytes_read, (counts[0].alignments + counts[1].alignments) as u64);
+    }
+    progress.finish();
+
+    for (i, filename) in filenames.iter().enumerate() {
+        if counts[i].alignments == 0 {
+            quit_with_error(ErrorType::NoAlignments, &format!("no alignments in {:?}", filename))
+        }
+    }
+    Ok((counts, overlap_count))
+}
+
+
+/// Returns the alignments of the next read from both files of a read pair, or None at the end of
+/// the files. This quits with an error if the files don't have the same reads in the same order.
+fn next_read_pair(groups: &mut [ReadGroups], filenames: [&Path; 2],
+                  needed_by: &str) -> io::Result<Option<[Vec<Alignment>; 2]>> {
+    let mates = [groups[0].next_group()?, groups[1].next_group()?];
+    if mates[0].is_empty() && mates[1].is_empty() {
+        return Ok(None);
+    }
+    let names = [mates[0].first().map(|a| &a.read_name),
+                 mates[1].first().map(|a| &a.read_name)];
+    if names[0] != names[1] {
+        quit_with_error(ErrorType::Input,
+                        &format!("{:?} and {:?} don't have the same reads in the same order \
+                                  (found {} and {}) - {} needs the two files of a read pair, \
+                                  either as bwa mem outputs them or both sorted by read name",
+                                 filenames[0], filenames[1],
+                                 names[0].map_or("end of file", |n| n),
+                                 names[1].map_or("end of file", |n| n), needed_by));
+    }
+    Ok(Some(mates))
+}
+
+
+/// Takes one read's records from one file of a read pair, counts them and returns its aligned
+/// records (none if the read can't be used).
+fn aligned_mate(mate: Vec<Alignment>, filename: &Path, counts: &mut SamCounts,
+                run: &RunContext, discarded: &mut DiscardCounts) -> Vec<Alignment> {
+    let total = mate.len();
+    let aligned: Vec<Alignment> = mate.into_iter().filter(|a| a.is_aligned()).collect();
+    counts.unmapped += total - aligned.len();
+    let usable = !aligned.is_empty() &&
+        check_read_is_grouped(&aligned, filename, run, discarded).unwrap_or_else(|e| e.quit());
+    if !usable {
+        return Vec::new();
+    }
+    counts.alignments += aligned.len();
+    counts.reads += 1;
+    if aligned.len() > 1 {
+        counts.multi_aligned_reads += 1;
+    }
+    aligned
+}
+
+
+/// Adds alignments to the pileup from the two files of a read pair, reading each file only once
+/// (for filter-polish). The first sample_size read pairs are held in memory and given to
+/// make_check, which returns a function to check each read pair's alignments (marking those which
+/// fail with fail_filter) before they are added to the pileup.
+pub fn process_sam_pair_checked<C: FnMut(&mut [Vec<Alignment>; 2])>(
+        filenames: [&Path; 2], pileups: &mut FastHashMap<String, Pileup>,
+        filter: &AlignmentFilter, downsampler: &Downsampler,
+        discarded: &mut DiscardCounts, sample_size: usize,
+        make_check: impl FnOnce(&[[Vec<Alignment>; 2]]) -> C) -> [SamCounts; 2] {
+    let result = add_checked_pair_to_pileup(filenames, pileups, filter, downsampler,
+                                            discarded, sample_size, make_check);
+    match result {
+        Ok(counts) => counts,
+        Err(_)     => quit_with_error(ErrorType::Io,
+                                      &format!("unable to load alignments from {:?} and {:?}",
+                                               filenames[0], filenames[1])),
+    }
+}
+
+
+fn add_checked_pair_to_pileup<C: FnMut(&mut [Vec<Alignment>; 2])>(
+        filenames: [&Path; 2], pileups: &mut FastHashMap<String, Pileup>,
+        filter: &AlignmentFilter, downsampler: &Downsampler,
+        discarded: &mut DiscardCounts, sample_size: usize,
+        make_check: impl FnOnce(&[[Vec<Alignment>; 2]]) -> C) -> io::Result<[SamCounts; 2]> {
+    let mut groups = Vec::new();
+    for filename in filenames {
+        if is_sorted_by_position(filename, &filter.run)? {
+            quit_with_error(ErrorType::Input,
+                            &format!("{:?} is sorted by position - filter-polish needs alignments \
+                                      grouped by read, so please sort the file by read name \
+                                      (e.g. samtools sort -n)", filename));
+        }
+        groups.push(ReadGroups { lines: SamLines::open(filename, &filter.run)?, line_count: 0,
+                                 next: None, filename, downsampler,
+                                 fail_tags: &filter.fail_tags });
+    }
+    let total_size = groups.iter().map(|g| g.lines.file_size).sum();
+    let mut counts = [SamCounts::default(); 2];
+    let mut sample = Vec::new();
+    while sample.len() < sample_size {
+        let Some([mate_1, mate_2]) = next_read_pair(&mut groups, filenames, "filter-polish")?
+            else { break; };
+        sample.push([aligned_mate(mate_1, filenames[0], &mut counts[0], &filter.run, discarded),
+                     aligned_mate(mate_2, filenames[1], &mut counts[1], &filter.run, discarded)]);
+    }
+    let mut check = make_check(&sample);
+
+    let mut progress = Progress::new(&format!("{} and {}", filenames[0].display(),
+                                              filenames[1].display()),
+                                     Unit::Bytes, total_size, "alignments");
+    let mut sample = sample.into_iter();
+    loop {
+        let mut pair = match sample.next() {
+            Some(pair) => pair,
+            None => {
+                let Some([mate_1, mate_2]) = next_read_pair(&mut groups, filenames,
+                                                            "filter-polish")? else { break; };
+                [aligned_mate(mate_1, filenames[0], &mut counts[0], &filter.run, discarded),
+                 aligned_mate(mate_2, filenames[1], &mut counts[1], &filter.run, discarded)]
+            },
+        };
+        check(&mut pair);
+        for (i, aligned) in pair.into_iter().enumerate() {
+            counts[i].used += process_one_read(aligned, pileups, filter, discarded)
+                .unwrap_or_else(|e| e.quit());
+        }
+        let bytes_read = groups.iter().map(|g| g.lines.bytes_read()).sum();
+        progress.update(bytes_read, (counts[0].alignments + counts[1].alignments) as u64);
+    }
+    progress.finish();
+
+    for (i, filename) in filenames.iter().enumerate() {
+        if counts[i].alignments == 0 {
+            quit_with_error(ErrorType::NoAlignments, &format!("no alignments in {:?}", filename))
+        }
+    }
+    Ok(counts)
+}
+
+
+/// Adds the usable alignments of a read pair to the pileup. Each first-mate alignment is paired
+/// with the first unpaired second-mate alignment it overlaps (if any), and those pairs are added
+/// together so their overlap is counted once. Returns the number of overlapping pairs.
+fn add_pair_alignments(good: [Vec<Alignment>; 2], filter: &AlignmentFilter,
+                       pileups: &mut FastHashMap<String, Pileup>) -> usize {
+    let depth_contributions = [depth_contributions(&good[0], filter.placement_weights),
+                               depth_contributions(&good[1], filter.placement_weights)];
+    let mut paired = vec![false; good[1].len()];
+    let mut overlap_count = 0;
+    for (i, a_1) in good[0].iter().enumerate() {
+        let partner = (0..good[1].len()).find(|&j| !paired[j] && a_1.overlaps_mate(&good[1][j]));
+        let pileup = get_pileup(pileups, a_1, filter).unwrap_or_else(|e| e.quit());
+        let Some(pileup) = pileup else { continue; };
+        match partner {
+            Some(j) => {
+                paired[j] = true;
+                overlap_count += 1;
+                pileup.add_overlapping_pair(a_1, depth_contributions[0][i], &good[1][j],
+                                            depth_contributions[1][j]);
+            },
+            None => pileup.add_alignment(a_1, depth_contributions[0][i]),
+        }
+    }
+    for (j, a_2) in good[1].iter().enumerate().filter(|&(j, _)| !paired[j]) {
+        if let Some(pileup) = get_pileup(pileups, a_2, filter).unwrap_or_else(|e| e.quit()) {
+            pileup.add_alignment(a_2, depth_contributions[1][j]);
+        }
+    }
+    overlap_count
+}
+
+
+/// Reads a file grouped by read name one read at a time. Unaligned records are included, so the
+/// two files of a read pair (which both contain every read) stay in step.
+struct ReadGroups<'a> {
+    lines: SamLines,
+    line_count: usize,
+    next: Option<Alignment>,
+    filename: &'a Path,
+    downsampler: &'a Downsampler,
+    fail_tags: &'a [String],
+}
+
+impl ReadGroups<'_> {
+    /// Returns the alignments for the next read, or an empty vector at the end of the file.
+    fn next_group(&mut self) -> io::Result<Vec<Alignment>> {
+        let mut group: Vec<Alignment> = self.next.take().into_iter().collect();
+        while let Some(line) = self.lines.next_line() {
+            self.line_count += 1;
+            let sam_line = line?;
+            if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+            if !self.downsampler.keeps(read_name_key(sam_line)) {continue;}
+            let alignment = match Alignment::new(sam_line, self.fail_tags) {
+                Ok(alignment) => alignment,
+                Err(e)        => {
+                    let record = sam_line.to_string();
+                    self.lines.run.bad_record(e, self.filename, self.line_count, &record);
+                    continue;
+                },
+            };
+            if group.first().is_some_and(|a| a.read_name != alignment.read_name) {
+                self.next = Some(alignment);
+                break;
+            }
+            group.push(alignment);
+        }
+        Ok(group)
+    }
+}
+
+
+/// Adds alignments to the pileup from a file which isn't grouped by read (e.g. sorted by
+/// position). A first pass finds the reads with multiple alignments, so each alignment's depth
+/// contribution can be worked out without having its read's other alignments at hand.
+fn add_to_pileup_regrouped(filename: &Path, pileups: &mut FastHashMap<String, Pileup>,
+                           filter: &AlignmentFilter, downsampler: &Downsampler,
+                           discarded: &mut DiscardCounts) -> io::Result<SamCounts> {
+    let (multi_aligned, alignment_count,
+         read_count) = find_multi_aligned_reads(filename, filter, !filter.careful,
+                                                downsampler)?;
+    if alignment_count == 0 {
+        quit_with_error(ErrorType::NoAlignments, &format!("no alignments in {:?}", filename))
+    }
+
+    let mut sam_lines = SamLines::open(filename, &filter.run)?;
+    let mut progress = Progress::new(&filename.display().to_string(), Unit::Bytes,
+                                     sam_lines.file_size, "alignments");
+    let mut line_count: usize = 0;
+    let mut loaded_count: usize = 0;
+    let mut used_count: usize = 0;
+    let mut unmapped_count: usize = 0;
+    while let Some(line) = sam_lines.next() {
+        line_count += 1;
+        let sam_line = line?;
+        progress.update(sam_lines.bytes_read(), loaded_count as u64);
+        if sam_line.starts_with('@') {
+            filter.run.nm_header_line(filename, &sam_line);
+        }
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        if !downsampler.keeps(read_name_key(&sam_line)) {continue;}
+        let alignment = match Alignment::new(&sam_line, &filter.fail_tags) {
+            Ok(alignment) => alignment,
+            Err(e)        => {
+                filter.run.bad_record(e, filename, line_count, &sam_line);
+                continue;
+            },
+        };
+        if !alignment.is_aligned() {
+            unmapped_count += 1;
+            continue;
+        }
+        filter.run.check_nm(filename, &alignment, pileups);
+        loaded_count += 1;
+        let Some((alignment, depth_contribution)) = prepare_alignment(alignment, &multi_aligned,
+                                                                      filter, discarded) else {
+            continue;
+        };
+        if let Some(pileup) = get_pileup(pileups, &alignment, filter).unwrap_or_else(|e| e.quit()) {
+            pileup.add_alignment(&alignment, depth_contribution);
+            used_count += 1;
+        }
+    }
+    progress.finish();
+    Ok(SamCounts { alignments: alignment_count, used: used_count, reads: read_count,
+                   multi_aligned_reads: multi_aligned.len(), unmapped: unmapped_count })
+}
+
+
+/// Returns true if the alignment file is sorted by position. The @HD header line is used if it
+/// gives a sort order. Otherwise, the first alignments are checked: if they are all in position
+/// order, the file is assumed to be sorted.
+pub fn is_sorted_by_position(filename: &Path, run: &RunContext) -> io::Result<bool> {
+    let mut finished_refs = HashSet::new();
+    let mut previous: Option<(String, usize)> = None;
+    let mut alignment_count = 0;
+    for line in SamLines::open(filename, run)? {
+        let sam_line = line?;
+        if sam_line.is_empty() {continue;}
+        if sam_line.starts_with("@HD") {
+            for field in sam_line.split('\t') {
+                match field {
+                    "SO:coordinate"             => return Ok(true),
+                    "SO:queryname" | "GO:query" => return Ok(false),
+                    _ => (),
+                }
+            }
+        }
+        if sam_line.starts_with('@') {continue;}
+        let Ok(alignment) = Alignment::new_quick(&sam_line) else { return Ok(false); };
+        if !alignment.is_aligned() {continue;}
+        if let Some((ref_name, ref_start)) = &previous {
+            if *ref_name == alignment.ref_name {
+                if alignment.ref_start < *ref_start {
+                    return Ok(false);
+                }
+            } else {
+                finished_refs.insert(ref_name.clone());
+                if finished_refs.contains(&alignment.ref_name) {
+                    return Ok(false);
+                }
+            }
+        }
+        previous = Some((alignment.ref_name, alignment.ref_start));
+        alignment_count += 1;
+        if alignment_count >= SORT_CHECK_SAMPLE_SIZE {
+            break;
+        }
+    }
+    Ok(alignment_count > 1)
+}
+
+
+/// Counts how many of the first alignments in the file have an XA tag. Aligners use this tag (e.g.
+/// bwa mem without -a) to list a read's other alignments instead of giving them their own records,
+/// so Polypolish can't use them. Returns the number with the tag and the number checked.
+pub fn count_alt_hit_tags(filename: &Path, run: &RunContext) -> io::Result<(usize, usize)> {
+    let mut alt_hit_count = 0;
+    let mut alignment_count = 0;
+    for line in SamLines::open(filename, run)? {
+        let sam_line = line?;
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        let mut parts = sam_line.split('\t');
+        let Some(flags) = parts.nth(1).and_then(|f| f.parse::<u32>().ok()) else { continue; };
+        if flags & 4 != 0 {continue;}
+        if parts.skip(9).any(|p| p.starts_with("XA:Z:")) {
+            alt_hit_count += 1;
+        }
+        alignment_count += 1;
+        if alignment_count >= ALT_HIT_CHECK_SAMPLE_SIZE {
+            break;
+        }
+    }
+    Ok((alt_hit_count, alignment_count))
+}
+
+
+/// Finds the reads with more than one alignment (i.e. with secondary or supplementary alignments)
+/// and counts their usable alignments. This takes two passes through the file: one to find the
+/// read names and one to gather their alignments. Read sequences are only stored if needed, as
+/// --careful mode ignores reads with multiple alignments. The total alignment and read counts are
+/// also returned.
+pub fn find_multi_aligned_reads(filename: &Path, filter: &AlignmentFilter, store_seqs: bool,
+                                downsampler: &Downsampler)
+        -> io::Result<(FastHashMap<String, MultiAlignedRead>, usize, usize)> {
+    let mut reads: FastHashMap<String, MultiAlignedRead> = FastHashMap::default();
+    let mut lines = SamLines::open(filename, &filter.run)?;
+    let mut progress = Progress::new(&format!("Scanning {}", filename.display()), Unit::Bytes,
+                                     lines.file_size, "alignments");
+    let mut line_count: usize = 0;
+    let mut alignment_count: usize = 0;
+    let mut read_count: usize = 0;
+    while let Some(line) = lines.next() {
+        line_count += 1;
+        let sam_line = line?;
+        progress.update(lines.bytes_read(), alignment_count as u64);
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        if !downsampler.keeps(read_name_key(&sam_line)) {continue;}
+        let alignment = match Alignment::new_quick(&sam_line) {
+            Ok(alignment) => alignment,
+            Err(e)        => {
+                filter.run.bad_record(e, filename, line_count, &sam_line);
+                continue;
+            },
+        };
+        if !alignment.is_aligned() {continue;}
+        alignment_count += 1;
+        if alignment.is_primary() {
+            read_count += 1;
+        } else {
+            reads.entry(alignment.read_name.to_string()).or_default();
+        }
+    }
+    progress.finish();
+
+    let mut lines = SamLines::open(filename, &filter.run)?;
+    let mut progress = Progress::new(&format!("Scanning {}", filename.display()), Unit::Bytes,
+                                     lines.file_size, "");
+    while let Some(line) = lines.next() {
+        let sam_line = line?;
+        progress.update(lines.bytes_read(), 0);
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        let read_name = sam_line.split('\t').next().unwrap();
+        let Some(read) = reads.get_mut(read_name) else { continue; };
+        let Ok(alignment) = Alignment::new(&sam_line, &filter.fail_tags) else { continue; };
+        if !alignment.is_aligned() {continue;}
+        read.total += 1;
+        read.best_score = read.best_score.max(alignment.alignment_score);
+        if alignment.is_usable(filter, None) {
+            read.placements.push(alignment.placement());
+        }
+        if store_seqs && read.seq.is_none() && alignment.read_seq != "*" {
+            read.seq = Some((alignment.read_seq.clone(), alignment.get_strand()));
+        }
+    }
+    progress.finish();
+
+    // Alignments can only be checked against their read's best score once all are seen.
+    for read in reads.values_mut() {
+        let best_score = read.best_score;
+        let usable: Vec<_> = std::mem::take(&mut read.placements).into_iter()
+            .filter(|&(_, score)| !filter.too_far_below_best(score, best_score)).collect();
+        read.usable = usable.len() as u32;
+        if filter.placement_weights != PlacementWeights::Equal {
+            read.shares = PlacementShares::new(filter.placement_weights, &usable);
+        }
+    }
+    Ok((reads, alignment_count, read_count))
+}
+
+
+/// Decides whether an alignment is used for polishing, using the reads found by
+/// find_multi_aligned_reads. If so, it is returned (with its read sequence, if that was missing)
+/// along with its depth contribution. If not, the reason is counted.
+pub fn prepare_alignment(mut alignment: Alignment,
+                         multi_aligned: &FastHashMap<String, MultiAlignedRead>,
+                         filter: &AlignmentFilter,
+                         discarded: &mut DiscardCounts) -> Option<(Alignment, f64)> {
+    let read = multi_aligned.get(&*alignment.read_name);
+    let total = read.map_or(1, |r| r.total);
+    let usable = read.map_or(1, |r| r.usable);
+    if filter.careful && total > 1 {
+        discarded.add(DiscardReason::Careful);
+        return None;
+    }
+    if filter.max_hits.is_some_and(|m| total as usize > m) {
+        discarded.add(DiscardReason::MaxHits);
+        return None;
+    }
+    let best_score = read.map_or(alignment.alignment_score, |r| r.best_score);
+    if let Some(reason) = alignment.discard_reason(filter, best_score) {
+        discarded.add(reason);
+        return None;
+    }
+    if alignment.read_seq == "*" {
+        match read.and_then(|r| r.seq.as_ref()) {
+            Some((seq, strand)) => alignment.add_read_seq(seq, *strand),
+            None => quit_with_error(ErrorType::Input,
+                                    &format!("no alignments for read {} contain sequence",
+                                             alignment.read_name)),
+        }
+    }
+    alignment.set_count_weight(filter);
+    let share = read.and_then(|r| r.shares.get(alignment.placement()));
+    Some((alignment, share.unwrap_or(1.0 / usable as f64)))
+}
+
+
+fn process_one_read(alignments: Vec<Alignment>, pileups: &mut FastHashMap<String, Pileup>,
+                    filter: &AlignmentFilter,
+                    discarded: &mut DiscardCounts) -> Result<usize, Error> {
+    let good_alignments = get_usable_alignments(alignments, filter, discarded)?;
+    let depth_contributions = depth_contributions(&good_alignments, filter.placement_weights);
+    let mut used_count = 0;
+    for (a, &depth_contribution) in good_alignments.iter().zip(&depth_contributions) {
+        if let Some(pileup) = get_pileup(pileups, a, filter)? {
+            pileup.add_alignment(a, depth_contribution);
+            used_count += 1;
+        }
+    }
+    Ok(used_count)
+}
+
+
+/// An alignment's weight from its error rate (--identity-weighting): the log odds of each of its
+/// bases being correct, relative to an alignment at FULL_WEIGHT_ERROR_RATE. E.g. an alignment
+/// with a 5% error rate gets a weight of 0.76 and one with a 20% error rate gets 0.36.
+fn identity_weight(error_rate: f64) -> f64 {
+    if error_rate >= 0.5 {
+        return 0.0;
+    }
+    let log_odds = |e: f64| ((1.0 - e) / e).ln();
+    log_odds(error_rate.max(FULL_WEIGHT_ERROR_RATE)) / log_odds(FULL_WEIGHT_ERROR_RATE)
+}
+
+
+/// Each of a read's usable alignments' share of its depth (see placement.rs).
+fn depth_contributions(alignments: &[Alignment], weights: PlacementWeights) -> Vec<f64> {
+    if weights == PlacementWeights::Equal {
+        return vec![1.0 / alignments.len() as f64; alignments.len()];
+    }
+    let placements: Vec<_> = alignments.iter().map(Alignment::placement).collect();
+    placement::shares(weights, &placements)
+}
+
+
+/// Takes all the alignments for one read and returns those usable for polishing, with the read
+/// sequence and qualities added to any that lack them. The reasons for discarding the others are
+/// counted.
+fn get_usable_alignments(alignments: Vec<Alignment>, filter: &AlignmentFilter,
+                         discarded: &mut DiscardCounts) -> Result<Vec<Alignment>, Error> {
+    if alignments.is_empty() {
+        return Ok(Vec::new());
+    }
+    if filter.careful && alignments.len() > 1 {
+        discarded.add_many(DiscardReason::Careful, alignments.len());
+        return Ok(Vec::new());
+    }
+    if filter.max_hits.is_some_and(|m| alignments.len() > m) {
+        discarded.add_many(DiscardReason::MaxHits, alignments.len());
+        return Ok(Vec::new());
+    }
+    let with_seq = get_alignment_with_seq(&alignments)?;
+    let (read_seq, read_qual) = (with_seq.read_seq.clone(), with_seq.read_qual.clone());
+    let strand = with_seq.get_strand();
+
+    let best_score = alignments.iter().filter_map(|a| a.alignment_score).max();
+    let mut good_alignments = Vec::new();
+    for a in alignments {
+        a.check_score(filter)?;
+        match a.discard_reason(filter, best_score) {
+            Some(reason) => discarded.add(reason),
+            None         => good_alignments.push(a),
+        }
+    }
+
+    for a in &mut good_alignments {
+        a.set_count_weight(filter);
+        let needs_length = a.read_seq == "*";
+        if needs_length {
+            a.add_read_seq(&read_seq, strand);
+        }
+        if a.read_qual.is_none() {
+            a.add_read_qual(read_qual.as_ref(), strand);
+        }
+    }
+    Ok(good_alignments)
+}
+
+
+/// Returns the pileup for an alignment's reference sequence, or None for a skippable sequence
+/// (e.g. one in another batch with --batch-size).
+fn get_pileup<'a>(pileups: &'a mut FastHashMap<String, Pileup>, alignment: &Alignment,
+                  filter: &AlignmentFilter) -> Result<Option<&'a mut Pileup>, Error> {
+    match pileups.get_mut(&alignment.ref_name) {
+        Some(pileup) => Ok(Some(pileup)),
+        None if filter.skippable_seqs.contains(&alignment.ref_name) => Ok(None),
+        None                => {
+            Err(Error::new(ErrorType::Input, format!("query name {} in SAM but not in assembly",
+                                                     alignment.ref_name)))
+        },
+    }
+}
+
+
+/// This function takes a vector of all the alignments for one read. At least one of these
+/// alignments should have the read seq included (i.e. not just "*"). This function will return
+/// that alignment, for its sequence, qualities and strand.
+fn get_alignment_with_seq(alignments: &[Alignment]) -> Result<&Alignment, Error> {
+    for a in alignments {
+        if a.read_seq == "*" {
+            continue;
+        } else {
+            return Ok(a);
+        }
+    }
+    let read_name = &alignments.first().unwrap().read_name;
+    Err(Error::new(ErrorType::Input,
+                   format!("no alignments for read {} contain sequence", read_name)))
+}
+
+
+/// Converts a SAM quality string to Phred scores, or None if the read has no qualities ("*").
+fn parse_quals(read_qual: &str) -> Option<Arc<[u8]>> {
+    (read_qual != "*").then(|| read_qual.bytes().map(|q| q.saturating_sub(33)).collect())
+}
+
+
+/// Some aligners (e.g. BBMap, or minimap2 with --eqx) write matches and mismatches as = and X.
+/// Polypolish doesn't need to tell them apart, so they are merged into M operations. An X still
+/// counts as an error: the error count (for --max-errors, --max-error-rate and --min-identity)
+/// always comes from the NM tag, which every mapped alignment must have, not from the CIGAR.
+fn parse_cigar(cigar: &str) -> Result<Vec<(u32, CigarOp)>, ()> {
+    if cigar == "*" {
+        return Ok(Vec::new());
+    }
+    let mut parts: Vec<(u32, CigarOp)> = Vec::new();
+    for op in CigarOps::new(cigar) {
+        let (num, op) = op?;
+        let op = match op {
+            CigarOp::SeqMatch | CigarOp::SeqMismatch => CigarOp::Match,
+            _                                        => op,
+        };
+        match parts.last_mut() {
+            Some((n, CigarOp::Match)) if op == CigarOp::Match => *n += num,
+            _                                                 => parts.push((num, op)),
+        }
+    }
+    Ok(parts)
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CigarOp {
+    Match,        // M
+    Insertion,    // I
+    Deletion,     // D
+    Skip,         // N
+    SoftClip,     // S
+    HardClip,     // H
+    Padding,      // P
+    SeqMatch,     // =
+    SeqMismatch,  // X
+}
+
+impl CigarOp {
+    fn from_byte(c: u8) -> Option<CigarOp> {
+        match c {
+            b'M' => Some(CigarOp::Match),
+            b'I' => Some(CigarOp::Insertion),
+            b'D' => Some(CigarOp::Deletion),
+            b'N' => Some(CigarOp::Skip),
+            b'S' => Some(CigarOp::SoftClip),
+            b'H' => Some(CigarOp::HardClip),
+            b'P' => Some(CigarOp::Padding),
+            b'=' => Some(CigarOp::SeqMatch),
+            b'X' => Some(CigarOp::SeqMismatch),
+            _    => None,
+        }
+    }
+
+    fn to_char(self) -> char {
+        match self {
+            CigarOp::Match       => 'M',
+            CigarOp::Insertion   => 'I',
+            CigarOp::Deletion    => 'D',
+            CigarOp::Skip        => 'N',
+            CigarOp::SoftClip    => 'S',
+            CigarOp::HardClip    => 'H',
+            CigarOp::Padding     => 'P',
+            CigarOp::SeqMatch    => '=',
+            CigarOp::SeqMismatch => 'X',
+        }
+    }
+
+    fn consumes_ref(self) -> bool {
+        matches!(self, CigarOp::Match | CigarOp::Deletion | CigarOp::Skip |
+                       CigarOp::SeqMatch | CigarOp::SeqMismatch)
+    }
+
+    fn consumes_read(self) -> bool {
+        matches!(self, CigarOp::Match | CigarOp::Insertion | CigarOp::SoftClip |
+                       CigarOp::SeqMatch | CigarOp::SeqMismatch)
+    }
+}
+
+
+/// An iterator over the (length, operation) pairs of a CIGAR string. This scans the bytes
+/// directly, as using a regex was a major hotspot for large inputs. If the CIGAR isn't valid (an
+/// unknown operation, an operation without a length or a length without an operation), it yields
+/// an error and then stops.
+struct CigarOps<'a> {
+    cigar: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CigarOps<'a> {
+    fn new(cigar: &'a str) -> CigarOps<'a> {
+        CigarOps { cigar: cigar.as_bytes(), pos: 0 }
+    }
+}
+
+impl Iterator for CigarOps<'_> {
+    type Item = Result<(u32, CigarOp), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.cigar.len() {
+            return None;
+        }
+        let mut num: u32 = 0;
+        let mut digit_count = 0;
+        while let Some(&c) = self.cigar.get(self.pos) {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            num = match num.checked_mul(10).and_then(|n| n.checked_add((c - b'0') as u32)) {
+                Some(n) => n,
+                None => break,
+            };
+            digit_count += 1;
+            self.pos += 1;
+        }
+        let op = self.cigar.get(self.pos).and_then(|&c| CigarOp::from_byte(c));
+        match op {
+            Some(op) if digit_count > 0 => {
+                self.pos += 1;
+                Some(Ok((num, op)))
+            },
+            _ => {
+                self.pos = self.cigar.len();
+                Some(Err(()))
+            },
+        }
+    }
+}
+
+
+/// Alignments that end in a homopolymer can cause trouble, as they can align cleanly
+/// (without an indel) even when an indel is needed.
+///
+/// For example, an alignment should look like this:
+///   read: ... T G A G T A C AG G G G G A A G T
+///   ref:  ... T G A G T A C A  G G G G A A G T C C A G T ...
+///
+/// But if the read ends in the homopolymer, it could look like this:
+///   read: ... T G A G T A C A G G
+///   ref:  ... T G A G T A C A G G G G A A G T C C A G T ...
+///
+/// Which results in a clean alignment on the 'A' that should be 'AG'. To avoid this, we
+/// trim off the last run of identical bases plus extra_bases more (one by default), so the example
+/// becomes:
+///   read: ... T G A G T A C
+///   ref:  ... T G A G T A C A G G G G A A G T C C A G T ...
+///
+/// Returns the number of positions trimmed.
+pub fn trim_bases_for_homopolymers(read_bases: &mut Vec<(usize, usize)>, read_seq: &str,
+                                   extra_bases: usize) -> usize {
+    let original_len = read_bases.len();
+    let Some(&(last_start, last_end)) = read_bases.last() else { return 0; };
+    let last_base = &read_seq[last_start..last_end];
+    while let Some(&(current_last_start, current_last_end)) = read_bases.last() {
+        let current_last_base = &read_seq[current_last_start..current_last_end];
+        if current_last_base != last_base {
+            break;
+        }
+        read_bases.pop();
+    }
+    read_bases.truncate(read_bases.len().saturating_sub(extra_bases));
+    original_len - read_bases.len()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cigar_good() {
+        use CigarOp::*;
+        assert_eq!(parse_cigar("10M").unwrap(), vec![(10, Match)]);
+        assert_eq!(parse_cigar("3M1I7M").unwrap(), vec![(3, Match), (1, Insertion), (7, Match)]);
+        assert_eq!(parse_cigar("5M2D4M").unwrap(), vec![(5, Match), (2, Deletion), (4, Match)]);
+        assert_eq!(parse_cigar("*").unwrap(), vec![]);
+        assert_eq!(parse_cigar("5=1X4=2I3=").unwrap(),
+                   vec![(10, Match), (2, Insertion), (3, Match)]);
+    }
+
+    #[test]
+    fn test_eqx_cigar_errors() {
+        let a_str = "r_1\t0\tx\t1000\t60\t3=1X2=1I2=\t*\t0\t0\tACGTACGTA\t*\tNM:i:2";
+        let a = Alignment::new(a_str, &[]).unwrap();
+        assert_eq!(a.nm(), 2);
+        assert!((a.error_rate() - 2.0 / 9.0).abs() < 1e-9);
+        let filter = AlignmentFilter { max_errors: Some(1), ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::MaxErrors));
+        let filter = AlignmentFilter { max_errors: Some(2), ..Default::default() };
+        assert!(a.is_usable(&filter, None));
+
+        // An X at the end of the alignment is still end-to-end.
+        let a_str = "r_1\t0\tx\t1000\t60\t4=1X\t*\t0\t0\tACGTA\t*\tNM:i:1";
+        assert!(Alignment::new(a_str, &[]).unwrap().is_usable(&AlignmentFilter::default(), None));
+
+        // Without NM, the mismatches can't be counted, so the alignment isn't accepted.
+        let a_str = "r_1\t0\tx\t1000\t60\t3=1X2=1I2=\t*\t0\t0\tACGTACGTA\t*";
+        assert_eq!(Alignment::new(a_str, &[]).unwrap_err(), "missing NM tag");
+    }
+
+    #[test]
+    fn test_parse_cigar_bad() {
+        assert!(parse_cigar("10Q").is_err());        // 'Q' isn't a CIGAR operator
+        assert!(parse_cigar("10MM1I10M").is_err());  // can't have consecutive letters
+        assert!(parse_cigar("100M5").is_err());      // can't end on a number
+    }
+
+    #[test]
+    fn test_cigar_ops() {
+        let ops: Vec<_> = CigarOps::new("3S10M2I1D=").collect();
+        assert_eq!(ops, vec![Ok((3, CigarOp::SoftClip)), Ok((10, CigarOp::Match)),
+                             Ok((2, CigarOp::Insertion)), Ok((1, CigarOp::Deletion)), Err(())]);
+        let ops: Vec<_> = CigarOps::new("12=1X").collect();
+        assert_eq!(ops, vec![Ok((12, CigarOp::SeqMatch)), Ok((1, CigarOp::SeqMismatch))]);
+        assert_eq!(CigarOps::new("").count(), 0);
+        assert_eq!(CigarOps::new("*").collect::<Vec<_>>(), vec![Err(())]);
+        assert_eq!(CigarOps::new("99999999999M").collect::<Vec<_>>(), vec![Err(())]);
+    }
+
+    #[test]
+    fn test_get_read_bases_for_each_target_base() {
+        let a_str = "r_1\t0\tx\t1000\t60\t3M1I2M2D2M\t*\t0\t0\tACGTACGT\tKKKKKKKK\tNM:i:3";
+        let alignment = Alignment::new(a_str, &[]).unwrap();
+        assert_eq!(alignment.get_read_bases_for_each_target_base(),
+                   vec![(0, 1), (1, 2), (2, 4), (4, 5), (5, 6), (6, 6), (6, 6), (6, 7), (7, 8)]);
+    }
+
+    #[test]
+    fn test_strip_clips() {
+        let a_str = "r_1\t0\tx\t1000\t60\t2H3S4M1I2M2S\t*\t0\t0\tTTTACGTACGTG\t*\tNM:i:1";
+        let mut a = Alignment::new(a_str, &[]).unwrap();
+        assert_eq!(a.discard_reason(&AlignmentFilter::default(), None),
+                   Some(DiscardReason::HardClipped));
+        a.strip_clips();
+        assert_eq!(a.read_seq, "ACGTACG");
+        assert!(a.is_usable(&AlignmentFilter::default(), None));
+        assert_eq!(a.get_read_bases_for_each_target_base(),
+                   vec![(0, 1), (1, 2), (2, 3), (3, 5), (5, 6), (6, 7)]);
+    }
+
+    #[test]
+    fn test_strip_clips_with_end_indels() {
+        let a_str = "r_1	0	x	1000	60	2S2I5M	*	0	0	TTGGACGTA	*	NM:i:2";
+        let mut a = Alignment::new(a_str, &[]).unwrap();
+        a.strip_clips();
+        assert_eq!(a.read_seq, "ACGTA");
+        assert_eq!((a.ref_start, a.get_ref_end()), (999, 1004));
+        assert!(a.has_simple_cigar());
+        assert_eq!(a.get_read_bases_for_each_target_base(),
+                   vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5)]);
+
+        let a_str = "r_1	0	x	1000	60	3D4M3D2S	*	0	0	ACGTTT	*	NM:i:6";
+        let mut a = Alignment::new(a_str, &[]).unwrap();
+        a.strip_clips();
+        assert_eq!(a.read_seq, "ACGT");
+        assert_eq!((a.ref_start, a.get_ref_end()), (1002, 1006));
+        assert!(a.has_simple_cigar());
+        assert_eq!(a.get_read_bases_for_each_target_base(), vec![(0, 1), (1, 2), (2, 3), (3, 4)]);
+
+        let a_str = "r_1	0	x	1000	60	2S3M10N3M	*	0	0	TTACGTAC	*	NM:i:0";
+        let mut a = Alignment::new(a_str, &[]).unwrap();
+        a.strip_clips();
+        assert!(!a.has_simple_cigar());
+    }
+
+    #[test]
+    fn test_trim_bases_for_homopolymers() {
+        let a_str = "r_1\t0\tx\t1000\t60\t3M1I2M2D2M\t*\t0\t0\tACGTACGT\tKKKKKKKK\tNM:i:3";
+        let alignment = Alignment::new(a_str, &[]).unwrap();
+        let full = alignment.get_read_bases_for_each_target_base();
+        let mut read_bases = full.clone();
+        assert_eq!(trim_bases_for_homopolymers(&mut read_bases, &alignment.read_seq, 1), 2);
+        assert_eq!(read_bases, full[..7]);
+        let mut read_bases = full.clone();
+        assert_eq!(trim_bases_for_homopolymers(&mut read_bases, &alignment.read_seq, 0), 1);
+        assert_eq!(read_bases, full[..8]);
+
+        // A run of identical read bases is trimmed along with the extra bases.
+        let a_str = "r_2\t0\tx\t1000\t60\t8M\t*\t0\t0\tACGTAGGG\tKKKKKKKK\tNM:i:0";
+        let alignment = Alignment::new(a_str, &[]).unwrap();
+        let mut read_bases = alignment.get_read_bases_for_each_target_base();
+        assert_eq!(trim_bases_for_homopolymers(&mut read_bases, &alignment.read_seq, 2), 5);
+        assert_eq!(read_bases, vec![(0, 1), (1, 2), (2, 3)]);
+        assert_eq!(trim_bases_for_homopolymers(&mut read_bases, &alignment.read_seq, 9), 3);
+        assert!(read_bases.is_empty());
+        assert_eq!(trim_bases_for_homopolymers(&mut read_bases, &alignment.read_seq, 1), 0);
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let good = "r_1\t0\tx\t1000\t60\t10M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\tNM:i:0";
+        assert!(Alignment::new(good, &[]).is_ok());
+        assert_eq!(Alignment::new("r_1\t0\tx\t1000\t60\t10M", &[]).err(), Some("too few columns"));
+        assert_eq!(Alignment::new(&good.replace("\t0\tx", "\tz\tx"), &[]).err(),
+                   Some("invalid flag"));
+        assert_eq!(Alignment::new(&good.replace("1000", "-"), &[]).err(), Some("invalid position"));
+        assert_eq!(Alignment::new(&good.replace("NM:i:0", "NM:i:"), &[]).err(),
+                   Some("invalid NM tag"));
+        assert_eq!(Alignment::new(&good.replace("10M", "10Q"), &[]).err(),
+                   Some("invalid CIGAR string"));
+        assert_eq!(Alignment::new(&good.replace("10M", "9M"), &[]).err(),
+                   Some("CIGAR and sequence lengths differ"));
+        assert!(Alignment::new(&good.replace("ACGTACGTAC\tKKKKKKKKKK", "*\t*"), &[]).is_ok());
+        assert_eq!(Alignment::new_quick(&good.replace("\t0\tx", "\tz\tx")).err(),
+                   Some("invalid flag"));
+    }
+
+    #[test]
+    fn test_discard_reason() {
+        let a = Alignment::new("r_1\t0\tx\t1000\t60\t3M1I2M2D4M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:4", &[]).unwrap();
+        assert_eq!(a.error_rate(), 4.0 / 12.0);  // 4 errors in 12 columns
+        let filter = AlignmentFilter { max_errors: Some(10), ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), None);
+        let filter = AlignmentFilter { max_errors: Some(3), ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::MaxErrors));
+        let filter = AlignmentFilter { max_error_rate: Some(0.3), ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::MaxErrorRate));
+        let filter = AlignmentFilter { max_error_rate: Some(0.4), ..Default::default() };
+        assert!(a.is_usable(&filter, None));
+        assert!((a.identity() - 8.0 / 12.0).abs() < 1e-9);
+        let filter = AlignmentFilter { min_identity: Some(0.7), ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::MinIdentity));
+        let filter = AlignmentFilter { min_identity: Some(0.6), ..Default::default() };
+        assert!(a.is_usable(&filter, None));
+
+        let a = Alignment::new("r_1\t0\tx\t1000\t60\t2S8M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:0", &[]).unwrap();
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::SoftClipped));
+        let a = Alignment::new("r_1\t0\tx\t1000\t60\t8M2S\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:0", &[]).unwrap();
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::SoftClipped));
+        let a = Alignment::new("r_1\t0\tx\t1000\t60\t2I8M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:2", &[]).unwrap();
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::NotEndToEnd));
+        let a = Alignment::new("r_1\t0\tx\t1000\t60\t10M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:0\tZP:Z:fail", &[]).unwrap();
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::FailedFilter));
+
+        let a = Alignment::new("r_1\t512\tx\t1000\t60\t10M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:0\tXA:Z:y,+500,10M,0;", &[]).unwrap();
+        assert!(a.is_usable(&AlignmentFilter::default(), None));
+        let filter = AlignmentFilter { exclude_qcfail: true, ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::QcFail));
+        let filter = AlignmentFilter { exclude_alt_hits: true, ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::AltHits));
+    }
+
+    #[test]
+    fn test_identity_weight() {
+        assert_eq!(identity_weight(0.0), 1.0);
+        assert_eq!(identity_weight(FULL_WEIGHT_ERROR_RATE), 1.0);
+        assert!((identity_weight(0.05) - 0.757).abs() < 0.001);
+        assert!((identity_weight(0.2) - 0.356).abs() < 0.001);
+        assert!(identity_weight(0.1) > identity_weight(0.11));
+        assert_eq!(identity_weight(0.5), 0.0);
+        assert_eq!(identity_weight(0.8), 0.0);
+    }
+
+    #[test]
+    fn test_count_weight() {
+        let alignment = || vec![Alignment::new("r_1\t0\tx\t1000\t60\t10M\t*\t0\t0\t\
+                                                ACGTACGTAC\tKKKKKKKKKK\tNM:i:2", &[]).unwrap()];
+        let mut discarded = DiscardCounts::default();
+        let filter = AlignmentFilter::default();
+        let good = get_usable_alignments(alignment(), &filter, &mut discarded).unwrap();
+        assert_eq!(good[0].count_weight(), 1.0);
+        let filter = AlignmentFilter { identity_weighting: true, ..Default::default() };
+        let good = get_usable_alignments(alignment(), &filter, &mut discarded).unwrap();
+        assert_eq!(good[0].count_weight(), identity_weight(0.2));
+        assert_eq!(alignment()[0].count_weight(), 1.0);
+    }
+
+    #[test]
+    fn test_alignment_score() {
+        let a = Alignment::new("r_1\t0\tx\t1000\t60\t10M\t*\t0\t0\tACGTACGTAC\tKKKKKKKKKK\t\
+                                NM:i:1\tAS:i:45\tXS:i:50", &[]).unwrap();
+        assert_eq!(a.alignment_score, Some(45));
+        let filter = AlignmentFilter { min_alignment_score: Some(40), ..Default::default() };
+        assert!(a.is_usable(&filter, None));
+        let filter = AlignmentFilter { min_alignment_score: Some(46), ..Default::default() };
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::MinAlignmentScore));
+
+        let filter = AlignmentFilter { max_score_diff: Some(5), ..Default::default() };
+        assert!(a.is_usable(&filter, Some(50)));
+        assert_eq!(a.discard_reason(&filter, Some(51)), Some(DiscardReason::MaxScoreDiff));
+        assert!(a.is_usable(&filter, None));
+        let filter = AlignmentFilter::default();
+        assert!(a.is_usable(&filter, Some(100)));
+    }
+
+    #[test]
+    fn test_read_pos_at() {
+        // Read ACGTACGTAC: 2 clipped bases, ACG aligned to 100-102, T inserted, AC aligned to
+        // 103-104, 105 deleted, then 2 more clipped bases.
+        let a = Alignment::new("r_1\t0\tx\t101\t60\t2S3M1I2M1D2S\t*\t0\t0\tACGTACGTAC\t*\t\
+                                NM:i:2", &[]).unwrap();
+        assert_eq!(a.read_pos_at(97), None);
+        assert_eq!(a.read_pos_at(98), Some(0));
+        assert_eq!(a.read_pos_at(100), Some(2));
+        assert_eq!(a.read_pos_at(102), Some(4));
+        assert_eq!(a.read_pos_at(103), Some(6));
+        assert_eq!(a.read_pos_at(105), Some(8));
+        assert_eq!(a.read_pos_at(106), Some(8));
+        assert_eq!(a.read_pos_at(108), Some(10));
+        assert_eq!(a.read_pos_at(109), None);
+    }
+
+    #[test]
+    fn test_get_usable_alignments_max_hits() {
+        let alignments = || (0..3).map(|i| {
+            Alignment::new(&format!("r_1\t{}\tx\t{}\t60\t4M\t*\t0\t0\tACTG\tKKKK\tNM:i:0",
+                                    if i == 0 { 0 } else { 256 }, 1000 * (i + 1)), &[]).unwrap()
+        }).collect::<Vec<_>>();
+        let mut discarded = DiscardCounts::default();
+        let filter = AlignmentFilter { max_hits: Some(3), ..Default::default() };
+        assert_eq!(get_usable_alignments(alignments(), &filter, &mut discarded).unwrap().len(), 3);
+        let filter = AlignmentFilter { max_hits: Some(2), ..Default::default() };
+        assert!(get_usable_alignments(alignments(), &filter, &mut discarded).unwrap().is_empty());
+        assert_eq!(discarded.get(DiscardReason::MaxHits), 3);
+    }
+
+    #[test]
+    fn test_get_usable_alignments_quals() {
+        let alignments = ["r_1\t0\tx\t1000\t60\t4M\t*\t0\t0\tACTG\t!+5?\tNM:i:0",
+                          "r_1\t256\tx\t2000\t60\t4M\t*\t0\t0\t*\t*\tNM:i:0",
+                          "r_1\t272\tx\t3000\t60\t4M\t*\t0\t0\t*\t*\tNM:i:0"]
+            .map(|a| Alignment::new(a, &[]).unwrap());
+        let mut discarded = DiscardCounts::default();
+        let usable = get_usable_alignments(alignments.into(), &AlignmentFilter::default(),
+                                           &mut discarded).unwrap();
+        let quals = |a: &Alignment| (0..4).map(|i| a.base_qual(i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(quals(&usable[0]), [0, 10, 20, 30]);
+        assert_eq!(quals(&usable[1]), [0, 10, 20, 30]);
+        assert_eq!(quals(&usable[2]), [30, 20, 10, 0]);
+        assert!(Arc::ptr_eq(usable[0].read_qual.as_ref().unwrap(),
+                            usable[1].read_qual.as_ref().unwrap()));
+        assert_eq!(usable[2].mean_qual_for_range((1, 3)), 15.0);
+        assert_eq!(usable[2].mean_qual_for_range((1, 1)), 25.0);
+    }
+
+    #[test]
+    fn test_discard_counts() {
+        let mut counts = DiscardCounts::default();
+        counts.add(DiscardReason::MaxErrors);
+        counts.add_many(DiscardReason::Careful, 3);
+        let mut total = DiscardCounts::default();
+        total.merge(&counts);
+        total.merge(&counts);
+        assert_eq!(total.get(DiscardReason::MaxErrors), 2);
+        assert_eq!(total.get(DiscardReason::Careful), 6);
+        assert_eq!(total.get(DiscardReason::NotEndToEnd), 0);
+    }
+
+    #[test]
+    fn test_get_ref_positions() {
+        let a_str = format!("r_1\t0\tx\t{}\t60\t4M\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
+        let alignment = Alignment::new(&a_str, &[]).unwrap();
+        assert_eq!(alignment.ref_start, 999);
+        assert_eq!(alignment.get_ref_end(), 1003);
+
+        let a_str = format!("r_1\t0\tx\t{}\t60\t2=1X1=\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
+        let alignment = Alignment::new(&a_str, &[]).unwrap();
+        assert_eq!(alignment.ref_start, 999);
+        assert_eq!(alignment.get_ref_end(), 1003);
+
+        let a_str = format!("r_1\t0\tx\t{}\t60\t2M1I1M\t*\t0\t0\tACTG\tKKKK\tNM:i:0", 1000);
+        let alignment = Alignment::new(&a_str, &[]).unwrap();
+        assert_eq!(alignment.ref_start, 999);
+        assert_eq!(alignment.get_ref_end(), 1002);
+
+        let a_str = format!("r_1\t0\tx\t{}\t60\t2M1D1M\t*\t0\t0\tACT\tKKK\tNM:i:0", 1000);
+        let alignment = Alignment::new(&a_str, &[]).unwrap();
+        assert_eq!(alignment.ref_start, 999);
+        assert_eq!(alignment.get_ref_end(), 1003);
+    }
+
+    #[test]
+    fn test_recompute_nm() {
+        let pileup = Pileup::new("ACGTACGTACGTACGT", u32::MAX, 0, Some(1), false);
+        let a = Alignment::new("r_1\t0\tx\t3\t60\t1S3M1I2M2D4M\t*\t0\t0\tTGCAGCGCGTA\t*\t\
+                                NM:i:4", &[]).unwrap();
+        assert_eq!(a.recompute_nm(&pileup), Some((1, 3)));  // T->C mismatch, 1 ins, 2 del
+        assert_eq!(a.nm(), 4);
+        let a = Alignment::new("r_1\t0\tx\t12\t60\t6M\t*\t0\t0\tACGTAC\t*\tNM:i:0", &[]).unwrap();
+        assert_eq!(a.recompute_nm(&pileup), None);  // past the end of the reference
+        let a = Alignment::new("r_1\t256\tx\t1\t60\t4M\t*\t0\t0\t*\t*\tNM:i:0", &[]).unwrap();
+        assert_eq!(a.recompute_nm(&pileup), None);  // no read sequence
+    }
+
+    fn sam_line(read_name: &str, flags: u32, ref_name: &str, pos: usize) -> String {
+        format!("{}\t{}\t{}\t{}\t60\t4M\t*\t0\t0\tACGT\t*\tNM:i:0\n",
+                read_name, flags, ref_name, pos)
+    }
+
+    fn sorted_by_position(contents: &str) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sam");
+        std::fs::write(&path, contents).unwrap();
+        is_sorted_by_position(&path, &RunContext::default()).unwrap()
+    }
+
+    #[test]
+    fn test_is_sorted_by_position() {
+        let sorted = [sam_line("r2", 0, "a", 5), sam_line("r1", 256, "a", 9),
+                      sam_line("r3", 4, "*", 0), sam_line("r1", 0, "b", 1)].concat();
+        let unsorted = [sam_line("r1", 0, "a", 9), sam_line("r1", 256, "a", 5)].concat();
+        let contig_repeated = [sam_line("r1", 0, "a", 1), sam_line("r2", 0, "b", 1),
+                               sam_line("r3", 0, "a", 5)].concat();
+        assert!(sorted_by_position(&sorted));
+        assert!(!sorted_by_position(&unsorted));
+        assert!(!sorted_by_position(&contig_repeated));
+
+        // The @HD line's sort order takes precedence over the order of the alignments.
+        assert!(sorted_by_position(&format!("@HD\tVN:1.6\tSO:coordinate\n{}", unsorted)));
+        assert!(!sorted_by_position(&format!("@HD\tVN:1.6\tSO:queryname\n{}", sorted)));
+        assert!(sorted_by_position(&format!("@HD\tVN:1.6\tSO:unsorted\n{}", sorted)));
+    }
+
+    #[test]
+    fn test_is_grouped_by_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sam");
+        let grouped = [sam_line("r2", 0, "a", 9), sam_line("r2", 256, "b", 1),
+                       sam_line("r1", 4, "*", 0), sam_line("r1", 0, "a", 5)].concat();
+        std::fs::write(&path, grouped).unwrap();
+        assert!(is_grouped_by_read(&path, &RunContext::default()).unwrap());
+        let not_grouped = [sam_line("r2", 0, "a", 9), sam_line("r1", 0, "a", 5),
+                           sam_line("r2", 256, "b", 1)].concat();
+        std::fs::write(&path, not_grouped).unwrap();
+        assert!(!is_grouped_by_read(&path, &RunContext::default()).unwrap());
+    }
+    #[test]
+    fn test_count_alt_hit_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sam");
+        let with_xa = sam_line("r2", 0, "a", 9).replace("\n", "\tXA:Z:b,+1,4M,0;\n");
+        let contents = ["@HD\tVN:1.6\n".to_string(), sam_line("r1", 0, "a", 5), with_xa,
+                        sam_line("r3", 4, "*", 0)].concat();
+        std::fs::write(&path, contents).unwrap();
+        assert_eq!(count_alt_hit_tags(&path, &RunContext::default()).unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn test_fail_tags() {
+        assert!(is_fail_tag("ZP:Z:fail", &[]));
+        assert!(is_fail_tag("zp:z:FAIL", &[]));
+        assert!(!is_fail_tag("XF:Z:bad", &[]));
+        let tags = vec!["XF:Z:bad".to_string(), "ZP:Z:fail".to_string()];
+        assert!(is_fail_tag("XF:Z:bad", &tags));
+        assert!(is_fail_tag("ZP:Z:fail", &tags));
+        assert!(!is_fail_tag("XF:Z:good", &tags));
+        assert_eq!(AlignmentFilter::default().fail_tag(), DEFAULT_FAIL_TAG);
+        assert_eq!(AlignmentFilter { fail_tags: tags, ..Default::default() }.fail_tag(),
+                   "XF:Z:bad");
+        assert!(is_valid_tag("ZP:Z:fail"));
+        assert!(is_valid_tag("X1:i:0"));
+        assert!(!is_valid_tag("ZP:Z:"));
+        assert!(!is_valid_tag("ZP:fail"));
+        assert!(!is_valid_tag("ZPX:Z:fail"));
+        assert!(!is_valid_tag("1P:Z:fail"));
+        assert!(!is_valid_tag("ZP:Q:fail"));
+    }
+
+    #[test]
+    fn test_sam_lines_with_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sam");
+        let contents = ["@HD\tVN:1.6\n".to_string(), sam_line("r1", 0, "a", 5),
+                        sam_line("r2", 0, "a", 9), sam_line("r2", 256, "b", 1)].concat();
+        std::fs::write(&path, contents).unwrap();
+        let lines: Vec<String> = SamLines::open(&path, &RunContext::default()).unwrap()
+            .with_failures(vec![0b1010], DEFAULT_FAIL_TAG).map(|l| l.unwrap()).collect();
+        assert_eq!(lines.len(), 4);
+        assert!(!lines[0].contains("ZP:Z:fail"));
+        assert!(lines[1].ends_with("NM:i:0\tZP:Z:fail"));
+        assert!(!lines[2].contains("ZP:Z:fail"));
+        assert!(lines[3].ends_with("NM:i:0\tZP:Z:fail"));
+        let filter = AlignmentFilter::default();
+        let a = Alignment::new(&lines[1], &filter.fail_tags).unwrap();
+        assert!(a.is_failed());
+        assert_eq!(a.discard_reason(&filter, None), Some(DiscardReason::FailedFilter));
+        let filter = AlignmentFilter { fail_tags: vec!["XF:Z:bad".to_string()],
+                                       ..Default::default() };
+        let a = Alignment::new(&lines[1], &filter.fail_tags).unwrap();
+        assert!(!a.is_failed());
+        assert!(a.is_usable(&filter, None));
+    }
+
+    #[test]
+    fn test_sam_lines_mmap() {
+        // Mapped lines (--mmap) are the same as read ones, whether they are borrowed or taken.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sam");
+        let contents = ["@HD\tVN:1.6\n".to_string(), sam_line("r1", 0, "a", 5),
+                        sam_line("r2", 0, "a", 9), sam_line("r2", 256, "b", 1)].concat();
+        std::fs::write(&path, &contents).unwrap();
+        let open = |use_mmap| {
+            let run = RunContext::new(false, None, false, use_mmap);
+            SamLines::open(&path, &run).unwrap().with_failures(vec![0b0100], DEFAULT_FAIL_TAG)
+        };
+        let expected: Vec<String> = open(false).map(|l| l.unwrap()).collect();
+        assert!(expected[2].ends_with("\tZP:Z:fail"));
+        for use_mmap in [false, true] {
+            let mut lines = open(use_mmap);
+            let mut borrowed = Vec::new();
+            while let Some(line) = lines.next_line() {
+                borrowed.push(line.unwrap().to_string());
+            }
+            assert_eq!(borrowed, expected);
+            assert_eq!(lines.bytes_read(), contents.len() as u64);
+            assert_eq!(open(use_mmap).map(|l| l.unwrap()).collect::<Vec<_>>(), expected);
+        }
     }
 }
@@ -0,0 +1,221 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// With --debug-format arrow, the per-base debugging file is written in the Arrow IPC file format
+// (also known as Feather v2) instead of TSV. It has the same columns as the TSV, but with proper
+// types and zstd compression, so even a metagenome's debug file stays a manageable size and can be
+// sliced by sequence or status in seconds with a dataframe library (e.g. polars or pyarrow). This
+// needs the arrow feature at build time (cargo build --release --features arrow).
+
+use arrow_array::builder::{BooleanBuilder, Float64Builder, StringBuilder, UInt32Builder,
+                           UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::{FileWriter, IpcWriteOptions};
+use arrow_ipc::CompressionType;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::misc::{quit_with_error, ErrorType};
+use crate::pileup::BaseSummary;
+
+
+/// Rows are buffered and written as a record batch once there are this many.
+const BATCH_ROWS: usize = 65536;
+
+
+pub struct ArrowDebugFile {
+    writer: FileWriter<BufWriter<File>>,
+    filename: PathBuf,
+    schema: SchemaRef,
+    rows: usize,
+    name: StringBuilder,
+    pos: UInt64Builder,
+    base: StringBuilder,
+    depth: Float64Builder,
+    invalid: UInt32Builder,
+    valid: UInt32Builder,
+    pileup: StringBuilder,
+    status: StringBuilder,
+    new_base: StringBuilder,
+    confidence: Float64Builder,
+    low_complexity: Option<BooleanBuilder>,
+    deletion_event: Option<StringBuilder>,
+    reads: Option<StringBuilder>,
+}
+
+impl ArrowDebugFile {
+    pub fn create(filename: &Path, read_names: bool, low_complexity: bool,
+                  deletion_events: bool) -> ArrowDebugFile {
+        let schema = Arc::new(debug_schema(read_names, low_complexity, deletion_events));
+        let writer = File::create(filename).ok().and_then(|file| {
+            let options = IpcWriteOptions::default()
+                .try_with_compression(Some(CompressionType::ZSTD)).ok()?;
+            FileWriter::try_new_with_options(BufWriter::new(file), &schema, options).ok()
+        });
+        let Some(writer) = writer else {
+            quit_with_error(ErrorType::Io, &format!("unable to create {:?}", filename))
+        };
+        ArrowDebugFile {
+            writer, filename: filename.to_path_buf(), schema, rows: 0,
+            name: StringBuilder::new(), pos: UInt64Builder::new(),
+            base: StringBuilder::new(), depth: Float64Builder::new(),
+            invalid: UInt32Builder::new(), valid: UInt32Builder::new(),
+            pileup: StringBuilder::new(), status: StringBuilder::new(),
+            new_base: StringBuilder::new(), confidence: Float64Builder::new(),
+            low_complexity: low_complexity.then(BooleanBuilder::new),
+            deletion_event: deletion_events.then(StringBuilder::new),
+            reads: read_names.then(StringBuilder::new),
+        }
+    }
+
+    /// Adds one base. The low-complexity, deletion event and read-name values are only used if the
+    /// file has those columns.
+    pub fn add(&mut self, name: &str, pos: usize, summary: &BaseSummary, low_complexity: bool,
+               event: Option<&str>, reads: &str) {
+        self.name.append_value(name);
+        self.pos.append_value(pos as u64);
+        self.base.append_value(summary.base.to_string());
+        self.depth.append_value(summary.depth);
+        self.invalid.append_value(summary.invalid);
+        self.valid.append_value(summary.valid);
+        self.pileup.append_value(&summary.pileup);
+        self.status.append_value(&summary.status);
+        self.new_base.append_value(&summary.new_base);
+        self.confidence.append_option(summary.confidence);
+        if let Some(column) = &mut self.low_complexity {
+            column.append_value(low_complexity);
+        }
+        if let Some(column) = &mut self.deletion_event {
+            column.append_option(event);
+        }
+        if let Some(column) = &mut self.reads {
+            column.append_option((!reads.is_empty()).then_some(reads));
+        }
+        self.rows += 1;
+        if self.rows >= BATCH_ROWS {
+            self.write_batch();
+        }
+    }
+
+    /// Writes any remaining rows and the file's footer.
+    pub fn finish(mut self) {
+        self.write_batch();
+        if self.writer.finish().is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename))
+        }
+    }
+
+    fn write_batch(&mut self) {
+        if self.rows == 0 {
+            return;
+        }
+        self.rows = 0;
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.name.finish()), Arc::new(self.pos.finish()),
+            Arc::new(self.base.finish()), Arc::new(self.depth.finish()),
+            Arc::new(self.invalid.finish()), Arc::new(self.valid.finish()),
+            Arc::new(self.pileup.finish()), Arc::new(self.status.finish()),
+            Arc::new(self.new_base.finish()), Arc::new(self.confidence.finish()),
+        ];
+        if let Some(column) = &mut self.low_complexity {
+            columns.push(Arc::new(column.finish()));
+        }
+        if let Some(column) = &mut self.deletion_event {
+            columns.push(Arc::new(column.finish()));
+        }
+        if let Some(column) = &mut self.reads {
+            columns.push(Arc::new(column.finish()));
+        }
+        let result = RecordBatch::try_new(self.schema.clone(), columns)
+            .and_then(|batch| self.writer.write(&batch));
+        if let Err(e) = result {
+            quit_with_error(ErrorType::Io,
+                            &format!("unable to write to file {:?}: {}", self.filename, e))
+        }
+    }
+}
+
+
+/// The columns match the TSV debug file's, with the optional ones only present when used.
+fn debug_schema(read_names: bool, low_complexity: bool, deletion_events: bool) -> Schema {
+    let mut fields = vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("pos", DataType::UInt64, false),
+        Field::new("base", DataType::Utf8, false),
+        Field::new("depth", DataType::Float64, true),
+        Field::new("invalid", DataType::UInt32, true),
+        Field::new("valid", DataType::UInt32, true),
+        Field::new("pileup", DataType::Utf8, false),
+        Field::new("status", DataType::Utf8, false),
+        Field::new("new_base", DataType::Utf8, false),
+        Field::new("confidence", DataType::Float64, true),
+    ];
+    if low_complexity {
+        fields.push(Field::new("low_complexity", DataType::Boolean, false));
+    }
+    if deletion_events {
+        fields.push(Field::new("deletion_event", DataType::Utf8, true));
+    }
+    if read_names {
+        fields.push(Field::new("reads", DataType::Utf8, true));
+    }
+    Schema::new(fields)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::Array;
+    use arrow_array::types::Float64Type;
+    use arrow_ipc::reader::FileReader;
+
+    #[test]
+    fn test_arrow_debug_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("debug.arrow");
+        let mut file = ArrowDebugFile::create(&filename, true, false, false);
+        file.add("chrom", 0, &summary('A', 10.0, "A:10", "kept", "A", None), false, None, "");
+        file.add("chrom", 1, &summary('C', 9.5, "T:9", "changed", "T", Some(99.9)), false, None,
+                 "r1,r2");
+        file.add("plasmid", 0, &summary('G', 0.0, "", "low_depth", "G", None), false, None, "");
+        file.finish();
+
+        let reader = FileReader::try_new(File::open(&filename).unwrap(), None).unwrap();
+        assert_eq!(reader.schema().fields().len(), 11);
+        let batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 3);
+        let depth = batch.column_by_name("depth").unwrap().as_primitive::<Float64Type>();
+        assert_eq!(depth.values().to_vec(), vec![10.0, 9.5, 0.0]);
+        let confidence = batch.column_by_name("confidence").unwrap()
+            .as_primitive::<Float64Type>();
+        assert!(confidence.is_null(0));
+        assert_eq!(confidence.value(1), 99.9);
+        let reads = batch.column_by_name("reads").unwrap().as_string::<i32>();
+        assert!(reads.is_null(0));
+        assert_eq!(reads.value(1), "r1,r2");
+        let status = batch.column_by_name("status").unwrap().as_string::<i32>();
+        assert_eq!(status.value(2), "low_depth");
+    }
+
+    fn summary(base: char, depth: f64, pileup: &str, status: &str, new_base: &str,
+               confidence: Option<f64>) -> BaseSummary {
+        BaseSummary { base, depth, invalid: 2, valid: 5, pileup: pileup.to_string(),
+                      status: status.to_string(), new_base: new_base.to_string(), confidence }
+    }
+}
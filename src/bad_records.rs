@@ -0,0 +1,189 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// By default, a malformed alignment record stops Polypolish with an error. With
+// --skip-bad-records, such records are skipped (and a truncated file is treated as ending at the
+// last readable record), so alignments from a crashed aligner job can still be used. Skipped
+// records are counted and reported at the end of the run. Since a file can be read more than once
+// (e.g. scanned and then loaded), each record is only counted once. The read names of skipped
+// records are kept, as the rest of a read's alignments may be unusable without the skipped one
+// (e.g. a secondary alignment without its primary).
+
+// With --max-bad-records N, up to N bad records are skipped in the same way, but each one is
+// logged (with its file, line number and the start of the line) and Polypolish stops with an
+// error if there are more, as that suggests a badly damaged file rather than a few corrupted lines.
+
+use serde_json::json;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::log;
+use crate::misc::{Error, ErrorType};
+
+
+/// How many skipped records are described in the final report.
+const MAX_EXAMPLES: usize = 5;
+
+/// How much of a bad record's line is logged with --max-bad-records.
+const MAX_LOGGED_LENGTH: usize = 80;
+
+
+#[derive(Default)]
+pub struct BadRecords {
+    max: usize,  // none by default, any number (usize::MAX) or the --max-bad-records value
+    seen: HashSet<(PathBuf, usize)>,  // filename and line number
+    read_names: HashSet<String>,
+    examples: Vec<String>,
+}
+
+impl BadRecords {
+    pub fn new(skip_bad_records: bool, max_bad_records: Option<usize>) -> BadRecords {
+        let max = if skip_bad_records { usize::MAX } else { max_bad_records.unwrap_or(0) };
+        BadRecords { max, ..Default::default() }
+    }
+
+    /// Returns records with the same settings but nothing found yet.
+    pub fn fresh(&self) -> BadRecords {
+        BadRecords { max: self.max, ..Default::default() }
+    }
+
+    pub fn skipping(&self) -> bool {
+        self.max > 0
+    }
+
+    /// Returns the --max-bad-records limit, or None if there isn't one.
+    fn limit(&self) -> Option<usize> {
+        (self.max != usize::MAX).then_some(self.max)
+    }
+
+    /// Handles a record which couldn't be used. Without --skip-bad-records or --max-bad-records,
+    /// this returns an error, as it does when there are more than --max-bad-records. Otherwise
+    /// the record is counted and the caller should skip it. The record's text can be empty if it
+    /// couldn't be read at all.
+    pub fn found(&mut self, error: &str, filename: &Path, line_number: usize,
+                 record: &str) -> Result<(), Error> {
+        let description = format!("{} in {:?} (line {})", error, filename, line_number);
+        if !self.skipping() {
+            return Err(Error::new(ErrorType::Input, description));
+        }
+        if self.seen.insert((filename.to_path_buf(), line_number)) {
+            if let Some(max) = self.limit() {
+                if self.seen.len() > max {
+                    return Err(Error::new(ErrorType::Input,
+                                          format!("more than {} malformed alignment records \
+                                                   (--max-bad-records), the last was {}", max,
+                                                  description)));
+                }
+                log_bad_record(&description, filename, line_number, error, record);
+            }
+            if self.examples.len() < MAX_EXAMPLES {
+                self.examples.push(description);
+            }
+        }
+        let read_name = record.split('\t').next().unwrap_or_default();
+        if !read_name.is_empty() {
+            self.read_names.insert(read_name.to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns true if a record for this read was skipped.
+    pub fn read_has_bad_record(&self, read_name: &str) -> bool {
+        self.read_names.contains(read_name)
+    }
+
+    pub fn count(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Logs how many records were skipped, with a few examples.
+    pub fn report(&self) {
+        let count = self.count();
+        if count == 0 {
+            return;
+        }
+        let option = match self.limit() {
+            Some(max) => format!("--max-bad-records {}", max),
+            None      => "--skip-bad-records".to_string(),
+        };
+        log::text!("Skipped {} malformed alignment record{} ({}), including:", count,
+                   if count == 1 { "" } else { "s" }, option);
+        for example in &self.examples {
+            log::text!("  {}", example);
+        }
+        log::text!();
+        log::event("bad_records_skipped", json!({"count": count, "examples": self.examples}));
+    }
+}
+
+
+fn log_bad_record(description: &str, filename: &Path, line_number: usize, error: &str,
+                  record: &str) {
+    let start = match record.char_indices().nth(MAX_LOGGED_LENGTH) {
+        Some((i, _)) => format!("{}...", &record[..i]),
+        None         => record.to_string(),
+    };
+    log::text!("Skipping bad record: {}", description);
+    log::text!("  {}", start.replace('\t', " "));
+    log::event("bad_record_skipped", json!({"file": filename, "line": line_number,
+                                            "error": error, "record": start}));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_bad_records() {
+        let mut bad_records = BadRecords::new(false, Some(3));
+        assert!(bad_records.skipping());
+        assert_eq!(bad_records.limit(), Some(3));
+        let filename = Path::new("test.sam");
+        for line_number in 1..=3 {
+            assert!(bad_records.found("too few columns", filename, line_number, "read\t0").is_ok());
+        }
+
+        // A record seen again (e.g. when a file is scanned and then loaded) isn't counted again.
+        assert!(bad_records.found("too few columns", filename, 3, "read\t0").is_ok());
+        assert_eq!(bad_records.count(), 3);
+        assert!(bad_records.read_has_bad_record("read"));
+        assert!(!bad_records.read_has_bad_record("other_read"));
+
+        let error = bad_records.found("too few columns", filename, 4, "read\t0").unwrap_err();
+        assert_eq!(error.error_type, ErrorType::Input);
+        assert!(error.message.starts_with("more than 3 malformed alignment records"));
+        assert!(error.message.ends_with("(line 4)"));
+    }
+
+    #[test]
+    fn test_skip_bad_records() {
+        let mut bad_records = BadRecords::new(true, None);
+        assert_eq!(bad_records.limit(), None);
+        for line_number in 1..=100 {
+            assert!(bad_records.found("bad CIGAR", Path::new("test.sam"), line_number, "")
+                        .is_ok());
+        }
+        assert_eq!(bad_records.count(), 100);
+        assert_eq!(bad_records.examples.len(), MAX_EXAMPLES);
+    }
+
+    #[test]
+    fn test_no_skipping() {
+        let mut bad_records = BadRecords::new(false, None);
+        assert!(!bad_records.skipping());
+        assert_eq!(bad_records.found("bad CIGAR", Path::new("test.sam"), 7, "read\t0"),
+                   Err(Error::new(ErrorType::Input,
+                                  "bad CIGAR in \"test.sam\" (line 7)".to_string())));
+        assert_eq!(bad_records.count(), 0);
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// A minimal BAM reader. Rather than having a separate code path for BAM, each record is converted
+// to a SAM line, so the rest of Polypolish only has to deal with SAM. The format is described in
+// the SAM/BAM specification: https://samtools.github.io/hts-specs/SAMv1.pdf
+
+use flate2::read::MultiGzDecoder;
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+
+
+const CIGAR_OPS: &[u8] = b"MIDNSHP=X";
+const SEQ_BASES: &[u8] = b"=ACMGRSVTWYHKDBN";
+
+
+/// Returns true if the file is BAM (a BGZF-compressed file starting with the BAM magic string).
+pub fn is_bam(filename: &Path) -> bool {
+    let Ok(file) = File::open(filename) else { return false; };
+    let mut magic = [0u8; 4];
+    let mut decoder = MultiGzDecoder::new(BufReader::new(file));
+    decoder.read_exact(&mut magic).is_ok() && &magic == b"BAM\x01"
+}
+
+
+/// Iterates over the lines of a BAM file in SAM format: first the header lines, then one line per
+/// alignment record. The reader gives the decompressed BAM data.
+pub struct BamLines<R: Read> {
+    reader: BufReader<R>,
+    header_lines: std::vec::IntoIter<String>,
+    ref_names: Vec<String>,
+    record: Vec<u8>,
+}
+
+impl<R: Read> BamLines<R> {
+    pub fn new(reader: R) -> io::Result<BamLines<R>> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"BAM\x01" {
+            return Err(invalid_data("missing BAM magic string"));
+        }
+        let text_len = read_i32(&mut reader)? as usize;
+        let mut text = vec![0u8; text_len];
+        reader.read_exact(&mut text)?;
+        let text = String::from_utf8_lossy(&text);
+        let mut header_lines: Vec<String> = text.trim_end_matches('\0').lines()
+            .filter(|l| !l.is_empty()).map(|l| l.to_string()).collect();
+
+        let ref_count = read_i32(&mut reader)?;
+        let mut ref_names = Vec::new();
+        let mut ref_lengths = Vec::new();
+        for _ in 0..ref_count {
+            let name_len = read_i32(&mut reader)? as usize;
+            let mut name = vec![0u8; name_len];
+            reader.read_exact(&mut name)?;
+            ref_names.push(String::from_utf8_lossy(&name).trim_end_matches('\0').to_string());
+            ref_lengths.push(read_i32(&mut reader)?);
+        }
+
+        // The header text is optional in BAM, so we make @SQ lines from the reference list if
+        // they're missing.
+        if !header_lines.iter().any(|l| l.starts_with("@SQ")) {
+            for (name, length) in ref_names.iter().zip(&ref_lengths) {
+                header_lines.push(format!("@SQ\tSN:{}\tLN:{}", name, length));
+            }
+        }
+        Ok(BamLines { reader, header_lines: header_lines.into_iter(), ref_names,
+                      record: Vec::new() })
+    }
+
+    /// Reads the next record into self.record, returning false at the end of the file.
+    fn read_record(&mut self) -> io::Result<bool> {
+        let mut size_bytes = [0u8; 4];
+        match self.reader.read(&mut size_bytes[..1])? {
+            0 => return Ok(false),
+            _ => self.reader.read_exact(&mut size_bytes[1..])?,
+        }
+        let block_size = i32::from_le_bytes(size_bytes);
+        if block_size < 32 {
+            return Err(invalid_data("BAM record is too short"));
+        }
+        self.record.resize(block_size as usize, 0);
+        self.reader.read_exact(&mut self.record)?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for BamLines<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(line) = self.header_lines.next() {
+            return Some(Ok(line));
+        }
+        match self.read_record() {
+            Ok(true)  => Some(record_to_sam(&self.record, &self.ref_names)),
+            Ok(false) => None,
+            Err(e)    => Some(Err(e)),
+        }
+    }
+}
+
+
+/// Converts one BAM alignment record (not including its block_size) to a SAM line.
+fn record_to_sam(r: &[u8], ref_names: &[String]) -> io::Result<String> {
+    let ref_id = le_i32(r, 0);
+    let pos = le_i32(r, 4);
+    let name_len = r[8] as usize;
+    let mapq = r[9];
+    let cigar_count = le_u16(r, 12) as usize;
+    let flag = le_u16(r, 14);
+    let seq_len = le_i32(r, 16).max(0) as usize;
+    let next_ref_id = le_i32(r, 20);
+    let next_pos = le_i32(r, 24);
+    let template_len = le_i32(r, 28);
+
+    let name_start = 32;
+    let cigar_start = name_start + name_len;
+    let seq_start = cigar_start + 4 * cigar_count;
+    let qual_start = seq_start + seq_len.div_ceil(2);
+    let tags_start = qual_start + seq_len;
+    if r.len() < tags_start {
+        return Err(invalid_data("BAM record is truncated"));
+    }
+
+    let mut line = String::with_capacity(2 * r.len());
+    let name = &r[name_start..cigar_start];
+    line.push_str(String::from_utf8_lossy(name).trim_end_matches('\0'));
+    write!(line, "\t{}\t{}\t{}\t{}\t", flag, ref_name(ref_id, ref_names)?, pos + 1, mapq).unwrap();
+
+    if cigar_count == 0 {
+        line.push('*');
+    }
+    for i in 0..cigar_count {
+        let op = le_u32(r, cigar_start + 4 * i);
+        let op_char = *CIGAR_OPS.get((op & 0xf) as usize)
+            .ok_or_else(|| invalid_data("invalid CIGAR operation in BAM record"))?;
+        write!(line, "{}{}", op >> 4, op_char as char).unwrap();
+    }
+
+    let next_ref = if next_ref_id >= 0 && next_ref_id == ref_id {
+        "="
+    } else {
+        ref_name(next_ref_id, ref_names)?
+    };
+    write!(line, "\t{}\t{}\t{}\t", next_ref, next_pos + 1, template_len).unwrap();
+
+    if seq_len == 0 {
+        line.push('*');
+    }
+    for i in 0..seq_len {
+        let byte = r[seq_start + i / 2];
+        let code = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+        line.push(SEQ_BASES[code as usize] as char);
+    }
+    line.push('\t');
+    if seq_len == 0 || r[qual_start] == 0xff {
+        line.push('*');
+    } else {
+        line.extend(r[qual_start..tags_start].iter().map(|q| q.saturating_add(33) as char));
+    }
+
+    append_tags(&mut line, &r[tags_start..])?;
+    Ok(line)
+}
+
+
+fn ref_name(ref_id: i32, ref_names: &[String]) -> io::Result<&str> {
+    if ref_id < 0 {
+        return Ok("*");
+    }
+    ref_names.get(ref_id as usize).map(|n| n.as_str())
+        .ok_or_else(|| invalid_data("BAM record has an invalid reference ID"))
+}
+
+
+/// Appends the BAM record's optional fields (tags) to the SAM line.
+fn append_tags(line: &mut String, mut tags: &[u8]) -> io::Result<()> {
+    let truncated = || invalid_data("BAM record has a truncated tag");
+    while !tags.is_empty() {
+        if tags.len() < 3 {
+            return Err(truncated());
+        }
+        let tag = String::from_utf8_lossy(&tags[..2]).to_string();
+        let tag_type = tags[2];
+        tags = &tags[3..];
+        match tag_type {
+            b'A' => {
+                let c = *tags.first().ok_or_else(truncated)?;
+                write!(line, "\t{}:A:{}", tag, c as char).unwrap();
+                tags = &tags[1..];
+            },
+            b'c' | b'C' | b's' | b'S' | b'i' | b'I' => {
+                let (value, size) = read_integer(tags, tag_type).ok_or_else(truncated)?;
+                write!(line, "\t{}:i:{}", tag, value).unwrap();
+                tags = &tags[size..];
+            },
+            b'f' => {
+                let bytes = tags.get(..4).ok_or_else(truncated)?;
+                let value = f32::from_le_bytes(bytes.try_into().unwrap());
+                write!(line, "\t{}:f:{}", tag, value).unwrap();
+                tags = &tags[4..];
+            },
+            b'Z' | b'H' => {
+                let end = tags.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+                write!(line, "\t{}:{}:{}", tag, tag_type as char,
+                       String::from_utf8_lossy(&tags[..end])).unwrap();
+                tags = &tags[end + 1..];
+            },
+            b'B' => {
+                let subtype = *tags.first().ok_or_else(truncated)?;
+                let count = tags.get(1..5).ok_or_else(truncated)?;
+                let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+                tags = &tags[5..];
+                write!(line, "\t{}:B:{}", tag, subtype as char).unwrap();
+                for _ in 0..count {
+                    if subtype == b'f' {
+                        let bytes = tags.get(..4).ok_or_else(truncated)?;
+                        write!(line, ",{}", f32::from_le_bytes(bytes.try_into().unwrap()))
+                            .unwrap();
+                        tags = &tags[4..];
+                    } else {
+                        let (value, size) = read_integer(tags, subtype).ok_or_else(truncated)?;
+                        write!(line, ",{}", value).unwrap();
+                        tags = &tags[size..];
+                    }
+                }
+            },
+            _ => return Err(invalid_data("BAM record has an unknown tag type")),
+        }
+    }
+    Ok(())
+}
+
+
+/// Reads a little-endian integer of the given BAM type, returning its value and size in bytes.
+fn read_integer(bytes: &[u8], int_type: u8) -> Option<(i64, usize)> {
+    let size = match int_type {
+        b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        b'i' | b'I' => 4,
+        _           => return None,
+    };
+    let b = bytes.get(..size)?;
+    let value = match int_type {
+        b'c' => b[0] as i8 as i64,
+        b'C' => b[0] as i64,
+        b's' => i16::from_le_bytes([b[0], b[1]]) as i64,
+        b'S' => u16::from_le_bytes([b[0], b[1]]) as i64,
+        b'i' => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64,
+        _    => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64,
+    };
+    Some((value, size))
+}
+
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+
+fn le_i32(bytes: &[u8], i: usize) -> i32 {
+    i32::from_le_bytes(bytes[i..i+4].try_into().unwrap())
+}
+
+
+fn le_u32(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bytes[i..i+4].try_into().unwrap())
+}
+
+
+fn le_u16(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes(bytes[i..i+2].try_into().unwrap())
+}
+
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    /// Builds a small BAM file (as a single gzip member, which is enough for our reader).
+    fn make_bam() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(b"BAM\x01");
+        let text = b"@HD\tVN:1.6\tSO:coordinate\n";
+        data.extend((text.len() as i32).to_le_bytes());
+        data.extend(text);
+        data.extend(1i32.to_le_bytes());
+        data.extend(4i32.to_le_bytes());
+        data.extend(b"ctg\0");
+        data.extend(1000i32.to_le_bytes());
+
+        let mut record = Vec::new();
+        record.extend(0i32.to_le_bytes());             // refID
+        record.extend(99i32.to_le_bytes());            // pos
+        record.push(6);                                // l_read_name
+        record.push(60);                               // mapq
+        record.extend(0u16.to_le_bytes());             // bin
+        record.extend(2u16.to_le_bytes());             // n_cigar_op
+        record.extend(16u16.to_le_bytes());            // flag
+        record.extend(5i32.to_le_bytes());             // l_seq
+        record.extend((-1i32).to_le_bytes());          // next_refID
+        record.extend((-1i32).to_le_bytes());          // next_pos
+        record.extend(0i32.to_le_bytes());             // tlen
+        record.extend(b"read1\0");
+        record.extend((4u32 << 4).to_le_bytes());        // 4M
+        record.extend(((1u32 << 4) | 1).to_le_bytes());  // 1I
+        record.extend([0x12, 0x48, 0xf0]);             // ACGTN
+        record.extend([30, 30, 30, 30, 2]);
+        record.extend(b"NMC\x01");
+        record.extend(b"ZPZfail\0");
+        record.extend(b"XBBs\x02\x00\x00\x00\xff\xff\x03\x00");
+        data.extend((record.len() as i32).to_le_bytes());
+        data.extend(record);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_bam_lines() {
+        let lines: Vec<String> = BamLines::new(MultiGzDecoder::new(make_bam().as_slice())).unwrap()
+            .map(|l| l.unwrap()).collect();
+        assert_eq!(lines, vec!["@HD\tVN:1.6\tSO:coordinate".to_string(),
+                               "@SQ\tSN:ctg\tLN:1000".to_string(),
+                               "read1\t16\tctg\t100\t60\t4M1I\t*\t0\t0\tACGTN\t????#\t\
+                                NM:i:1\tZP:Z:fail\tXB:B:s,-1,3".to_string()]);
+    }
+
+    #[test]
+    fn test_not_bam() {
+        assert!(BamLines::new(b"@HD\tVN:1.6\n".as_slice()).is_err());
+    }
+}
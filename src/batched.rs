@@ -0,0 +1,94 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Normal polishing keeps a pileup base for every position of the assembly, which for a large
+// metagenome co-assembly can need more memory than a node has. With --batch-size, the sequences
+// are split into batches which are polished one at a time: the batch's pileups are made, the
+// alignment files are read (skipping alignments to other batches' sequences), and the batch is
+// polished and written out before its pileups are freed. The alignment files are read once per
+// batch, so this trades time for memory. Unlike --windowed, the alignments don't need to be sorted
+// by position, and since a read's alignments to other batches are still seen, depth contributions
+// are the same as when polishing all at once.
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use crate::log;
+use crate::pileup::{PileupSettings, Thresholds};
+use crate::polish;
+use crate::polish::{AlignmentInputs, ExtraInputs, OutputFiles, PolishedSeq};
+
+
+pub fn polish_batched(outputs: &mut OutputFiles, thresholds: &Thresholds,
+                      inputs: &AlignmentInputs, pileup_settings: PileupSettings,
+                      fasta: Vec<(String, String, String)>,
+                      batch_size: usize) -> Vec<PolishedSeq> {
+    let mut filter = inputs.filter.clone();
+    filter.skippable_seqs.extend(fasta.iter().map(|(name, _, _)| name.clone()));
+    let inputs = AlignmentInputs { filter: &filter, ..*inputs };
+    let batches = make_batches(fasta, batch_size);
+    let batch_count = batches.len();
+    let mut polished_seqs = Vec::new();
+    for (i, batch) in batches.into_iter().enumerate() {
+        let length: usize = batch.iter().map(|(_, _, seq)| seq.len()).sum();
+        log::section_header(&format!("Batch {} of {}", i + 1, batch_count));
+        log::text!("{} sequence{}, {} bp", batch.len(), if batch.len() == 1 { "" } else { "s" },
+                   length.to_formatted_string(&Locale::en));
+        log::text!();
+        log::event("batch_started", json!({"batch": i + 1, "batches": batch_count,
+                                           "sequences": batch.len(), "length": length}));
+        let (seq_names, mut pileups) = polish::make_pileups(batch, pileup_settings);
+        polish::load_alignments(&inputs, None, &mut pileups);
+        polished_seqs.extend(polish::polish_sequences(outputs, thresholds, None, false,
+                                                      &seq_names, &pileups,
+                                                      &ExtraInputs::default()));
+    }
+    polished_seqs
+}
+
+
+/// Splits the sequences (in order) into batches, each holding sequences until their total length
+/// reaches the batch size. A sequence longer than the batch size gets a batch of its own.
+fn make_batches(fasta: Vec<(String, String, String)>,
+                batch_size: usize) -> Vec<Vec<(String, String, String)>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut length = 0;
+    for record in fasta {
+        if !batch.is_empty() && length + record.2.len() > batch_size {
+            batches.push(std::mem::take(&mut batch));
+            length = 0;
+        }
+        length += record.2.len();
+        batch.push(record);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_batches() {
+        let fasta: Vec<_> = [("a", 5), ("b", 3), ("c", 4), ("d", 12), ("e", 1)].iter()
+            .map(|(name, len)| (name.to_string(), String::new(), "A".repeat(*len))).collect();
+        let batches = make_batches(fasta, 10);
+        let names: Vec<Vec<&str>> = batches.iter()
+            .map(|b| b.iter().map(|(name, _, _)| name.as_str()).collect()).collect();
+        assert_eq!(names, vec![vec!["a", "b"], vec!["c"], vec!["d"], vec!["e"]]);
+        assert!(make_batches(Vec::new(), 10).is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::misc::{quit_with_error, ErrorType};
+
+
+/// Writes BED intervals (or bedGraph, if values are given) from positions added one at a time,
+/// in order along each sequence. Adjacent positions with the same value are merged into a single
+/// interval.
+pub struct BedWriter {
+    writer: BufWriter<File>,
+    filename: PathBuf,
+    interval: Option<(String, usize, usize, String)>,  // name, start, end and value
+}
+
+impl BedWriter {
+    pub fn create(filename: &Path) -> BedWriter {
+        let file = match File::create(filename) {
+            Ok(file) => file,
+            Err(_)   => quit_with_error(ErrorType::Io, &format!("unable to create {:?}", filename)),
+        };
+        BedWriter { writer: BufWriter::new(file), filename: filename.to_path_buf(),
+                    interval: None }
+    }
+
+    /// Adds one position. The value can be empty, giving plain BED (name, start and end).
+    pub fn add(&mut self, name: &str, pos: usize, value: &str) {
+        if let Some((n, _, end, v)) = &mut self.interval {
+            if n == name && *end == pos && v == value {
+                *end += 1;
+                return;
+            }
+        }
+        self.write_interval();
+        self.interval = Some((name.to_string(), pos, pos + 1, value.to_string()));
+    }
+
+    /// Writes the last interval and flushes the file.
+    pub fn finish(mut self) {
+        self.write_interval();
+        if self.writer.flush().is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename));
+        }
+    }
+
+    fn write_interval(&mut self) {
+        let Some((name, start, end, value)) = self.interval.take() else { return; };
+        let result = if value.is_empty() {
+            writeln!(self.writer, "{}\t{}\t{}", name, start, end)
+        } else {
+            writeln!(self.writer, "{}\t{}\t{}\t{}", name, start, end, value)
+        };
+        if result.is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bed_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("test.bedgraph");
+        let mut bed = BedWriter::create(&filename);
+        for (pos, value) in ["1", "1", "2", "2", "2", "1"].iter().enumerate() {
+            bed.add("a", pos, value);
+        }
+        bed.add("b", 0, "1");
+        bed.add("b", 1, "1");
+        bed.add("b", 5, "1");
+        bed.finish();
+        assert_eq!(std::fs::read_to_string(&filename).unwrap(),
+                   "a\t0\t2\t1\na\t2\t5\t2\na\t5\t6\t1\nb\t0\t2\t1\nb\t5\t6\t1\n");
+
+        let mut bed = BedWriter::create(&filename);
+        bed.add("a", 3, "");
+        bed.add("a", 4, "");
+        bed.finish();
+        assert_eq!(std::fs::read_to_string(&filename).unwrap(), "a\t3\t5\n");
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// With --reads1/--reads2, polish aligns the reads itself instead of taking alignment files. It
+// runs bwa (or bwa-mem2) from the PATH: the assembly is indexed in a temporary directory, and the
+// SAM lines from bwa mem -a are read straight from its stdout into the pileups, so no alignment
+// file is ever written. bwa's stderr goes to a log file in the same directory, which is only
+// shown if bwa fails.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use serde_json::json;
+use tempfile::TempDir;
+
+use crate::aligner::Aligner;
+use crate::alignment::SamLines;
+use crate::log;
+use crate::misc;
+use crate::misc::ErrorType;
+use crate::run::RunContext;
+use crate::source::SamSource;
+
+
+/// The aligners which can be run, in order of preference.
+const ALIGNERS: [Aligner; 2] = [Aligner::Bwa, Aligner::BwaMem2];
+
+
+/// An assembly indexed for bwa, in a temporary directory which is removed when this is dropped.
+pub struct BwaIndex {
+    aligner: Aligner,
+    program: PathBuf,
+    threads: usize,
+    dir: TempDir,
+}
+
+impl BwaIndex {
+    /// Finds bwa on the PATH and indexes the given sequences (the assembly as the pileups are
+    /// made from it, e.g. rotated with --rotate, so the alignments always match).
+    pub fn build(fasta: &[(String, String, String)], threads: usize) -> BwaIndex {
+        log::section_header("Indexing assembly");
+        log::explanation("The reads will be aligned with bwa mem -a, so Polypolish gets all of \
+                          each read's alignments. The assembly is first indexed in a temporary \
+                          directory.");
+        let Some((aligner, program)) = find_aligner() else {
+            misc::quit_with_error(ErrorType::Io, "--reads1 needs bwa or bwa-mem2, but neither \
+                                                  was found on the PATH")
+        };
+        log::text!("Aligner: {}", program.display());
+        log::event("aligner_found", json!({"aligner": aligner.name(), "path": program}));
+        let dir = match tempfile::Builder::new().prefix("polypolish_").tempdir() {
+            Ok(dir) => dir,
+            Err(_)  => misc::quit_with_error(ErrorType::Io,
+                                             "unable to create a temporary directory for the \
+                                              bwa index"),
+        };
+        let index = BwaIndex { aligner, program, threads, dir };
+        if write_fasta(&index.reference(), fasta).is_err() {
+            misc::quit_with_error(ErrorType::Io,
+                                  &format!("unable to write to file {:?}", index.reference()))
+        }
+        let output = Command::new(&index.program).arg("index").arg(index.reference()).output();
+        match output {
+            Ok(output) if output.status.success() => (),
+            Ok(output) => misc::quit_with_error(ErrorType::Io,
+                                                &format!("{} index failed: {}", aligner.name(),
+                                                         last_line(&output.stderr))),
+            Err(_)     => misc::quit_with_error(ErrorType::Io,
+                                                &format!("unable to run {:?}", index.program)),
+        }
+        log::text!("Index built");
+        log::text!();
+        index
+    }
+
+    fn reference(&self) -> PathBuf {
+        self.dir.path().join("assembly.fasta")
+    }
+
+    /// Starts bwa mem -a on a reads file, giving its alignments as they are made.
+    pub fn align(&self, reads: &Path, run: &RunContext) -> SamSource {
+        let log_path = self.dir.path().join("mem.log");
+        let stderr = match File::create(&log_path) {
+            Ok(file) => file,
+            Err(_)   => misc::quit_with_error(ErrorType::Io,
+                                              &format!("unable to write to file {:?}", log_path)),
+        };
+        let child = Command::new(&self.program)
+            .arg("mem").arg("-a").arg("-t").arg(self.threads.to_string())
+            .arg(self.reference()).arg(reads)
+            .stdout(Stdio::piped()).stderr(stderr).spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(_)    => misc::quit_with_error(ErrorType::Io,
+                                               &format!("unable to run {:?}", self.program)),
+        };
+        log::event("alignment_started", json!({"aligner": self.aligner.name(), "reads": reads,
+                                               "threads": self.threads}));
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let lines = BwaLines { lines: stdout.lines(), child: Some(child), log_path,
+                               aligner: self.aligner, reads: reads.to_path_buf() };
+        SamSource::new(reads, SamLines::from_lines(reads, Box::new(lines), run))
+    }
+}
+
+
+/// The lines of bwa mem's output. Once they run out, bwa is waited for, and Polypolish quits if
+/// bwa failed, as its output may then be incomplete.
+struct BwaLines {
+    lines: io::Lines<BufReader<ChildStdout>>,
+    child: Option<Child>,
+    log_path: PathBuf,
+    aligner: Aligner,
+    reads: PathBuf,
+}
+
+impl Iterator for BwaLines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next();
+        if line.is_none() {
+            if let Some(mut child) = self.child.take() {
+                if !child.wait().is_ok_and(|status| status.success()) {
+                    let stderr = std::fs::read(&self.log_path).unwrap_or_default();
+                    misc::quit_with_error(ErrorType::Input,
+                                          &format!("{} mem failed for {:?}: {}",
+                                                   self.aligner.name(), self.reads,
+                                                   last_line(&stderr)))
+                }
+            }
+        }
+        line
+    }
+}
+
+
+/// Returns the first of the aligners found on the PATH, with its full path.
+fn find_aligner() -> Option<(Aligner, PathBuf)> {
+    let path = std::env::var_os("PATH")?;
+    ALIGNERS.into_iter().find_map(|aligner| {
+        std::env::split_paths(&path).map(|dir| dir.join(aligner.name()))
+            .find(|p| p.is_file()).map(|p| (aligner, p))
+    })
+}
+
+
+fn write_fasta(filename: &Path, fasta: &[(String, String, String)]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for (name, _, seq) in fasta {
+        writeln!(writer, ">{}", name)?;
+        writeln!(writer, "{}", seq)?;
+    }
+    writer.flush()
+}
+
+
+/// The last non-empty line of an aligner's stderr, for error messages.
+fn last_line(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let line = stderr.lines().map(str::trim).rfind(|l| !l.is_empty());
+    line.unwrap_or("no error message").to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_line() {
+        assert_eq!(last_line(b"[M::bwa_idx_load] loading\n[E::main] fail to open\n\n"),
+                   "[E::main] fail to open");
+        assert_eq!(last_line(b""), "no error message");
+    }
+}
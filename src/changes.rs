@@ -0,0 +1,178 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::misc::{quit_with_error, ErrorType};
+use crate::sink::ChangeSink;
+
+
+/// Each change is shown with this many bases of context on each side.
+const FLANK_SIZE: usize = 20;
+
+
+/// Writes one line for each change, with the original and polished sequence around it, e.g.
+/// ACGT[A]CGTA and ACGT[AT]CGTA for an insertion. Bases are added one at a time, in order along
+/// each sequence, so a change is written once the bases after it have been polished.
+pub struct ChangesFile {
+    writer: BufWriter<File>,
+    filename: PathBuf,
+    name: String,
+    original_flank: VecDeque<char>,  // the original bases just before the current one
+    polished_flank: VecDeque<char>,  // the polished bases just before the current one
+    pending: VecDeque<PendingChange>,
+}
+
+struct PendingChange {
+    pos: usize,
+    base: char,
+    new_seq: String,
+    confidence: f64,
+    original_context: (String, String),  // before and after the change
+    polished_context: (String, String),
+}
+
+impl PendingChange {
+    fn is_complete(&self) -> bool {
+        self.original_context.1.len() >= FLANK_SIZE && self.polished_context.1.len() >= FLANK_SIZE
+    }
+}
+
+impl ChangesFile {
+    pub fn create(filename: &Path) -> ChangesFile {
+        let file = match File::create(filename) {
+            Ok(file) => file,
+            Err(_)   => quit_with_error(ErrorType::Io, &format!("unable to create {:?}", filename)),
+        };
+        let mut changes = ChangesFile {
+            writer: BufWriter::new(file), filename: filename.to_path_buf(), name: String::new(),
+            original_flank: VecDeque::new(), polished_flank: VecDeque::new(),
+            pending: VecDeque::new(),
+        };
+        changes.write_line("name\tpos\tbase\tnew_base\tconfidence\toriginal_context\t\
+                            polished_context");
+        changes
+    }
+
+    /// Writes any changes still waiting for context (near the end of a sequence) and flushes the
+    /// file.
+    pub fn finish(mut self) {
+        self.write_pending();
+        if self.writer.flush().is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename));
+        }
+    }
+
+    fn write_pending(&mut self) {
+        while let Some(p) = self.pending.pop_front() {
+            self.write_change(&p);
+        }
+    }
+
+    fn write_change(&mut self, p: &PendingChange) {
+        let polished = p.new_seq.replace('-', "");
+        self.write_line(&format!("{}\t{}\t{}\t{}\t{:.2}\t{}[{}]{}\t{}[{}]{}", self.name, p.pos,
+                                 p.base, p.new_seq, p.confidence, p.original_context.0, p.base,
+                                 p.original_context.1, p.polished_context.0, polished,
+                                 p.polished_context.1));
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if writeln!(self.writer, "{}", line).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename));
+        }
+    }
+}
+
+impl ChangeSink for ChangesFile {
+    fn add_base(&mut self, name: &str, pos: usize, base: char, polished: &str,
+                change: Option<(&str, f64)>) {
+        if name != self.name {
+            self.write_pending();
+            self.name = name.to_string();
+            self.original_flank.clear();
+            self.polished_flank.clear();
+        }
+        for p in &mut self.pending {
+            if p.original_context.1.len() < FLANK_SIZE {
+                p.original_context.1.push(base);
+            }
+            for c in polished.chars() {
+                if p.polished_context.1.len() < FLANK_SIZE {
+                    p.polished_context.1.push(c);
+                }
+            }
+        }
+        while self.pending.front().is_some_and(|p| p.is_complete()) {
+            let p = self.pending.pop_front().unwrap();
+            self.write_change(&p);
+        }
+        if let Some((new_seq, confidence)) = change {
+            self.pending.push_back(PendingChange {
+                pos, base, new_seq: new_seq.to_string(), confidence,
+                original_context: (self.original_flank.iter().collect(), String::new()),
+                polished_context: (self.polished_flank.iter().collect(), String::new()),
+            });
+        }
+        push_flank(&mut self.original_flank, base);
+        for c in polished.chars() {
+            push_flank(&mut self.polished_flank, c);
+        }
+    }
+
+    fn finish(self: Box<Self>) {
+        ChangesFile::finish(*self);
+    }
+}
+
+
+fn push_flank(flank: &mut VecDeque<char>, c: char) {
+    flank.push_back(c);
+    if flank.len() > FLANK_SIZE {
+        flank.pop_front();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("changes.tsv");
+        let mut changes = ChangesFile::create(&filename);
+        let original = "ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT";
+        for (pos, base) in original.chars().enumerate() {
+            match pos {
+                22 => changes.add_base("a", pos, base, "GT", Some(("GT", 30.0))),
+                30 => changes.add_base("a", pos, base, "", Some(("-", 25.5))),
+                _  => changes.add_base("a", pos, base, &base.to_string(), None),
+            }
+        }
+        changes.add_base("b", 0, 'T', "C", Some(("C", 10.0)));
+        changes.add_base("b", 1, 'T', "T", None);
+        changes.finish();
+        let lines: Vec<String> = std::fs::read_to_string(&filename).unwrap()
+            .lines().map(|l| l.to_string()).collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("name\tpos\t"));
+        assert_eq!(lines[1], "a\t22\tG\tGT\t30.00\tGTACGTACGTACGTACGTAC[G]TACGTACGTACGTACGTACG\t\
+                              GTACGTACGTACGTACGTAC[GT]TACGTACTACGTACGTACGT");
+        assert_eq!(lines[2], "a\t30\tG\t-\t25.50\tGTACGTACGTACGTACGTAC[G]TACGTACGTACGTACGT\t\
+                              TACGTACGTACGTTACGTAC[]TACGTACGTACGTACGT");
+        assert_eq!(lines[3], "b\t0\tT\tC\t10.00\t[T]T\t[C]T");
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Loading alignments is by far the slowest part of a large polishing run. With --checkpoint, the
+// pileups are saved after each alignment file is loaded, so a run which is stopped (e.g. by a
+// cluster's time limit) can be resumed without loading those files again. A checkpoint is only
+// used if the input files and the settings which affect loading are unchanged.
+
+// The same format is used by --save-pileup and --load-pileup, which let the alignments be loaded
+// once and then polished repeatedly with different thresholds.
+
+use clap::crate_version;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::alignment::{DiscardCounts, SamCounts};
+use crate::log;
+use crate::misc::{quit_with_error, ErrorType, FastHashMap};
+use crate::pileup::Pileup;
+
+
+const CHECKPOINT_FILENAME: &str = "pileups.tsv.gz";
+
+
+/// Combines the settings which affect alignment loading with the path, size and modification time
+/// of each input file, to tell whether saved pileups came from the same inputs.
+pub fn fingerprint(settings: Value, inputs: &[&PathBuf]) -> Value {
+    let files: Vec<Value> = inputs.iter().map(|f| file_fingerprint(f)).collect();
+    json!({"version": crate_version!(), "settings": settings, "files": files})
+}
+
+
+/// How far alignment loading has got: the counts for each alignment file loaded so far.
+#[derive(Default, Serialize, Deserialize)]
+pub struct LoadState {
+    #[serde(rename = "files")]
+    pub file_counts: Vec<SamCounts>,
+    pub discarded: DiscardCounts,
+    #[serde(rename = "overlapping_pairs")]
+    pub overlap_count: usize,  // read pairs with overlapping mates (--merge-overlaps)
+}
+
+
+pub struct Checkpoint {
+    dir: PathBuf,
+    fingerprint: Value,
+}
+
+impl Checkpoint {
+    /// Creates the checkpoint directory (if needed).
+    pub fn new(dir: &Path, fingerprint: Value) -> Checkpoint {
+        if let Err(e) = fs::create_dir_all(dir) {
+            quit_with_error(ErrorType::Io,
+                            &format!("unable to create checkpoint directory {:?}: {}", dir, e));
+        }
+        Checkpoint { dir: dir.to_path_buf(), fingerprint }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(CHECKPOINT_FILENAME)
+    }
+
+    /// Restores the pileups from the checkpoint and returns how far loading got. Returns None
+    /// (with the pileups untouched) if there is no checkpoint or it was made for a different run.
+    pub fn resume(&self, pileups: &mut FastHashMap<String, Pileup>) -> Option<LoadState> {
+        let path = self.path();
+        if !path.exists() {
+            return None;
+        }
+        let (header, lines) = open_pileup_file(&path);
+        if header["fingerprint"] != self.fingerprint {
+            log::text!("{} was made with different inputs or settings, so it will be replaced",
+                       path.display());
+            log::text!();
+            return None;
+        }
+        let state = restore_pileups(&path, &header, lines, pileups);
+        log::text!("Resuming from {} ({} alignment file{} already loaded)", path.display(),
+                   state.file_counts.len(), if state.file_counts.len() == 1 { "" } else { "s" });
+        log::event("checkpoint_resumed", json!({"file": path,
+                                                "files_loaded": state.file_counts.len()}));
+        Some(state)
+    }
+
+    /// Saves the pileups and loading state, replacing any earlier checkpoint.
+    pub fn save(&self, state: &LoadState, pileups: &FastHashMap<String, Pileup>) {
+        let path = self.path();
+        if let Err(e) = write_pileup_file(&path, &self.fingerprint, state, pileups) {
+            quit_with_error(ErrorType::Io,
+                            &format!("unable to save checkpoint {:?}: {}", path, e));
+        }
+        log::text!("  checkpoint saved to {}", path.display());
+        log::event("checkpoint_saved", json!({"file": path,
+                                              "files_loaded": state.file_counts.len()}));
+    }
+}
+
+
+/// Saves the pileups for --save-pileup.
+pub fn save_pileup(filename: &Path, fingerprint: &Value, state: &LoadState,
+                   pileups: &FastHashMap<String, Pileup>) {
+    if let Err(e) = write_pileup_file(filename, fingerprint, state, pileups) {
+        quit_with_error(ErrorType::Io, &format!("unable to save pileup {:?}: {}", filename, e));
+    }
+    log::text!("Pileup saved to {}", filename.display());
+    log::event("pileup_saved", json!({"file": filename}));
+    log::text!();
+}
+
+
+/// Loads pileups saved with --save-pileup (or a checkpoint) for --load-pileup. Unlike a
+/// checkpoint, the fingerprint isn't checked, as the point is to polish the same pileup with
+/// different settings, but the saved pileups must match the assembly's sequences.
+pub fn load_pileup(filename: &Path, pileups: &mut FastHashMap<String, Pileup>) -> LoadState {
+    let (header, lines) = open_pileup_file(filename);
+    let state = restore_pileups(filename, &header, lines, pileups);
+    log::text!("{}: pileup from {} alignment file{}", filename.display(),
+               state.file_counts.len(), if state.file_counts.len() == 1 { "" } else { "s" });
+    log::event("pileup_loaded", json!({"file": filename,
+                                       "files_loaded": state.file_counts.len()}));
+    state
+}
+
+
+/// The pileup file is written to a temporary file which then replaces the target, so a run
+/// stopped while saving still leaves the old file intact.
+fn write_pileup_file(filename: &Path, fingerprint: &Value, state: &LoadState,
+                     pileups: &FastHashMap<String, Pileup>) -> io::Result<()> {
+    let dir = match filename.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _                                        => Path::new("."),
+    };
+    let temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    let mut writer = GzEncoder::new(BufWriter::new(temp_file), Compression::fast());
+    writeln!(writer, "{}", json!({"fingerprint": fingerprint, "state": state}))?;
+    for (name, pileup) in pileups {
+        writeln!(writer, ">{}\t{}\t{}", name, pileup.bases.len(), pileup.homopolymer_trimmed)?;
+        for base in &pileup.bases {
+            writeln!(writer, "{}", base.saved_line())?;
+        }
+    }
+    let temp_file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    temp_file.as_file().sync_all()?;
+    temp_file.persist(filename)?;
+    Ok(())
+}
+
+
+/// Opens a pileup file and reads its header line, leaving the remaining lines to be read.
+fn open_pileup_file(filename: &Path) -> (Value, impl Iterator<Item=io::Result<String>>) {
+    let mut lines = match File::open(filename) {
+        Ok(file) => BufReader::new(MultiGzDecoder::new(file)).lines(),
+        Err(_)   => quit_with_error(ErrorType::Io, &format!("unable to load {:?}", filename)),
+    };
+    let header: Option<Value> = lines.next().and_then(|l| l.ok())
+        .and_then(|l| serde_json::from_str(&l).ok());
+    let Some(header) = header else { malformed_pileup_file(filename); };
+    (header, lines)
+}
+
+
+fn file_fingerprint(filename: &Path) -> Value {
+    let path = fs::canonicalize(filename).unwrap_or_else(|_| filename.to_path_buf());
+    let metadata = fs::metadata(filename).ok();
+    let modified = metadata.as_ref().and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+    json!({"path": path, "size": metadata.map(|m| m.len()), "modified": modified})
+}
+
+
+/// Reads the saved pileups (which must cover exactly the given pileups' sequences) and returns
+/// the saved loading state.
+fn restore_pileups(filename: &Path, header: &Value, lines: impl Iterator<Item=io::Result<String>>,
+                   pileups: &mut FastHashMap<String, Pileup>) -> LoadState {
+    let Ok(state) = LoadState::deserialize(&header["state"]) else {
+        malformed_pileup_file(filename);
+    };
+    if restore_pileup_lines(lines, pileups).is_none() {
+        malformed_pileup_file(filename);
+    }
+    state
+}
+
+
+fn restore_pileup_lines(mut lines: impl Iterator<Item=io::Result<String>>,
+                        pileups: &mut FastHashMap<String, Pileup>) -> Option<()> {
+    let mut restored_count = 0;
+    while let Some(header) = lines.next() {
+        let header = header.ok()?;
+        let mut parts = header.strip_prefix('>')?.split('\t');
+        let (name, length, homopolymer_trimmed) = (parts.next()?, parts.next()?, parts.next()?);
+        let pileup = pileups.get_mut(name)?;
+        if pileup.bases.len() != length.parse::<usize>().ok()? {
+            return None;
+        }
+        pileup.homopolymer_trimmed = homopolymer_trimmed.parse().ok()?;
+        for base in pileup.bases.iter_mut() {
+            if !base.restore(&lines.next()?.ok()?) {
+                return None;
+            }
+        }
+        restored_count += 1;
+    }
+    (restored_count == pileups.len()).then_some(())
+}
+
+
+fn malformed_pileup_file(filename: &Path) -> ! {
+    quit_with_error(ErrorType::Input,
+                    &format!("{:?} is malformed or doesn't match the assembly", filename))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alignment::DiscardReason;
+
+    fn make_pileups() -> FastHashMap<String, Pileup> {
+        let mut pileups = FastHashMap::default();
+        pileups.insert("a".to_string(), Pileup::new("ACGT", u32::MAX, 0, Some(1), false));
+        pileups.insert("b".to_string(), Pileup::new("GGA", u32::MAX, 0, Some(1), false));
+        pileups
+    }
+
+    #[test]
+    fn test_save_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = Checkpoint::new(dir.path(), fingerprint(json!({"careful": false}), &[]));
+        let mut pileups = make_pileups();
+        assert!(checkpoint.resume(&mut pileups).is_none());
+
+        pileups.get_mut("a").unwrap().bases[1].add_seq("CT", 1.0, 1.0);
+        pileups.get_mut("b").unwrap().bases[2].add_seq("-", 0.5, 1.0);
+        pileups.get_mut("b").unwrap().homopolymer_trimmed = 3;
+        let mut state = LoadState::default();
+        state.file_counts.push(SamCounts { alignments: 10, used: 8, reads: 9,
+                                           multi_aligned_reads: 1, unmapped: 3 });
+        state.discarded.add_many(DiscardReason::SoftClipped, 2);
+        checkpoint.save(&state, &pileups);
+
+        let mut resumed_pileups = make_pileups();
+        let resumed = checkpoint.resume(&mut resumed_pileups).unwrap();
+        assert_eq!(resumed.file_counts.len(), 1);
+        assert_eq!(resumed.file_counts[0].used, 8);
+        assert_eq!(resumed.file_counts[0].unmapped, 3);
+        assert_eq!(resumed.discarded, state.discarded);
+        for (name, pileup) in &pileups {
+            let resumed_pileup = &resumed_pileups[name];
+            assert_eq!(pileup.homopolymer_trimmed, resumed_pileup.homopolymer_trimmed);
+            for (a, b) in pileup.bases.iter().zip(&resumed_pileup.bases) {
+                assert_eq!(a.saved_line(), b.saved_line());
+            }
+        }
+
+        // A checkpoint made with different settings isn't used.
+        let other = Checkpoint::new(dir.path(), fingerprint(json!({"careful": true}), &[]));
+        assert!(other.resume(&mut make_pileups()).is_none());
+
+        // But its pileups can still be loaded directly.
+        let mut loaded_pileups = make_pileups();
+        let loaded = load_pileup(&checkpoint.path(), &mut loaded_pileups);
+        assert_eq!(loaded.file_counts.len(), 1);
+        assert_eq!(loaded_pileups["b"].homopolymer_trimmed, 3);
+    }
+
+    #[test]
+    fn test_load_state_json() {
+        let mut state = LoadState { overlap_count: 4, ..Default::default() };
+        state.file_counts.push(SamCounts { alignments: 10, used: 8, reads: 9,
+                                           multi_aligned_reads: 1, unmapped: 0 });
+        state.discarded.add_many(DiscardReason::BadRecord, 2);
+        let value = json!(state);
+        assert_eq!(value["files"][0]["multi_aligned_reads"], 1);
+        assert_eq!(value["discarded"]["bad_record"], 2);
+        assert_eq!(value["discarded"]["careful"], 0);
+        assert_eq!(value["overlapping_pairs"], 4);
+        let restored = LoadState::deserialize(&value).unwrap();
+        assert_eq!(restored.discarded, state.discarded);
+        assert_eq!(restored.overlap_count, 4);
+
+        // Every discard reason must be present.
+        let mut value = value;
+        value["discarded"].as_object_mut().unwrap().remove("careful");
+        assert!(LoadState::deserialize(&value).is_err());
+
+        // Except those added since older checkpoints (clipped reasons and unmapped counts).
+        let mut value = json!(state);
+        value["discarded"].as_object_mut().unwrap().remove("soft_clipped");
+        value["discarded"].as_object_mut().unwrap().remove("hard_clipped");
+        value["files"][0].as_object_mut().unwrap().remove("unmapped");
+        let restored = LoadState::deserialize(&value).unwrap();
+        assert_eq!(restored.discarded, state.discarded);
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Low-complexity regions (e.g. long homopolymers) are found with a DUST score, using the same
+// window size and threshold as sdust. Each window's score is based on how often its triplets
+// repeat: sum(c * (c - 1) / 2) / (l - 1), where c is the count of each triplet and l is the
+// number of triplets in the window.
+
+
+const DUST_WINDOW: usize = 64;
+const DUST_THRESHOLD: f64 = 20.0;
+
+
+/// Returns whether each position of the sequence is in a low-complexity window. Sequences shorter
+/// than the window size are scored as a single window.
+pub fn low_complexity_mask(seq: &str) -> Vec<bool> {
+    let seq = seq.as_bytes();
+    let mut mask = vec![false; seq.len()];
+    let window = DUST_WINDOW.min(seq.len());
+    if window < 4 {
+        return mask;
+    }
+    let triplets: Vec<Option<usize>> = seq.windows(3).map(triplet_index).collect();
+    let window_triplets = window - 2;
+    let mut counts = [0usize; 64];
+    let mut score_sum = 0;  // sum of c * (c - 1) / 2 for the current window
+    let mut marked_up_to = 0;
+    for i in 0..triplets.len() {
+        if let Some(t) = triplets[i] {
+            score_sum += counts[t];
+            counts[t] += 1;
+        }
+        if i >= window_triplets {
+            if let Some(t) = triplets[i - window_triplets] {
+                counts[t] -= 1;
+                score_sum -= counts[t];
+            }
+        }
+        if i + 1 < window_triplets {
+            continue;
+        }
+        let start = i + 1 - window_triplets;
+        let score = score_sum as f64 / (window_triplets - 1) as f64;
+        if score > DUST_THRESHOLD {
+            mask[marked_up_to.max(start)..start + window].iter_mut().for_each(|m| *m = true);
+            marked_up_to = start + window;
+        }
+    }
+    mask
+}
+
+
+/// Returns a number (0-63) for the triplet, or None if it contains a base other than A, C, G or T.
+fn triplet_index(triplet: &[u8]) -> Option<usize> {
+    let mut index = 0;
+    for b in triplet {
+        let code = match b {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _           => return None,
+        };
+        index = index * 4 + code;
+    }
+    Some(index)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triplet_index() {
+        assert_eq!(triplet_index(b"AAA"), Some(0));
+        assert_eq!(triplet_index(b"TTT"), Some(63));
+        assert_eq!(triplet_index(b"acg"), Some(6));
+        assert_eq!(triplet_index(b"ANA"), None);
+    }
+
+    #[test]
+    fn test_low_complexity_mask() {
+        let random = "GATCCTAGGCTAACGTTGCAAGTCCGATAGCTTACGGATCAGTTCAGCGATCCAATGGCTACGTAGCTTGA";
+        assert!(low_complexity_mask(random).iter().all(|m| !m));
+
+        let homopolymer = "A".repeat(80);
+        let seq = format!("{}{}{}", random, homopolymer, random);
+        let mask = low_complexity_mask(&seq);
+        assert_eq!(mask.len(), seq.len());
+        assert!(mask[random.len()..random.len() + 80].iter().all(|m| *m));
+        assert!(!mask[0]);
+        assert!(!mask[seq.len() - 1]);
+
+        assert!(low_complexity_mask(&"C".repeat(50)).iter().all(|m| *m));
+        assert_eq!(low_complexity_mask("AAA"), vec![false; 3]);
+        assert!(low_complexity_mask("").is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Gzipped alignment files (BAM and gzipped SAM) are decompressed on their own thread, which works
+// a few chunks ahead of the reader. Decompression and parsing then happen at the same time, rather
+// than decompression limiting how fast alignments can be loaded.
+
+use flate2::read::MultiGzDecoder;
+
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+
+
+/// The size of each decompressed chunk passed from the decompression thread.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// How many decompressed chunks can be waiting to be read.
+const CHUNKS_AHEAD: usize = 4;
+
+
+/// Reads decompressed data from a gzip (or BGZF) stream which is decompressed on another thread.
+pub struct ThreadedGzReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl ThreadedGzReader {
+    pub fn new<R: Read + Send + 'static>(reader: R) -> ThreadedGzReader {
+        let (sender, receiver) = sync_channel(CHUNKS_AHEAD);
+        thread::spawn(move || {
+            let mut decoder = MultiGzDecoder::new(BufReader::new(reader));
+            loop {
+                let (chunk, error) = read_chunk(&mut decoder);
+                let at_end = chunk.is_empty();
+
+                // A failed send means the reader was dropped, so there's no need to continue.
+                // Data read before an error (e.g. from a truncated file) is sent before the error.
+                if !at_end && sender.send(Ok(chunk)).is_err() {
+                    break;
+                }
+                if let Some(e) = error {
+                    let _ = sender.send(Err(e));
+                    break;
+                }
+                if at_end {
+                    let _ = sender.send(Ok(Vec::new()));
+                    break;
+                }
+            }
+        });
+        ThreadedGzReader { receiver, chunk: Vec::new(), pos: 0, finished: false }
+    }
+}
+
+impl Read for ThreadedGzReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.chunk = match self.receiver.recv() {
+                Ok(chunk) => chunk?,
+                Err(_)    => Vec::new(),  // the thread has stopped
+            };
+            self.pos = 0;
+            if self.chunk.is_empty() {
+                self.finished = true;
+                return Ok(0);
+            }
+        }
+        let count = buf.len().min(self.chunk.len() - self.pos);
+        buf[..count].copy_from_slice(&self.chunk[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+
+/// Reads up to CHUNK_SIZE bytes, returning a shorter (or empty) chunk only at the end of the data
+/// or when there was an error. Any data read before an error is kept.
+fn read_chunk(reader: &mut impl Read) -> (Vec<u8>, Option<io::Error>) {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    let error = reader.take(CHUNK_SIZE as u64).read_to_end(&mut chunk).err();
+    (chunk, error)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_threaded_gz_reader() {
+        // Multiple gzip members (as in BGZF) spanning several chunks.
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| b"ACGT\n"[i % 5]).collect();
+        let mut compressed = gzip(&data[..CHUNK_SIZE + 100]);
+        compressed.extend(gzip(&data[CHUNK_SIZE + 100..]));
+        let mut decompressed = Vec::new();
+        ThreadedGzReader::new(io::Cursor::new(compressed))
+            .read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_threaded_gz_reader_truncated() {
+        // Data before the point of truncation is still read, followed by an error.
+        let data: Vec<u8> = (0..100000).map(|i| b"ACGT\n"[i % 5]).collect();
+        let compressed = gzip(&data);
+        let mut reader = ThreadedGzReader::new(io::Cursor::new(compressed[..100].to_vec()));
+        let mut decompressed = vec![0; data.len()];
+        let mut count = 0;
+        while let Ok(n) = reader.read(&mut decompressed[count..]) {
+            assert!(n > 0);
+            count += n;
+        }
+        assert!(count > 0);
+        assert_eq!(decompressed[..count], data[..count]);
+    }
+
+    #[test]
+    fn test_threaded_gz_reader_error() {
+        let mut decompressed = Vec::new();
+        let result = ThreadedGzReader::new(io::Cursor::new(b"not gzipped".to_vec()))
+            .read_to_end(&mut decompressed);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::alignment::{Alignment, SamLines};
+use crate::log;
+use crate::misc::{quit_with_error, ErrorType};
+use crate::run::RunContext;
+
+
+/// How many primary alignments are looked at (from the start of each file) when estimating depth.
+const DEPTH_SAMPLE_SIZE: usize = 100000;
+
+
+/// Decides which reads are kept when downsampling. The choice is made from a hash of the read
+/// name, so all of a read's alignments are kept or discarded together, as are a pair's mates
+/// (which share a name) in the two alignment files. The same seed always gives the same reads.
+#[derive(Clone, Copy)]
+pub struct Downsampler {
+    threshold: Option<u64>,  // reads with a hash below this are kept (None keeps all reads)
+    seed: u64,
+}
+
+impl Downsampler {
+    /// A downsampler which keeps all reads.
+    pub fn keep_all() -> Downsampler {
+        Downsampler { threshold: None, seed: 0 }
+    }
+
+    /// A downsampler which keeps approximately the given fraction of reads.
+    pub fn new(fraction: f64, seed: u64) -> Downsampler {
+        if fraction >= 1.0 {
+            return Downsampler::keep_all();
+        }
+        Downsampler { threshold: Some((fraction * u64::MAX as f64) as u64), seed }
+    }
+
+    pub fn keeps(&self, read_name: &str) -> bool {
+        match self.threshold {
+            Some(threshold) => hash_read_name(read_name, self.seed) < threshold,
+            None            => true,
+        }
+    }
+}
+
+
+/// Returns a well-mixed 64-bit hash of a read name (FNV-1a, starting from a seed-dependent value,
+/// followed by a SplitMix64 finaliser). This is implemented here (instead of using the standard
+/// library's hasher) so that downsampling gives the same reads with any version of Rust.
+fn hash_read_name(read_name: &str, seed: u64) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325 ^ seed.wrapping_mul(0x9e3779b97f4a7c15);
+    for &b in read_name.as_bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+
+/// Estimates the read depth of the alignments and, if it's over the target depth, returns a
+/// downsampler to bring it down to the target.
+pub fn make_downsampler(target_depth: Option<f64>, seed: u64, sam: &[PathBuf],
+                        assembly_length: usize, run: &RunContext) -> Downsampler {
+    let Some(target_depth) = target_depth else { return Downsampler::keep_all(); };
+    log::section_header("Downsampling alignments");
+    log::explanation("The read depth is estimated from the first alignments in each file. If it \
+                      is over the target depth, reads are randomly discarded to reduce it.");
+    let mut aligned_bases = 0.0;
+    for s in sam {
+        let file_bases = match estimate_aligned_bases(s, run) {
+            Ok(file_bases) => file_bases,
+            Err(_) => quit_with_error(ErrorType::Io,
+                                      &format!("unable to load alignments from {:?}", s)),
+        };
+        log::text!("{}: ~{} aligned bases", s.display(),
+                   (file_bases as u64).to_formatted_string(&Locale::en));
+        aligned_bases += file_bases;
+    }
+    let depth = aligned_bases / assembly_length as f64;
+    let fraction = (target_depth / depth).min(1.0);
+    log::text!("Estimated depth: {:.1}x", depth);
+    if fraction < 1.0 {
+        log::text!("Keeping {:.2}% of reads for a target depth of {}x (seed {})",
+                   100.0 * fraction, target_depth, seed);
+    } else {
+        log::text!("Not downsampling, as the depth is not over the target of {}x", target_depth);
+    }
+    log::text!();
+    log::event("downsampling", json!({"estimated_depth": depth, "target_depth": target_depth,
+                                      "fraction_kept": fraction, "seed": seed}));
+    Downsampler::new(fraction, seed)
+}
+
+
+/// Estimates the number of reference bases covered by primary alignments in the file. The first
+/// alignments are counted and extrapolated to the whole file by size, so this doesn't need to
+/// read through large files.
+fn estimate_aligned_bases(filename: &Path, run: &RunContext) -> io::Result<f64> {
+    let mut lines = SamLines::open(filename, run)?;
+    let mut start_bytes = None;
+    let mut sample_bases = 0;
+    let mut sample_count = 0;
+    while let Some(line) = lines.next() {
+        let sam_line = line?;
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        let start = *start_bytes.get_or_insert(lines.bytes_read());
+        let Ok(alignment) = Alignment::new_quick(&sam_line) else { continue; };
+        if !alignment.is_aligned() || !alignment.is_primary() {continue;}
+        sample_bases += alignment.get_ref_end() - alignment.ref_start;
+        sample_count += 1;
+        if sample_count >= DEPTH_SAMPLE_SIZE {
+            let sample_bytes = lines.bytes_read().saturating_sub(start).max(1);
+            let remaining_bytes = lines.file_size.saturating_sub(start);
+            return Ok(sample_bases as f64 * remaining_bytes as f64 / sample_bytes as f64);
+        }
+    }
+    Ok(sample_bases as f64)  // the whole file was read, so this is exact
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downsampler() {
+        let names: Vec<String> = (0..10000).map(|i| format!("read_{}", i)).collect();
+        assert!(names.iter().all(|n| Downsampler::keep_all().keeps(n)));
+        assert!(names.iter().all(|n| Downsampler::new(1.5, 0).keeps(n)));
+        let kept = names.iter().filter(|n| Downsampler::new(0.25, 0).keeps(n)).count();
+        assert!(kept > 2300 && kept < 2700);
+
+        // Reads kept at a lower fraction are also kept at a higher one.
+        assert!(names.iter().filter(|n| Downsampler::new(0.1, 0).keeps(n))
+                .all(|n| Downsampler::new(0.2, 0).keeps(n)));
+    }
+
+    #[test]
+    fn test_downsampler_seed() {
+        let names: Vec<String> = (0..1000).map(|i| format!("read_{}", i)).collect();
+        let kept = |seed| names.iter().map(|n| Downsampler::new(0.5, seed).keeps(n))
+            .collect::<Vec<bool>>();
+        assert_eq!(kept(1), kept(1));
+        assert_ne!(kept(1), kept(2));
+        assert_ne!(kept(0), kept(1));
+    }
+}
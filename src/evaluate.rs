@@ -0,0 +1,484 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Evaluation compares each assembly sequence to a trusted reference (e.g. for a mock community).
+// Sequences are lined up using k-mers which occur once in the reference, and the bases between
+// these anchors are aligned to count the remaining substitutions and indels. This assumes the
+// assembly is already close to the reference, which is the case when benchmarking polishers.
+
+use clap::crate_version;
+use indicatif::HumanBytes;
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::path::{Path, PathBuf};
+
+use crate::log;
+use crate::memory;
+use crate::misc;
+use crate::misc::{FastHashMap, Instant};
+use crate::polish;
+
+
+const KMER_SIZE: usize = 31;
+
+/// Extra diagonals on each side of the band used when aligning between anchors.
+const BAND_MARGIN: isize = 50;
+
+/// Regions between anchors which would need a larger alignment than this are counted as
+/// unaligned.
+const MAX_ALIGNMENT_CELLS: usize = 100_000_000;
+
+/// Chains with fewer anchors than this are ignored (they are likely spurious).
+const MIN_CHAIN_ANCHORS: usize = 10;
+
+
+pub fn evaluate(truth: PathBuf, assembly: PathBuf) {
+    let start_time = Instant::now();
+    misc::check_if_file_exists(&truth);
+    misc::check_if_file_exists(&assembly);
+    starting_message(&truth, &assembly);
+
+    log::section_header("Loading sequences");
+    let truth_seqs = misc::load_fasta(&truth);
+    let assembly_seqs = misc::load_fasta(&assembly);
+    log::text!("{}: {} sequences", truth.display(), truth_seqs.len());
+    log::text!("{}: {} sequences", assembly.display(), assembly_seqs.len());
+    log::text!();
+    let index = KmerIndex::new(&truth_seqs);
+
+    log::section_header("Comparing sequences");
+    log::explanation("Each assembly sequence is lined up with the reference sequence it shares the \
+                      most unique k-mers with, and the bases between shared k-mers are aligned to \
+                      count substitutions and indels. Indels are counted as events, regardless of \
+                      their length.");
+    println!("name\ttruth\tstrand\tlength\taligned\tunaligned\tsubstitutions\tinsertions\t\
+              deletions\taccuracy\tqscore");
+    let mut total = Comparison::default();
+    for (name, _, seq) in &assembly_seqs {
+        let comparison = compare_to_truth(seq, &truth_seqs, &index);
+        print_comparison(name, seq.len(), &comparison);
+        total.add(&comparison);
+    }
+    let assembly_length = assembly_seqs.iter().map(|(_, _, seq)| seq.len()).sum();
+    finished_message(&total, assembly_length, start_time);
+}
+
+
+fn starting_message(truth: &Path, assembly: &Path) {
+    log::section_header("Starting Polypolish evaluate");
+    log::explanation("This compares an assembly to a trusted reference and counts the remaining \
+                      errors in each sequence. It is intended for benchmarking, e.g. polishing a \
+                      mock community with a known reference.");
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Reference:");
+    log::text!("  {}", truth.display());
+    log::text!();
+    log::text!("Assembly:");
+    log::text!("  {}", assembly.display());
+    log::text!();
+    log::event("run_started", json!({"command": "evaluate", "version": crate_version!(),
+                                     "truth": truth, "assembly": assembly}));
+}
+
+
+fn finished_message(total: &Comparison, assembly_length: usize, start_time: Instant) {
+    log::section_header("Finished!");
+    let errors = total.errors();
+    log::text!("Aligned bases:   {}", total.aligned.to_formatted_string(&Locale::en));
+    log::text!("Unaligned bases: {}", (assembly_length - total.aligned)
+                                          .to_formatted_string(&Locale::en));
+    log::text!("Substitutions:   {}", total.substitutions.to_formatted_string(&Locale::en));
+    log::text!("Insertions:      {}", total.insertions.to_formatted_string(&Locale::en));
+    log::text!("Deletions:       {}", total.deletions.to_formatted_string(&Locale::en));
+    log::text!("Accuracy:        {:.6}% ({})", total.accuracy(),
+               polish::qscore(total.accuracy()));
+    log::text!();
+    log::text!("Time to run: {}", misc::format_duration(start_time.elapsed()));
+    let peak_memory = memory::peak_memory();
+    if let Some(peak) = peak_memory {
+        log::text!("Peak memory usage: {}", HumanBytes(peak));
+    }
+    log::text!();
+    log::event("finished", json!({"aligned": total.aligned,
+                                  "unaligned": assembly_length - total.aligned,
+                                  "substitutions": total.substitutions,
+                                  "insertions": total.insertions, "deletions": total.deletions,
+                                  "errors": errors, "accuracy": total.accuracy(),
+                                  "qscore": polish::qscore_value(total.accuracy()),
+                                  "seconds": start_time.elapsed().as_secs_f64(),
+                                  "peak_memory_bytes": peak_memory}));
+    log::end_stage();
+}
+
+
+fn print_comparison(name: &str, length: usize, c: &Comparison) {
+    let truth_name = c.truth_name.as_deref().unwrap_or("none");
+    let qscore = match polish::qscore_value(c.accuracy()) {
+        Some(qscore) => format!("{:.2}", qscore),
+        None         => "inf".to_string(),
+    };
+    println!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}", name, truth_name, c.strand, length,
+             c.aligned, length - c.aligned, c.substitutions, c.insertions, c.deletions,
+             c.accuracy(), qscore);
+    log::text!("{} ({} bp): {} aligned to {} ({} strand), {} substitutions, {} insertions, {} \
+                deletions ({})", name, length.to_formatted_string(&Locale::en),
+               c.aligned.to_formatted_string(&Locale::en), truth_name, c.strand, c.substitutions,
+               c.insertions, c.deletions, polish::qscore(c.accuracy()));
+    log::event("sequence_evaluated", json!({"name": name, "truth": c.truth_name,
+                                            "strand": c.strand.to_string(), "length": length,
+                                            "aligned": c.aligned,
+                                            "unaligned": length - c.aligned,
+                                            "substitutions": c.substitutions,
+                                            "insertions": c.insertions,
+                                            "deletions": c.deletions,
+                                            "accuracy": c.accuracy(),
+                                            "qscore": polish::qscore_value(c.accuracy())}));
+}
+
+
+/// The result of comparing one assembly sequence to the reference. Insertions are extra bases in
+/// the assembly and deletions are bases missing from it.
+#[derive(Debug, Default, PartialEq)]
+struct Comparison {
+    truth_name: Option<String>,
+    strand: char,
+    aligned: usize,
+    substitutions: usize,
+    insertions: usize,
+    deletions: usize,
+}
+
+impl Comparison {
+    fn add(&mut self, other: &Comparison) {
+        self.aligned += other.aligned;
+        self.substitutions += other.substitutions;
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+    }
+
+    fn errors(&self) -> usize {
+        self.substitutions + self.insertions + self.deletions
+    }
+
+    fn accuracy(&self) -> f64 {
+        if self.aligned == 0 {
+            return 0.0;
+        }
+        100.0 * (1.0 - (self.errors() as f64 / self.aligned as f64)).max(0.0)
+    }
+}
+
+
+/// The k-mers which occur exactly once in the reference, with their sequence index and position.
+struct KmerIndex {
+    kmers: FastHashMap<u64, Option<(usize, usize)>>,  // None for k-mers occurring more than once
+}
+
+impl KmerIndex {
+    fn new(seqs: &[(String, String, String)]) -> KmerIndex {
+        let mut kmers = FastHashMap::default();
+        for (i, (_, _, seq)) in seqs.iter().enumerate() {
+            for (pos, kmer) in kmer_positions(seq.as_bytes()) {
+                kmers.entry(kmer).and_modify(|e| *e = None).or_insert(Some((i, pos)));
+            }
+        }
+        KmerIndex { kmers }
+    }
+
+    fn get(&self, kmer: u64) -> Option<(usize, usize)> {
+        self.kmers.get(&kmer).copied().flatten()
+    }
+}
+
+
+/// Returns the position and 2-bit encoding of each k-mer in the sequence, skipping those with
+/// bases other than A, C, G and T.
+fn kmer_positions(seq: &[u8]) -> Vec<(usize, u64)> {
+    let mask = (1u64 << (2 * KMER_SIZE)) - 1;
+    let mut kmers = Vec::with_capacity(seq.len());
+    let mut kmer = 0u64;
+    let mut valid_length = 0;
+    for (i, b) in seq.iter().enumerate() {
+        let code = match b {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _           => { valid_length = 0; continue; },
+        };
+        kmer = ((kmer << 2) | code) & mask;
+        valid_length += 1;
+        if valid_length >= KMER_SIZE {
+            kmers.push((i + 1 - KMER_SIZE, kmer));
+        }
+    }
+    kmers
+}
+
+
+fn compare_to_truth(seq: &str, truth_seqs: &[(String, String, String)],
+                    index: &KmerIndex) -> Comparison {
+    // Find the reference sequence and strand with the most anchors.
+    let strands = [('+', seq.to_string()), ('-', misc::reverse_complement(seq))];
+    let mut best = None;
+    let mut anchors = Vec::new();
+    for (strand, strand_seq) in &strands {
+        let mut strand_anchors: FastHashMap<usize, Vec<(usize, usize)>> = FastHashMap::default();
+        for (pos, kmer) in kmer_positions(strand_seq.as_bytes()) {
+            if let Some((truth_index, truth_pos)) = index.get(kmer) {
+                strand_anchors.entry(truth_index).or_default().push((pos, truth_pos));
+            }
+        }
+        for (truth_index, truth_anchors) in strand_anchors {
+            if truth_anchors.len() > anchors.len() {
+                best = Some((*strand, truth_index));
+                anchors = truth_anchors;
+            }
+        }
+    }
+    let Some((strand, truth_index)) = best else {
+        return Comparison { strand: '.', ..Default::default() };
+    };
+    let seq = if strand == '+' { &strands[0].1 } else { &strands[1].1 };
+    let truth_seq = &truth_seqs[truth_index].2;
+    let mut comparison = Comparison { truth_name: Some(truth_seqs[truth_index].0.clone()), strand,
+                                      ..Default::default() };
+
+    // Chain anchors into colinear blocks, taking the longest first. More than one block can
+    // occur if the sequence starts at a different position than the reference (e.g. a circular
+    // sequence) or has a large rearrangement.
+    loop {
+        let chain = longest_chain(&anchors);
+        if chain.len() < MIN_CHAIN_ANCHORS {
+            break;
+        }
+        comparison.add(&compare_chain(seq.as_bytes(), truth_seq.as_bytes(), &chain));
+        let (start, truth_start) = chain[0];
+        let (end, truth_end) = chain[chain.len() - 1];
+        anchors.retain(|&(pos, truth_pos)| {
+            (pos + KMER_SIZE <= start || pos >= end + KMER_SIZE) &&
+                (truth_pos + KMER_SIZE <= truth_start || truth_pos >= truth_end + KMER_SIZE)
+        });
+    }
+    comparison
+}
+
+
+/// Returns the longest chain of anchors (sorted by position) whose reference positions are also
+/// increasing.
+fn longest_chain(anchors: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut tails: Vec<usize> = Vec::new();  // index of the anchor ending each chain length
+    let mut previous = vec![usize::MAX; anchors.len()];
+    for (i, &(_, truth_pos)) in anchors.iter().enumerate() {
+        let length = tails.partition_point(|&j| anchors[j].1 < truth_pos);
+        if length > 0 {
+            previous[i] = tails[length - 1];
+        }
+        if length == tails.len() {
+            tails.push(i);
+        } else {
+            tails[length] = i;
+        }
+    }
+    let mut chain = Vec::with_capacity(tails.len());
+    let mut i = tails.last().copied().unwrap_or(usize::MAX);
+    while i != usize::MAX {
+        chain.push(anchors[i]);
+        i = previous[i];
+    }
+    chain.reverse();
+    chain
+}
+
+
+/// Counts the differences in the region covered by a chain of anchors.
+fn compare_chain(seq: &[u8], truth_seq: &[u8], chain: &[(usize, usize)]) -> Comparison {
+    let mut comparison = Comparison::default();
+    let (start, _) = chain[0];
+    let (end, _) = chain[chain.len() - 1];
+    comparison.aligned = end + KMER_SIZE - start;
+    for pair in chain.windows(2) {
+        let (pos_1, truth_pos_1) = pair[0];
+        let (pos_2, truth_pos_2) = pair[1];
+        let a = &seq[pos_1..pos_2];
+        let t = &truth_seq[truth_pos_1..truth_pos_2];
+        if a == t {
+            continue;
+        }
+        match align(a, t) {
+            Some(differences) => comparison.add(&differences),
+            None              => comparison.aligned -= a.len(),
+        }
+    }
+    comparison
+}
+
+
+/// Aligns two sequences (edit distance, within a band around the diagonal) and counts their
+/// substitutions, insertions and deletions. Returns None if the band would be too large.
+fn align(a: &[u8], t: &[u8]) -> Option<Comparison> {
+    let (n, m) = (a.len(), t.len());
+    let length_diff = m as isize - n as isize;
+    let lo = length_diff.min(0) - BAND_MARGIN;  // range of diagonals (j - i) in the band
+    let hi = length_diff.max(0) + BAND_MARGIN;
+    let width = (hi - lo + 1) as usize;
+    if (n + 1).saturating_mul(width) > MAX_ALIGNMENT_CELLS {
+        return None;
+    }
+
+    // Each cell's traceback: 0 for a match/substitution, 1 for an insertion (an extra base in a)
+    // and 2 for a deletion (a base missing from a).
+    let mut traceback = vec![0u8; (n + 1) * width];
+    let mut previous = vec![u32::MAX; width];
+    let mut current = vec![u32::MAX; width];
+    for i in 0..=n {
+        for (k, d) in (lo..=hi).enumerate() {
+            let j = i as isize + d;
+            current[k] = u32::MAX;
+            if j < 0 || j > m as isize {
+                continue;
+            }
+            let j = j as usize;
+            if i == 0 && j == 0 {
+                current[k] = 0;
+                continue;
+            }
+            let mut best = (u32::MAX, 0);
+            if i > 0 && j > 0 && previous[k] != u32::MAX {
+                best = (previous[k] + u32::from(a[i - 1] != t[j - 1]), 0);
+            }
+            if i > 0 && k + 1 < width && previous[k + 1] != u32::MAX &&
+                    previous[k + 1] + 1 < best.0 {
+                best = (previous[k + 1] + 1, 1);
+            }
+            if j > 0 && k > 0 && current[k - 1] != u32::MAX && current[k - 1] + 1 < best.0 {
+                best = (current[k - 1] + 1, 2);
+            }
+            current[k] = best.0;
+            traceback[i * width + k] = best.1;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    let mut comparison = Comparison::default();
+    let (mut i, mut j) = (n, m);
+    let mut last_op = 0;
+    while i > 0 || j > 0 {
+        let k = (j as isize - i as isize - lo) as usize;
+        let op = traceback[i * width + k];
+        match op {
+            0 => {
+                if a[i - 1] != t[j - 1] {
+                    comparison.substitutions += 1;
+                }
+                i -= 1;
+                j -= 1;
+            },
+            1 => {
+                if last_op != 1 {
+                    comparison.insertions += 1;
+                }
+                i -= 1;
+            },
+            _ => {
+                if last_op != 2 {
+                    comparison.deletions += 1;
+                }
+                j -= 1;
+            },
+        }
+        last_op = op;
+    }
+    Some(comparison)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diffs(a: &str, t: &str) -> (usize, usize, usize) {
+        let c = align(a.as_bytes(), t.as_bytes()).unwrap();
+        (c.substitutions, c.insertions, c.deletions)
+    }
+
+    #[test]
+    fn test_align() {
+        assert_eq!(diffs("ACGTACGT", "ACGTACGT"), (0, 0, 0));
+        assert_eq!(diffs("ACGTTCGT", "ACGTACGT"), (1, 0, 0));
+        assert_eq!(diffs("ACGTAACGT", "ACGTACGT"), (0, 1, 0));
+        assert_eq!(diffs("ACGCGT", "ACGTACGT"), (0, 0, 1));
+        assert_eq!(diffs("ACGTGGGACGT", "ACGTACGT"), (0, 1, 0));
+        assert_eq!(diffs("ACGTGGCGT", "ACGTACGT"), (1, 1, 0));
+        assert_eq!(diffs("", "ACG"), (0, 0, 1));
+        assert_eq!(diffs("ACG", ""), (0, 1, 0));
+    }
+
+    #[test]
+    fn test_longest_chain() {
+        let anchors = vec![(0, 10), (1, 11), (2, 50), (3, 12), (4, 13), (5, 5)];
+        assert_eq!(longest_chain(&anchors), vec![(0, 10), (1, 11), (3, 12), (4, 13)]);
+        assert!(longest_chain(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_kmer_positions() {
+        let seq = "ACGTACGTACGTACGTACGTACGTACGTACGTACG";  // 35 bases
+        let kmers = kmer_positions(seq.as_bytes());
+        assert_eq!(kmers.len(), 5);
+        assert_eq!(kmers[0].1, kmers[4].1);
+        assert_ne!(kmers[0].1, kmers[1].1);
+        let with_n = "ACGTACGTACGTACGTACGTACGTACGTACGTNACGT";
+        assert_eq!(kmer_positions(with_n.as_bytes()).len(), 2);
+    }
+
+    /// Makes a pseudo-random sequence (so its k-mers are unique).
+    fn random_seq(length: usize, seed: u64) -> String {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        }).collect()
+    }
+
+    #[test]
+    fn test_compare_to_truth() {
+        let truth = random_seq(5000, 1);
+        let truth_seqs = vec![("other".to_string(), String::new(), random_seq(3000, 2)),
+                              ("truth".to_string(), String::new(), truth.clone())];
+        let index = KmerIndex::new(&truth_seqs);
+
+        // A substitution, an insertion and a deletion.
+        let mut seq = truth.clone();
+        let sub = if &seq[1000..1001] == "A" { "C" } else { "A" };
+        seq.replace_range(1000..1001, sub);
+        seq.insert(2000, 'G');
+        seq.replace_range(3000..3002, "");
+        let c = compare_to_truth(&seq, &truth_seqs, &index);
+        assert_eq!(c.truth_name.as_deref(), Some("truth"));
+        assert_eq!(c.strand, '+');
+        assert_eq!(c.aligned, seq.len());
+        assert_eq!((c.substitutions, c.insertions, c.deletions), (1, 1, 1));
+
+        // The reverse strand, rotated like a circular sequence.
+        let rotated = format!("{}{}", &seq[2500..], &seq[..2500]);
+        let c = compare_to_truth(&misc::reverse_complement(&rotated), &truth_seqs, &index);
+        assert_eq!(c.strand, '-');
+        assert_eq!((c.substitutions, c.insertions, c.deletions), (1, 1, 1));
+        assert!(c.aligned > seq.len() - 2 * KMER_SIZE);
+
+        let c = compare_to_truth(&random_seq(1000, 3), &truth_seqs, &index);
+        assert_eq!(c.truth_name, None);
+        assert_eq!(c.aligned, 0);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use tempfile::TempDir;
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader, BufWriter, Lines};
+use std::path::PathBuf;
+
+
+/// Sorts lines of text which are too numerous to hold in memory. Lines are collected in chunks,
+/// each chunk is sorted and written to a temporary file, and the chunks are then merged into a
+/// single sorted file. Lines are sorted by a key extracted from each line, and the sort is stable
+/// (lines with equal keys stay in the order they were added).
+pub struct ExternalSorter {
+    dir: TempDir,
+    key: fn(&str) -> &str,
+    chunk: Vec<String>,
+    chunk_size: usize,
+    chunk_files: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    /// Creates a new sorter which holds up to chunk_size lines in memory at once. Temporary files
+    /// go in the system's temporary directory (which can be set with the TMPDIR variable).
+    pub fn new(chunk_size: usize, key: fn(&str) -> &str) -> io::Result<ExternalSorter> {
+        Ok(ExternalSorter {
+            dir: tempfile::Builder::new().prefix("polypolish_").tempdir()?,
+            key,
+            chunk: Vec::new(),
+            chunk_size,
+            chunk_files: Vec::new(),
+        })
+    }
+
+    pub fn push(&mut self, line: String) -> io::Result<()> {
+        self.chunk.push(line);
+        if self.chunk.len() >= self.chunk_size {
+            self.write_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        let key = self.key;
+        self.chunk.sort_by(|a, b| key(a).cmp(key(b)));
+        let path = self.dir.path().join(format!("chunk_{}", self.chunk_files.len()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for line in self.chunk.drain(..) {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.flush()?;
+        self.chunk_files.push(path);
+        Ok(())
+    }
+
+    /// Merges all chunks into one sorted file, which can then be read (any number of times).
+    pub fn finish(mut self) -> io::Result<SortedFile> {
+        self.write_chunk()?;
+        let path = self.dir.path().join("sorted");
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut chunks = Vec::new();
+        for chunk_file in &self.chunk_files {
+            chunks.push(BufReader::new(File::open(chunk_file)?).lines());
+        }
+
+        // The heap holds the next line from each chunk. Ties on the key are broken by the chunk
+        // index, which keeps the sort stable.
+        let mut heap = BinaryHeap::new();
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            push_next_line(&mut heap, chunk, i, self.key)?;
+        }
+        while let Some(Reverse((_, i, line))) = heap.pop() {
+            writeln!(writer, "{}", line)?;
+            push_next_line(&mut heap, &mut chunks[i], i, self.key)?;
+        }
+        writer.flush()?;
+        for chunk_file in &self.chunk_files {
+            std::fs::remove_file(chunk_file)?;
+        }
+        Ok(SortedFile { _dir: self.dir, path })
+    }
+}
+
+
+type MergeHeap = BinaryHeap<Reverse<(String, usize, String)>>;
+
+fn push_next_line(heap: &mut MergeHeap, chunk: &mut Lines<BufReader<File>>, i: usize,
+                  key: fn(&str) -> &str) -> io::Result<()> {
+    if let Some(line) = chunk.next() {
+        let line = line?;
+        heap.push(Reverse((key(&line).to_string(), i, line)));
+    }
+    Ok(())
+}
+
+
+/// A sorted temporary file produced by ExternalSorter. It is deleted when this is dropped.
+pub struct SortedFile {
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl SortedFile {
+    pub fn lines(&self) -> io::Result<Lines<BufReader<File>>> {
+        Ok(BufReader::new(File::open(&self.path)?).lines())
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_field(line: &str) -> &str {
+        line.split('\t').next().unwrap()
+    }
+
+    fn sort(lines: &[&str], chunk_size: usize) -> Vec<String> {
+        let mut sorter = ExternalSorter::new(chunk_size, first_field).unwrap();
+        for line in lines {
+            sorter.push(line.to_string()).unwrap();
+        }
+        let sorted = sorter.finish().unwrap();
+        sorted.lines().unwrap().map(|l| l.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let lines = ["c\t1", "a\t2", "b\t3", "a\t4", "c\t5", "b\t6", "a\t7"];
+        let expected = vec!["a\t2", "a\t4", "a\t7", "b\t3", "b\t6", "c\t1", "c\t5"];
+        for chunk_size in [1, 2, 3, 100] {
+            assert_eq!(sort(&lines, chunk_size), expected);
+        }
+        assert!(sort(&[], 2).is_empty());
+    }
+}
@@ -9,173 +9,834 @@
 // Public License for more details. You should have received a copy of the GNU General Public
 // License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
 
-use std::path::PathBuf;
+use std::path::Path;
+use std::sync::Arc;
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
 use std::fs::File;
 use std::io;
 use std::io::{prelude::*, BufReader, BufWriter};
 use clap::crate_version;
+use indicatif::HumanBytes;
 use num_format::{Locale, ToFormattedString};
+use serde_json::json;
 
-use crate::alignment::Alignment;
+use crate::alignment;
+use crate::alignment::{Alignment, AlignmentFilter, DiscardCounts, SamCounts, SamLines};
+use crate::downsample::Downsampler;
+use crate::external_sort::{ExternalSorter, SortedFile};
 use crate::log;
-use crate::misc::{quit_with_error, format_duration};
+use crate::memory;
+use crate::misc::{quit_with_error, format_duration, ErrorType, FastHashMap, Instant};
+use crate::options::FilterOptions;
+use crate::pileup::Pileup;
+use crate::progress::{Progress, Unit};
+use crate::run::RunContext;
 
 
-pub fn filter(in1: PathBuf, in2: PathBuf, out1: PathBuf, out2: PathBuf,
-              orientation: String, low: f64, high: f64) {
+/// Alignments for one SAM file, grouped by read name.
+type AlignmentMap = FastHashMap<Arc<str>, Vec<Alignment>>;
+
+
+/// The number of alignments sampled from the start of each SAM file to estimate memory usage.
+const MEMORY_SAMPLE_SIZE: usize = 10000;
+
+/// The number of spill records held in memory (and sorted) at once in low-memory mode.
+const SPILL_CHUNK_SIZE: usize = 1000000;
+
+/// The insert size histogram has about this many bins, each shown as one line of the log.
+const HISTOGRAM_BINS: u32 = 20;
+
+/// The length of the insert size histogram's longest bar, in characters.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// The number of read pairs held in memory by filter-polish to set the insert size thresholds.
+const THRESHOLD_SAMPLE_PAIRS: usize = 100000;
+
+/// The possible read pair orientations. --orientation can be auto, any, or a comma-separated list
+/// of these, and a pair is good if its orientation is in the list.
+const ORIENTATIONS: [&str; 4] = ["fr", "rf", "ff", "rr"];
+
+
+/// Why an alignment failed the filter: its mapping quality is too low, or it doesn't make a good
+/// pair with any of its mate's alignments. When the mate has several alignments which fail for
+/// different reasons, the reason is NoConcordantPartner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailReason {
+    LowMapq,
+    DifferentReference,
+    WrongOrientation,
+    InsertTooSmall,
+    InsertTooLarge,
+    NoConcordantPartner,
+}
+
+impl FailReason {
+    pub const ALL: [FailReason; 6] = [FailReason::LowMapq, FailReason::DifferentReference,
+                                      FailReason::WrongOrientation, FailReason::InsertTooSmall,
+                                      FailReason::InsertTooLarge, FailReason::NoConcordantPartner];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailReason::LowMapq             => "low_mapq",
+            FailReason::DifferentReference  => "different_reference",
+            FailReason::WrongOrientation    => "wrong_orientation",
+            FailReason::InsertTooSmall      => "insert_too_small",
+            FailReason::InsertTooLarge      => "insert_too_large",
+            FailReason::NoConcordantPartner => "no_concordant_partner",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            FailReason::LowMapq             => "MAPQ below --min-mapq",
+            FailReason::DifferentReference  => "mate on a different sequence",
+            FailReason::WrongOrientation    => "wrong orientation",
+            FailReason::InsertTooSmall      => "insert size below --low",
+            FailReason::InsertTooLarge      => "insert size above --high",
+            FailReason::NoConcordantPartner => "no concordant partner (mixed reasons)",
+        }
+    }
+}
+
+
+/// The number of failed alignments for each reason, and the number of read pairs which weren't
+/// checked because they have more than --max-hits alignments.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FailCounts {
+    counts: [usize; FailReason::ALL.len()],
+    pub max_hits_pairs: usize,
+}
+
+impl FailCounts {
+    pub fn add(&mut self, reason: FailReason) {
+        self.counts[reason as usize] += 1;
+    }
+
+    pub fn get(&self, reason: FailReason) -> usize {
+        self.counts[reason as usize]
+    }
+}
+
+
+/// The expected read pair orientation and the insert size percentiles for the thresholds, from
+/// --orientation, --low and --high. With --preset mate-pair, the read pair orientations are also
+/// reported as the library's mix of junction fragments and paired-end fragments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairSettings {
+    pub orientation: String,
+    pub low: f64,
+    pub high: f64,
+    pub mate_pair: bool,
+}
+
+
+/// The orientations of a good read pair, each with its own insert size range, set from the read
+/// pairs with one alignment per read. Orientations can have very different insert sizes (e.g. a
+/// mate-pair library's rf junction fragments and fr paired-end fragments), so a pair is checked
+/// against the range for its orientation.
+#[derive(Clone, Debug, PartialEq)]
+struct InsertSizeThresholds {
+    ranges: Vec<(String, u32, u32)>,  // orientation, low and high
+}
+
+impl InsertSizeThresholds {
+    /// Returns the insert size range for the orientation, or None if it isn't a good orientation.
+    fn range(&self, orientation: &str) -> Option<(u32, u32)> {
+        self.ranges.iter().find(|(o, _, _)| o == orientation).map(|&(_, low, high)| (low, high))
+    }
+}
+
+
+pub fn filter(options: &FilterOptions) {
     let start_time = Instant::now();
-    check_inputs(&in1, &in2, &out1, &out2, low, high);
-    starting_message(&in1, &in2, &out1, &out2, &orientation, low, high);
-    let (alignments, before_count) = load_alignments(&in1, &in2);
-    let (low, high, correct_orientation) = get_insert_size_thresholds(&alignments, &orientation,
-                                                                      low, high);
-    let after_count = filter_sams(&in1, &in2, &out1, &out2, &alignments, low, high,
-                                  correct_orientation);
-    finished_message(start_time, before_count, after_count)
+    let FilterOptions { in1, in2, out1, out2, strict_memory, fail_tag, .. } = options;
+    let pair = apply_preset(&options.preset, options.orientation.clone(), options.low,
+                            options.high);
+    check_inputs(in1, in2, out1, out2, pair.low, pair.high);
+    check_fail_tag(fail_tag);
+    check_orientation(&pair.orientation);
+    starting_message(options, &pair);
+    log::section_header("Loading alignments");
+    let low_memory = options.low_memory || !check_memory(in1, in2, *strict_memory);
+    let (before_count, after_count, fail_counts) = if low_memory {
+        filter_low_memory(options, &pair)
+    } else {
+        filter_in_memory(options, &pair)
+    };
+    finished_message(start_time, before_count, after_count, &fail_counts)
+}
+
+
+fn filter_in_memory(options: &FilterOptions, pair: &PairSettings) -> (usize, usize, FailCounts) {
+    let FilterOptions { in1, in2, min_mapq, max_hits, .. } = options;
+    let max_hits = *max_hits;
+    let (alignments, before_count) = load_alignments(in1, in2);
+    let insert_sizes = get_insert_sizes(&alignments);
+    let thresholds = get_insert_size_thresholds(insert_sizes, pair);
+    let mut fail_counts = FailCounts::default();
+    let no_alignments = Vec::new();
+    for (name, alignments_1) in &alignments[0] {
+        let alignments_2 = alignments[1].get(name).unwrap_or(&no_alignments);
+        if exceeds_max_hits(alignments_1, alignments_2, max_hits) {
+            fail_counts.max_hits_pairs += 1;
+        }
+    }
+    for (name, alignments_2) in &alignments[1] {
+        if !alignments[0].contains_key(name) &&
+                exceeds_max_hits(alignments_2, &no_alignments, max_hits) {
+            fail_counts.max_hits_pairs += 1;
+        }
+    }
+    let after_count = filter_sams(options, |a, read_num, _| {
+        let this_alignments = &alignments[read_num - 1][&a.read_name];
+        let pair_alignments = alignments[2 - read_num].get(&a.read_name)
+            .map_or(&[][..], |v| v.as_slice());
+        let reason = alignment_fail_reason(a, this_alignments, pair_alignments, &thresholds,
+                                           *min_mapq, max_hits);
+        if let Some(reason) = reason {
+            fail_counts.add(reason);
+        }
+        reason.is_none()
+    });
+    (before_count, after_count, fail_counts)
 }
 
 
-fn check_inputs(in1: &PathBuf, in2: &PathBuf, out1: &PathBuf, out2: &PathBuf,
+fn check_inputs(in1: &Path, in2: &Path, out1: &Path, out2: &Path,
                 low: f64, high: f64) {
     let mut files = HashSet::new();
-    if !files.insert(in1.clone()) || !files.insert(in2.clone()) || 
-        !files.insert(out1.clone()) || !files.insert(out2.clone()) {
-        quit_with_error("--in1, --in2, --out1 and --out2 must all have unique values");
+    if !files.insert(in1) || !files.insert(in2) || !files.insert(out1) || !files.insert(out2) {
+        quit_with_error(ErrorType::Args,
+                        "--in1, --in2, --out1 and --out2 must all have unique values");
+    }
+    check_percentiles(low, high);
+}
+
+
+pub fn check_fail_tag(tag: &str) {
+    if !alignment::is_valid_tag(tag) {
+        quit_with_error(ErrorType::Args, &format!("invalid fail tag {:?} (must be a complete SAM \
+                                                   tag, e.g. ZP:Z:fail)", tag))
     }
+}
+
+
+/// Fills in --orientation, --low and --high from --preset, unless they were given. Mate-pair
+/// (jumping) libraries are mostly rf pairs with multi-kb inserts (junction fragments), but also
+/// have fr pairs with short inserts (paired-end fragments without a junction) and more chimeras,
+/// so both orientations are accepted and the insert size thresholds are tighter.
+pub fn apply_preset(preset: &str, orientation: Option<String>, low: Option<f64>,
+                    high: Option<f64>) -> PairSettings {
+    let mate_pair = preset == "mate-pair";
+    let (default_orientation, default_low, default_high) = if mate_pair {
+        ("rf,fr", 1.0, 99.0)
+    } else {
+        ("auto", 0.1, 99.9)
+    };
+    PairSettings { orientation: orientation.unwrap_or_else(|| default_orientation.to_string()),
+                   low: low.unwrap_or(default_low), high: high.unwrap_or(default_high),
+                   mate_pair }
+}
+
+
+pub fn check_orientation(orientation: &str) {
+    if orientation == "auto" || orientation == "any" {
+        return;
+    }
+    if !orientation.split(',').all(|o| ORIENTATIONS.contains(&o)) {
+        quit_with_error(ErrorType::Args,
+                        &format!("invalid --orientation {:?} (must be auto, any, or one or more \
+                                  of fr, rf, ff and rr separated by commas)", orientation))
+    }
+}
+
+
+pub fn check_percentiles(low: f64, high: f64) {
     if low <= 0.0 || low >= 50.0 {
-        quit_with_error("--low must be greater than 0 and less than 50")
+        quit_with_error(ErrorType::Args, "--low must be greater than 0 and less than 50")
     }
     if high <= 50.0 || high >= 100.0 {
-        quit_with_error("--high must be greater than 50 and less than 100")
+        quit_with_error(ErrorType::Args, "--high must be greater than 50 and less than 100")
     }
 }
 
 
-fn starting_message(in1: &PathBuf, in2: &PathBuf, out1: &PathBuf, out2: &PathBuf,
-                    orientation: &String, low: f64, high: f64) {
+fn starting_message(options: &FilterOptions, pair: &PairSettings) {
+    let &FilterOptions { ref in1, ref in2, ref out1, ref out2, min_mapq, max_hits, strict_memory,
+                         low_memory, ref fail_tag, .. } = options;
+    let PairSettings { orientation, low, high, .. } = pair;
     log::section_header("Starting Polypolish filter");
     log::explanation("This runs a pre-processing filter on SAM alignments before they are used to \
                       polish. It looks at each read pair and flags alignments that do not seem to \
                       be part of a concordant pair. This can improve the accuracy Polypolish, \
                       especially near the edges of repeats.");
-    eprintln!("Polypolish version: {}", crate_version!());
-    eprintln!();
-    eprintln!("Input alignments:");
-    eprintln!("  {}", in1.display());
-    eprintln!("  {}", in2.display());
-    eprintln!();
-    eprintln!("Output alignments:");
-    eprintln!("  {}", out1.display());
-    eprintln!("  {}", out2.display());
-    eprintln!();
-    eprintln!("Settings:");
-    eprintln!("  --orientation {}", orientation);
-    eprintln!("  --low {}", low);
-    eprintln!("  --high {}", high);
-    eprintln!();
-}
-
-
-fn finished_message(start_time: Instant, before_count: usize, after_count: usize) {
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input alignments:");
+    log::text!("  {}", in1.display());
+    log::text!("  {}", in2.display());
+    log::text!();
+    log::text!("Output alignments:");
+    log::text!("  {}", out1.display());
+    log::text!("  {}", out2.display());
+    log::text!();
+    log::text!("Settings:");
+    log::text!("  --orientation {}", orientation);
+    log::text!("  --low {}", low);
+    log::text!("  --high {}", high);
+    if min_mapq > 0 {
+        log::text!("  --min-mapq {}", min_mapq);
+    }
+    if let Some(max_hits) = max_hits {
+        log::text!("  --max-hits {}", max_hits);
+    }
+    log::text!("  --fail-tag {}", fail_tag);
+    if strict_memory {
+        log::text!("  --strict-memory");
+    }
+    if low_memory {
+        log::text!("  --low-memory");
+    }
+    log::text!();
+    log::event("run_started", json!({"command": "filter", "version": crate_version!(),
+                                     "in1": in1, "in2": in2, "out1": out1, "out2": out2,
+                                     "orientation": orientation, "low": low, "high": high,
+                                     "min_mapq": min_mapq, "max_hits": max_hits,
+                                     "strict_memory": strict_memory,
+                                     "low_memory": low_memory, "fail_tag": fail_tag}));
+}
+
+
+fn finished_message(start_time: Instant, before_count: usize, after_count: usize,
+                    fail_counts: &FailCounts) {
     log::section_header("Finished!");
-    eprintln!("Alignments before filtering: {}", before_count.to_formatted_string(&Locale::en));
-    eprintln!("Alignments after filtering:  {}", after_count.to_formatted_string(&Locale::en));
-    eprintln!();
-    eprintln!("Time to run: {}", format_duration(start_time.elapsed()));
-    eprintln!();
+    log::text!("Alignments before filtering: {}", before_count.to_formatted_string(&Locale::en));
+    log::text!("Alignments after filtering:  {}", after_count.to_formatted_string(&Locale::en));
+    if fail_counts.max_hits_pairs > 0 {
+        log::text!("  read pairs with more than --max-hits alignments (not checked): {}",
+                   fail_counts.max_hits_pairs.to_formatted_string(&Locale::en));
+    }
+    let mut reasons = serde_json::Map::new();
+    for reason in FailReason::ALL {
+        let count = fail_counts.get(reason);
+        if count > 0 {
+            log::text!("  failed with {}: {}", reason.description(),
+                       count.to_formatted_string(&Locale::en));
+        }
+        reasons.insert(reason.name().to_string(), json!(count));
+    }
+    log::text!();
+    log::text!("Time to run: {}", format_duration(start_time.elapsed()));
+    let peak_memory = memory::peak_memory();
+    if let Some(peak) = peak_memory {
+        log::text!("Peak memory usage: {}", HumanBytes(peak));
+    }
+    log::text!();
+    log::event("finished", json!({"alignments_before": before_count,
+                                  "alignments_after": after_count,
+                                  "fail_reasons": reasons,
+                                  "max_hits_pairs": fail_counts.max_hits_pairs,
+                                  "seconds": start_time.elapsed().as_secs_f64(),
+                                  "peak_memory_bytes": peak_memory}));
+    log::end_stage();
 }
 
 
-fn load_alignments(sam_1: &PathBuf, sam_2: &PathBuf) -> (HashMap<String, Vec<Alignment>>, usize) {
-    log::section_header("Loading alignments");
-    let mut alignments = HashMap::new();
-    let result_1 = load_alignments_one_file(sam_1, &mut alignments, "_1");
+/// Loads the alignments from both SAM files into one map per file, keyed by read name.
+fn load_alignments(sam_1: &Path, sam_2: &Path) -> ([AlignmentMap; 2], usize) {
+    let mut alignments = [FastHashMap::default(), FastHashMap::default()];
+    let result_1 = load_alignments_one_file(sam_1, &mut alignments, 1);
     match result_1 {
         Ok(()) => (),
-        Err(_) => quit_with_error(&format!("unable to load alignments from {:?}", sam_1)),
+        Err(_) => quit_with_error(ErrorType::Io,
+                                  &format!("unable to load alignments from {:?}", sam_1)),
     }
-    let result_2 = load_alignments_one_file(sam_2, &mut alignments, "_2");
+    let result_2 = load_alignments_one_file(sam_2, &mut alignments, 2);
     match result_2 {
         Ok(()) => (),
-        Err(_) => quit_with_error(&format!("unable to load alignments from {:?}", sam_2)),
+        Err(_) => quit_with_error(ErrorType::Io,
+                                  &format!("unable to load alignments from {:?}", sam_2)),
     }
-    eprintln!();
-    let count = alignments.values().map(|v| v.len()).sum();
+    log::text!();
+    let count = alignments.iter().flat_map(|m| m.values()).map(|v| v.len()).sum();
     (alignments, count)
 }
 
 
-fn load_alignments_one_file(sam_filename: &PathBuf,
-                            alignments: &mut HashMap<String, Vec<Alignment>>,
-                            read_name_suffix: &str) -> io::Result<()> {
-    eprint!("{}: ", sam_filename.display());
+/// Compares the estimated memory needed to load the alignments with the available memory. Returns
+/// false if the alignments won't fit, in which case filtering switches to low-memory mode (or
+/// quits with an error, with --strict-memory) instead of risking an out-of-memory kill later on.
+fn check_memory(sam_1: &Path, sam_2: &Path, strict_memory: bool) -> bool {
+    let estimate = estimate_memory(sam_1) + estimate_memory(sam_2);
+    let available = memory::available_memory();
+    log::text!("Estimated memory required: {}", HumanBytes(estimate));
+    match available {
+        Some(a) => log::text!("Available memory: {}", HumanBytes(a)),
+        None    => log::text!("Available memory: unknown"),
+    }
+    log::text!();
+    log::event("memory_estimate", json!({"estimated_bytes": estimate,
+                                         "available_bytes": available}));
+    if let Some(available) = available {
+        if estimate > available {
+            let message = format!("estimated memory required ({}) exceeds available memory ({})",
+                                  HumanBytes(estimate), HumanBytes(available));
+            if strict_memory {
+                quit_with_error(ErrorType::Memory, &message);
+            }
+            log::text!("Warning: {}, switching to low-memory mode\n", message);
+            log::event("warning", json!({"message": message}));
+            return false;
+        }
+    }
+    true
+}
+
+
+/// Estimates the memory needed to hold a SAM file's alignments by loading a sample of alignments
+/// from the start of the file and extrapolating based on the file size.
+fn estimate_memory(sam_filename: &Path) -> u64 {
+    let Ok(sam_file) = File::open(sam_filename) else { return 0; };
+    let file_size = sam_file.metadata().map(|m| m.len()).unwrap_or(0);
+    let mut header_bytes = 0;
+    let mut sample_bytes = 0;
+    let mut sample_memory = 0;
+    let mut sample_count = 0;
+    let mut read_names = HashSet::new();
+    for line in BufReader::new(sam_file).lines().map_while(Result::ok) {
+        if line.starts_with('@') {
+            header_bytes += line.len() + 1;
+            continue;
+        }
+        let Ok(alignment) = Alignment::new_quick(&line) else { continue; };
+        sample_bytes += line.len() + 1;
+        sample_memory += alignment.memory_size();
+        sample_count += 1;
+        if read_names.insert(alignment.read_name.clone()) {
+            // Each read's name is stored once and shared by its alignments and its HashMap key.
+            sample_memory += alignment.read_name.len() + 2 * std::mem::size_of::<usize>() +
+                             std::mem::size_of::<Arc<str>>() +
+                             std::mem::size_of::<Vec<Alignment>>();
+        }
+        if sample_count >= MEMORY_SAMPLE_SIZE {
+            break;
+        }
+    }
+    extrapolate_memory(file_size, header_bytes, sample_bytes, sample_memory)
+}
+
+
+fn extrapolate_memory(file_size: u64, header_bytes: usize, sample_bytes: usize,
+                      sample_memory: usize) -> u64 {
+    if sample_bytes == 0 {
+        return 0;
+    }
+    let alignment_bytes = file_size.saturating_sub(header_bytes as u64) as f64;
+    let memory_per_byte = sample_memory as f64 / sample_bytes as f64;
+
+    // HashMaps also need some spare capacity, so we add some overhead to the estimate.
+    (1.25 * alignment_bytes * memory_per_byte) as u64
+}
+
+
+fn load_alignments_one_file(sam_filename: &Path, alignments: &mut [AlignmentMap; 2],
+                            read_num: usize) -> io::Result<()> {
     let sam_file = File::open(sam_filename)?;
+    let file_size = sam_file.metadata()?.len();
     let reader = BufReader::new(sam_file);
+    let mut progress = Progress::new(&sam_filename.display().to_string(), Unit::Bytes, file_size,
+                                     "alignments");
+    let mut bytes_read: u64 = 0;
     let mut alignment_count = 0;
-    let mut read_names = HashSet::new();
     let mut line_count: usize = 0;
     for line in reader.lines() {
         line_count += 1;
         let sam_line = line?;
+        bytes_read += sam_line.len() as u64 + 1;
+        progress.update(bytes_read, alignment_count as u64);
         if sam_line.starts_with('@') {
             continue;
         }
         let alignment_result = Alignment::new_quick(&sam_line);
         match alignment_result {
             Ok(_)  => (),
-            Err(e) => quit_with_error(&format!("{} in {:?} (line {})",
-                                               e, sam_filename, line_count)),
+            Err(e) => quit_with_error(ErrorType::Input, &format!("{} in {:?} (line {})",
+                                                                 e, sam_filename, line_count)),
         }
         let mut alignment = alignment_result.unwrap();
         if !alignment.is_aligned() {continue;}
-        alignment.read_name.push_str(read_name_suffix);
-        read_names.insert(alignment.read_name.clone());
-        alignments.entry(alignment.read_name.clone()).or_insert_with(Vec::new).push(alignment);
+        alignment.read_name = intern_read_name(alignments, &alignment.read_name);
+        alignments[read_num - 1].entry(alignment.read_name.clone()).or_default().push(alignment);
         alignment_count += 1;
     }
-    eprintln!("{} alignments from {} reads",
-              alignment_count.to_formatted_string(&Locale::en),
-              read_names.len().to_formatted_string(&Locale::en));
+    progress.finish();
+    log::text!("{}: {} alignments from {} reads", sam_filename.display(),
+               alignment_count.to_formatted_string(&Locale::en),
+               alignments[read_num - 1].len().to_formatted_string(&Locale::en));
+    log::event("sam_loaded", json!({"file": sam_filename, "alignments": alignment_count,
+                                    "reads": alignments[read_num - 1].len()}));
     Ok(())
 }
 
 
-fn get_insert_size_thresholds(alignments: &HashMap<String, Vec<Alignment>>,
-                              correct_orientation: &String,
-                              low_percentile: f64, high_percentile: f64) -> (u32, u32, String) {
-    log::section_header("Finding insert size thresholds");
-    log::explanation("Read pairs with exactly one alignment per read are used to determine the \
-                      orientation and insert size thresholds for the read set.");
-    let mut insert_sizes: HashMap<String, Vec<u32>> = HashMap::new();
-    for (name_1, alignments_1) in alignments {
-        if !name_1.ends_with("_1") || alignments_1.len() != 1 {
+/// Returns a shared copy of the read name if it's already in either map, so each read name is
+/// only stored once no matter how many alignments the read (and its pair) has.
+fn intern_read_name(alignments: &[AlignmentMap; 2], read_name: &Arc<str>) -> Arc<str> {
+    for map in alignments {
+        if let Some((name, _)) = map.get_key_value(read_name) {
+            return name.clone();
+        }
+    }
+    read_name.clone()
+}
+
+
+/// Low-memory mode does the same filtering as the in-memory mode, but instead of holding every
+/// alignment in a HashMap, it writes a compact record for each alignment to temporary files which
+/// are then sorted by read name. The sorted records are read twice: once to collect insert sizes
+/// and once to find failing alignments, which are stored as one bit per SAM line.
+fn filter_low_memory(options: &FilterOptions, pair: &PairSettings) -> (usize, usize, FailCounts) {
+    log::text!("Low-memory mode: alignments will be sorted by read name using temporary files");
+    log::text!();
+    let (failed, before_count, fail_counts) = find_failures(&options.in1, &options.in2, pair,
+                                                            options.min_mapq, options.max_hits,
+                                                            &RunContext::default());
+    let after_count = filter_sams(options, |_, read_num, line_index| {
+        failed[read_num - 1][line_index / 64] & (1 << (line_index % 64)) == 0
+    });
+    (before_count, after_count, fail_counts)
+}
+
+
+/// Runs the filter without writing any files, returning a bit set for each alignment file
+/// (indexed by line) with the failing alignments' bits set, the total alignment count and why
+/// alignments failed. This is done with the sorted temporary files of low-memory mode, so polish
+/// --paired can filter alignments as it loads them.
+pub fn find_failures(in1: &Path, in2: &Path, pair: &PairSettings, min_mapq: u8,
+                     max_hits: Option<usize>,
+                     run: &RunContext) -> ([Vec<u64>; 2], usize, FailCounts) {
+    let (spilled, before_count, line_counts) = match spill_alignments(in1, in2, run) {
+        Ok(result) => result,
+        Err(e) => quit_with_error(ErrorType::Io,
+                                  &format!("unable to write temporary files: {}", e)),
+    };
+    let insert_sizes = match get_spilled_insert_sizes(&spilled) {
+        Ok(insert_sizes) => insert_sizes,
+        Err(e) => quit_with_error(ErrorType::Io, &format!("unable to read temporary files: {}", e)),
+    };
+    let thresholds = get_insert_size_thresholds(insert_sizes, pair);
+    let (failed, fail_counts) = match find_spilled_failures(&spilled, line_counts, &thresholds,
+                                                            min_mapq, max_hits) {
+        Ok(failures) => failures,
+        Err(e) => quit_with_error(ErrorType::Io, &format!("unable to read temporary files: {}", e)),
+    };
+    (failed, before_count, fail_counts)
+}
+
+
+/// Writes a spill record for every aligned line of both SAM files and sorts them by read name.
+/// Returns the sorted records, the total alignment count and the line count of each SAM file.
+fn spill_alignments(sam_1: &Path, sam_2: &Path,
+                    run: &RunContext) -> io::Result<(SortedFile, usize, [usize; 2])> {
+    let mut sorter = ExternalSorter::new(SPILL_CHUNK_SIZE, spill_record_key)?;
+    let (count_1, lines_1) = spill_alignments_one_file(sam_1, 1, run, &mut sorter)?;
+    let (count_2, lines_2) = spill_alignments_one_file(sam_2, 2, run, &mut sorter)?;
+    log::text!();
+    log::text!("Sorting alignments by read name");
+    log::text!();
+    Ok((sorter.finish()?, count_1 + count_2, [lines_1, lines_2]))
+}
+
+
+/// The alignment file is read with SamLines, so the line indices match those seen when polish
+/// --paired loads the file.
+fn spill_alignments_one_file(sam_filename: &Path, read_num: usize, run: &RunContext,
+                             sorter: &mut ExternalSorter) -> io::Result<(usize, usize)> {
+    let mut sam_lines = SamLines::open(sam_filename, run)?;
+    let mut progress = Progress::new(&sam_filename.display().to_string(), Unit::Bytes,
+                                     sam_lines.file_size, "alignments");
+    let mut alignment_count = 0;
+    let mut line_count: usize = 0;
+    while let Some(line) = sam_lines.next() {
+        let line_index = line_count;
+        line_count += 1;
+        let sam_line = line?;
+        progress.update(sam_lines.bytes_read(), alignment_count as u64);
+        if sam_line.is_empty() || sam_line.starts_with('@') {
             continue;
         }
-        let name_2 = format!("{}_2", &name_1[..name_1.len() - 2]);
-        if let Some(alignments_2) = alignments.get(&name_2) {
-            if alignments_2.len() == 1 && alignments_1[0].ref_name == alignments_2[0].ref_name {
-                let orientation = get_orientation(&alignments_1[0], &alignments_2[0]);
-                let insert_size = get_insert_size(&alignments_1[0], &alignments_2[0]);
-                insert_sizes.entry(orientation).or_default().push(insert_size);
+        let alignment = match Alignment::new_quick(&sam_line) {
+            Ok(alignment) => alignment,
+            Err(e) => { run.bad_record(e, sam_filename, line_count, &sam_line); continue; },
+        };
+        if !alignment.is_aligned() {continue;}
+        sorter.push(spill_record(&sam_line, read_num, line_index))?;
+        alignment_count += 1;
+    }
+    progress.finish();
+    log::text!("{}: {} alignments", sam_filename.display(),
+               alignment_count.to_formatted_string(&Locale::en));
+    log::event("sam_loaded", json!({"file": sam_filename, "alignments": alignment_count}));
+    Ok((alignment_count, line_count))
+}
+
+
+/// A spill record holds just the parts of an alignment needed for filtering, along with its read
+/// number (1 or 2) and its line index in the SAM file. The read name comes first, as the sort key.
+fn spill_record(sam_line: &str, read_num: usize, line_index: usize) -> String {
+    let parts: Vec<&str> = sam_line.splitn(7, '\t').collect();
+    format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", parts[0], read_num, line_index,
+            parts[1], parts[2], parts[3], parts[4], parts[5])
+}
+
+
+fn spill_record_key(record: &str) -> &str {
+    record.split('\t').next().unwrap()
+}
+
+
+fn parse_spill_record(record: &str) -> (usize, usize, Alignment) {
+    let parts: Vec<&str> = record.split('\t').collect();
+    let read_num = parts[1].parse::<usize>().unwrap();
+    let line_index = parts[2].parse::<usize>().unwrap();
+    let sam_line = format!("{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t*\t*",
+                           parts[0], parts[3], parts[4], parts[5], parts[6], parts[7]);
+    (read_num, line_index, Alignment::new_quick(&sam_line).unwrap())
+}
+
+
+/// All of the spilled alignments for one read name, along with their SAM line indices.
+#[derive(Default)]
+struct SpilledRead {
+    alignments: [Vec<Alignment>; 2],
+    line_indices: [Vec<usize>; 2],
+}
+
+
+/// Reads back the sorted spill records, calling the given function once for each read name.
+fn for_each_spilled_read(spilled: &SortedFile, label: &str,
+                         mut f: impl FnMut(&SpilledRead)) -> io::Result<()> {
+    let file_size = std::fs::metadata(spilled.path())?.len();
+    let mut progress = Progress::new(label, Unit::Bytes, file_size, "");
+    let mut bytes_read: u64 = 0;
+    let mut read = SpilledRead::default();
+    for line in spilled.lines()? {
+        let record = line?;
+        bytes_read += record.len() as u64 + 1;
+        progress.update(bytes_read, 0);
+        let (read_num, line_index, alignment) = parse_spill_record(&record);
+        let name_changed = read.alignments.iter().flatten().next()
+            .is_some_and(|a| a.read_name != alignment.read_name);
+        if name_changed {
+            f(&read);
+            read = SpilledRead::default();
+        }
+        read.alignments[read_num - 1].push(alignment);
+        read.line_indices[read_num - 1].push(line_index);
+    }
+    if read.alignments.iter().any(|a| !a.is_empty()) {
+        f(&read);
+    }
+    progress.finish();
+    Ok(())
+}
+
+
+fn get_spilled_insert_sizes(spilled: &SortedFile) -> io::Result<HashMap<String, Vec<u32>>> {
+    let mut insert_sizes = HashMap::new();
+    for_each_spilled_read(spilled, "Collecting insert sizes", |read| {
+        add_insert_size(&mut insert_sizes, &read.alignments[0], &read.alignments[1]);
+    })?;
+    Ok(insert_sizes)
+}
+
+
+/// Returns a bit set for each SAM file, indexed by line, with the failing alignments' bits set,
+/// and why they failed.
+fn find_spilled_failures(spilled: &SortedFile, line_counts: [usize; 2],
+                         thresholds: &InsertSizeThresholds, min_mapq: u8,
+                         max_hits: Option<usize>) -> io::Result<([Vec<u64>; 2], FailCounts)> {
+    let mut failed = line_counts.map(|count| vec![0u64; count.div_ceil(64)]);
+    let mut fail_counts = FailCounts::default();
+    for_each_spilled_read(spilled, "Checking read pairs", |read| {
+        if exceeds_max_hits(&read.alignments[0], &read.alignments[1], max_hits) {
+            fail_counts.max_hits_pairs += 1;
+        }
+        for (this, pair) in [(0, 1), (1, 0)] {
+            for (a, &i) in read.alignments[this].iter().zip(&read.line_indices[this]) {
+                if let Some(reason) = alignment_fail_reason(a, &read.alignments[this],
+                                                            &read.alignments[pair], thresholds,
+                                                            min_mapq, max_hits) {
+                    failed[this][i / 64] |= 1 << (i % 64);
+                    fail_counts.add(reason);
+                }
+            }
+        }
+    })?;
+    Ok((failed, fail_counts))
+}
+
+
+/// Filters the alignments of a read pair while adding them to the pileups (for filter-polish), so
+/// each file is only read once. The insert size thresholds come from the first read pairs in the
+/// files, instead of all of them.
+pub fn load_filtered_pair(in1: &Path, in2: &Path, pair: &PairSettings,
+                          pileups: &mut FastHashMap<String, Pileup>, filter: &AlignmentFilter,
+                          downsampler: &Downsampler,
+                          discarded: &mut DiscardCounts) -> [SamCounts; 2] {
+    alignment::process_sam_pair_checked([in1, in2], pileups, filter, downsampler, discarded,
+                                        THRESHOLD_SAMPLE_PAIRS, |sample| {
+        let mut insert_sizes = HashMap::new();
+        for [alignments_1, alignments_2] in sample {
+            add_insert_size(&mut insert_sizes, alignments_1, alignments_2);
+        }
+        let thresholds = get_insert_size_thresholds(insert_sizes, pair);
+        move |pair: &mut [Vec<Alignment>; 2]| {
+            for (this, other) in [(0, 1), (1, 0)] {
+                let failed: Vec<bool> = pair[this].iter().map(|a| {
+                    alignment_fail_reason(a, &pair[this], &pair[other], &thresholds, 0,
+                                          None).is_some()
+                }).collect();
+                for (a, failed) in pair[this].iter_mut().zip(failed) {
+                    if failed {
+                        a.fail_filter();
+                    }
+                }
             }
         }
+    })
+}
+
+
+/// Collects insert sizes (grouped by orientation) from read pairs where each read has exactly one
+/// alignment.
+fn get_insert_sizes(alignments: &[AlignmentMap; 2]) -> HashMap<String, Vec<u32>> {
+    let mut insert_sizes = HashMap::new();
+    for (name, alignments_1) in &alignments[0] {
+        if let Some(alignments_2) = alignments[1].get(name) {
+            add_insert_size(&mut insert_sizes, alignments_1, alignments_2);
+        }
+    }
+    insert_sizes
+}
+
+
+fn add_insert_size(insert_sizes: &mut HashMap<String, Vec<u32>>,
+                   alignments_1: &[Alignment], alignments_2: &[Alignment]) {
+    if alignments_1.len() == 1 && alignments_2.len() == 1 &&
+            alignments_1[0].ref_name == alignments_2[0].ref_name {
+        let orientation = get_orientation(&alignments_1[0], &alignments_2[0]);
+        let insert_size = get_insert_size(&alignments_1[0], &alignments_2[0]);
+        insert_sizes.entry(orientation).or_default().push(insert_size);
+    }
+}
+
+
+fn get_insert_size_thresholds(mut insert_sizes: HashMap<String, Vec<u32>>,
+                              pair: &PairSettings) -> InsertSizeThresholds {
+    let PairSettings { orientation: correct_orientation, low: low_percentile,
+                       high: high_percentile, mate_pair } = pair;
+    log::section_header("Finding insert size thresholds");
+    log::explanation("Read pairs with exactly one alignment per read are used to determine the \
+                      orientation and insert size thresholds for the read set.");
+    let correct_orientation = determine_correct_orientation(correct_orientation, *mate_pair,
+                                                            &insert_sizes);
+    let orientations: Vec<(&str, Vec<u32>)> = correct_orientation.split(',')
+        .map(|o| {
+            let mut sizes = insert_sizes.remove(o).unwrap_or_default();
+            sizes.sort_unstable();
+            (o, sizes)
+        }).collect();
+    let mut all_sizes: Vec<u32> = orientations.iter().flat_map(|(_, s)| s).cloned().collect();
+    if all_sizes.is_empty() {
+        quit_with_error(ErrorType::NoAlignments,
+                        "no read pairs available to determine insert size thresholds");
+    }
+    all_sizes.sort_unstable();
+
+    // Each orientation's range is from its own pairs. An orientation without any pairs gets the
+    // range of all the pairs together.
+    let label = |o: &str| if orientations.len() == 1 { String::new() } else { format!(" ({})", o) };
+    let mut ranges = Vec::new();
+    for (orientation, sizes) in &orientations {
+        let sizes = if sizes.is_empty() {
+            log::text!("No {} pairs, so its thresholds are from all pairs", orientation);
+            &all_sizes
+        } else {
+            sizes
+        };
+        let low_threshold = get_percentile(sizes, *low_percentile);
+        let high_threshold = get_percentile(sizes, *high_percentile);
+        log::text!("Low threshold{}:  {} ({})", label(orientation), low_threshold,
+                   get_percentile_name(*low_percentile));
+        log::text!("High threshold{}: {} ({})", label(orientation), high_threshold,
+                   get_percentile_name(*high_percentile));
+        log::text!();
+        log::event("insert_size_thresholds", json!({"orientation": orientation,
+                                                    "low": low_threshold,
+                                                    "high": high_threshold}));
+        ranges.push((orientation.to_string(), low_threshold, high_threshold));
+    }
+    for ((orientation, sizes), &(_, low, high)) in orientations.iter().zip(&ranges) {
+        if !sizes.is_empty() {
+            log_insert_size_histogram(orientation, sizes, low, high);
+        }
     }
+    InsertSizeThresholds { ranges }
+}
+
+
+fn log_insert_size_histogram(orientation: &str, sorted_sizes: &[u32], low: u32, high: u32) {
+    let bins = insert_size_histogram(sorted_sizes, low, high);
+    log::text!("Insert sizes ({} pairs, end bins include sizes beyond them):", orientation);
+    for line in histogram_lines(&bins, low, high) {
+        log::text!("  {}", line);
+    }
+    log::text!();
+    log::event("insert_size_histogram",
+               json!({"orientation": orientation,
+                      "bins": bins.iter().map(|&(start, end, count)| {
+                          json!({"start": start, "end": end, "count": count})
+                      }).collect::<Vec<_>>()}));
+}
 
-    let correct_orientation = determine_correct_orientation(correct_orientation, &insert_sizes);
-    let mut sizes = insert_sizes.remove(&correct_orientation).unwrap_or_else(Vec::new);
-    if sizes.is_empty() {
-        quit_with_error("no read pairs available to determine insert size thresholds");
+
+/// Counts the sorted insert sizes in bins of equal size. The bins span the thresholds plus a margin
+/// on each side, so the tails beyond the thresholds are visible, and sizes beyond the margins are
+/// counted in the end bins. Each bin is given as its first size, last size and count.
+fn insert_size_histogram(sorted_sizes: &[u32], low: u32, high: u32) -> Vec<(u32, u32, usize)> {
+    let margin = (high - low) / 4;
+    let start = low.saturating_sub(margin);
+    let end = high.saturating_add(margin);
+    let bin_size = (end - start) / HISTOGRAM_BINS + 1;
+    let mut counts = vec![0; ((end - start) / bin_size + 1) as usize];
+    for &size in sorted_sizes {
+        counts[((size.clamp(start, end) - start) / bin_size) as usize] += 1;
     }
-    sizes.sort_unstable();
-    let low_threshold = get_percentile(&sizes, low_percentile);
-    let high_threshold = get_percentile(&sizes, high_percentile);
-    eprintln!("Low threshold:  {} ({})", low_threshold, get_percentile_name(low_percentile));
-    eprintln!("High threshold: {} ({})", high_threshold, get_percentile_name(high_percentile));
-    eprintln!();
+    counts.into_iter().enumerate().map(|(i, count)| {
+        let bin_start = start + i as u32 * bin_size;
+        (bin_start, bin_start + bin_size - 1, count)
+    }).collect()
+}
 
-    (low_threshold, high_threshold, correct_orientation)
+
+/// Draws the histogram as text, one line per bin, with the thresholds marked.
+fn histogram_lines(bins: &[(u32, u32, usize)], low: u32, high: u32) -> Vec<String> {
+    let max_count = bins.iter().map(|b| b.2).max().unwrap_or(0).max(1);
+    let size_width = bins.last().map_or(1, |b| b.1.to_string().len());
+    bins.iter().map(|&(start, end, count)| {
+        let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(max_count));
+        let mut line = format!("{:>w$}-{:<w$} {:<bar_w$} {}", start, end, bar,
+                               count.to_formatted_string(&Locale::en), w = size_width,
+                               bar_w = HISTOGRAM_WIDTH);
+        if (start..=end).contains(&low) {
+            line.push_str("  <- low threshold");
+        }
+        if (start..=end).contains(&high) {
+            line.push_str("  <- high threshold");
+        }
+        line
+    }).collect()
 }
 
 
@@ -211,35 +872,65 @@ fn get_insert_size(alignment_1: &Alignment, alignment_2: &Alignment) -> u32 {
 }
 
 
-fn determine_correct_orientation(correct_orientation: &str,
+fn determine_correct_orientation(correct_orientation: &str, mate_pair: bool,
                                  insert_sizes: &HashMap<String, Vec<u32>>) -> String {
-    for orientation in ["fr", "rf", "ff", "rr"].iter() {
+    let mut pair_counts = serde_json::Map::new();
+    for orientation in ORIENTATIONS.iter() {
         let count = insert_sizes.get(*orientation).map_or(0, |v| v.len());
-        eprintln!("{}: {} pairs", orientation, count.to_formatted_string(&Locale::en));
+        log::text!("{}: {} pairs", orientation, count.to_formatted_string(&Locale::en));
+        pair_counts.insert(orientation.to_string(), json!(count));
+    }
+    log::event("orientation_pair_counts", json!(pair_counts));
+    if mate_pair {
+        log_mate_pair_fractions(insert_sizes);
     }
     if correct_orientation == "auto" {
         let auto_orientation = auto_determine_orientation(insert_sizes);
-        eprintln!("\nAutomatically determined correct orientation: {}\n", auto_orientation);
+        log::text!("\nAutomatically determined correct orientation: {}\n", auto_orientation);
         auto_orientation
+    } else if correct_orientation == "any" {
+        log::text!("\nAll orientations are accepted\n");
+        ORIENTATIONS.join(",")
     } else {
-        eprintln!("\nUser-specified correct orientation: {}\n", correct_orientation);
-        correct_orientation.to_string()
+        let mut orientations: Vec<&str> = Vec::new();
+        for o in correct_orientation.split(',') {
+            if !orientations.contains(&o) {
+                orientations.push(o);
+            }
+        }
+        let orientations = orientations.join(",");
+        log::text!("\nUser-specified correct orientation: {}\n", orientations);
+        orientations
     }
 }
 
 
+/// For a mate-pair library, rf pairs are junction fragments (true mate pairs), fr pairs are
+/// paired-end fragments which lack a junction and ff/rr pairs are likely chimeras.
+fn log_mate_pair_fractions(insert_sizes: &HashMap<String, Vec<u32>>) {
+    let count = |o: &str| insert_sizes.get(o).map_or(0, |v| v.len());
+    let total = ORIENTATIONS.iter().map(|o| count(o)).sum::<usize>().max(1) as f64;
+    let junction = count("rf") as f64 / total;
+    let paired_end = count("fr") as f64 / total;
+    let other = 1.0 - junction - paired_end;
+    log::text!("\nMate-pair library: {:.1}% junction fragments (rf), {:.1}% paired-end fragments \
+                (fr), {:.1}% other (ff or rr)", 100.0 * junction, 100.0 * paired_end,
+               100.0 * other);
+    log::event("mate_pair_fractions", json!({"junction": junction, "paired_end": paired_end,
+                                             "other": other}));
+}
+
+
 fn auto_determine_orientation(insert_sizes: &HashMap<String, Vec<u32>>) -> String {
     let max_count = insert_sizes.values().map(|v| v.len()).max().unwrap_or(0);
-    let orientations: Vec<&str> = ["fr", "rf", "ff", "rr"].iter()
+    let orientations: Vec<&str> = ORIENTATIONS.iter()
         .filter(|&&orientation| insert_sizes.get(orientation).map_or(0, |v| v.len()) == max_count)
         .cloned().collect();
-    let mut best_orientation = String::new();
-    if orientations.len() == 1 {
-        best_orientation = orientations[0].to_string();
-    } else {
-        quit_with_error("could not automatically determine read pair orientation");
+    if orientations.len() != 1 {
+        quit_with_error(ErrorType::Input,
+                        "could not automatically determine read pair orientation");
     }
-    best_orientation
+    orientations[0].to_string()
 }
 
 
@@ -267,44 +958,54 @@ fn get_percentile_name(p: f64) -> String {
 }
 
 
-fn filter_sams(in1: &PathBuf, in2: &PathBuf, out1: &PathBuf, out2: &PathBuf,
-               alignments: &HashMap<String, Vec<Alignment>>, low: u32, high: u32,
-               correct_orientation: String) -> usize {
+/// Writes the filtered SAM files. The pass function decides whether each alignment passes, given
+/// the alignment, its read number (1 or 2) and its line index in the input SAM file. Failing
+/// alignments are written with the fail tag.
+fn filter_sams(options: &FilterOptions,
+               mut pass: impl FnMut(&Alignment, usize, usize) -> bool) -> usize {
+    let FilterOptions { in1, in2, out1, out2, fail_tag, .. } = options;
     log::section_header("Filtering SAM files");
-    log::explanation("Read alignments that are part of a good pair (correct orientation and \
-                      insert size) pass the filter and are written unaltered to the output file. \
-                      Read alignments which are not part of good pair are written to the output \
-                      file with a \"ZP:Z:fail\" tag so Polypolish will not use them.");
+    log::explanation(&format!("Read alignments that are part of a good pair (correct orientation \
+                               and insert size) pass the filter and are written unaltered to the \
+                               output file. Read alignments which are not part of good pair are \
+                               written to the output file with a \"{}\" tag so Polypolish will \
+                               not use them.", fail_tag));
     let mut after_count = 0;
-    let result_1 = filter_sam(&in1, &out1, &alignments, low, high, &correct_orientation, 1);
+    let result_1 = filter_sam(in1, out1, fail_tag, |a, line_index| pass(a, 1, line_index));
     match result_1 {
         Ok(count) => { after_count += count },
-        Err(_) => quit_with_error(&format!("unable to write alignments to {:?}", out1)),
+        Err(_) => quit_with_error(ErrorType::Io,
+                                  &format!("unable to write alignments to {:?}", out1)),
     }
-    let result_2 = filter_sam(&in2, &out2, &alignments, low, high, &correct_orientation, 2);
+    let result_2 = filter_sam(in2, out2, fail_tag, |a, line_index| pass(a, 2, line_index));
     match result_2 {
         Ok(count) => { after_count += count },
-        Err(_) => quit_with_error(&format!("unable to write alignments to {:?}", out2)),
+        Err(_) => quit_with_error(ErrorType::Io,
+                                  &format!("unable to write alignments to {:?}", out2)),
     }
     after_count
 }
 
 
-fn filter_sam(in_filename: &PathBuf, out_filename: &PathBuf,
-              alignments: &HashMap<String, Vec<Alignment>>, low: u32, high: u32,
-              correct_orientation: &String, read_num: usize) -> io::Result<usize> {
-    eprintln!("Filtering {}:", in_filename.display());
+fn filter_sam(in_filename: &Path, out_filename: &Path, fail_tag: &str,
+              mut pass: impl FnMut(&Alignment, usize) -> bool) -> io::Result<usize> {
+    log::text!("Filtering {}:", in_filename.display());
     let mut pass_count = 0;
     let mut fail_count = 0;
 
     let in_file = File::open(in_filename)?;
+    let file_size = in_file.metadata()?.len();
     let reader = io::BufReader::new(in_file);
+    let mut progress = Progress::new(&format!("Filtering {}", in_filename.display()), Unit::Bytes,
+                                     file_size, "alignments");
+    let mut bytes_read: u64 = 0;
     let out_file = File::create(out_filename)?;
     let mut writer = BufWriter::new(out_file);
-    static NO_ALIGNMENTS: Vec<Alignment> = Vec::new();
 
-    for line in reader.lines() {
+    for (line_index, line) in reader.lines().enumerate() {
         let sam_line = line?;
+        bytes_read += sam_line.len() as u64 + 1;
+        progress.update(bytes_read, (pass_count + fail_count) as u64);
         if sam_line.starts_with('@') {
             writeln!(writer, "{}", sam_line)?;
             continue;
@@ -316,38 +1017,31 @@ fn filter_sam(in_filename: &PathBuf, out_filename: &PathBuf,
             continue;
         }
 
-        let (this_name, pair_name) = if read_num == 1 {
-            (format!("{}_1", a.read_name), format!("{}_2", a.read_name))
-        } else {
-            (format!("{}_2", a.read_name), format!("{}_1", a.read_name))
-        };
-
-        let this_alignments = &alignments[&this_name];
-        let pair_alignments = match alignments.get(&pair_name) {
-            Some(alignments) => alignments,
-            None => &NO_ALIGNMENTS,
-        };
-
-        if alignment_pass_qc(&a, this_alignments, pair_alignments, low, high, correct_orientation) {
+        if pass(&a, line_index) {
             writeln!(writer, "{}", sam_line)?;
             pass_count += 1;
         } else {
             let mut parts: Vec<&str> = sam_line.split('\t').collect();
-            parts.push("ZP:Z:fail");
+            parts.push(fail_tag);
             writeln!(writer, "{}", parts.join("\t"))?;
             fail_count += 1;
         }
     }
+    progress.finish();
 
-    eprintln!("  {} pass", pass_count.to_formatted_string(&Locale::en));
-    eprintln!("  {} fail", fail_count.to_formatted_string(&Locale::en));
-    eprintln!();
+    log::text!("  {} pass", pass_count.to_formatted_string(&Locale::en));
+    log::text!("  {} fail", fail_count.to_formatted_string(&Locale::en));
+    log::text!();
+    log::event("sam_filtered", json!({"file": in_filename, "out": out_filename,
+                                      "pass": pass_count, "fail": fail_count}));
     Ok(pass_count)
 }
 
 
-fn alignment_pass_qc(a: &Alignment, this_alignments: &[Alignment], pair_alignments: &[Alignment],
-                     low: u32, high: u32, correct_orientation: &str) -> bool {
+/// Returns why an alignment fails filtering, or None if it passes.
+fn alignment_fail_reason(a: &Alignment, this_alignments: &[Alignment],
+                         pair_alignments: &[Alignment], thresholds: &InsertSizeThresholds,
+                         min_mapq: u8, max_hits: Option<usize>) -> Option<FailReason> {
     // Rules for whether an alignment passes or fails filtering:
     // * If there are no pair alignments, it passes. I.e. if we can't use read pairs to assess the
     //   alignment, we keep it.
@@ -356,21 +1050,54 @@ fn alignment_pass_qc(a: &Alignment, this_alignments: &[Alignment], pair_alignmen
     // * If there are multiple alignments for this read and at least one pair alignment, then the
     //   alignment passes if it makes a good pair (same reference seq, good insert size and correct
     //   orientation) with any of the pair alignments.
-    if pair_alignments.is_empty() {
-        return true;
+    // Alignments with a mapping quality below --min-mapq fail before any of these rules, but can
+    // still make a good pair for their mate's alignments. Read pairs with more than --max-hits
+    // alignments for either read pass without checking every combination of their alignments.
+    if a.mapq().is_some_and(|q| q < min_mapq) {
+        return Some(FailReason::LowMapq);
     }
-    if this_alignments.len() == 1 {
-        return true;
+    if pair_alignments.is_empty() || this_alignments.len() == 1 ||
+            exceeds_max_hits(this_alignments, pair_alignments, max_hits) {
+        return None;
     }
+    let mut reason = None;
     for pair_alignment in pair_alignments {
-        let same_ref = a.ref_name == pair_alignment.ref_name;
-        let insert = get_insert_size(a, pair_alignment);
-        let orientation = get_orientation(a, pair_alignment);
-        if same_ref && low <= insert && insert <= high && orientation == correct_orientation {
-            return true;
+        // Any good pair means the alignment passes.
+        let pair_reason = pair_fail_reason(a, pair_alignment, thresholds)?;
+        if reason.is_some_and(|r| r != pair_reason) {
+            reason = Some(FailReason::NoConcordantPartner);
+        } else {
+            reason = Some(pair_reason);
         }
     }
-    false
+    reason
+}
+
+
+fn exceeds_max_hits(alignments_1: &[Alignment], alignments_2: &[Alignment],
+                    max_hits: Option<usize>) -> bool {
+    max_hits.is_some_and(|m| alignments_1.len() > m || alignments_2.len() > m)
+}
+
+
+/// Returns why two alignments don't make a good pair, or None if they do.
+fn pair_fail_reason(a: &Alignment, pair_alignment: &Alignment,
+                    thresholds: &InsertSizeThresholds) -> Option<FailReason> {
+    if a.ref_name != pair_alignment.ref_name {
+        return Some(FailReason::DifferentReference);
+    }
+    let orientation = get_orientation(a, pair_alignment);
+    let Some((low, high)) = thresholds.range(&orientation) else {
+        return Some(FailReason::WrongOrientation);
+    };
+    let insert = get_insert_size(a, pair_alignment);
+    if insert < low {
+        Some(FailReason::InsertTooSmall)
+    } else if insert > high {
+        Some(FailReason::InsertTooLarge)
+    } else {
+        None
+    }
 }
 
 
@@ -420,6 +1147,94 @@ mod tests {
         run_get_orientation_test(100000, 200000, 16, 16, "rr");
     }
 
+    #[test]
+    fn test_alignment_fail_reason() {
+        let a = |flag: u32, ref_name: &str, pos: usize| {
+            Alignment::new_quick(&format!("r\t{}\t{}\t{}\t60\t150M\t*\t0\t0\tACTG\tKKKK",
+                                          flag, ref_name, pos)).unwrap()
+        };
+        let thresholds = |orientations: &str| {
+            InsertSizeThresholds { ranges: orientations.split(',')
+                                               .map(|o| (o.to_string(), 300, 600)).collect() }
+        };
+        let this = vec![a(0, "x", 1001), a(0, "y", 1001)];
+        let pair = [a(0, "x", 1301)];
+        assert_eq!(alignment_fail_reason(&this[0], &this, &pair, &thresholds("fr,ff"), 0, None),
+                   None);
+        let pair = [a(16, "x", 1301)];
+        assert_eq!(alignment_fail_reason(&this[0], &this, &pair, &thresholds("rf,ff"), 0, None),
+                   Some(FailReason::WrongOrientation));
+        assert_eq!(alignment_fail_reason(&this[0], &this, &pair, &thresholds("fr"), 60, None),
+                   None);
+        assert_eq!(alignment_fail_reason(&this[0], &this, &pair, &thresholds("fr"), 61, None),
+                   Some(FailReason::LowMapq));
+        assert_eq!(alignment_fail_reason(&this[0], &this[..1], &[], &thresholds("fr"), 61, None),
+                   Some(FailReason::LowMapq));
+        let check = |pairs: Vec<Alignment>| {
+            alignment_fail_reason(&this[0], &this, &pairs, &thresholds("fr"), 0, None)
+        };
+        assert_eq!(check(vec![a(16, "x", 1301)]), None);
+        assert_eq!(check(vec![a(16, "x", 1101)]), Some(FailReason::InsertTooSmall));
+        assert_eq!(check(vec![a(16, "x", 5001)]), Some(FailReason::InsertTooLarge));
+        assert_eq!(check(vec![a(16, "z", 1301)]), Some(FailReason::DifferentReference));
+        assert_eq!(check(vec![a(0, "x", 1301)]), Some(FailReason::WrongOrientation));
+        assert_eq!(check(vec![a(16, "x", 1101), a(16, "x", 1121)]),
+                   Some(FailReason::InsertTooSmall));
+        assert_eq!(check(vec![a(16, "x", 1101), a(16, "z", 1301)]),
+                   Some(FailReason::NoConcordantPartner));
+        assert_eq!(check(vec![a(16, "x", 1101), a(16, "x", 1301)]), None);
+        assert_eq!(check(vec![]), None);
+        let pairs = vec![a(16, "x", 1101), a(16, "x", 1121)];
+        assert_eq!(alignment_fail_reason(&this[0], &this, &pairs, &thresholds("fr"), 0, Some(2)),
+                   Some(FailReason::InsertTooSmall));
+        assert_eq!(alignment_fail_reason(&this[0], &this, &pairs, &thresholds("fr"), 0, Some(1)),
+                   None);
+        assert_eq!(alignment_fail_reason(&this[0], &this[..1], &[a(16, "z", 1301)],
+                                         &thresholds("fr"), 0, None), None);
+    }
+
+    #[test]
+    fn test_insert_size_thresholds_per_orientation() {
+        // A mate-pair library: rf junction fragments with long inserts and fr paired-end fragments
+        // with short inserts. Each orientation gets its own range, and ff (accepted but without
+        // any pairs) gets the range of all pairs.
+        let insert_sizes: HashMap<String, Vec<u32>> = [
+            ("rf", (3001..=5000).collect::<Vec<u32>>()), ("fr", (301..=500).collect())
+        ].into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        let pair = PairSettings { orientation: "rf,fr,ff".to_string(), low: 1.0, high: 99.0,
+                                  mate_pair: true };
+        let thresholds = get_insert_size_thresholds(insert_sizes, &pair);
+        assert_eq!(thresholds.range("rf"), Some((3020, 4980)));
+        assert_eq!(thresholds.range("fr"), Some((302, 498)));
+        assert_eq!(thresholds.range("ff"), Some((322, 4978)));
+        assert_eq!(thresholds.range("rr"), None);
+
+        // Each pair is checked against its own orientation's range.
+        let a = |flag: u32, pos: usize| {
+            Alignment::new_quick(&format!("r\t{}\tx\t{}\t60\t100M\t*\t0\t0\tACTG\tKKKK",
+                                          flag, pos)).unwrap()
+        };
+        let fr = |insert: usize| pair_fail_reason(&a(0, 1001), &a(16, 901 + insert), &thresholds);
+        let rf = |insert: usize| pair_fail_reason(&a(16, 1001), &a(0, 901 + insert), &thresholds);
+        assert_eq!(fr(400), None);
+        assert_eq!(fr(4000), Some(FailReason::InsertTooLarge));
+        assert_eq!(rf(4000), None);
+        assert_eq!(rf(400), Some(FailReason::InsertTooSmall));
+        assert_eq!(pair_fail_reason(&a(16, 1001), &a(16, 2001), &thresholds),
+                   Some(FailReason::WrongOrientation));
+    }
+
+    #[test]
+    fn test_apply_preset() {
+        let pair = |orientation: &str, low, high, mate_pair| {
+            PairSettings { orientation: orientation.to_string(), low, high, mate_pair }
+        };
+        assert_eq!(apply_preset("paired-end", None, None, None), pair("auto", 0.1, 99.9, false));
+        assert_eq!(apply_preset("mate-pair", None, None, None), pair("rf,fr", 1.0, 99.0, true));
+        assert_eq!(apply_preset("mate-pair", Some("rf".to_string()), None, Some(95.0)),
+                   pair("rf", 1.0, 95.0, true));
+    }
+
     #[test]
     fn test_auto_determine_orientation() {
         let insert_sizes: HashMap<String, Vec<u32>> = [
@@ -443,6 +1258,42 @@ mod tests {
         assert_eq!(auto_determine_orientation(&insert_sizes), "rr");
     }
 
+    #[test]
+    fn test_extrapolate_memory() {
+        assert_eq!(extrapolate_memory(0, 0, 0, 0), 0);
+        assert_eq!(extrapolate_memory(1000, 0, 1000, 400), 500);
+        assert_eq!(extrapolate_memory(1100, 100, 100, 40), 500);
+        assert_eq!(extrapolate_memory(1000000, 1000, 20000, 10000), 624375);
+    }
+
+    #[test]
+    fn test_intern_read_name() {
+        let mut alignments = [FastHashMap::default(), FastHashMap::default()];
+        let name: Arc<str> = Arc::from("read_a");
+        alignments[0].insert(name.clone(), Vec::new());
+        let interned = intern_read_name(&alignments, &Arc::from("read_a"));
+        assert!(Arc::ptr_eq(&interned, &name));
+        let other: Arc<str> = Arc::from("read_b");
+        assert!(Arc::ptr_eq(&intern_read_name(&alignments, &other), &other));
+    }
+
+    #[test]
+    fn test_spill_record() {
+        let sam_line = "read_a\t16\tcontig_1\t1234\t60\t100M\t*\t0\t0\tACGT\tKKKK\tNM:i:0";
+        let record = spill_record(sam_line, 2, 17);
+        assert_eq!(record, "read_a\t2\t17\t16\tcontig_1\t1234\t60\t100M");
+        assert_eq!(spill_record_key(&record), "read_a");
+        let (read_num, line_index, a) = parse_spill_record(&record);
+        assert_eq!(read_num, 2);
+        assert_eq!(line_index, 17);
+        assert_eq!(&*a.read_name, "read_a");
+        assert_eq!(a.ref_name, "contig_1");
+        assert_eq!(a.ref_start, 1233);
+        assert!(!a.is_on_forward_strand());
+        assert_eq!(a.get_ref_end(), 1333);
+        assert_eq!(a.mapq(), Some(60));
+    }
+
     #[test]
     fn test_get_percentile() {
         let nums: Vec<u32> = vec![15, 20, 35, 40, 50];
@@ -458,6 +1309,32 @@ mod tests {
         assert_eq!(get_percentile(&nums, 99.9), 50);
     }
 
+    #[test]
+    fn test_insert_size_histogram() {
+        let mut sizes: Vec<u32> = (0..100).map(|i| 300 + i).collect();
+        sizes.extend([10, 5000]);
+        sizes.sort_unstable();
+        let bins = insert_size_histogram(&sizes, 300, 399);
+        assert_eq!(bins.first(), Some(&(276, 283, 1)));  // includes the 10 bp insert
+        assert_eq!(bins.last(), Some(&(420, 427, 1)));   // includes the 5000 bp insert
+        assert_eq!(bins.iter().map(|b| b.2).sum::<usize>(), sizes.len());
+        assert!(bins.iter().all(|b| b.1 - b.0 == 7));
+
+        let lines = histogram_lines(&bins, 300, 399);
+        assert_eq!(lines.len(), bins.len());
+        assert!(lines[0].starts_with("276-283 ##### "));
+        assert_eq!(lines.iter().filter(|l| l.ends_with("<- low threshold")).count(), 1);
+        assert_eq!(lines.iter().filter(|l| l.ends_with("<- high threshold")).count(), 1);
+    }
+
+    #[test]
+    fn test_insert_size_histogram_one_size() {
+        let bins = insert_size_histogram(&[250, 250, 250], 250, 250);
+        assert_eq!(bins, vec![(250, 250, 3)]);
+        let lines = histogram_lines(&bins, 250, 250);
+        assert!(lines[0].ends_with("3  <- low threshold  <- high threshold"));
+    }
+
     #[test]
     fn test_get_percentile_name() {
         assert_eq!(get_percentile_name(1.0), "1st percentile");
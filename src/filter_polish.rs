@@ -0,0 +1,123 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// filter-polish does the work of polypolish filter and polypolish polish in one run. The two
+// alignment files are read together, one read pair at a time, so each file is only read once: the
+// first read pairs set the insert size thresholds, and then every read pair is filtered as its
+// alignments are added to the pileup.
+
+use clap::crate_version;
+use serde_json::json;
+
+use std::path::{Path, PathBuf};
+
+use crate::alignment::{AlignmentFilter, DiscardCounts};
+use crate::downsample::Downsampler;
+use crate::filter;
+use crate::filter::PairSettings;
+use crate::gfa;
+use crate::log;
+use crate::misc::Instant;
+use crate::options::FilterPolishOptions;
+use crate::pileup::{PileupSettings, Thresholds};
+use crate::polish;
+use crate::polish::{ExtraInputs, OutputFiles, OutputPaths};
+use crate::stats;
+use crate::stats::AssemblyStats;
+
+
+pub fn filter_polish(options: &FilterPolishOptions) {
+    let start_time = Instant::now();
+    let pair = filter::apply_preset(&options.preset, options.orientation.clone(), options.low,
+                                    options.high);
+    let filter = AlignmentFilter { max_errors: Some(options.max_errors), careful: options.careful,
+                                   ..Default::default() };
+    let thresholds = Thresholds::new(options.min_depth, options.fraction_valid,
+                                     options.fraction_invalid);
+    polish::check_option_values(&thresholds, &filter, None, None, None, None);
+    filter::check_orientation(&pair.orientation);
+    filter::check_percentiles(pair.low, pair.high);
+    let sam = [options.in1.clone(), options.in2.clone()];
+    let assembly = &options.assembly;
+    polish::check_inputs_exist(std::slice::from_ref(assembly), &sam);
+    let assembly_is_gfa = gfa::is_gfa(assembly);
+    starting_message(&thresholds, &filter, &pair, &options.output, assembly, &sam);
+
+    let (fasta, graph) = polish::load_assembly(std::slice::from_ref(assembly), assembly_is_gfa);
+    let original_stats = AssemblyStats::new(&fasta.iter()
+        .map(|(_, _, seq)| (seq.len(), stats::gc_count(seq))).collect::<Vec<_>>());
+    let (seq_names, mut pileups) = polish::make_pileups(fasta, PileupSettings::default());
+    let mut discarded = DiscardCounts::default();
+    let counts = filter::load_filtered_pair(&sam[0], &sam[1], &pair, &mut pileups, &filter,
+                                            &Downsampler::keep_all(), &mut discarded);
+    log::section_header("Loading alignments");
+    log::explanation("Alignments which are not part of a good read pair (correct orientation and \
+                      insert size) were excluded as the alignments were loaded.");
+    for (s, counts) in sam.iter().zip(&counts) {
+        polish::log_sam_counts(s, counts, false, &filter.run);
+    }
+    log::text!();
+    polish::print_alignment_filtering(filter.careful, &counts, &discarded, &filter.run);
+
+    let paths = OutputPaths { output: options.output.clone(), ..Default::default() };
+    let mut outputs = OutputFiles {
+        debug: None, depth: None, uncovered: None, multi_mapped: None,
+        seqs: polish::seq_sinks(graph, &paths, false), html: None, plot: None, changes: None,
+        mixtures: None, gvcf: None, fastq: false, annotate_headers: false,
+        run: filter.run.clone(),
+    };
+    let polished_seqs = polish::polish_sequences(&mut outputs, &thresholds, None, false,
+                                                 &seq_names, &pileups, &ExtraInputs::default());
+    outputs.finish(&polished_seqs);
+    polish::finished_message(&paths, &original_stats, polished_seqs, assembly_is_gfa,
+                             &filter.run, start_time);
+}
+
+
+fn starting_message(thresholds: &Thresholds, filter: &AlignmentFilter, pair: &PairSettings,
+                    output: &Option<PathBuf>, assembly: &Path, sam: &[PathBuf]) {
+    let PairSettings { orientation, low, high, .. } = pair;
+    log::section_header("Starting Polypolish filter-polish");
+    log::explanation("This filters paired-end alignments based on insert size and polishes the \
+                      assembly with them, reading each alignment file only once.");
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input assembly:");
+    log::text!("  {}", assembly.display());
+    log::text!();
+    log::text!("Input short-read alignments:");
+    for s in sam {
+        log::text!("  {}", s.display());
+    }
+    log::text!();
+    log::text!("Settings:");
+    log::text!("  --fraction_invalid {}", thresholds.fraction_invalid);
+    log::text!("  --fraction_valid {}", thresholds.fraction_valid);
+    polish::log_filter_settings(filter);
+    log::text!("  --min_depth {}", thresholds.min_depth);
+    log::text!("  --orientation {}", orientation);
+    log::text!("  --low {}", low);
+    log::text!("  --high {}", high);
+    if filter.careful {
+        log::text!("  --careful");
+    }
+    if let Some(filename) = output {
+        log::text!("  --output {}", filename.display());
+    }
+    log::text!();
+    log::event("run_started", json!({"command": "filter-polish", "version": crate_version!(),
+                                     "assembly": assembly, "alignments": sam,
+                                     "fraction_invalid": thresholds.fraction_invalid,
+                                     "fraction_valid": thresholds.fraction_valid,
+                                     "min_depth": thresholds.min_depth,
+                                     "filter": format!("{:?}", filter), "careful": filter.careful,
+                                     "orientation": orientation, "low": low, "high": high}));
+}
@@ -0,0 +1,483 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Polypolish only uses end-to-end alignments, so where the assembly is badly wrong (e.g. a missing
+// or extra chunk of sequence), no alignments fit and the region has zero depth. Reads near such a
+// gap often still align up to it with their ends soft-clipped, and their clipped tails contain the
+// missing sequence. With --gap-patches, the alignment files are read again after polishing, and
+// for each zero-depth gap, the read sequences extending into it from the left and from the right
+// are combined into consensus sequences. When the two sides meet, they give a candidate patch for
+// the whole gap. The patches are only written to a FASTA file for manual review and are never
+// applied to the polished assembly.
+
+// Runs of Ns in the assembly (e.g. scaffold gaps) are different: no read base can match an N, so
+// polishing leaves them alone, but there is nothing there worth keeping. With --fill-ns, the same
+// read sequences are collected for each N-run before polishing, and each run whose two sides meet
+// is replaced by the joined sequence in the polished assembly.
+
+// Linear contigs from fragmented assemblies often stop a little short of where the reads could
+// take them, and reads aligned to a contig's ends have their overhangs soft-clipped. With
+// --extend-ends, these overhangs are collected in the same way (treating each end as a gap beyond
+// the sequence) and a consensus of them, up to a maximum length, is added to each end.
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::alignment::{Alignment, SamLines};
+use crate::log;
+use crate::misc;
+use crate::misc::{quit_with_error, ErrorType};
+use crate::pileup::Pileup;
+use crate::rotate;
+use crate::run::RunContext;
+
+
+/// A read must align to at least this many bases next to a gap to contribute to its patch.
+const MIN_ANCHOR: usize = 10;
+
+/// Consensus sequences stop when fewer reads than this cover a position...
+const MIN_READS: usize = 2;
+
+/// ...or when no base makes up more than this fraction of them.
+const MIN_AGREEMENT: f64 = 0.5;
+
+/// Consensus sequences from the two sides of a gap are joined when they share at least this many
+/// bases, or when one of them reaches this many bases of the assembly on the other side.
+const MIN_JOIN: usize = 15;
+
+
+struct Gap {
+    start: usize,               // 0-based, inclusive
+    end: usize,                 // 0-based, exclusive
+    from_left: Vec<String>,     // read sequences starting at the gap's first position
+    from_right: Vec<String>,    // read sequences ending at the gap's last position
+}
+
+impl Gap {
+    fn new(start: usize, end: usize) -> Gap {
+        Gap { start, end, from_left: Vec::new(), from_right: Vec::new() }
+    }
+}
+
+
+/// A read-derived sequence which replaces a run of Ns in the polished assembly (--fill-ns).
+pub struct Fill {
+    pub start: usize,  // 0-based, inclusive
+    pub end: usize,    // 0-based, exclusive
+    pub seq: String,
+}
+
+
+/// Read-derived sequences added to the ends of a linear sequence (--extend-ends).
+#[derive(Default)]
+pub struct Extension {
+    pub start: String,
+    pub end: String,
+}
+
+
+/// Finds the zero-depth gaps, collects read sequences extending into them and writes each gap's
+/// consensus sequences to a FASTA file.
+pub fn write_gap_patches(filename: &Path, seq_names: &[(String, String)],
+                         pileups: &misc::FastHashMap<String, Pileup>, sam: &[PathBuf],
+                         run: &RunContext) {
+    log::section_header("Gap patches");
+    log::explanation("Read sequences extending into each zero-depth gap (e.g. from soft-clipped \
+                      alignments) are combined into consensus sequences from either side. These \
+                      are candidate patches for manual review and are not applied to the polished \
+                      assembly.");
+    let mut gaps: misc::FastHashMap<String, Vec<Gap>> = seq_names.iter()
+        .map(|(name, _)| (name.clone(), find_gaps(&pileups[name]))).collect();
+    let gap_count: usize = gaps.values().map(|g| g.len()).sum();
+    if gap_count == 0 {
+        log::text!("No zero-depth gaps");
+        log::text!();
+        log::event("gap_patches", json!({"gaps": 0, "closed": 0}));
+        return;
+    }
+    collect_all_extensions(sam, run, &mut gaps);
+
+    let mut records = Vec::new();
+    let mut closed_count = 0;
+    for (name, _) in seq_names {
+        let seq = pileups[name].original_seq();
+        for gap in &gaps[name] {
+            let region = format!("{}:{}-{}", name, gap.start + 1, gap.end);
+            let left = consensus(&gap.from_left, false);
+            let right = consensus(&gap.from_right, true);
+            let patch = join(&left, &right, &seq[..gap.start], &seq[gap.end..]);
+            let description = format!("gap_length={} left_reads={} right_reads={}",
+                                      gap.end - gap.start, gap.from_left.len(),
+                                      gap.from_right.len());
+            match &patch {
+                Some(patch) => {
+                    closed_count += 1;
+                    log::text!("{}: {} bp patch from {} + {} reads", region, patch.len(),
+                               gap.from_left.len(), gap.from_right.len());
+                    records.push((format!("{} {} patch_length={}", region, description,
+                                          patch.len()), patch.clone()));
+                },
+                None => {
+                    log::text!("{}: not closed ({} bp from the left, {} bp from the right)",
+                               region, left.len(), right.len());
+                    if !left.is_empty() {
+                        records.push((format!("{}_left {}", region, description), left.clone()));
+                    }
+                    if !right.is_empty() {
+                        records.push((format!("{}_right {}", region, description), right.clone()));
+                    }
+                },
+            }
+            log::event("gap_patch", json!({"name": name, "start": gap.start, "end": gap.end,
+                                           "left_reads": gap.from_left.len(),
+                                           "right_reads": gap.from_right.len(),
+                                           "patch_length": patch.as_ref().map(|p| p.len()),
+                                           "left_length": left.len(),
+                                           "right_length": right.len()}));
+        }
+    }
+    if write_fasta(filename, &records).is_err() {
+        quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", filename))
+    }
+    log::text!();
+    log::text!("{} of {} gaps closed", closed_count.to_formatted_string(&Locale::en),
+               gap_count.to_formatted_string(&Locale::en));
+    log::text!();
+    log::event("gap_patches", json!({"gaps": gap_count, "closed": closed_count}));
+}
+
+
+/// Finds the runs of Ns in each sequence and collects read sequences extending into them. Each run
+/// whose consensus sequences join gets a fill, which replaces the run when polishing.
+pub fn fill_n_runs(seq_names: &[(String, String)], pileups: &misc::FastHashMap<String, Pileup>,
+                   sam: &[PathBuf], run: &RunContext) -> misc::FastHashMap<String, Vec<Fill>> {
+    log::section_header("Filling N-runs");
+    log::explanation("Runs of Ns in the assembly (e.g. scaffold gaps) can't be polished, since no \
+                      read base matches an N. Read sequences extending into or across each run \
+                      are combined into consensus sequences from either side, and where these \
+                      join, the joined sequence replaces the run in the polished assembly.");
+    let mut runs: misc::FastHashMap<String, Vec<Gap>> = seq_names.iter()
+        .map(|(name, _)| (name.clone(), find_n_runs(&pileups[name].original_seq()))).collect();
+    let run_count: usize = runs.values().map(|r| r.len()).sum();
+    let mut fills = misc::FastHashMap::default();
+    if run_count == 0 {
+        log::text!("No N-runs in the assembly");
+        log::text!();
+        log::event("n_runs", json!({"runs": 0, "filled": 0}));
+        return fills;
+    }
+    collect_all_extensions(sam, run, &mut runs);
+
+    let mut filled_count = 0;
+    for (name, _) in seq_names {
+        let seq = pileups[name].original_seq();
+        let mut seq_fills = Vec::new();
+        for run in &runs[name] {
+            let region = format!("{}:{}-{}", name, run.start + 1, run.end);
+            let left = consensus(&run.from_left, false);
+            let right = consensus(&run.from_right, true);
+            let patch = join(&left, &right, &seq[..run.start], &seq[run.end..]);
+            match &patch {
+                Some(patch) => {
+                    filled_count += 1;
+                    log::text!("{} ({} bp): filled with {} bp from {} + {} reads", region,
+                               run.end - run.start, patch.len(), run.from_left.len(),
+                               run.from_right.len());
+                    seq_fills.push(Fill { start: run.start, end: run.end, seq: patch.clone() });
+                },
+                None => {
+                    log::text!("{} ({} bp): not filled ({} bp from the left, {} bp from the \
+                                right)", region, run.end - run.start, left.len(), right.len());
+                },
+            }
+            log::event("n_run", json!({"name": name, "start": run.start, "end": run.end,
+                                       "left_reads": run.from_left.len(),
+                                       "right_reads": run.from_right.len(),
+                                       "fill_length": patch.as_ref().map(|p| p.len())}));
+        }
+        if !seq_fills.is_empty() {
+            fills.insert(name.clone(), seq_fills);
+        }
+    }
+    log::text!();
+    log::text!("{} of {} N-runs filled", filled_count.to_formatted_string(&Locale::en),
+               run_count.to_formatted_string(&Locale::en));
+    log::text!();
+    log::event("n_runs", json!({"runs": run_count, "filled": filled_count}));
+    fills
+}
+
+
+/// Collects the read overhangs at the ends of each linear sequence and makes each end's extension
+/// from their consensus, at most max_length bases long. Circular sequences (circular=true in their
+/// header) have no ends to extend.
+pub fn extend_ends(seq_names: &[(String, String)], pileups: &misc::FastHashMap<String, Pileup>,
+                   sam: &[PathBuf], run: &RunContext, max_length: usize,
+                   mask: bool) -> misc::FastHashMap<String, Extension> {
+    log::section_header("Extending sequence ends");
+    log::explanation(&format!("Read sequences overhanging the ends of each linear sequence (e.g. \
+                               soft-clipped alignments) are combined into a consensus, and up to \
+                               {} bp of it is added to that end of the polished sequence{}.",
+                              max_length, if mask { " in lowercase" } else { "" }));
+    let mut ends: misc::FastHashMap<String, Vec<Gap>> = seq_names.iter()
+        .filter(|(_, description)| !rotate::is_circular(description))
+        .map(|(name, _)| {
+            let seq_len = pileups[name].bases.len();
+            (name.clone(), vec![Gap::new(0, 0), Gap::new(seq_len, seq_len)])
+        }).collect();
+    collect_all_extensions(sam, run, &mut ends);
+
+    let mut extensions = misc::FastHashMap::default();
+    let mut total_length = 0;
+    for (name, _) in seq_names {
+        let Some([start, end]) = ends.get(name).map(|e| [&e[0], &e[1]]) else {
+            log::text!("{}: circular, not extended", name);
+            continue;
+        };
+        let start_seq = consensus(&start.from_right, true);
+        let end_seq = consensus(&end.from_left, false);
+        let mut extension = Extension {
+            start: start_seq[start_seq.len().saturating_sub(max_length)..].to_string(),
+            end: end_seq[..end_seq.len().min(max_length)].to_string(),
+        };
+        if mask {
+            extension.start.make_ascii_lowercase();
+            extension.end.make_ascii_lowercase();
+        }
+        log::text!("{}: {} bp added to the start ({} reads), {} bp added to the end ({} reads)",
+                   name, extension.start.len(), start.from_right.len(), extension.end.len(),
+                   end.from_left.len());
+        log::event("sequence_extended", json!({"name": name, "start_length": extension.start.len(),
+                                               "start_reads": start.from_right.len(),
+                                               "end_length": extension.end.len(),
+                                               "end_reads": end.from_left.len()}));
+        total_length += extension.start.len() + extension.end.len();
+        extensions.insert(name.clone(), extension);
+    }
+    log::text!();
+    log::text!("{} bp added to sequence ends", total_length.to_formatted_string(&Locale::en));
+    log::text!();
+    extensions
+}
+
+
+fn find_gaps(pileup: &Pileup) -> Vec<Gap> {
+    find_runs(pileup.bases.iter().map(|b| b.depth == 0.0))
+}
+
+
+fn find_n_runs(seq: &str) -> Vec<Gap> {
+    find_runs(seq.bytes().map(|b| b.eq_ignore_ascii_case(&b'N')))
+}
+
+
+/// Makes a gap for each maximal run of true values.
+fn find_runs(values: impl Iterator<Item = bool>) -> Vec<Gap> {
+    let mut gaps = Vec::new();
+    let mut start = None;
+    let mut len = 0;
+    for (i, value) in values.enumerate() {
+        match (value, start) {
+            (true, None)     => start = Some(i),
+            (false, Some(s)) => {
+                gaps.push(Gap::new(s, i));
+                start = None;
+            },
+            _ => {},
+        }
+        len = i + 1;
+    }
+    if let Some(s) = start {
+        gaps.push(Gap::new(s, len));
+    }
+    gaps
+}
+
+
+fn collect_all_extensions(sam: &[PathBuf], run: &RunContext,
+                          gaps: &mut misc::FastHashMap<String, Vec<Gap>>) {
+    for s in sam {
+        if collect_extensions(s, run, gaps).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to load alignments from {:?}", s))
+        }
+    }
+}
+
+
+/// Reads an alignment file and adds the read sequences which extend into each gap.
+fn collect_extensions(filename: &Path, run: &RunContext,
+                      gaps: &mut misc::FastHashMap<String, Vec<Gap>>) -> io::Result<()> {
+    for line in SamLines::open(filename, run)? {
+        let sam_line = line?;
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        let Ok(a) = Alignment::new(&sam_line, &[]) else { continue; };
+        if !a.is_aligned() || a.read_seq == "*" {continue;}
+        let Some(seq_gaps) = gaps.get_mut(&a.ref_name) else { continue; };
+        let ref_end = a.get_ref_end();
+        let read_len = a.read_seq.len();
+        let first = seq_gaps.partition_point(|g| g.end + read_len <= a.ref_start);
+        for gap in seq_gaps[first..].iter_mut().take_while(|g| g.start < ref_end + read_len) {
+            add_extensions(&a, ref_end, gap);
+        }
+    }
+    Ok(())
+}
+
+
+/// A read anchored to the left of the gap gives its sequence from the gap's first position to its
+/// end, and one anchored to the right gives its sequence from its start to the gap's last
+/// position.
+fn add_extensions(a: &Alignment, ref_end: usize, gap: &mut Gap) {
+    if a.ref_start + MIN_ANCHOR <= gap.start && ref_end > gap.start.saturating_sub(MIN_ANCHOR) {
+        if let Some(i) = a.read_pos_at(gap.start).filter(|&i| i < a.read_seq.len()) {
+            gap.from_left.push(a.read_seq[i..].to_string());
+        }
+    }
+    if ref_end >= gap.end + MIN_ANCHOR && a.ref_start < gap.end + MIN_ANCHOR {
+        if let Some(i) = a.read_pos_at(gap.end).filter(|&i| i > 0) {
+            gap.from_right.push(a.read_seq[..i].to_string());
+        }
+    }
+}
+
+
+/// Builds a majority-rule consensus of sequences which share their start (or their end, if
+/// from_end is true), stopping when too few reads agree.
+fn consensus(seqs: &[String], from_end: bool) -> String {
+    let seqs: Vec<Vec<u8>> = seqs.iter().map(|s| {
+        let mut s = s.as_bytes().to_vec();
+        if from_end {
+            s.reverse();
+        }
+        s
+    }).collect();
+    let mut consensus = Vec::new();
+    for i in 0.. {
+        let mut counts = [0; 5];  // A, C, G, T, other
+        for s in seqs.iter().filter_map(|s| s.get(i)) {
+            counts["ACGT".bytes().position(|b| b == *s).unwrap_or(4)] += 1;
+        }
+        let total: usize = counts.iter().sum();
+        let (best, &best_count) = counts[..4].iter().enumerate().max_by_key(|(_, &c)| c).unwrap();
+        if total < MIN_READS || (best_count as f64) <= MIN_AGREEMENT * total as f64 {
+            break;
+        }
+        consensus.push(b"ACGT"[best]);
+    }
+    if from_end {
+        consensus.reverse();
+    }
+    String::from_utf8(consensus).unwrap()
+}
+
+
+/// Joins the consensus sequences from the two sides of a gap into a patch for the whole gap, if
+/// possible. Since reads extend past the gap, the left consensus can reach the assembly after the
+/// gap (or the right consensus the assembly before it). Otherwise, the two need to overlap.
+fn join(left: &str, right: &str, before: &str, after: &str) -> Option<String> {
+    if after.len() >= MIN_JOIN {
+        if let Some(i) = left.find(&after[..MIN_JOIN]) {
+            return Some(left[..i].to_string());
+        }
+    }
+    if before.len() >= MIN_JOIN {
+        if let Some(i) = right.rfind(&before[before.len() - MIN_JOIN..]) {
+            return Some(right[i + MIN_JOIN..].to_string());
+        }
+    }
+    (MIN_JOIN..=left.len().min(right.len())).rev()
+        .find(|&overlap| left.ends_with(&right[..overlap]))
+        .map(|overlap| format!("{}{}", left, &right[overlap..]))
+}
+
+
+fn write_fasta(filename: &Path, records: &[(String, String)]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for (header, seq) in records {
+        writeln!(writer, ">{}", header)?;
+        writeln!(writer, "{}", seq)?;
+    }
+    writer.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consensus() {
+        let seqs = vec!["ACGTT".to_string(), "ACGAC".to_string(), "ACGT".to_string()];
+        assert_eq!(consensus(&seqs, false), "ACGT");
+        let seqs = vec!["TTACG".to_string(), "GACG".to_string(), "CCACG".to_string()];
+        assert_eq!(consensus(&seqs, true), "ACG");
+        assert_eq!(consensus(&["ACGT".to_string()], false), "");
+    }
+
+    #[test]
+    fn test_join() {
+        let before = "GGGGGGGGGGGGGGGGGGGA";
+        let after = "CTTTTTTTTTTTTTTTTTTT";
+        // The left consensus reaches the assembly after the gap.
+        assert_eq!(join("ACGTCTTTTTTTTTTTTTTT", "", before, after), Some("ACGT".to_string()));
+        // The right consensus reaches the assembly before the gap.
+        assert_eq!(join("", "GGGGGGGGGGGGGGAACGT", before, after), Some("ACGT".to_string()));
+        // The two consensus sequences overlap.
+        assert_eq!(join("AAAAACCCCCGGGGGTTTTT", "CCCCCGGGGGTTTTTACGT", "", ""),
+                   Some("AAAAACCCCCGGGGGTTTTTACGT".to_string()));
+        assert_eq!(join("ACGT", "ACGT", before, after), None);
+    }
+
+    #[test]
+    fn test_find_gaps() {
+        let mut pileup = Pileup::new("ACGTACGT", u32::MAX, 0, None, false);
+        for i in [0, 1, 4, 5] {
+            pileup.bases[i].depth = 1.0;
+        }
+        let gaps = find_gaps(&pileup);
+        assert_eq!(gaps.iter().map(|g| (g.start, g.end)).collect::<Vec<_>>(),
+                   vec![(2, 4), (6, 8)]);
+    }
+
+    #[test]
+    fn test_find_n_runs() {
+        let runs = find_n_runs("NNACGTnnnnACN");
+        assert_eq!(runs.iter().map(|r| (r.start, r.end)).collect::<Vec<_>>(),
+                   vec![(0, 2), (6, 10), (12, 13)]);
+        assert!(find_n_runs("ACGT").is_empty());
+        assert!(find_n_runs("").is_empty());
+    }
+
+    #[test]
+    fn test_end_overhangs() {
+        // A 30 bp sequence, with one read overhanging its start by 3 bases and one overhanging
+        // its end by 4 bases.
+        let mut start = Gap::new(0, 0);
+        let mut end = Gap::new(30, 30);
+        let a = Alignment::new("r_1\t0\tx\t1\t60\t3S12M\t*\t0\t0\tTTTACGTACGTACGT\t*\t\
+                                NM:i:0", &[]).unwrap();
+        let b = Alignment::new("r_2\t0\tx\t15\t60\t16M4S\t*\t0\t0\tACGTACGTACGTACGTGGGG\t*\t\
+                                NM:i:0", &[]).unwrap();
+        for alignment in [&a, &b] {
+            add_extensions(alignment, alignment.get_ref_end(), &mut start);
+            add_extensions(alignment, alignment.get_ref_end(), &mut end);
+        }
+        assert_eq!(start.from_right, vec!["TTT".to_string()]);
+        assert!(start.from_left.is_empty());
+        assert_eq!(end.from_left, vec!["GGGG".to_string()]);
+        assert!(end.from_right.is_empty());
+    }
+}
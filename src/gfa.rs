@@ -0,0 +1,230 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Assembly graphs in GFA (v1) format can be polished in place of a FASTA assembly. Each segment's
+// sequence is polished, and the graph is written back out with all other lines (links, paths,
+// etc.) unchanged. Only a segment's LN tag is updated, as polishing can change its length.
+
+use flate2::read::MultiGzDecoder;
+
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+
+use crate::misc;
+use crate::misc::{quit_with_error, ErrorType, FastHashMap};
+use crate::output::SeqWriter;
+use crate::sink::SeqSink;
+
+
+pub struct Gfa {
+    lines: Vec<GfaLine>,
+    segments: Vec<Segment>,
+    segment_indices: FastHashMap<String, usize>,
+}
+
+enum GfaLine {
+    Segment(usize),  // index into the segments
+    Other(String),
+}
+
+struct Segment {
+    name: String,
+    seq: String,
+    tags: Vec<String>,
+}
+
+
+/// Returns true if the file (gzipped or not) looks like GFA: its first line is a GFA record.
+pub fn is_gfa(filename: &Path) -> bool {
+    let Ok(mut lines) = open_lines(filename) else { return false; };
+    let mut first_line = String::new();
+    if lines.read_line(&mut first_line).is_err() {
+        return false;
+    }
+    matches!(first_line.split('\t').next(), Some("H" | "S" | "L" | "P" | "W"))
+}
+
+
+/// Loads a GFA file, quitting with an error if it can't be loaded or has a segment without a
+/// sequence.
+pub fn load_gfa(filename: &Path) -> Gfa {
+    let gfa = match load_gfa_lines(filename) {
+        Ok(gfa) => gfa,
+        Err(_)  => quit_with_error(ErrorType::Io, &format!("unable to load {:?}", filename)),
+    };
+    if gfa.segments.is_empty() {
+        quit_with_error(ErrorType::Input, &format!("{:?} contains no segments", filename));
+    }
+    if gfa.segment_indices.len() < gfa.segments.len() {
+        quit_with_error(ErrorType::Input, &format!("{:?} has a duplicated segment name", filename));
+    }
+    gfa
+}
+
+
+fn load_gfa_lines(filename: &Path) -> io::Result<Gfa> {
+    let mut gfa = Gfa { lines: Vec::new(), segments: Vec::new(),
+                        segment_indices: FastHashMap::default() };
+    for line in open_lines(filename)?.lines() {
+        let text = line?;
+        if text.is_empty() {continue;}
+        if text.starts_with("H\t") && text.contains("\tVN:Z:2") {
+            quit_with_error(ErrorType::Input,
+                            &format!("{:?} is GFA2, but only GFA1 is supported", filename));
+        }
+        let Some(segment) = text.strip_prefix("S\t") else {
+            gfa.lines.push(GfaLine::Other(text));
+            continue;
+        };
+        let mut parts = segment.split('\t');
+        let name = parts.next().unwrap_or_default().to_string();
+        let seq = parts.next().unwrap_or_default().to_ascii_uppercase();
+        if name.is_empty() || seq.is_empty() {
+            quit_with_error(ErrorType::Input,
+                            &format!("{:?} is not correctly formatted", filename));
+        }
+        if seq == "*" {
+            quit_with_error(ErrorType::Input,
+                            &format!("segment {} in {:?} has no sequence", name, filename));
+        }
+        let tags = parts.map(|t| t.to_string()).collect();
+        gfa.segment_indices.insert(name.clone(), gfa.segments.len());
+        gfa.lines.push(GfaLine::Segment(gfa.segments.len()));
+        gfa.segments.push(Segment { name, seq, tags });
+    }
+    Ok(gfa)
+}
+
+
+fn open_lines(filename: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(filename)?;
+    if misc::is_file_gzipped(filename) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+
+impl Gfa {
+    /// Moves the segment sequences out of the graph, in the same name/description/sequence form as
+    /// a loaded FASTA. Their polished sequences are then added back with push_seq.
+    pub fn take_seqs(&mut self) -> Vec<(String, String, String)> {
+        self.segments.iter_mut()
+            .map(|s| (s.name.clone(), String::new(), std::mem::take(&mut s.seq))).collect()
+    }
+
+    /// Appends to a segment's sequence.
+    pub fn push_seq(&mut self, name: &str, seq: &str) {
+        let i = self.segment_indices[name];
+        self.segments[i].seq.push_str(seq);
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        for line in &self.lines {
+            match line {
+                GfaLine::Segment(i) => {
+                    let s = &self.segments[*i];
+                    write!(writer, "S\t{}\t{}", s.name, s.seq)?;
+                    for tag in &s.tags {
+                        if tag.starts_with("LN:i:") {
+                            write!(writer, "\tLN:i:{}", s.seq.len())?;
+                        } else {
+                            write!(writer, "\t{}", tag)?;
+                        }
+                    }
+                    writeln!(writer)?;
+                },
+                GfaLine::Other(text) => writeln!(writer, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// A graph's polished sequences are held in it, and the graph is written (to the polished assembly
+/// output) when polishing is finished.
+pub struct GraphOutput {
+    graph: Gfa,
+    writer: SeqWriter,
+    name: String,  // the segment currently being polished
+}
+
+impl GraphOutput {
+    pub fn new(graph: Gfa, writer: SeqWriter) -> GraphOutput {
+        GraphOutput { graph, writer, name: String::new() }
+    }
+}
+
+impl SeqSink for GraphOutput {
+    fn start_seq(&mut self, name: &str, _header: &str) {
+        self.name = name.to_string();
+    }
+
+    fn write_seq(&mut self, seq: &str) {
+        self.graph.push_seq(&self.name, seq);
+    }
+
+    fn finish_seq(&mut self, _quals: Option<&str>) {}
+
+    fn finish(self: Box<Self>) {
+        let GraphOutput { graph, mut writer, .. } = *self;
+        if graph.write(&mut writer).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to {}", writer.destination()));
+        }
+        writer.finish();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn test_gfa_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.gfa");
+        std::fs::write(&path, "H\tVN:Z:1.0\n\
+                               S\t1\tacgtacgt\tLN:i:8\tdp:f:1.5\n\
+                               S\t2\tGGGAAA\n\
+                               L\t1\t+\t2\t-\t0M\n\
+                               P\tp1\t1+,2-\t*\n").unwrap();
+        assert!(is_gfa(&path));
+        let mut gfa = load_gfa(&path);
+        let seqs = gfa.take_seqs();
+        assert_eq!(seqs, vec![("1".to_string(), "".to_string(), "ACGTACGT".to_string()),
+                              ("2".to_string(), "".to_string(), "GGGAAA".to_string())]);
+        gfa.push_seq("1", "ACGTA");
+        gfa.push_seq("1", "CGTT");
+        gfa.push_seq("2", "GGGAAA");
+        let mut out = Vec::new();
+        gfa.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "H\tVN:Z:1.0\n\
+                                                     S\t1\tACGTACGTT\tLN:i:9\tdp:f:1.5\n\
+                                                     S\t2\tGGGAAA\n\
+                                                     L\t1\t+\t2\t-\t0M\n\
+                                                     P\tp1\t1+,2-\t*\n");
+    }
+
+    #[test]
+    fn test_is_gfa() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.fasta");
+        std::fs::write(&path, ">1\nACGT\n").unwrap();
+        assert!(!is_gfa(&path));
+        assert!(!is_gfa(&dir.path().join("missing.gfa")));
+    }
+}
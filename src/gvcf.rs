@@ -0,0 +1,279 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The gVCF (--gvcf) covers every position of the assembly, like GATK's gVCFs. Runs of bases which
+// were checked and kept are reference blocks (with their mean and minimum depth and minimum
+// confidence), as are runs of bases with too little depth to check (with a LowDepth filter).
+// Changed bases and bases polishing couldn't settle (e.g. two well-supported sequences) get their
+// own records, with the status and pileup from the debug file. The assembly is treated as one
+// haploid sample, so a change's genotype is 1 and a kept base's is 0.
+
+use clap::crate_version;
+
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::misc::{quit_with_error, ErrorType};
+use crate::pileup::{format_count, BaseStatus, PileupBase};
+
+
+/// Kept bases are grouped into reference blocks by confidence, in bands of this width.
+const GQ_BAND_WIDTH: f64 = 10.0;
+
+
+pub struct GvcfFile {
+    writer: BufWriter<File>,
+    filename: PathBuf,
+    name: String,
+    block: Option<Block>,
+    last: Option<Site>,  // held back, as a deletion after it is given with it (REF AT, ALT A)
+}
+
+/// A run of bases which are either kept or have too little depth to check.
+struct Block {
+    start: usize,
+    end: usize,
+    base: char,
+    low_depth: bool,
+    band: u32,
+    depth_total: f64,
+    min_depth: f64,
+    min_quality: f64,
+}
+
+/// One base, or a deletion with the base before it.
+struct Site {
+    pos: usize,
+    ref_seq: String,
+    alt_seq: String,
+    depth: f64,
+    quality: f64,
+    status: BaseStatus,
+    pileup: String,      // only for records, not reference blocks
+    needs_next: bool,    // for a deletion at a sequence's start, given with the base after it
+}
+
+impl Site {
+    fn is_record(&self) -> bool {
+        !matches!(self.status, BaseStatus::OriginalBaseKept | BaseStatus::DepthTooLow)
+    }
+
+    fn merge(&mut self, depth: f64, quality: f64) {
+        self.depth = self.depth.min(depth);
+        self.quality = self.quality.min(quality);
+    }
+}
+
+impl GvcfFile {
+    /// Creates the file and writes its header, which lists each sequence with its length.
+    pub fn create(filename: &Path, contigs: &[(String, usize)]) -> GvcfFile {
+        let file = match File::create(filename) {
+            Ok(file) => file,
+            Err(_)   => quit_with_error(ErrorType::Io, &format!("unable to create {:?}", filename)),
+        };
+        let mut gvcf = GvcfFile { writer: BufWriter::new(file), filename: filename.to_path_buf(),
+                                  name: String::new(), block: None, last: None };
+        gvcf.write_line("##fileformat=VCFv4.2");
+        gvcf.write_line(&format!("##source=Polypolish v{}", crate_version!()));
+        for (name, length) in contigs {
+            gvcf.write_line(&format!("##contig=<ID={},length={}>", name, length));
+        }
+        for line in [
+            "##ALT=<ID=NON_REF,Description=\"Any allele other than those given\">",
+            "##FILTER=<ID=LowDepth,Description=\"Too little read depth to check the bases\">",
+            "##INFO=<ID=END,Number=1,Type=Integer,Description=\"End of the reference block\">",
+            "##INFO=<ID=STATUS,Number=1,Type=String,Description=\"How the base was polished (as \
+             in the debug file)\">",
+            "##INFO=<ID=PILEUP,Number=.,Type=String,Description=\"Each sequence in the base's \
+             pileup with its read count\">",
+            "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">",
+            "##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Read depth (the mean for \
+             reference blocks)\">",
+            "##FORMAT=<ID=GQ,Number=1,Type=Integer,Description=\"Phred-scaled confidence in the \
+             base (the minimum for reference blocks)\">",
+            "##FORMAT=<ID=MIN_DP,Number=1,Type=Integer,Description=\"Minimum read depth in the \
+             reference block\">",
+            "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE",
+        ] {
+            gvcf.write_line(line);
+        }
+        gvcf
+    }
+
+    /// Adds one base, with its polishing status and polished sequence (- for a deletion). Bases
+    /// are added in order along each sequence.
+    pub fn add_base(&mut self, name: &str, pos: usize, b: &PileupBase, status: &BaseStatus,
+                    seq: &str) {
+        if name != self.name {
+            self.finish_seq();
+            self.name = name.to_string();
+        }
+        let original = b.original();
+        let polished = seq.replace('-', "");
+        let quality = b.seq_confidence(seq);
+        if status.is_change() && polished.is_empty() {
+            if let Some(last) = &mut self.last {
+                last.ref_seq.push(original);
+                last.merge(b.depth, quality);
+                if !last.status.is_change() {
+                    last.status = *status;
+                    last.pileup = pileup_str(b);
+                }
+                return;
+            }
+        } else if let Some(last) = self.last.as_mut().filter(|l| l.needs_next) {
+            last.ref_seq.push(original);
+            last.alt_seq.push_str(&polished);
+            last.merge(b.depth, quality);
+            last.needs_next = false;
+            return;
+        }
+        self.write_last();
+        let mut site = Site { pos, ref_seq: original.to_string(), alt_seq: polished,
+                              depth: b.depth, quality, status: *status, pileup: String::new(),
+                              needs_next: false };
+        site.needs_next = site.status.is_change() && site.alt_seq.is_empty();
+        if site.is_record() {
+            site.pileup = pileup_str(b);
+        }
+        self.last = Some(site);
+    }
+
+    /// Writes the last sequence's remaining block or record and flushes the file.
+    pub fn finish(mut self) {
+        self.finish_seq();
+        if self.writer.flush().is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename));
+        }
+    }
+
+    fn finish_seq(&mut self) {
+        self.write_last();
+        self.write_block();
+    }
+
+    fn write_last(&mut self) {
+        let Some(site) = self.last.take() else { return; };
+        if site.is_record() {
+            self.write_block();
+            self.write_record(&site);
+            return;
+        }
+        let low_depth = matches!(site.status, BaseStatus::DepthTooLow);
+        let band = (site.quality / GQ_BAND_WIDTH) as u32;
+        if let Some(block) = &mut self.block {
+            if block.end == site.pos && block.low_depth == low_depth && block.band == band {
+                block.end += 1;
+                block.depth_total += site.depth;
+                block.min_depth = block.min_depth.min(site.depth);
+                block.min_quality = block.min_quality.min(site.quality);
+                return;
+            }
+        }
+        self.write_block();
+        self.block = Some(Block { start: site.pos, end: site.pos + 1,
+                                  base: site.ref_seq.chars().next().unwrap(), low_depth, band,
+                                  depth_total: site.depth, min_depth: site.depth,
+                                  min_quality: site.quality });
+    }
+
+    fn write_block(&mut self) {
+        let Some(block) = self.block.take() else { return; };
+        let (genotype, filter) = if block.low_depth { (".", "LowDepth") } else { ("0", "PASS") };
+        let mean_depth = block.depth_total / (block.end - block.start) as f64;
+        self.write_line(&format!("{}\t{}\t.\t{}\t<NON_REF>\t.\t{}\tEND={}\tGT:DP:GQ:MIN_DP\t\
+                                  {}:{:.0}:{:.0}:{:.0}", self.name, block.start + 1, block.base,
+                                 filter, block.end, genotype, mean_depth, block.min_quality,
+                                 block.min_depth));
+    }
+
+    fn write_record(&mut self, site: &Site) {
+        if site.alt_seq.is_empty() {
+            return;  // the whole sequence was deleted
+        }
+        let changed = site.status.is_change();
+        let (alt, qual, genotype) = if changed {
+            (format!("{},<NON_REF>", site.alt_seq), format!("{:.0}", site.quality), "1")
+        } else {
+            ("<NON_REF>".to_string(), ".".to_string(), "0")
+        };
+        let mut info = format!("STATUS={}", site.status.name());
+        if !site.pileup.is_empty() {
+            info.push_str(&format!(";PILEUP={}", site.pileup));
+        }
+        self.write_line(&format!("{}\t{}\t.\t{}\t{}\t{}\tPASS\t{}\tGT:DP:GQ\t{}:{:.0}:{:.0}",
+                                 self.name, site.pos + 1, site.ref_seq, alt, qual, info,
+                                 genotype, site.depth, site.quality));
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if writeln!(self.writer, "{}", line).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}", self.filename));
+        }
+    }
+}
+
+
+/// Each sequence in the base's pileup with its count, e.g. A:20,C:2.
+fn pileup_str(b: &PileupBase) -> String {
+    b.seq_counts().iter().map(|(seq, count)| format!("{}:{}", seq, format_count(*count)))
+        .collect::<Vec<_>>().join(",")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pileup::Pileup;
+
+    #[test]
+    fn test_gvcf_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("out.g.vcf");
+        let mut pileup = Pileup::new("ACGTACGT", u32::MAX, 0, None, false);
+        for (pos, seq) in ["A", "C", "G", "-", "A", "C", "G", "T"].iter().enumerate() {
+            for _ in 0..10 {
+                pileup.bases[pos].add_seq(seq, 1.0, 1.0);
+            }
+        }
+        for _ in 0..10 {
+            pileup.bases[5].add_seq("CA", 1.0, 1.0);
+        }
+        let contigs = vec![("a".to_string(), 8), ("b".to_string(), 2)];
+        let mut gvcf = GvcfFile::create(&filename, &contigs);
+        let kept = BaseStatus::OriginalBaseKept;
+        gvcf.add_base("a", 0, &pileup.bases[0], &kept, "A");
+        gvcf.add_base("a", 1, &pileup.bases[1], &kept, "C");
+        gvcf.add_base("a", 2, &pileup.bases[2], &kept, "G");
+        gvcf.add_base("a", 3, &pileup.bases[3], &BaseStatus::Changed, "-");
+        gvcf.add_base("a", 4, &pileup.bases[4], &kept, "A");
+        gvcf.add_base("a", 5, &pileup.bases[5], &BaseStatus::MultipleValidOptions, "C");
+        gvcf.add_base("a", 6, &pileup.bases[6], &kept, "G");
+        gvcf.add_base("a", 7, &pileup.bases[7], &BaseStatus::DepthTooLow, "T");
+        gvcf.add_base("b", 0, &pileup.bases[3], &BaseStatus::Changed, "-");
+        gvcf.add_base("b", 1, &pileup.bases[0], &kept, "A");
+        gvcf.finish();
+        let text = std::fs::read_to_string(&filename).unwrap();
+        assert!(text.contains("##contig=<ID=b,length=2>\n"));
+        let records: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(records, vec![
+            "a\t1\t.\tA\t<NON_REF>\t.\tPASS\tEND=2\tGT:DP:GQ:MIN_DP\t0:10:30:10",
+            "a\t3\t.\tGT\tG,<NON_REF>\t30\tPASS\tSTATUS=changed;PILEUP=-:10\tGT:DP:GQ\t1:10:30",
+            "a\t5\t.\tA\t<NON_REF>\t.\tPASS\tEND=5\tGT:DP:GQ:MIN_DP\t0:10:30:10",
+            "a\t6\t.\tC\t<NON_REF>\t.\tPASS\tSTATUS=multiple;PILEUP=C:10,CA:10\tGT:DP:GQ\t\
+             0:20:2",
+            "a\t7\t.\tG\t<NON_REF>\t.\tPASS\tEND=7\tGT:DP:GQ:MIN_DP\t0:10:30:10",
+            "a\t8\t.\tT\t<NON_REF>\t.\tLowDepth\tEND=8\tGT:DP:GQ:MIN_DP\t.:10:30:10",
+            "b\t1\t.\tTA\tA,<NON_REF>\t30\tPASS\tSTATUS=changed;PILEUP=-:10\tGT:DP:GQ\t1:10:30",
+        ]);
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Polishing without files: the assembly and alignments are given as streams or byte slices, and
+// the polished sequences and changes are returned. This is just the core of polypolish polish
+// (no read pairs, samples, long reads or per-base files), with the same defaults. Nothing here
+// uses the file system or threads, so it also works in a browser: the library builds for wasm32
+// (cargo build --lib --target wasm32-unknown-unknown), where timings use the browser's clock.
+// Problems with the inputs (e.g. a malformed record) are returned as a misc::Error instead of
+// ending the program.
+
+use serde::{Deserialize, Serialize};
+
+use std::io;
+use std::io::BufRead;
+
+use crate::alignment;
+use crate::alignment::{AlignmentFilter, DiscardCounts};
+use crate::misc;
+use crate::misc::Error;
+use crate::normalise::LeftNormaliser;
+use crate::pileup::{PileupSettings, Thresholds};
+use crate::polish;
+use crate::polish::{ExtraInputs, OutputFiles, PolishedSeq};
+use crate::run::RunContext;
+use crate::sink::{Change, ChangeCollector, PolishedContig, SeqCollector};
+use crate::source::{AlignmentSource, SamSource};
+
+
+/// The settings for in-memory polishing. The default values match polypolish polish's.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub thresholds: Thresholds,
+    pub filter: AlignmentFilter,
+    pub max_depth: u32,
+    pub trim_ends: usize,
+    pub homopolymer_trim: Option<usize>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { thresholds: Thresholds::new(5, 0.5, 0.2),
+                   filter: AlignmentFilter { max_errors: Some(10), ..Default::default() },
+                   max_depth: u32::MAX, trim_ends: 0, homopolymer_trim: Some(1) }
+    }
+}
+
+
+/// The polished sequences, the changes made to them and a summary of each, along with the number
+/// of bad records skipped (if settings.filter.run skips them) and any warnings.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolishResult {
+    pub seqs: Vec<PolishedContig>,
+    pub changes: Vec<Change>,
+    pub summaries: Vec<PolishedSeq>,
+    pub bad_records: usize,
+    pub warnings: Vec<String>,
+}
+
+
+/// Polishes an uncompressed FASTA assembly with alignments from any number of sources. Bad records
+/// in a SamSource are handled by its own run (see SamSource::in_run), so they aren't counted here,
+/// and its fail tags are its own too (see SamSource::with_fail_tags).
+pub fn polish_streams<S: AlignmentSource>(assembly: impl BufRead,
+                                          sources: impl IntoIterator<Item = S>,
+                                          settings: &Settings) -> Result<PolishResult, Error> {
+    polish_in_run(assembly, sources, settings, settings.filter.run.fresh())
+}
+
+
+/// Polishes an uncompressed FASTA assembly with alignments in uncompressed SAM format.
+pub fn polish_bytes(assembly: &[u8], sams: &[&[u8]],
+                    settings: &Settings) -> Result<PolishResult, Error> {
+    let run = settings.filter.run.fresh();
+    let sources = sams.iter().enumerate().map(|(i, sam)| {
+        SamSource::from_sam(&format!("alignments {}", i + 1), io::Cursor::new(sam.to_vec()))
+            .in_run(&run).with_fail_tags(&settings.filter.fail_tags)
+    }).collect::<Vec<_>>();
+    polish_in_run(assembly, sources, settings, run)
+}
+
+
+/// Each call is its own run, so nothing recorded in one (e.g. bad records) carries over to the
+/// next call with the same settings.
+fn polish_in_run<S: AlignmentSource>(assembly: impl BufRead, sources: impl IntoIterator<Item = S>,
+                                     settings: &Settings,
+                                     run: RunContext) -> Result<PolishResult, Error> {
+    let filter = AlignmentFilter { run, ..settings.filter.clone() };
+    let fasta = misc::load_fasta_from_stream(assembly, "assembly")?;
+    let pileup_settings = PileupSettings { max_depth: settings.max_depth,
+                                           trim_ends: settings.trim_ends,
+                                           homopolymer_trim: settings.homopolymer_trim,
+                                           record_reads: false };
+    let (seq_names, mut pileups) = polish::make_pileups(fasta, pileup_settings);
+    let mut discarded = DiscardCounts::default();
+    for source in sources {
+        alignment::add_to_pileup(source, &mut pileups, &filter, &mut discarded)?;
+    }
+
+    let seqs = SeqCollector::new();
+    let changes = ChangeCollector::new();
+    let changes_sink = Box::new(LeftNormaliser::new(Box::new(changes.clone())));
+    let mut outputs = OutputFiles { debug: None, depth: None, uncovered: None, multi_mapped: None,
+                                    seqs: vec![Box::new(seqs.clone())], html: None, plot: None,
+                                    changes: Some(changes_sink), mixtures: None, gvcf: None,
+                                    fastq: false, annotate_headers: false,
+                                    run: filter.run.clone() };
+    let summaries = polish::polish_sequences(&mut outputs, &settings.thresholds, None, false,
+                                             &seq_names, &pileups, &ExtraInputs::default());
+    outputs.finish(&summaries);
+    Ok(PolishResult { seqs: seqs.take(), changes: changes.take(), summaries,
+                      bad_records: filter.run.bad_record_count(),
+                      warnings: filter.run.warnings() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::ErrorType;
+
+    #[test]
+    fn test_polish_bytes() {
+        let seq = "ACGATCGTAGCTAGCTAGGCTAGCATCGATCGACTAGCTAGCATCGACTGACTAGCTACG";
+        let assembly = format!(">chrom description\n{}\n", seq);
+        let read_seq = format!("{}T{}", &seq[..30], &seq[31..]);
+        let mut sam = format!("@SQ\tSN:chrom\tLN:{}\n", seq.len());
+        for i in 0..6 {
+            sam.push_str(&format!("r{}\t0\tchrom\t1\t60\t{}M\t*\t0\t0\t{}\t*\tNM:i:1\n",
+                                  i, seq.len(), read_seq));
+        }
+        let result = polish_bytes(assembly.as_bytes(), &[sam.as_bytes()], &Settings::default())
+            .unwrap();
+        assert_eq!(result.seqs.len(), 1);
+        assert_eq!(result.seqs[0].name, "chrom");
+        assert_eq!(result.seqs[0].seq, read_seq);
+        assert_eq!(result.changes.len(), 1);
+        assert_eq!(result.changes[0].pos, 30);
+        assert_eq!(result.changes[0].new_seq, "T");
+        assert_eq!(result.summaries[0].changed_count, 1);
+    }
+
+    #[test]
+    fn test_polish_bytes_bad_records() {
+        // Each call is a separate run, so the bad record is counted once in each result.
+        let seq = "ACGATCGTAGCTAGCTAGGCTAGCATCGATCGACTAGCTAGCATCGACTGACTAGCTACG";
+        let assembly = format!(">chrom\n{}\n", seq);
+        let sam = format!("r1\t0\tchrom\t1\t60\t{}M\t*\t0\t0\t{}\t*\tNM:i:0\nbad\n",
+                          seq.len(), seq);
+        let mut settings = Settings::default();
+        settings.filter.run = RunContext::new(true, None, false, false);
+        for _ in 0..2 {
+            let result = polish_bytes(assembly.as_bytes(), &[sam.as_bytes()], &settings).unwrap();
+            assert_eq!(result.bad_records, 1);
+            assert_eq!(result.seqs[0].seq, seq);
+        }
+        assert_eq!(settings.filter.run.bad_record_count(), 0);
+    }
+
+    #[test]
+    fn test_polish_bytes_errors() {
+        // Problems with the inputs are returned, not quit with.
+        let seq = "ACGATCGTAGCTAGCTAGGCTAGCATCGATCGACTAGCTAGCATCGACTGACTAGCTACG";
+        let assembly = format!(">chrom\n{}\n", seq);
+        let sam = format!("r1\t0\tchrom\t1\t60\t{}M\t*\t0\t0\t{}\t*\tNM:i:0\n", seq.len(), seq);
+        let polish = |assembly: &str, sam: &str| {
+            polish_bytes(assembly.as_bytes(), &[sam.as_bytes()], &Settings::default())
+                .unwrap_err()
+        };
+        let error = polish(&assembly, &format!("{}bad\n", sam));
+        assert_eq!(error.error_type, ErrorType::Input);
+        assert_eq!(error.message, "too few columns in \"alignments 1\" (line 2)");
+        assert_eq!(polish(&assembly, "").error_type, ErrorType::NoAlignments);
+        assert_eq!(polish(">other\nACGT\n", &sam).message,
+                   "query name chrom in SAM but not in assembly");
+        assert_eq!(polish("", &sam).message, "\"assembly\" contains no sequences");
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The polishing itself is done by this library, with main.rs providing the command-line interface.
+// Library users can also build pileups from their own alignments (see source.rs), or polish
+// in memory without any files (see in_memory.rs), which also works when compiled to wasm32.
+
+#![recursion_limit = "256"]  // for the large json! objects in run events
+
+pub mod aligner;
+pub mod alignment;
+#[cfg(feature = "arrow")]
+pub mod arrow_debug;
+pub mod bad_records;
+pub mod batched;
+pub mod bam;
+pub mod bed;
+pub mod bwa;
+pub mod changes;
+pub mod checkpoint;
+pub mod complexity;
+pub mod decompress;
+pub mod downsample;
+pub mod evaluate;
+pub mod external_sort;
+pub mod filter;
+pub mod filter_polish;
+pub mod gaps;
+pub mod gfa;
+pub mod gvcf;
+pub mod in_memory;
+pub mod log;
+pub mod long_reads;
+pub mod man;
+pub mod memory;
+pub mod misc;
+pub mod mixture;
+pub mod mmap;
+pub mod nm_check;
+pub mod normalise;
+pub mod options;
+pub mod output;
+pub mod paf;
+pub mod pileup;
+pub mod placement;
+pub mod polish;
+pub mod progress;
+pub mod report;
+pub mod residual;
+pub mod rotate;
+pub mod run;
+pub mod sink;
+pub mod source;
+pub mod split;
+pub mod stats;
+pub mod subsample;
+pub mod variants;
+pub mod warnings;
+pub mod windowed;
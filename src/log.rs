@@ -11,26 +11,213 @@
 
 use chrono::prelude::*;
 use colored::Colorize;
+use serde_json::{Map, Value};
 
+use std::io::IsTerminal;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::misc::Instant;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,  // human-readable text on stderr
+    Json,  // one JSON object per line on stderr
+}
+
+
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+static CURRENT_STAGE: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+static EVENT_CALLBACK: RwLock<Option<EventCallback>> = RwLock::new(None);
+static HAS_CALLBACK: AtomicBool = AtomicBool::new(false);
+
+
+/// A function which is given each event, as the same JSON object which --log-format json writes.
+pub type EventCallback = Box<dyn Fn(&Value) + Send + Sync>;
+
+
+/// Prints human-readable log text to stderr (same arguments as eprintln). In JSON log mode this
+/// prints nothing, as the same information is reported via log::event instead.
+macro_rules! text {
+    ($($arg:tt)*) => {
+        if !$crate::log::is_json() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use text;
+
+
+pub fn init_format(log_format: LogFormat) {
+    JSON_MODE.store(log_format == LogFormat::Json, Ordering::Relaxed);
+}
+
+
+pub fn is_json() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+
+/// Gives every event to a callback (with either log format), so programs using Polypolish as a
+/// library can show progress and get results as they're made, instead of reading stderr. The
+/// callback also gets events which are too frequent for the log: change_applied for each change
+/// and progress every second. None removes the callback.
+pub fn set_event_callback(callback: Option<EventCallback>) {
+    HAS_CALLBACK.store(callback.is_some(), Ordering::Relaxed);
+    *EVENT_CALLBACK.write().unwrap() = callback;
+}
+
+
+pub fn has_event_callback() -> bool {
+    HAS_CALLBACK.load(Ordering::Relaxed)
+}
+
+
+/// Decides once (at startup) whether log output should be coloured. Colour is used for
+/// interactive terminals, but not when stderr is redirected (e.g. to a SLURM log file), when
+/// --no-color is used or when the NO_COLOR environment variable is set (https://no-color.org).
+pub fn init_colour(no_color: bool) {
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let colour = use_colour(no_color, no_color_env, std::io::stderr().is_terminal());
+    colored::control::set_override(colour);
+}
+
+
+fn use_colour(no_color_flag: bool, no_color_env: bool, stderr_is_terminal: bool) -> bool {
+    !no_color_flag && !no_color_env && stderr_is_terminal
+}
+
+
+/// Begins a new stage of the run. In text mode this prints a header, and in JSON mode it emits a
+/// stage_end event for the previous stage (if any) and a stage_start event for the new one.
 pub fn section_header(text: &str) {
-    colored::control::set_override(true);
+    if is_json() || has_event_callback() {
+        end_stage();
+        event("stage_start", serde_json::json!({"stage": text}));
+        *CURRENT_STAGE.lock().unwrap() = Some((text.to_string(), Instant::now()));
+    }
+    if is_json() {
+        return;
+    }
     let now = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
     let date = format!("({})", now);
     eprintln!();
     eprintln!("{} {}", text.bold().bright_yellow().underline(), date.dimmed());
-    colored::control::unset_override();
+}
+
+
+/// Emits a stage_end event for the current stage (JSON mode or with a callback). This happens
+/// automatically when a new section starts, so it only needs to be called explicitly at the end of
+/// a run.
+pub fn end_stage() {
+    let stage = CURRENT_STAGE.lock().unwrap().take();
+    if let Some((name, start_time)) = stage {
+        event("stage_end", serde_json::json!({"stage": name,
+                                              "seconds": start_time.elapsed().as_secs_f64()}));
+    }
+}
+
+
+/// Writes one JSON log event to stderr (JSON mode only) and gives it to the callback (if any). The
+/// fields (which should be a JSON object) are added after the event name and timestamp.
+pub fn event(name: &str, fields: Value) {
+    if !is_json() && !has_event_callback() {
+        return;
+    }
+    let event = build_event(name, &Local::now().to_rfc3339(), fields);
+    if is_json() {
+        eprintln!("{}", event);
+    }
+    run_callback(&event);
+}
+
+
+/// Gives an event only to the callback, for events which would be too frequent for the log. The
+/// fields are only made if there is a callback.
+pub fn callback_event(name: &str, fields: impl FnOnce() -> Value) {
+    if has_event_callback() {
+        run_callback(&build_event(name, &Local::now().to_rfc3339(), fields()));
+    }
+}
+
+
+fn run_callback(event: &Value) {
+    if let Some(callback) = EVENT_CALLBACK.read().unwrap().as_ref() {
+        callback(event);
+    }
+}
+
+
+fn build_event(name: &str, time: &str, fields: Value) -> Value {
+    let mut obj = Map::new();
+    obj.insert("event".to_string(), Value::from(name));
+    obj.insert("time".to_string(), Value::from(time));
+    if let Value::Object(fields) = fields {
+        obj.extend(fields);
+    }
+    Value::Object(obj)
 }
 
 
 pub fn explanation(text: &str) {
-    colored::control::set_override(true);
-    let mut term_width = 80;
-    if let Some((w, _)) = term_size::dimensions_stderr() {
-        term_width = w;
+    if is_json() {
+        return;
     }
+    let term_width = terminal_width().unwrap_or(80);
     let indented_text = format!("    {}", text);
     eprintln!("{}", textwrap::fill(&indented_text, term_width).dimmed());
     eprintln!();
-    colored::control::unset_override();
+}
+
+
+#[cfg(not(target_arch = "wasm32"))]
+fn terminal_width() -> Option<usize> {
+    term_size::dimensions_stderr().map(|(w, _)| w)
+}
+
+
+#[cfg(target_arch = "wasm32")]
+fn terminal_width() -> Option<usize> {
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_use_colour() {
+        assert!(use_colour(false, false, true));
+        assert!(!use_colour(true, false, true));
+        assert!(!use_colour(false, true, true));
+        assert!(!use_colour(false, false, false));
+        assert!(!use_colour(true, true, false));
+    }
+
+    #[test]
+    fn test_event_callback() {
+        static EVENTS: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+        set_event_callback(Some(Box::new(|e| EVENTS.lock().unwrap().push(e.clone()))));
+        event("callback_test", serde_json::json!({"count": 1}));
+        callback_event("callback_test", || serde_json::json!({"count": 2}));
+        set_event_callback(None);
+        event("callback_test", serde_json::json!({"count": 3}));
+        callback_event("callback_test", || panic!("fields made without a callback"));
+        let counts: Vec<Value> = EVENTS.lock().unwrap().iter()
+            .filter(|e| e["event"] == "callback_test").map(|e| e["count"].clone()).collect();
+        assert_eq!(counts, vec![Value::from(1), Value::from(2)]);
+    }
+
+    #[test]
+    fn test_build_event() {
+        let e = build_event("sam_loaded", "2024-01-01T00:00:00+00:00",
+                            serde_json::json!({"file": "a.sam", "alignments": 12}));
+        assert_eq!(e.to_string(), "{\"event\":\"sam_loaded\",\
+                                   \"time\":\"2024-01-01T00:00:00+00:00\",\
+                                   \"file\":\"a.sam\",\"alignments\":12}");
+        let e = build_event("stage_start", "t", serde_json::json!({}));
+        assert_eq!(e.to_string(), "{\"event\":\"stage_start\",\"time\":\"t\"}");
+    }
 }
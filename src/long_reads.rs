@@ -0,0 +1,96 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Long reads can resolve positions where the short reads are ambiguous, e.g. at the boundaries of
+// repeats which are longer than the short reads' inserts. Their alignments go into a separate
+// pileup which is only consulted to break ties (see PileupBase::get_polished_seq), so long-read
+// errors can't cause a change which the short reads don't support.
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::io;
+use std::path::Path;
+
+use crate::alignment::{Alignment, SamLines};
+use crate::log;
+use crate::misc::{quit_with_error, ErrorType, FastHashMap};
+use crate::pileup::Pileup;
+use crate::progress::{Progress, Unit};
+use crate::run::RunContext;
+
+
+/// Loads the long-read alignments into pileups for the same sequences as the short-read pileups.
+/// Long-read alignments are rarely end-to-end, so their clipped parts are ignored. Secondary
+/// alignments are skipped, as are alignments with operations other than M, I and D (e.g. N).
+pub fn load_long_reads(filename: &Path, pileups: &FastHashMap<String, Pileup>,
+                       run: &RunContext) -> FastHashMap<String, Pileup> {
+    log::section_header("Loading long-read alignments");
+    log::explanation("Long-read alignments are only used to break ties, at positions where the \
+                      short reads support more than one sequence.");
+    let mut long_read_pileups: FastHashMap<String, Pileup> = pileups.iter()
+        .map(|(name, p)| (name.clone(), Pileup::new(&p.original_seq(), u32::MAX, 0, None, false)))
+        .collect();
+    let used_count = match add_long_reads(filename, run, &mut long_read_pileups) {
+        Ok(count) => count,
+        Err(_)    => quit_with_error(ErrorType::Io,
+                                     &format!("unable to load alignments from {:?}", filename)),
+    };
+    let total_length: usize = long_read_pileups.values().map(|p| p.bases.len()).sum();
+    let total_depth: f64 = long_read_pileups.values().flat_map(|p| &p.bases)
+        .map(|b| b.depth).sum();
+    let mean_depth = if total_length == 0 { 0.0 } else { total_depth / total_length as f64 };
+    log::text!("{}: {} alignments used, mean depth {:.1}x", filename.display(),
+               used_count.to_formatted_string(&Locale::en), mean_depth);
+    log::text!();
+    log::event("long_reads_loaded", json!({"file": filename, "alignments": used_count,
+                                           "mean_depth": mean_depth}));
+    long_read_pileups
+}
+
+
+fn add_long_reads(filename: &Path, run: &RunContext,
+                  pileups: &mut FastHashMap<String, Pileup>) -> io::Result<usize> {
+    let mut sam_lines = SamLines::open(filename, run)?;
+    let mut progress = Progress::new(&filename.display().to_string(), Unit::Bytes,
+                                     sam_lines.file_size, "alignments");
+    let mut line_count: usize = 0;
+    let mut used_count: usize = 0;
+    while let Some(line) = sam_lines.next() {
+        line_count += 1;
+        let sam_line = line?;
+        progress.update(sam_lines.bytes_read(), used_count as u64);
+        if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+        let mut alignment = match Alignment::new(&sam_line, &[]) {
+            Ok(alignment) => alignment,
+            Err(e)        => { run.bad_record(e, filename, line_count, &sam_line); continue; },
+        };
+        if !alignment.is_aligned() || alignment.is_secondary() || alignment.read_seq == "*" {
+            continue;
+        }
+        alignment.strip_clips();
+        if !alignment.has_simple_cigar() {
+            continue;
+        }
+        match pileups.get_mut(&alignment.ref_name) {
+            Some(pileup) => pileup.add_alignment(&alignment, 1.0),
+            None => quit_with_error(ErrorType::Input,
+                                    &format!("query name {} in long-read alignments but not in \
+                                              assembly", alignment.ref_name)),
+        }
+        used_count += 1;
+    }
+    progress.finish();
+    if used_count == 0 {
+        quit_with_error(ErrorType::NoAlignments, &format!("no alignments in {:?}", filename))
+    }
+    Ok(used_count)
+}
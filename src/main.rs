@@ -9,15 +9,12 @@
 // Public License for more details. You should have received a copy of the GNU General Public
 // License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
 
-mod alignment;
-mod filter;
-mod log;
-mod misc;
-mod pileup;
-mod polish;
-
 use std::path::PathBuf;
-use clap::{Parser, Subcommand, crate_version};
+use clap::{CommandFactory, Parser, Subcommand, crate_version};
+
+use polypolish::{evaluate, filter, filter_polish, log, man, polish, rotate, split, subsample,
+                 variants};
+use polypolish::options::{FilterOptions, FilterPolishOptions, PolishOptions, VariantsOptions};
 
 
 #[derive(Parser)]
@@ -31,7 +28,10 @@ use clap::{Parser, Subcommand, crate_version};
                              r#" | |   | (_) || || |_| || |_) || (_) || || |\__ \| | | |"#, "\n",
                              r#" |_|    \___/ |_| \__, || .__/  \___/ |_||_||___/|_| |_|"#, "\n",
                              r#"                   __/ || |                             "#, "\n",
-                             r#"                  |___/ |_|                             "#))]
+                             r#"                  |___/ |_|                             "#),
+       after_help = "Exit codes: 2 = invalid options, 3 = invalid input file, 4 = no alignments, \
+                     5 = unable to read or write a file, 6 = not enough memory, 7 = read depth \
+                     below --require-mean-depth")]
 #[command(author, version, about, long_about = None, disable_help_subcommand = true,
           propagate_version = true)]
 #[clap(subcommand_required = true)]
@@ -39,87 +39,128 @@ use clap::{Parser, Subcommand, crate_version};
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Disable coloured output (also disabled by NO_COLOR or when stderr is not a terminal)
+    #[clap(long = "no-color", global = true)]
+    no_color: bool,
+
+    /// Format for log messages on stderr: human-readable text or one JSON object per line
+    #[clap(long = "log-format", value_enum, default_value = "text", global = true)]
+    log_format: log::LogFormat,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]  // only one is made, when parsing the command line
 enum Commands {
     /// filter paired-end alignments based on insert size
-    Filter {
-        /// Input SAM file - first read in pairs
-        #[clap(long = "in1")]
-        in1: PathBuf,
-
-        /// Input SAM file - first second in pairs
-        #[clap(long = "in2")]
-        in2: PathBuf,
-    
-        /// Output SAM file - first read in pairs
-        #[clap(long = "out1")]
-        out1: PathBuf,
-
-        /// Output SAM file - first second in pairs
-        #[clap(long = "out2")]
-        out2: PathBuf,
-
-        /// Expected pair orientation
-        #[clap(long = "orientation", default_value = "auto")]
-        orientation: String,
-
-        /// Low percentile threshold
-        #[clap(long = "low", default_value = "0.1")]
-        low: f64,
-
-        /// High percentile threshold
-        #[clap(long = "high", default_value = "99.9")]
-        high: f64,
-    },
+    Filter(FilterOptions),
 
     /// polish a long-read assembly using short-read alignments
-    Polish {
-        /// Optional file to store per-base information for debugging purposes
-        #[clap(long = "debug")]
-        debug: Option<PathBuf>,
+    Polish(PolishOptions),
+
+    /// filter paired-end alignments and polish with them, reading the alignments only once
+    FilterPolish(FilterPolishOptions),
+
+    /// report non-reference alleles in the short-read pileup (VCF-like output)
+    Variants(VariantsOptions),
+
+    /// downsample an alignment file to a target depth, keeping each read's alignments together
+    Subsample {
+        /// Target read depth
+        #[arg(long = "depth")]
+        depth: f64,
+
+        /// Seed for the random choice of reads (use the same seed for both files of a pair)
+        #[arg(long = "seed", default_value = "0")]
+        seed: u64,
+
+        /// Assembly the reads were aligned to, for its length [default: from the @SQ header
+        /// lines]
+        #[arg(long = "assembly")]
+        assembly: Option<PathBuf>,
+
+        /// Input alignments (SAM, BAM or PAF format)
+        input: PathBuf,
+
+        /// Output alignments (SAM format)
+        output: PathBuf,
+    },
 
-        /// A base must make up less than this fraction of the read depth to be considered invalid
-        #[clap(short = 'i', long = "fraction_invalid", default_value = "0.2")]
-        fraction_invalid: f64,
+    /// split an alignment file into one file per reference sequence, for polishing each sequence
+    /// separately
+    Split {
+        /// Directory for the output alignment files (one SAM file per sequence)
+        #[arg(long = "outdir")]
+        outdir: PathBuf,
 
-        /// A base must make up at least this fraction of the read depth to be considered valid
-        #[clap(short = 'v', long = "fraction_valid", default_value = "0.5")]
-        fraction_valid: f64,
+        /// Input alignments (SAM, BAM or PAF format, grouped by read)
+        input: PathBuf,
+    },
 
-        /// Ignore alignments with more than this many mismatches and indels
-        #[clap(short = 'm', long = "max_errors", default_value = "10")]
-        max_errors: u32,
+    /// rotate circular sequences, so reads can be aligned across their start/end junctions
+    Rotate {
+        /// Number of bases to rotate each circular sequence by, or mid to move its start/end
+        /// junction to the middle
+        #[arg(long = "by", default_value = "mid")]
+        by: String,
 
-        /// A base must occur at least this many times in the pileup to be considered valid
-        #[clap(short = 'd', long = "min_depth", default_value = "5")]
-        min_depth: u32,
+        /// Assembly to rotate (FASTA format, circular sequences have circular=true in their
+        /// header)
+        assembly: PathBuf,
+    },
 
-        /// Ignore any reads with multiple alignments
-        #[arg(long = "careful")]
-        careful: bool,
+    /// compare an assembly to a trusted reference and count its remaining errors
+    Evaluate {
+        /// Trusted reference sequences (FASTA format)
+        #[arg(long = "truth")]
+        truth: PathBuf,
 
-        /// Assembly to polish (one file in FASTA format)
+        /// Assembly to evaluate (FASTA format)
         assembly: PathBuf,
+    },
 
-        /// Short read alignments (one or more files in SAM format)
-        sam: Vec<PathBuf>,
+    /// write man pages for Polypolish and its subcommands
+    #[command(hide = true)]
+    Man {
+        /// Write one man page file per command to this directory [default: all pages to stdout]
+        #[arg(long = "out-dir")]
+        out_dir: Option<PathBuf>,
     },
 }
 
 
 fn main() {
     let cli = Cli::parse();
+    log::init_colour(cli.no_color);
+    log::init_format(cli.log_format);
 
     match cli.command {
-        Some(Commands::Filter { in1, in2, out1, out2, orientation, low, high }) => {
-            filter::filter(in1, in2, out1, out2, orientation, low, high);
+        Some(Commands::Filter(options)) => {
+            filter::filter(&options);
+        },
+        Some(Commands::Polish(options)) => {
+            polish::polish(&options);
+        },
+        Some(Commands::FilterPolish(options)) => {
+            filter_polish::filter_polish(&options);
+        },
+        Some(Commands::Variants(options)) => {
+            variants::variants(&options);
+        },
+        Some(Commands::Subsample { depth, seed, assembly, input, output }) => {
+            subsample::subsample(depth, seed, assembly, input, output);
+        },
+        Some(Commands::Split { outdir, input }) => {
+            split::split(input, outdir);
+        },
+        Some(Commands::Rotate { by, assembly }) => {
+            rotate::rotate(by, assembly);
+        },
+        Some(Commands::Evaluate { truth, assembly }) => {
+            evaluate::evaluate(truth, assembly);
         },
-        Some(Commands::Polish { debug, fraction_invalid, fraction_valid, max_errors, min_depth,
-                                careful, assembly, sam}) => {
-            polish::polish(debug, fraction_invalid, fraction_valid, max_errors, min_depth,
-                           careful, assembly, sam);
+        Some(Commands::Man { out_dir }) => {
+            man::man(Cli::command(), out_dir);
         },
         None => {}
     }
@@ -0,0 +1,80 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Man pages are made from the same clap definitions as the --help text, so they always match the
+// installed version and can be installed on systems without internet access.
+
+use clap::Command;
+use clap_mangen::Man;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::log;
+use crate::misc::{quit_with_error, ErrorType};
+
+
+/// Writes a man page for Polypolish and one for each of its subcommands, either as files in the
+/// given directory (polypolish.1, polypolish-polish.1, etc.) or all together to stdout.
+pub fn man(command: Command, out_dir: Option<PathBuf>) {
+    let pages = man_pages(command);
+    let result = match &out_dir {
+        Some(dir) => write_pages(&pages, dir),
+        None      => pages.iter().try_for_each(|page| page.render(&mut io::stdout().lock())),
+    };
+    if let Err(e) = result {
+        quit_with_error(ErrorType::Io, &format!("unable to write man pages: {}", e));
+    }
+}
+
+
+fn man_pages(command: Command) -> Vec<Man> {
+    let mut command = command.name("polypolish").bin_name("polypolish")
+        .disable_help_subcommand(true);
+    command.build();
+    let mut pages = vec![Man::new(command.clone())];
+    pages.extend(command.get_subcommands().filter(|s| !s.is_hide_set())
+        .map(|s| Man::new(s.clone())));
+    pages
+}
+
+
+fn write_pages(pages: &[Man], dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for page in pages {
+        let filename = page.generate_to(dir)?;
+        log::text!("{}", filename.display());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_man_pages() {
+        let command = Command::new("Polypolish")
+            .subcommand(Command::new("polish").about("polish an assembly"))
+            .subcommand(Command::new("hidden").hide(true));
+        let pages = man_pages(command);
+        let filenames: Vec<String> = pages.iter().map(|p| p.get_filename()).collect();
+        assert_eq!(filenames, vec!["polypolish.1", "polypolish-polish.1"]);
+
+        let mut roff = Vec::new();
+        pages[1].render(&mut roff).unwrap();
+        let roff = String::from_utf8(roff).unwrap();
+        assert!(roff.starts_with(".ie \\n(.g .ds Aq"));
+        assert!(roff.contains("polish an assembly"));
+    }
+}
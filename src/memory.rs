@@ -0,0 +1,125 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+
+
+/// Returns the amount of memory (in bytes) available to this process, or None if it can't be
+/// determined (e.g. on non-Linux systems). This is the smaller of the system's available memory
+/// and any cgroup limit, since cluster schedulers like SLURM often enforce limits with cgroups.
+pub fn available_memory() -> Option<u64> {
+    let system = fs::read_to_string("/proc/meminfo").ok().and_then(|t| parse_meminfo(&t));
+    let cgroup = ["/sys/fs/cgroup/memory.max",                      // cgroups v2
+                  "/sys/fs/cgroup/memory/memory.limit_in_bytes"]    // cgroups v1
+        .iter().filter_map(|f| fs::read_to_string(f).ok())
+        .filter_map(|t| parse_cgroup_limit(&t)).min();
+    match (system, cgroup) {
+        (Some(s), Some(c)) => Some(s.min(c)),
+        (s, c)             => s.or(c),
+    }
+}
+
+
+/// Returns the peak resident memory (in bytes) used by this process so far, or None if it can't
+/// be determined.
+pub fn peak_memory() -> Option<u64> {
+    if let Some(peak) = fs::read_to_string("/proc/self/status").ok()
+                            .and_then(|t| parse_proc_status(&t)) {
+        return Some(peak);
+    }
+    peak_memory_from_rusage()
+}
+
+
+#[cfg(unix)]
+fn peak_memory_from_rusage() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let max_rss = u64::try_from(usage.ru_maxrss).ok()?;
+    if cfg!(target_os = "macos") {
+        Some(max_rss)  // macOS reports bytes
+    } else {
+        Some(max_rss * 1024)  // other systems report kilobytes
+    }
+}
+
+
+#[cfg(not(unix))]
+fn peak_memory_from_rusage() -> Option<u64> {
+    None
+}
+
+
+fn parse_proc_status(status: &str) -> Option<u64> {
+    parse_kb_field(status, "VmHWM:")
+}
+
+
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    parse_kb_field(meminfo, "MemAvailable:")
+}
+
+
+/// Finds a "name: value kB" line in a /proc file and returns the value in bytes.
+fn parse_kb_field(text: &str, field_name: &str) -> Option<u64> {
+    for line in text.lines() {
+        if let Some(value) = line.strip_prefix(field_name) {
+            let kb = value.trim().trim_end_matches("kB").trim().parse::<u64>().ok()?;
+            return Some(kb * 1024);
+        }
+    }
+    None
+}
+
+
+fn parse_cgroup_limit(text: &str) -> Option<u64> {
+    let limit = text.trim().parse::<u64>().ok()?;  // "max" (no limit) fails to parse
+    if limit >= i64::MAX as u64 / 2 {  // cgroups v1 uses a huge number for no limit
+        return None;
+    }
+    Some(limit)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16318664 kB\n\
+                       MemFree:         1234567 kB\n\
+                       MemAvailable:    8000000 kB\n\
+                       Buffers:          123456 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some(8000000 * 1024));
+        assert_eq!(parse_meminfo("MemTotal:       16318664 kB\n"), None);
+    }
+
+    #[test]
+    fn test_parse_proc_status() {
+        let status = "Name:\tpolypolish\n\
+                      VmPeak:\t  123456 kB\n\
+                      VmHWM:\t    54321 kB\n\
+                      VmRSS:\t    12345 kB\n";
+        assert_eq!(parse_proc_status(status), Some(54321 * 1024));
+        assert_eq!(parse_proc_status("Name:\tpolypolish\n"), None);
+        assert!(peak_memory().is_some());
+    }
+
+    #[test]
+    fn test_parse_cgroup_limit() {
+        assert_eq!(parse_cgroup_limit("17179869184\n"), Some(17179869184));
+        assert_eq!(parse_cgroup_limit("max\n"), None);
+        assert_eq!(parse_cgroup_limit("9223372036854771712\n"), None);
+    }
+}
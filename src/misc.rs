@@ -9,60 +9,169 @@
 // Public License for more details. You should have received a copy of the GNU General Public
 // License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
 
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+
+use crate::log;
 
 use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::{prelude::*, BufReader};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+
+
+/// A HashMap using the Fx hash function, which is much faster than the default SipHash. SipHash
+/// protects against hash-flooding attacks, but our keys (read and contig names, sequences) come
+/// from the user's own files, so that isn't a concern for Polypolish's hot maps.
+pub type FastHashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+pub type FastHashSet<K> = rustc_hash::FxHashSet<K>;
+
 
+/// std's Instant panics on wasm32 (which has no clock of its own), so there it comes from
+/// web-time, which uses the browser's clock.
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+pub use web_time::Instant;
 
-pub fn check_if_file_exists(filename: &PathBuf) {
+
+pub fn check_if_file_exists(filename: &Path) {
     if !Path::new(filename).exists() {
         let error_message = format!("{:?} file does not exist", filename);
-        quit_with_error(&error_message);
+        quit_with_error(ErrorType::Io, &error_message);
     }
 }
 
 
-pub fn quit_with_error(text: &str) {
-    eprintln!();
-    eprintln!("Error: {}", text);
-    std::process::exit(1);
+/// The type of a fatal error, which sets Polypolish's exit code. This lets pipelines tell errors
+/// which might not happen again (e.g. running out of memory or disk space) from those which will
+/// keep happening until the inputs are fixed. Clap also exits with 2 for bad arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorType {
+    Args = 2,          // invalid or conflicting options
+    Input = 3,         // an input file can't be parsed or doesn't fit with the other inputs
+    NoAlignments = 4,  // no alignments to work with
+    Io = 5,            // a file can't be found, read or written
+    Memory = 6,        // not enough memory
+    LowDepth = 7,      // read depth below --require-mean-depth
 }
 
 
-/// This function loads a FASTA file and runs a few checks on the result. If everything looks good,
-/// it returns a vector of name+sequence tuples.
-pub fn load_fasta(filename: &PathBuf) -> Vec<(String, String, String)> {
-    let load_result = if is_file_gzipped(&filename) {
-        load_fasta_gzipped(&filename)
+/// A fatal error, for the library functions which return one instead of quitting (see
+/// in_memory.rs). The command line tool quits with it, as with quit_with_error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub error_type: ErrorType,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(error_type: ErrorType, message: String) -> Error {
+        Error { error_type, message }
+    }
+
+    pub fn quit(&self) -> ! {
+        quit_with_error(self.error_type, &self.message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+
+pub fn quit_with_error(error_type: ErrorType, text: &str) -> ! {
+    if log::is_json() {
+        log::event("error", serde_json::json!({"message": text,
+                                               "exit_code": error_type as i32}));
     } else {
-        load_fasta_not_gzipped(&filename)
+        eprintln!();
+        eprintln!("Error: {}", text);
+    }
+    exit(error_type, text)
+}
+
+
+#[cfg(not(target_arch = "wasm32"))]
+fn exit(error_type: ErrorType, _text: &str) -> ! {
+    std::process::exit(error_type as i32);
+}
+
+
+/// In a browser there's no process to exit, so errors are panics, which the page can catch.
+#[cfg(target_arch = "wasm32")]
+fn exit(error_type: ErrorType, text: &str) -> ! {
+    panic!("{} (error {:?})", text, error_type);
+}
+
+
+/// This function loads a FASTA file (or stdin, if the filename is "-") and runs a few checks on
+/// the result. If everything looks good, it returns a vector of name+sequence tuples.
+pub fn load_fasta(filename: &Path) -> Vec<(String, String, String)> {
+    let fasta_seqs = match open_fasta(filename).and_then(|r| load_fasta_from_reader(r, filename)) {
+        Ok(fasta_seqs) => fasta_seqs,
+        Err(_)         => quit_with_error(ErrorType::Io, &format!("unable to load {:?}", filename)),
     };
-    match load_result {
-        Ok(_)  => (),
-        Err(_) => quit_with_error(&format!("unable to load {:?}", filename)),
+    if let Err(e) = check_load_fasta(&fasta_seqs, filename) {
+        e.quit();
     }
-    let fasta_seqs = load_result.unwrap();
-    check_load_fasta(&fasta_seqs, &filename);
     fasta_seqs
 }
 
 
+/// Loads uncompressed FASTA from a stream, named in error messages by the given name.
+pub fn load_fasta_from_stream(reader: impl BufRead,
+                              name: &str) -> Result<Vec<(String, String, String)>, Error> {
+    let filename = Path::new(name);
+    let fasta_seqs = load_fasta_from_reader(reader, filename)
+        .map_err(|_| Error::new(ErrorType::Input, format!("unable to load {:?}", name)))?;
+    check_load_fasta(&fasta_seqs, filename)?;
+    Ok(fasta_seqs)
+}
+
+
+/// Opens a FASTA file or stdin for reading, decompressing it if gzipped. Gzip is detected by
+/// peeking at the buffered start of the input, so this works for pipes which can't be rewound.
+fn open_fasta(filename: &Path) -> io::Result<Box<dyn BufRead>> {
+    let input: Box<dyn Read> = if is_stdin(filename) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(filename)?)
+    };
+    let mut reader = BufReader::new(input);
+    if reader.fill_buf()?.starts_with(&[31, 139]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+
+/// Returns true if the filename is "-", meaning stdin.
+pub fn is_stdin(filename: &Path) -> bool {
+    filename == Path::new("-")
+}
+
+
 /// This function looks at the result of the load_fasta function and does some checks to make sure
-/// everything looks okay. If any problems are found, it will quit with an error message.
-fn check_load_fasta(fasta_seqs: &Vec<(String, String, String)>, filename: &PathBuf) {
-    if fasta_seqs.len() == 0 {
-        quit_with_error(&format!("{:?} contains no sequences", filename));
+/// everything looks okay. If any problems are found, it returns an error.
+fn check_load_fasta(fasta_seqs: &[(String, String, String)], filename: &Path) -> Result<(), Error> {
+    let error = |problem: &str| Err(Error::new(ErrorType::Input,
+                                               format!("{:?} {}", filename, problem)));
+    if fasta_seqs.is_empty() {
+        return error("contains no sequences");
     }
     for (name, _, sequence) in fasta_seqs {
-        if name.len() == 0 {
-            quit_with_error(&format!("{:?} has an unnamed sequence", filename));
+        if name.is_empty() {
+            return error("has an unnamed sequence");
         }
-        if sequence.len() == 0 {
-            quit_with_error(&format!("{:?} has an empty sequence", filename));
+        if sequence.is_empty() {
+            return error("has an empty sequence");
         }
     }
     let mut set = HashSet::new();
@@ -70,19 +179,20 @@ fn check_load_fasta(fasta_seqs: &Vec<(String, String, String)>, filename: &PathB
         set.insert(name);
     }
     if set.len() < fasta_seqs.len() {
-        quit_with_error(&format!("{:?} has a duplicated name", filename));
+        return error("has a duplicated name");
     }
+    Ok(())
 }
 
 
 /// This function returns true if the file appears to be gzipped (based on the first two bytes) and
 /// false if not. If it can't open the file or read the first two bytes, it will quit with an error
 /// message.
-fn is_file_gzipped(filename: &PathBuf) -> bool {
-    let open_result = File::open(&filename);
+pub fn is_file_gzipped(filename: &Path) -> bool {
+    let open_result = File::open(filename);
     match open_result {
         Ok(_)  => (),
-        Err(_) => quit_with_error(&format!("unable to open {:?}", filename)),
+        Err(_) => quit_with_error(ErrorType::Io, &format!("unable to open {:?}", filename)),
     }
     let file = open_result.unwrap();
 
@@ -92,74 +202,53 @@ fn is_file_gzipped(filename: &PathBuf) -> bool {
     let read_result = reader.read_exact(&mut buf);
     match read_result {
         Ok(_)  => (),
-        Err(_) => quit_with_error(&format!("{:?} is too small", filename)),
+        Err(_) => quit_with_error(ErrorType::Input, &format!("{:?} is too small", filename)),
     }
 
     buf[0] == 31 && buf[1] == 139
 }
 
 
-fn load_fasta_not_gzipped(filename: &PathBuf) -> io::Result<Vec<(String, String, String)>> {
-    let mut fasta_seqs = Vec::new();
-    let file = File::open(&filename)?;
-    let reader = BufReader::new(file);
-    let mut name = String::new();
-    let mut description = String::new();
-    let mut sequence = String::new();
-    for line in reader.lines() {
-        let text = line?;
-        if text.len() == 0 {continue;}
-        if text.starts_with('>') {
-            if name.len() > 0 {
-                sequence.make_ascii_uppercase();
-                fasta_seqs.push((name, description, sequence));
-                sequence = String::new();
-            }
-            let mut split = text[1..].splitn(2, char::is_whitespace);
-            name = split.next().unwrap_or_default().to_string();
-            description = split.next().unwrap_or_default().to_string();
-        } else {
-            if name.len() == 0 {
-                quit_with_error(&format!("{:?} is not correctly formatted", filename));
-            }
-            sequence.push_str(&text);
-        }
-    }
-    if name.len() > 0 {
-        sequence.make_ascii_uppercase();
-        fasta_seqs.push((name, description, sequence));
-    }
-    Ok(fasta_seqs)
+/// Returns true if the file (gzipped or not) starts with a FASTA header.
+pub fn is_fasta(filename: &Path) -> bool {
+    let Ok(file) = File::open(filename) else { return false; };
+    let mut first_byte = [0u8; 1];
+    let read_result = if is_file_gzipped(filename) {
+        GzDecoder::new(file).read_exact(&mut first_byte)
+    } else {
+        BufReader::new(file).read_exact(&mut first_byte)
+    };
+    read_result.is_ok() && first_byte[0] == b'>'
 }
 
 
-fn load_fasta_gzipped(filename: &PathBuf) -> io::Result<Vec<(String, String, String)>> {
+fn load_fasta_from_reader(reader: impl BufRead,
+                         filename: &Path) -> io::Result<Vec<(String, String, String)>> {
     let mut fasta_seqs = Vec::new();
-    let file = File::open(&filename)?;
-    let reader = BufReader::new(GzDecoder::new(file));
     let mut name = String::new();
     let mut description = String::new();
     let mut sequence = String::new();
     for line in reader.lines() {
         let text = line?;
-        if text.len() == 0 {continue;}
-        if text.starts_with('>') {
-            if name.len() > 0 {
+        if text.is_empty() {continue;}
+        if let Some(header) = text.strip_prefix('>') {
+            if !name.is_empty() {
                 sequence.make_ascii_uppercase();
                 fasta_seqs.push((name, description, sequence));
                 sequence = String::new();
             }
-            let mut split = text[1..].splitn(2, char::is_whitespace);
+            let mut split = header.splitn(2, char::is_whitespace);
             name = split.next().unwrap_or_default().to_string();
             description = split.next().unwrap_or_default().to_string();
         } else {
-            if name.len() == 0 {
-                quit_with_error(&format!("{:?} is not correctly formatted", filename));
+            if name.is_empty() {
+                quit_with_error(ErrorType::Input,
+                                &format!("{:?} is not correctly formatted", filename));
             }
             sequence.push_str(&text);
         }
     }
-    if name.len() > 0 {
+    if !name.is_empty() {
         sequence.make_ascii_uppercase();
         fasta_seqs.push((name, description, sequence));
     }
@@ -191,6 +280,14 @@ pub fn reverse_complement(seq: &str) -> String {
 }
 
 
+/// Makes a sequence name safe to use as a filename (e.g. for one file per sequence), replacing
+/// characters other than letters, digits, dots, dashes and underscores with underscores.
+pub fn safe_filename(name: &str) -> String {
+    let filename: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' }).collect();
+    if filename.chars().all(|c| c == '.') { filename.replace('.', "_") } else { filename }
+}
+
 
 pub fn format_duration(duration: std::time::Duration) -> String {
     let microseconds = duration.as_micros() % 1000000;
@@ -215,6 +312,63 @@ pub fn bankers_rounding(float: f64) -> u32 {
 }
 
 
+/// Returns the Phred-scaled probability of at least this many successes from this many trials,
+/// each with a 50% chance of success. The sum is done in log space so it works for large counts.
+pub fn binomial_tail_phred(successes: u32, trials: u32) -> f64 {
+    if successes == 0 {
+        return 0.0;
+    }
+    let (k, n) = (successes as f64, trials as f64);
+    let mut log_choose: f64 = (1..=successes).map(|j| ((n - k + j as f64) / j as f64).ln()).sum();
+    let mut log_terms = Vec::new();
+    for i in successes..=trials {
+        log_terms.push(log_choose);
+        log_choose += ((n - i as f64) / (i as f64 + 1.0)).ln();
+    }
+    let max_term = log_terms.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = max_term + log_terms.iter().map(|t| (t - max_term).exp()).sum::<f64>().ln();
+    let log_tail = log_sum + n * 0.5_f64.ln();
+    (-10.0 * log_tail / std::f64::consts::LN_10).max(0.0)
+}
+
+
+/// Returns the natural log of the gamma function for a positive value, using the Lanczos
+/// approximation (g = 7, accurate to about 15 significant figures).
+pub fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [0.999_999_999_999_809_9, 676.520_368_121_885_1,
+                                    -1_259.139_216_722_402_8, 771.323_428_777_653_1,
+                                    -176.615_029_162_140_6, 12.507_343_278_686_905,
+                                    -0.138_571_095_265_720_12, 9.984_369_578_019_572e-6,
+                                    1.505_632_735_149_311_6e-7];
+    if x < 0.5 {  // reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = COEFFICIENTS[1..].iter().enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| sum + c / (x + i as f64 + 1.0));
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+
+/// Returns the median of the values (the mean of the middle two for an even count), or 0 if there
+/// are none.
+pub fn median(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use flate2::Compression;
@@ -222,6 +376,7 @@ mod tests {
     use std::fs::File;
     use std::io::Write;
     use tempfile::{TempDir,tempdir};
+    use std::path::PathBuf;
     use super::*;
 
     fn make_test_file(contents: &str) -> (PathBuf, TempDir) {
@@ -242,6 +397,14 @@ mod tests {
         (file_path, dir)
     }
 
+    #[test]
+    fn test_exit_codes() {
+        // These are documented in the help text, so they shouldn't change.
+        let codes = [ErrorType::Args, ErrorType::Input, ErrorType::NoAlignments, ErrorType::Io,
+                     ErrorType::Memory, ErrorType::LowDepth].map(|e| e as i32);
+        assert_eq!(codes, [2, 3, 4, 5, 6, 7]);
+    }
+
     #[test]
     fn test_load_fasta_1() {
         let (path, _dir) = make_test_file(">seq_1 123 456\nACGAT\n\
@@ -266,6 +429,24 @@ mod tests {
                                ("seq_3".to_string(), "".to_string(), "CTCGCATCAG".to_string())]);
     }
 
+    #[test]
+    fn test_is_fasta() {
+        let (path, _dir) = make_test_file(">seq_1\nACGAT\n");
+        assert!(is_fasta(&path));
+        let (path, _dir) = make_gzipped_test_file(">seq_1\nACGAT\n");
+        assert!(is_fasta(&path));
+        let (path, _dir) = make_test_file("@HD\tVN:1.6\n");
+        assert!(!is_fasta(&path));
+    }
+
+    #[test]
+    fn test_safe_filename() {
+        assert_eq!(safe_filename("contig_1"), "contig_1");
+        assert_eq!(safe_filename("NZ_CP012345.1"), "NZ_CP012345.1");
+        assert_eq!(safe_filename("a/b|c d"), "a_b_c_d");
+        assert_eq!(safe_filename(".."), "__");
+    }
+
     #[test]
     fn test_format_duration() {
         let d1 = std::time::Duration::from_micros(123456789);
@@ -302,4 +483,30 @@ mod tests {
         assert_eq!(reverse_complement("atatataaattttttttCCCC"), "GGGGaaaaaaaatttatatat");
         assert_eq!(reverse_complement("ACGT123"), "NNNACGT");
     }
+
+    #[test]
+    fn test_binomial_tail_phred() {
+        assert_eq!(binomial_tail_phred(0, 10), 0.0);
+        assert!((binomial_tail_phred(1, 1) - 3.0103).abs() < 0.001);
+        assert!((binomial_tail_phred(10, 10) - 30.103).abs() < 0.001);
+        assert!((binomial_tail_phred(5, 10) - 2.0548).abs() < 0.001);  // 638/1024
+        assert!((binomial_tail_phred(1000, 1000) - 3010.3).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_ln_gamma() {
+        assert!(ln_gamma(1.0).abs() < 1e-12);
+        assert!(ln_gamma(2.0).abs() < 1e-12);
+        assert!((ln_gamma(5.0) - 24.0_f64.ln()).abs() < 1e-12);
+        assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-12);
+        assert!((ln_gamma(101.0) - 363.739_375_555_563_5).abs() < 1e-9);  // ln(100!)
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[]), 0.0);
+        assert_eq!(median(&[3.0]), 3.0);
+        assert_eq!(median(&[5.0, 1.0, 3.0]), 3.0);
+        assert_eq!(median(&[4.0, 1.0, 2.0, 8.0]), 3.0);
+    }
 }
@@ -0,0 +1,200 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// When the reads come from more than one strain (e.g. a metagenome bin), many positions have two
+// well-supported sequences and Polypolish keeps the original base. The mixture report counts these
+// positions and their minor allele fractions for each sequence, so strain mixtures can be spotted
+// instead of being silently left alone.
+
+use serde_json::json;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::log;
+use crate::misc::{quit_with_error, ErrorType};
+use crate::pileup::{BaseStatus, PileupBase};
+
+
+/// Minor allele fractions are counted in bins of this width (from 0 to 0.5).
+const BIN_WIDTH: f64 = 0.1;
+const BIN_COUNT: usize = 5;
+
+/// A sequence is flagged as a likely mixture if it has at least this many mixed positions...
+const MIN_MIXED_SITES: usize = 10;
+
+/// ...and at least this many per kbp.
+const MIN_MIXED_SITES_PER_KBP: f64 = 0.5;
+
+
+pub struct MixtureReport {
+    filename: PathBuf,
+    seqs: Vec<SeqMixture>,
+}
+
+struct SeqMixture {
+    name: String,
+    length: usize,
+    minor_fractions: Vec<f64>,
+}
+
+impl SeqMixture {
+    fn sites_per_kbp(&self) -> f64 {
+        if self.length == 0 { 0.0 } else { 1000.0 * self.minor_fractions.len() as f64
+                                                  / self.length as f64 }
+    }
+
+    fn is_likely_mixture(&self) -> bool {
+        self.minor_fractions.len() >= MIN_MIXED_SITES &&
+            self.sites_per_kbp() >= MIN_MIXED_SITES_PER_KBP
+    }
+}
+
+impl MixtureReport {
+    pub fn new(filename: &Path) -> MixtureReport {
+        MixtureReport { filename: filename.to_path_buf(), seqs: Vec::new() }
+    }
+
+    /// Adds one base. A base is a mixed position if polishing found it ambiguous and its two most
+    /// common sequences both occur at least min_depth times.
+    pub fn add_base(&mut self, name: &str, b: &PileupBase, status: &BaseStatus, min_depth: u32) {
+        if self.seqs.last().map_or(true, |s| s.name != name) {
+            self.seqs.push(SeqMixture { name: name.to_string(), length: 0,
+                                        minor_fractions: Vec::new() });
+        }
+        let seq = self.seqs.last_mut().unwrap();
+        seq.length += 1;
+        if status.is_ambiguous() {
+            if let Some(fraction) = minor_allele_fraction(b, min_depth as f64) {
+                seq.minor_fractions.push(fraction);
+            }
+        }
+    }
+
+    /// Writes the report file and logs the sequences which look like strain mixtures.
+    pub fn finish(self) {
+        log::section_header("Mixed populations");
+        log::explanation(&format!("Positions where two sequences are both well supported are \
+                                   counted for each sequence, along with their minor allele \
+                                   fractions. Sequences with at least {} such positions and at \
+                                   least {} per kbp may contain a mixture of strains.",
+                                  MIN_MIXED_SITES, MIN_MIXED_SITES_PER_KBP));
+        let mut lines = vec!["name\tlength\tmixed_sites\tsites_per_kbp\tmedian_minor_fraction\t\
+                              minor_fraction_histogram\tlikely_mixture".to_string()];
+        let mut all_fractions = Vec::new();
+        let mut flagged_count = 0;
+        for s in &self.seqs {
+            let median = median_fraction(&s.minor_fractions);
+            let histogram = histogram(&s.minor_fractions);
+            lines.push(format!("{}\t{}\t{}\t{:.3}\t{}\t{}\t{}", s.name, s.length,
+                               s.minor_fractions.len(), s.sites_per_kbp(),
+                               median.map_or("-".to_string(), |m| format!("{:.3}", m)),
+                               histogram_str(&histogram),
+                               if s.is_likely_mixture() { "yes" } else { "no" }));
+            if s.is_likely_mixture() {
+                flagged_count += 1;
+                log::text!("{}: {} mixed positions ({:.2} per kbp), median minor allele fraction \
+                            {:.3}", s.name, s.minor_fractions.len(), s.sites_per_kbp(),
+                           median.unwrap_or(0.0));
+            }
+            log::event("mixture", json!({"name": s.name, "mixed_sites": s.minor_fractions.len(),
+                                         "sites_per_kbp": s.sites_per_kbp(),
+                                         "median_minor_fraction": median,
+                                         "minor_fraction_histogram": histogram,
+                                         "likely_mixture": s.is_likely_mixture()}));
+            all_fractions.extend(&s.minor_fractions);
+        }
+        if flagged_count == 0 {
+            log::text!("No sequences look like strain mixtures");
+        }
+        log::text!("Minor allele fractions over all sequences: {}",
+                   histogram_str(&histogram(&all_fractions)));
+        log::text!();
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        if fs::write(&self.filename, text).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to file {:?}",
+                                                    self.filename));
+        }
+    }
+}
+
+
+/// The fraction of the base's sequences which are its second-most common sequence, if both of
+/// its two most common sequences occur at least min_count times.
+fn minor_allele_fraction(b: &PileupBase, min_count: f64) -> Option<f64> {
+    let mut counts: Vec<f64> = b.seq_counts().into_iter().map(|(_, count)| count).collect();
+    counts.sort_by(|a, b| b.total_cmp(a));
+    let total: f64 = counts.iter().sum();
+    let second = *counts.get(1)?;
+    (second >= min_count).then(|| second / total)
+}
+
+
+fn median_fraction(fractions: &[f64]) -> Option<f64> {
+    if fractions.is_empty() {
+        return None;
+    }
+    let mut sorted = fractions.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    Some(sorted[sorted.len() / 2])
+}
+
+
+fn histogram(fractions: &[f64]) -> [usize; BIN_COUNT] {
+    let mut bins = [0; BIN_COUNT];
+    for f in fractions {
+        bins[((f / BIN_WIDTH) as usize).min(BIN_COUNT - 1)] += 1;
+    }
+    bins
+}
+
+
+/// Formats a histogram like 0.0-0.1:3,0.1-0.2:5,...
+fn histogram_str(bins: &[usize; BIN_COUNT]) -> String {
+    bins.iter().enumerate()
+        .map(|(i, count)| format!("{:.1}-{:.1}:{}", i as f64 * BIN_WIDTH,
+                                  (i + 1) as f64 * BIN_WIDTH, count))
+        .collect::<Vec<_>>().join(",")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pileup::Pileup;
+
+    #[test]
+    fn test_minor_allele_fraction() {
+        let mut pileup = Pileup::new("AC", u32::MAX, 0, None, false);
+        for _ in 0..6 {
+            pileup.bases[0].add_seq("A", 1.0, 1.0);
+        }
+        for _ in 0..3 {
+            pileup.bases[0].add_seq("G", 1.0, 1.0);
+        }
+        pileup.bases[0].add_seq("T", 1.0, 1.0);
+        pileup.bases[1].add_seq("C", 1.0, 1.0);
+        assert_eq!(minor_allele_fraction(&pileup.bases[0], 3.0), Some(0.3));
+        assert_eq!(minor_allele_fraction(&pileup.bases[0], 4.0), None);
+        assert_eq!(minor_allele_fraction(&pileup.bases[1], 1.0), None);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let bins = histogram(&[0.05, 0.12, 0.15, 0.45, 0.5]);
+        assert_eq!(bins, [1, 2, 0, 0, 2]);
+        assert_eq!(histogram_str(&bins), "0.0-0.1:1,0.1-0.2:2,0.2-0.3:0,0.3-0.4:0,0.4-0.5:2");
+        assert_eq!(median_fraction(&[0.3, 0.1, 0.2]), Some(0.2));
+        assert_eq!(median_fraction(&[]), None);
+    }
+}
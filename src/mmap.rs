@@ -0,0 +1,113 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// With --mmap, uncompressed SAM files are memory-mapped instead of being read through a buffer.
+// Lines are parsed where they are in the mapped pages, without being copied into a String, which
+// also saves most of the read system calls. This helps with very large alignment files on fast
+// local storage (e.g. NVMe scratch space on a cluster), where reading is limited by the CPU rather
+// than the disk. BAM and gzipped files are decompressed as they are read and PAF records are
+// converted to SAM lines, so these files aren't mapped.
+
+use memmap2::Mmap;
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// The lines of a memory-mapped file. The bytes_read counter is updated as lines are taken, for
+/// progress reporting.
+pub struct MappedLines {
+    map: Mmap,
+    pos: usize,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl MappedLines {
+    pub fn new(file: &File, bytes_read: Arc<AtomicU64>) -> io::Result<MappedLines> {
+        // SAFETY: the map is read-only, so Polypolish can't change the file through it. Another
+        // process changing the file while it is mapped would change the lines under us, and
+        // truncating it would crash Polypolish (SIGBUS) on reading past the new end. This is why
+        // --mmap is optional, and its help says the files must not change during the run.
+        let map = unsafe { Mmap::map(file)? };
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Sequential);  // only a hint, so failure is fine
+        Ok(MappedLines { map, pos: 0, bytes_read })
+    }
+
+    /// Takes the next line (without its trailing \n or \r\n) and returns where it is in the map,
+    /// or an error if it isn't valid UTF-8 (like BufRead::lines).
+    pub fn next_range(&mut self) -> Option<io::Result<Range<usize>>> {
+        if self.pos >= self.map.len() {
+            return None;
+        }
+        let start = self.pos;
+        let mut end = match self.map[start..].iter().position(|&b| b == b'\n') {
+            Some(i) => start + i,
+            None    => self.map.len(),
+        };
+        self.pos = (end + 1).min(self.map.len());
+        self.bytes_read.store(self.pos as u64, Ordering::Relaxed);
+        if end > start && self.map[end - 1] == b'\r' {
+            end -= 1;
+        }
+        match std::str::from_utf8(&self.map[start..end]) {
+            Ok(_)  => Some(Ok(start..end)),
+            Err(_) => Some(Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "stream did not contain valid UTF-8"))),
+        }
+    }
+
+    /// Returns a line from its range, as given by next_range.
+    pub fn line(&self, range: Range<usize>) -> &str {
+        let bytes = &self.map[range];
+        debug_assert!(std::str::from_utf8(bytes).is_ok());
+        // SAFETY: next_range only returns ranges of valid UTF-8, and the map doesn't change.
+        unsafe { std::str::from_utf8_unchecked(bytes) }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped_lines(contents: &[u8]) -> Vec<io::Result<String>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sam");
+        std::fs::write(&path, contents).unwrap();
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let mut lines = MappedLines::new(&File::open(&path).unwrap(), bytes_read.clone()).unwrap();
+        let mut result = Vec::new();
+        while let Some(range) = lines.next_range() {
+            result.push(range.map(|r| lines.line(r).to_string()));
+        }
+        assert_eq!(bytes_read.load(Ordering::Relaxed), contents.len() as u64);
+        result
+    }
+
+    fn ok_lines(contents: &str) -> Vec<String> {
+        mapped_lines(contents.as_bytes()).into_iter().map(|l| l.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_mapped_lines() {
+        assert_eq!(ok_lines("@HD\tVN:1.6\nr1\t0\tx\n"), vec!["@HD\tVN:1.6", "r1\t0\tx"]);
+        assert_eq!(ok_lines("a\r\nb\r\n\nc"), vec!["a", "b", "", "c"]);
+        assert!(ok_lines("").is_empty());
+        let lines = mapped_lines(b"a\n\xff\nb\n");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(lines[2].as_ref().unwrap(), "b");
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Alignments are filtered by their NM tag (--max_errors, --max-error-rate and --min-identity), so
+// an aligner which writes a different NM (e.g. counting only mismatches, not indels) silently
+// changes which alignments are used. With --validate-nm, each alignment's edit distance is
+// recomputed from its CIGAR, its read sequence and the assembly, and compared to its NM tag. The
+// results are reported per aligner, taken from each file's first @PG header line. Alignments
+// without a read sequence (SEQ of *, e.g. bwa's secondary alignments) can't be checked.
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::path::{Path, PathBuf};
+
+use crate::alignment::Alignment;
+use crate::log;
+use crate::misc::FastHashMap;
+use crate::pileup::Pileup;
+use crate::warnings::Warnings;
+
+
+/// How many differing alignments are described for each aligner.
+const MAX_EXAMPLES: usize = 3;
+
+
+#[derive(Default)]
+pub struct NmChecks {
+    programs: FastHashMap<PathBuf, String>,  // the aligner for each file
+    aligners: Vec<AlignerCounts>,
+}
+
+
+struct AlignerCounts {
+    name: String,
+    files: Vec<PathBuf>,
+    checked: usize,
+    different: usize,
+    without_indels: usize,  // differing alignments whose NM only counts mismatches
+    examples: Vec<String>,
+}
+
+
+impl NmChecks {
+    /// Notes a file's aligner from its header lines: the program (PN, or ID if there's no PN) of
+    /// the first @PG line, as later ones are usually from tools like samtools.
+    pub fn header_line(&mut self, filename: &Path, line: &str) {
+        if !line.starts_with("@PG") {
+            return;
+        }
+        let field = |tag: &str| line.split('\t').find_map(|f| f.strip_prefix(tag));
+        let Some(program) = field("PN:").or_else(|| field("ID:")) else { return; };
+        self.programs.entry(filename.to_path_buf()).or_insert_with(|| program.to_string());
+    }
+
+    /// Compares an alignment's NM tag to its recomputed edit distance. Alignments to sequences
+    /// without a pileup or without a read sequence are skipped.
+    pub fn check(&mut self, filename: &Path, alignment: &Alignment,
+                 pileups: &FastHashMap<String, Pileup>) {
+        let Some(pileup) = pileups.get(&alignment.ref_name) else { return; };
+        let Some((mismatches, indel_bases)) = alignment.recompute_nm(pileup) else { return; };
+        let nm = alignment.nm();
+        let program = self.programs.get(filename).cloned()
+            .unwrap_or_else(|| "unknown aligner".to_string());
+        let counts = match self.aligners.iter().position(|a| a.name == program) {
+            Some(i) => &mut self.aligners[i],
+            None    => {
+                self.aligners.push(AlignerCounts { name: program, files: Vec::new(), checked: 0,
+                                                   different: 0, without_indels: 0,
+                                                   examples: Vec::new() });
+                self.aligners.last_mut().unwrap()
+            },
+        };
+        if !counts.files.iter().any(|f| f == filename) {
+            counts.files.push(filename.to_path_buf());
+        }
+        counts.checked += 1;
+        let edit_distance = mismatches + indel_bases;
+        if nm != edit_distance {
+            counts.different += 1;
+            if indel_bases > 0 && nm == mismatches {
+                counts.without_indels += 1;
+            }
+            if counts.examples.len() < MAX_EXAMPLES {
+                counts.examples.push(format!("{} in {}: NM {}, recomputed {}",
+                                             alignment.read_name, filename.display(), nm,
+                                             edit_distance));
+            }
+        }
+    }
+
+    /// Logs the results for each aligner, with a warning for any aligner with differing NM tags.
+    pub fn report(&self, warnings: &mut Warnings) {
+        if self.aligners.is_empty() {
+            return;
+        }
+        log::text!("NM tags compared to recomputed edit distances (--validate-nm):");
+        let mut aligners = Vec::new();
+        for a in &self.aligners {
+            let files: Vec<String> = a.files.iter().map(|f| f.display().to_string()).collect();
+            log::text!("  {} ({}): {} alignments checked, {} with a different NM", a.name,
+                       files.join(", "), a.checked.to_formatted_string(&Locale::en),
+                       a.different.to_formatted_string(&Locale::en));
+            for example in &a.examples {
+                log::text!("    {}", example);
+            }
+            aligners.push(json!({"aligner": a.name, "files": a.files, "checked": a.checked,
+                                 "different": a.different, "without_indels": a.without_indels,
+                                 "examples": a.examples}));
+        }
+        log::text!();
+        log::event("nm_validated", json!({"aligners": aligners}));
+        for a in self.aligners.iter().filter(|a| a.different > 0) {
+            let cause = if a.without_indels * 2 > a.different {
+                ", mostly because its NM tags don't count indels"
+            } else {
+                ""
+            };
+            warnings.add(format!("{} of {} alignments from {} have an NM tag which doesn't \
+                                  match the alignment{}, which changes the alignments kept by \
+                                  --max_errors", a.different.to_formatted_string(&Locale::en),
+                                 a.checked.to_formatted_string(&Locale::en), a.name, cause));
+        }
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// An indel in a homopolymer or tandem repeat can be placed at any of several positions, all giving
+// the same sequence, and where the aligner puts it can vary between runs. Reported indels (in
+// --changes, library changes and polypolish variants VCFs) are moved to their left-most position,
+// as variant callers do, so the same change is always reported the same way. An indel is only
+// moved past unchanged bases. The polished sequence isn't affected, and the debug file still
+// gives each base's pileup at the position where the reads put it.
+
+use std::collections::VecDeque;
+
+use crate::sink::ChangeSink;
+
+
+/// Indels are moved left by at most this many bases, as that many bases are held back by
+/// LeftNormaliser.
+const MAX_SHIFT: usize = 1000;
+
+
+/// Returns the left-most start of a deleted run of bases (start..end): the run can move left one
+/// base whenever the base before it matches its last base. The unchanged function says whether a
+/// base can be moved past.
+pub fn left_shift_deletion(base: impl Fn(usize) -> char, unchanged: impl Fn(usize) -> bool,
+                           mut start: usize, mut end: usize, min_start: usize) -> usize {
+    while start > min_start && unchanged(start - 1) && base(start - 1) == base(end - 1) {
+        start -= 1;
+        end -= 1;
+    }
+    start
+}
+
+
+/// Returns the left-most position and inserted bases of an insertion after the given base: it can
+/// move left one base whenever its last inserted base matches the base it follows.
+pub fn left_shift_insertion(base: impl Fn(usize) -> char, unchanged: impl Fn(usize) -> bool,
+                            mut pos: usize, inserted: &str, min_pos: usize) -> (usize, String) {
+    let mut inserted = inserted.to_string();
+    while pos > min_pos && unchanged(pos - 1) && inserted.ends_with(base(pos)) {
+        inserted.pop();
+        inserted.insert(0, base(pos));
+        pos -= 1;
+    }
+    (pos, inserted)
+}
+
+
+/// One polished base, as given to a ChangeSink.
+struct Entry {
+    pos: usize,
+    base: char,
+    polished: String,
+    change: Option<(String, f64)>,
+}
+
+impl Entry {
+    fn is_unchanged(&self) -> bool {
+        self.change.is_none()
+    }
+
+    fn is_deletion(&self) -> bool {
+        self.change.as_ref().is_some_and(|(seq, _)| seq == "-")
+    }
+
+    /// The inserted bases, for a change which keeps the original base and adds more after it.
+    fn insertion(&self) -> Option<&str> {
+        let (seq, _) = self.change.as_ref()?;
+        seq.strip_prefix(self.base).filter(|s| !s.is_empty() && !s.contains('-'))
+    }
+
+    fn set_unchanged(&mut self) {
+        self.polished = self.base.to_string();
+        self.change = None;
+    }
+}
+
+
+/// A ChangeSink which left-normalises the indels before passing the bases on to another sink.
+/// Bases are held back until no later indel can move onto them.
+pub struct LeftNormaliser {
+    sink: Box<dyn ChangeSink>,
+    name: String,
+    entries: VecDeque<Entry>,
+}
+
+impl LeftNormaliser {
+    pub fn new(sink: Box<dyn ChangeSink>) -> LeftNormaliser {
+        LeftNormaliser { sink, name: String::new(), entries: VecDeque::new() }
+    }
+
+    /// Moves the deleted run (if any) which ends with the last entry.
+    fn normalise_deletion(&mut self) {
+        let end = self.entries.len();
+        let run = self.entries.iter().rev().take_while(|e| e.is_deletion()).count();
+        if run == 0 {
+            return;
+        }
+        let entries = &self.entries;
+        let start = left_shift_deletion(|i| entries[i].base, |i| entries[i].is_unchanged(),
+                                        end - run, end, 0);
+        if start == end - run {
+            return;
+        }
+        let confidences: Vec<f64> = self.entries.range(end - run..)
+            .map(|e| e.change.as_ref().unwrap().1).collect();
+        for i in start..end {
+            self.entries[i].set_unchanged();
+        }
+        for (i, confidence) in (start..).zip(confidences) {
+            self.entries[i].polished = String::new();
+            self.entries[i].change = Some(("-".to_string(), confidence));
+        }
+    }
+
+    /// Moves the last entry's insertion (if it has one).
+    fn normalise_insertion(&mut self) {
+        let last = self.entries.len() - 1;
+        let Some(inserted) = self.entries[last].insertion() else { return; };
+        let entries = &self.entries;
+        let (pos, inserted) = left_shift_insertion(|i| entries[i].base,
+                                                   |i| entries[i].is_unchanged(), last, inserted,
+                                                   0);
+        if pos == last {
+            return;
+        }
+        let confidence = self.entries[last].change.as_ref().unwrap().1;
+        self.entries[last].set_unchanged();
+        let new_seq = format!("{}{}", self.entries[pos].base, inserted);
+        self.entries[pos].polished = new_seq.clone();
+        self.entries[pos].change = Some((new_seq, confidence));
+    }
+
+    fn pass_on(&mut self, entry: Entry) {
+        let change = entry.change.as_ref().map(|(seq, confidence)| (seq.as_str(), *confidence));
+        self.sink.add_base(&self.name, entry.pos, entry.base, &entry.polished, change);
+    }
+
+    fn flush(&mut self) {
+        self.normalise_deletion();
+        while let Some(entry) = self.entries.pop_front() {
+            self.pass_on(entry);
+        }
+    }
+}
+
+impl ChangeSink for LeftNormaliser {
+    fn add_base(&mut self, name: &str, pos: usize, base: char, polished: &str,
+                change: Option<(&str, f64)>) {
+        if name != self.name {
+            self.flush();
+            self.name = name.to_string();
+        }
+        let entry = Entry { pos, base, polished: polished.to_string(),
+                            change: change.map(|(seq, confidence)| (seq.to_string(), confidence)) };
+        if !entry.is_deletion() {
+            self.normalise_deletion();
+        }
+        self.entries.push_back(entry);
+        self.normalise_insertion();
+        while self.entries.len() > MAX_SHIFT && !self.entries[MAX_SHIFT].is_deletion() {
+            let entry = self.entries.pop_front().unwrap();
+            self.pass_on(entry);
+        }
+    }
+
+    fn finish(mut self: Box<Self>) {
+        self.flush();
+        self.sink.finish();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Change, ChangeCollector};
+
+    fn shift_deletion(seq: &str, start: usize, end: usize) -> usize {
+        let seq = seq.as_bytes();
+        left_shift_deletion(|i| seq[i] as char, |_| true, start, end, 0)
+    }
+
+    fn shift_insertion(seq: &str, pos: usize, inserted: &str) -> (usize, String) {
+        let seq = seq.as_bytes();
+        left_shift_insertion(|i| seq[i] as char, |_| true, pos, inserted, 0)
+    }
+
+    #[test]
+    fn test_left_shift_deletion() {
+        assert_eq!(shift_deletion("GAAAAC", 4, 5), 1);
+        assert_eq!(shift_deletion("GCACACAT", 5, 7), 1);
+        assert_eq!(shift_deletion("GCACACAT", 4, 6), 1);
+        assert_eq!(shift_deletion("GACT", 2, 3), 2);
+        assert_eq!(shift_deletion("AAAA", 2, 4), 0);
+    }
+
+    #[test]
+    fn test_left_shift_insertion() {
+        assert_eq!(shift_insertion("GAAAC", 3, "A"), (0, "A".to_string()));
+        assert_eq!(shift_insertion("GCACAT", 4, "CA"), (0, "CA".to_string()));
+        assert_eq!(shift_insertion("GCACAT", 3, "AC"), (0, "CA".to_string()));
+        assert_eq!(shift_insertion("GACT", 2, "G"), (2, "G".to_string()));
+    }
+
+    #[test]
+    fn test_left_normaliser() {
+        let collector = ChangeCollector::new();
+        let mut sink: Box<dyn ChangeSink> =
+            Box::new(LeftNormaliser::new(Box::new(collector.clone())));
+        let original = "TGAAACCACACAGT";
+        for (pos, base) in original.chars().enumerate() {
+            match pos {
+                4  => sink.add_base("a", pos, base, "AA", Some(("AA", 30.0))),
+                9  => sink.add_base("a", pos, base, "", Some(("-", 20.0))),
+                10 => sink.add_base("a", pos, base, "", Some(("-", 25.0))),
+                12 => sink.add_base("a", pos, base, "C", Some(("C", 40.0))),
+                _  => sink.add_base("a", pos, base, &base.to_string(), None),
+            }
+        }
+        sink.add_base("b", 0, 'C', "", Some(("-", 10.0)));
+        sink.add_base("b", 1, 'C', "C", None);
+        sink.finish();
+        let change = |name: &str, pos, base, new_seq: &str, confidence| {
+            Change { name: name.to_string(), pos, base, new_seq: new_seq.to_string(), confidence }
+        };
+        assert_eq!(collector.take(), vec![change("a", 1, 'G', "GA", 30.0),
+                                          change("a", 6, 'C', "-", 20.0),
+                                          change("a", 7, 'A', "-", 25.0),
+                                          change("a", 12, 'G', "C", 40.0),
+                                          change("b", 0, 'C', "-", 10.0)]);
+    }
+}
@@ -0,0 +1,652 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The options for the larger subcommands, parsed by main.rs and passed by reference to the
+// function which runs the subcommand. Keeping them in named fields (instead of passing each one
+// as a positional argument) means two options of the same type can't be silently swapped.
+
+use std::path::PathBuf;
+use clap::Args;
+
+
+#[derive(Args)]
+pub struct FilterOptions {
+    /// Input SAM file - first read in pairs
+    #[clap(long = "in1")]
+    pub in1: PathBuf,
+
+    /// Input SAM file - first second in pairs
+    #[clap(long = "in2")]
+    pub in2: PathBuf,
+
+    /// Output SAM file - first read in pairs
+    #[clap(long = "out1")]
+    pub out1: PathBuf,
+
+    /// Output SAM file - first second in pairs
+    #[clap(long = "out2")]
+    pub out2: PathBuf,
+
+    /// Library type, which sets the defaults for --orientation, --low and --high: paired-end
+    /// (auto, 0.1, 99.9) or mate-pair for long-insert jumping libraries (rf,fr, 1, 99)
+    #[clap(long = "preset", default_value = "paired-end",
+           value_parser = ["paired-end", "mate-pair"])]
+    pub preset: String,
+
+    /// Expected pair orientation: auto, any, or one or more of fr, rf, ff and rr (e.g. fr,rf)
+    /// [default: from --preset]
+    #[clap(long = "orientation")]
+    pub orientation: Option<String>,
+
+    /// Low percentile threshold [default: from --preset]
+    #[clap(long = "low")]
+    pub low: Option<f64>,
+
+    /// High percentile threshold [default: from --preset]
+    #[clap(long = "high")]
+    pub high: Option<f64>,
+
+    /// Alignments with a lower mapping quality fail, whether or not they make a good pair
+    /// (note that aligners give MAPQ 0 to reads in repeats, which Polypolish can still use)
+    #[clap(long = "min-mapq", default_value = "0")]
+    pub min_mapq: u8,
+
+    /// Read pairs where either read has more than this many alignments (e.g. in a high-copy
+    /// IS element) pass without checking their alignments' pairing, which saves time
+    #[clap(long = "max-hits")]
+    pub max_hits: Option<usize>,
+
+    /// Quit with an error (instead of switching to low-memory mode) if the estimated memory
+    /// usage exceeds the available memory
+    #[clap(long = "strict-memory")]
+    pub strict_memory: bool,
+
+    /// Pair alignments using sorted temporary files instead of in memory (slower, but needs
+    /// much less memory)
+    #[clap(long = "low-memory")]
+    pub low_memory: bool,
+
+    /// SAM tag added to alignments which fail the filter
+    #[clap(long = "fail-tag", default_value = "ZP:Z:fail")]
+    pub fail_tag: String,
+}
+
+
+#[derive(Args)]
+pub struct PolishOptions {
+    /// Optional file to store per-base information for debugging purposes
+    #[clap(long = "debug")]
+    pub debug: Option<PathBuf>,
+
+    /// A base must make up less than this fraction of the read depth to be considered invalid
+    #[clap(short = 'i', long = "fraction_invalid", default_value = "0.2")]
+    pub fraction_invalid: f64,
+
+    /// A base must make up at least this fraction of the read depth to be considered valid
+    #[clap(short = 'v', long = "fraction_valid", default_value = "0.5")]
+    pub fraction_valid: f64,
+
+    /// How each position's sequence is chosen: with depth and fraction thresholds, or with a
+    /// Bayesian model using base qualities (which replaces --min_depth, --fraction_valid and
+    /// --fraction_invalid and can make changes at lower depths)
+    #[arg(long = "model", default_value = "fractions", value_parser = ["fractions", "bayes"])]
+    pub model: String,
+
+    /// With --model bayes, a sequence must have at least this posterior probability to be
+    /// used
+    #[arg(long = "min-posterior", default_value = "0.99")]
+    pub min_posterior: f64,
+
+    /// Ignore alignments with more than this many mismatches and indels
+    #[clap(short = 'm', long = "max_errors", default_value = "10")]
+    pub max_errors: u32,
+
+    /// Ignore alignments whose mismatches and indels make up more than this fraction of the
+    /// alignment length (used instead of --max_errors)
+    #[arg(long = "max-error-rate", conflicts_with = "max_errors")]
+    pub max_error_rate: Option<f64>,
+
+    /// Ignore alignments with an alignment score (AS tag) below this value
+    #[arg(long = "min-alignment-score", allow_negative_numbers = true)]
+    pub min_alignment_score: Option<i32>,
+
+    /// Ignore alignments whose alignment score (AS tag) is more than this far below the best
+    /// score for the same read
+    #[arg(long = "max-score-diff")]
+    pub max_score_diff: Option<i32>,
+
+    /// Ignore alignments whose identity (the fraction of the alignment length without a
+    /// mismatch or indel) is below this value (used instead of --max_errors)
+    #[arg(long = "min-identity", conflicts_with_all = ["max_errors", "max_error_rate"])]
+    pub min_identity: Option<f64>,
+
+    /// A base must occur at least this many times in the pileup to be considered valid
+    #[clap(short = 'd', long = "min_depth", default_value = "5")]
+    pub min_depth: u32,
+
+    /// Ignore any reads with multiple alignments
+    #[arg(long = "careful")]
+    pub careful: bool,
+
+    /// Ignore reads with more than this many alignments (e.g. in a high-copy repeat), a softer
+    /// version of --careful
+    #[arg(long = "max-hits", conflicts_with = "careful")]
+    pub max_hits: Option<usize>,
+
+    /// How a read's depth is shared between its alignments: equally, by MAPQ (the best
+    /// placements get their chance of being correct) or by alignment score (each 10 points
+    /// below the read's best AS divide the share by 10)
+    #[arg(long = "placement-weights", default_value = "equal",
+          value_parser = ["equal", "mapq", "score"])]
+    pub placement_weights: String,
+
+    /// Count each alignment's sequences with a weight from its error rate (from its NM tag), so
+    /// error-rich reads count for less instead of being either used fully or discarded
+    #[arg(long = "identity-weighting")]
+    pub identity_weighting: bool,
+
+    /// Polish in sliding windows using coordinate-sorted alignments (uses less memory for large
+    /// assemblies)
+    #[arg(long = "windowed")]
+    pub windowed: bool,
+
+    /// Polish the assembly in batches of sequences totalling about this many bases, reading
+    /// the alignment files once per batch (uses less memory for large assemblies, without
+    /// needing sorted alignments)
+    #[arg(long = "batch-size",
+          conflicts_with_all = ["windowed", "checkpoint", "save_pileup", "load_pileup",
+                                "samples", "long_reads", "plasmid_aware", "rotate",
+                                "rotated_sam", "paired", "local_depth_window",
+                                "require_mean_depth", "gap_patches", "fill_ns",
+                                "extend_ends"])]
+    pub batch_size: Option<usize>,
+
+    /// Sort alignments by read name (using temporary files) if they aren't grouped by read
+    #[arg(long = "auto-sort", conflicts_with = "windowed")]
+    pub auto_sort: bool,
+
+    /// Randomly discard reads to bring the mean depth down to this value [default: no
+    /// downsampling]
+    #[arg(long = "target-depth")]
+    pub target_depth: Option<f64>,
+
+    /// Seed for the random choice of reads when downsampling
+    #[arg(long = "seed", default_value = "0")]
+    pub seed: u64,
+
+    /// Stop adding reads to a position once it has this many [default: no limit]
+    #[arg(long = "max-depth")]
+    pub max_depth: Option<u32>,
+
+    /// Count the overlap of overlapping mates only once (requires two alignment files: first
+    /// and second reads of the pair)
+    #[arg(long = "merge-overlaps", conflicts_with = "windowed")]
+    pub merge_overlaps: bool,
+
+    /// Ignore this many aligned bases at each end of each read
+    #[arg(long = "trim-ends", default_value = "0")]
+    pub trim_ends: usize,
+
+    /// When an alignment ends in a homopolymer, trim the homopolymer and this many more bases
+    /// from its end
+    #[arg(long = "homopolymer-trim", default_value = "1")]
+    pub homopolymer_trim: usize,
+
+    /// Don't trim alignment ends in homopolymers (e.g. for reads with few homopolymer errors)
+    #[arg(long = "no-homopolymer-trim", conflicts_with = "homopolymer_trim")]
+    pub no_homopolymer_trim: bool,
+
+    /// A base must make up this fraction of the read depth to be considered valid, for
+    /// insertions and deletions [default: --fraction_valid]
+    #[arg(long = "indel-fraction-valid")]
+    pub indel_fraction_valid: Option<f64>,
+
+    /// A base must occur at least this many times in the pileup to be considered valid, for
+    /// insertions and deletions [default: --min_depth]
+    #[arg(long = "indel-min-depth")]
+    pub indel_min_depth: Option<u32>,
+
+    /// Call each run of adjacent deleted bases as one deletion, made or not made as a whole,
+    /// instead of calling each base on its own
+    #[arg(long = "joint-indels", conflicts_with = "samples")]
+    pub joint_indels: bool,
+
+    /// Only apply substitutions (can be combined with other --only-* options)
+    #[arg(long = "only-substitutions")]
+    pub only_substitutions: bool,
+
+    /// Only apply insertions (can be combined with other --only-* options)
+    #[arg(long = "only-insertions")]
+    pub only_insertions: bool,
+
+    /// Only apply deletions (can be combined with other --only-* options)
+    #[arg(long = "only-deletions")]
+    pub only_deletions: bool,
+
+    /// Output FASTQ (with per-base qualities from the read pileup) instead of FASTA
+    #[arg(long = "output-fastq")]
+    pub output_fastq: bool,
+
+    /// Add each sequence's number of changes and mean read depth to its output header, e.g.
+    /// polypolish=3changes depth=142.5x
+    #[arg(long = "annotate-headers", conflicts_with = "windowed")]
+    pub annotate_headers: bool,
+
+    /// Write the polished assembly to this file instead of stdout
+    #[arg(short = 'o', long = "output")]
+    pub output: Option<PathBuf>,
+
+    /// Compress the polished assembly with bgzip (BGZF), which samtools and IGV can read
+    /// directly
+    #[arg(long = "bgzip")]
+    pub bgzip: bool,
+
+    /// Write a samtools faidx index (.fai, plus .gzi with --bgzip) alongside the output file
+    #[arg(long = "fai", requires = "output", conflicts_with = "output_fastq")]
+    pub fai: bool,
+
+    /// Also write each polished sequence to its own file (named after the sequence) in this
+    /// directory
+    #[arg(long = "split-output")]
+    pub split_output: Option<PathBuf>,
+
+    /// Only write the --split-output files, not the combined output
+    #[arg(long = "split-only", requires = "split_output", conflicts_with = "output")]
+    pub split_only: bool,
+
+    /// Skip malformed alignment records (and stop reading a truncated file at its last good
+    /// record) instead of stopping with an error
+    #[arg(long = "skip-bad-records")]
+    pub skip_bad_records: bool,
+
+    /// Skip up to this many malformed alignment records (logging each one), stopping with an
+    /// error if there are more
+    #[arg(long = "max-bad-records", conflicts_with = "skip_bad_records")]
+    pub max_bad_records: Option<usize>,
+
+    /// Recompute each alignment's edit distance from its CIGAR and the assembly, and report
+    /// any differences from its NM tag (which is used by --max_errors)
+    #[arg(long = "validate-nm", conflicts_with_all = ["windowed", "merge_overlaps"])]
+    pub validate_nm: bool,
+
+    /// Read uncompressed SAM files through a memory map, which can be faster for very large
+    /// files on fast local storage (the files must not change while Polypolish runs)
+    #[arg(long = "mmap")]
+    pub mmap: bool,
+
+    /// Stop with an error (before writing any output) if the mean read depth of the kept
+    /// alignments is below this [default: no minimum]
+    #[arg(long = "require-mean-depth", conflicts_with = "windowed")]
+    pub require_mean_depth: Option<f64>,
+
+    /// Stop with an error (instead of a warning) if the alignments seem to only include each
+    /// read's best alignment (e.g. bwa mem was run without -a)
+    #[arg(long = "strict")]
+    pub strict: bool,
+
+    /// Save the loaded alignments to this directory after each alignment file, so a stopped
+    /// run can be resumed (by running the same command again) without reloading them
+    #[arg(long = "checkpoint", conflicts_with_all = ["windowed", "debug_reads"])]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Save the loaded alignments' pileup to this file, for use with --load-pileup
+    #[arg(long = "save-pileup", conflicts_with_all = ["windowed", "debug_reads"])]
+    pub save_pileup: Option<PathBuf>,
+
+    /// Polish using a pileup saved with --save-pileup instead of alignment files (alignment
+    /// filtering settings then come from the run which saved it)
+    #[arg(long = "load-pileup",
+          conflicts_with_all = ["windowed", "debug_reads", "checkpoint", "save_pileup",
+                                "target_depth", "merge_overlaps", "auto_sort"])]
+    pub load_pileup: Option<PathBuf>,
+
+    /// Only include bases with these statuses in the debug file (comma-separated)
+    #[arg(long = "debug-filter", requires = "debug", value_delimiter = ',',
+          value_parser = ["kept", "changed", "low_depth", "none", "multiple", "too_close",
+                          "not_allowed", "few_samples", "long_read"])]
+    pub debug_filter: Vec<String>,
+
+    /// Add the names of the reads behind each sequence to the debug file, for bases which
+    /// weren't simply kept (uses more memory)
+    #[arg(long = "debug-reads", requires = "debug")]
+    pub debug_reads: bool,
+
+    /// Format of the debug file: tsv, or arrow for a compressed Arrow IPC (Feather) file which
+    /// dataframe libraries can query quickly (needs Polypolish built with --features arrow)
+    #[arg(long = "debug-format", requires = "debug", default_value = "tsv",
+          value_parser = ["tsv", "arrow"])]
+    pub debug_format: String,
+
+    /// Optional bedGraph file to store the read depth used at each position (reads with
+    /// multiple alignments contribute a fraction to each)
+    #[arg(long = "depth-out")]
+    pub depth_out: Option<PathBuf>,
+
+    /// Optional BED file to store the intervals with zero read depth
+    #[arg(long = "uncovered-bed")]
+    pub uncovered_bed: Option<PathBuf>,
+
+    /// Optional bedGraph file to store the fraction of each position's read sequences which
+    /// came from reads with multiple alignments (a sign of a repeat)
+    #[arg(long = "multi-mapped-out")]
+    pub multi_mapped_out: Option<PathBuf>,
+
+    /// Optional HTML file to store a report with a read depth plot for each sequence, marking
+    /// the positions of changes
+    #[arg(long = "html")]
+    pub html: Option<PathBuf>,
+
+    /// Optional SVG file to store a read depth plot for each sequence, marking the positions
+    /// of changes (the same plots as --html, as one image)
+    #[arg(long = "plot")]
+    pub plot: Option<PathBuf>,
+
+    /// Optional TSV file to store each change with 20 bp of original and polished sequence on
+    /// each side (indels are given at their left-most position)
+    #[arg(long = "changes")]
+    pub changes: Option<PathBuf>,
+
+    /// Optional TSV file to store, for each sequence, the positions with two well-supported
+    /// sequences and their minor allele fractions, flagging likely strain mixtures
+    #[arg(long = "mixtures")]
+    pub mixtures: Option<PathBuf>,
+
+    /// Optional gVCF file to store reference blocks (with depth and confidence) for the
+    /// checked bases and a record for each changed or ambiguous base
+    #[arg(long = "gvcf")]
+    pub gvcf: Option<PathBuf>,
+
+    /// Sample name for each alignment file (comma-separated, in the same order as the files),
+    /// e.g. for replicates which must agree on each change
+    #[arg(long = "samples", value_delimiter = ',',
+          conflicts_with_all = ["windowed", "checkpoint", "save_pileup", "load_pileup",
+                                "merge_overlaps"])]
+    pub samples: Vec<String>,
+
+    /// With --samples, only make changes which at least this many samples would each make on
+    /// their own
+    #[arg(long = "min-samples", default_value = "2", requires = "samples")]
+    pub min_samples: usize,
+
+    /// Weight for each alignment file (comma-separated, in the same order as the files), to
+    /// scale its contribution to the base counts and read depth, e.g. 1,0.5 for a
+    /// lower-quality second library [default: all 1]
+    #[arg(long = "weights", value_delimiter = ',',
+          conflicts_with_all = ["load_pileup", "merge_overlaps"])]
+    pub weights: Vec<f64>,
+
+    /// Long-read alignments (SAM or BAM format), only used to break ties at positions where
+    /// the short reads support more than one sequence
+    #[arg(long = "long-reads", conflicts_with = "windowed")]
+    pub long_reads: Option<PathBuf>,
+
+    /// Detect short contigs with a much higher read depth than the rest of the assembly
+    /// (high-copy plasmids), scale their minimum depths up by their copy number and report
+    /// them separately
+    #[arg(long = "plasmid-aware", conflicts_with = "windowed")]
+    pub plasmid_aware: bool,
+
+    /// Rotate circular sequences (circular=true in their header) by this many bases or to
+    /// their midpoint (mid) before polishing, and rotate them back for output. The alignments
+    /// must be to the rotated assembly, e.g. from polypolish rotate
+    #[arg(long = "rotate", conflicts_with = "windowed")]
+    pub rotate: Option<String>,
+
+    /// Alignments (comma-separated files) to the assembly with its circular sequences rotated
+    /// to their midpoints (from polypolish rotate). Circular sequences are polished a second
+    /// time with these, and bases near their start/end junctions use the second pass
+    #[arg(long = "rotated-sam", value_delimiter = ',',
+          conflicts_with_all = ["windowed", "rotate", "samples", "load_pileup"])]
+    pub rotated_sam: Vec<PathBuf>,
+
+    /// Filter read pairs (as polypolish filter does) while loading the alignments, instead of
+    /// using filtered files (alignment files are given with --in1 and --in2)
+    #[arg(long = "paired", requires_all = ["in1", "in2"],
+          conflicts_with_all = ["windowed", "merge_overlaps", "auto_sort", "samples",
+                                "load_pileup", "checkpoint"])]
+    pub paired: bool,
+
+    /// With --paired: alignments for the first read in each pair (SAM, BAM or PAF format)
+    #[arg(long = "in1", requires = "paired")]
+    pub in1: Option<PathBuf>,
+
+    /// With --paired: alignments for the second read in each pair (SAM, BAM or PAF format)
+    #[arg(long = "in2", requires = "paired")]
+    pub in2: Option<PathBuf>,
+
+    /// With --paired: library type, which sets the defaults for --orientation, --low and
+    /// --high: paired-end (auto, 0.1, 99.9) or mate-pair for long-insert jumping libraries
+    /// (rf,fr, 1, 99)
+    #[arg(long = "preset", default_value = "paired-end",
+          value_parser = ["paired-end", "mate-pair"])]
+    pub preset: String,
+
+    /// With --paired: expected pair orientation (auto, any, or one or more of fr, rf, ff and
+    /// rr, e.g. fr,rf) [default: from --preset]
+    #[arg(long = "orientation")]
+    pub orientation: Option<String>,
+
+    /// With --paired: low percentile threshold for insert sizes [default: from --preset]
+    #[arg(long = "low")]
+    pub low: Option<f64>,
+
+    /// With --paired: high percentile threshold for insert sizes [default: from --preset]
+    #[arg(long = "high")]
+    pub high: Option<f64>,
+
+    /// Scale --min_depth and --indel-min-depth down in regions with low read depth (e.g. from
+    /// GC bias), in proportion to each window's median depth relative to the assembly's
+    #[arg(long = "local-depth-window", conflicts_with = "windowed")]
+    pub local_depth_window: Option<usize>,
+
+    /// Instead of scaling --min_depth down in low-depth windows, set it in every window to this
+    /// fraction of the window's median depth, e.g. 0.1 for assemblies with very uneven depth
+    /// such as metagenomes (requires --local-depth-window)
+    #[arg(long = "relative-min-depth", requires = "local_depth_window")]
+    pub relative_min_depth: Option<f64>,
+
+    /// Use this stricter valid fraction (for substitutions and indels) in low-complexity
+    /// regions, e.g. long homopolymers, found with a DUST score [default: --fraction_valid]
+    #[arg(long = "low-complexity-fraction-valid")]
+    pub low_complexity_fraction_valid: Option<f64>,
+
+    /// Ignore alignments with any of these SAM tags (comma-separated), e.g. fail tags from
+    /// other pre-filters
+    #[arg(long = "fail-tag", value_delimiter = ',', default_value = "ZP:Z:fail")]
+    pub fail_tags: Vec<String>,
+
+    /// Ignore alignments with the QCFAIL flag (0x200), e.g. from a vendor or demultiplexer
+    /// quality check
+    #[arg(long = "exclude-qcfail")]
+    pub exclude_qcfail: bool,
+
+    /// Ignore alignments with an XA tag, as their other placements are only listed in the tag
+    /// and can't be used (e.g. from bwa mem without -a)
+    #[arg(long = "exclude-xa")]
+    pub exclude_xa: bool,
+
+    /// Write candidate patch sequences for zero-depth gaps (built from read sequences, e.g.
+    /// soft-clipped tails, extending into each gap) to this FASTA file for manual review
+    #[arg(long = "gap-patches", conflicts_with_all = ["windowed", "load_pileup", "rotate"])]
+    pub gap_patches: Option<PathBuf>,
+
+    /// Replace runs of Ns in the assembly (e.g. scaffold gaps) with sequence from reads which
+    /// extend into or across them, where the reads from both sides agree
+    #[arg(long = "fill-ns", conflicts_with_all = ["windowed", "load_pileup", "rotate"])]
+    pub fill_ns: bool,
+
+    /// Extend the ends of linear sequences by up to this many bases, using a consensus of the
+    /// read sequences which overhang them (e.g. soft-clipped alignments)
+    #[arg(long = "extend-ends", conflicts_with_all = ["windowed", "load_pileup", "rotate"])]
+    pub extend_ends: Option<usize>,
+
+    /// Add the --extend-ends extensions in lowercase
+    #[arg(long = "mask-extensions", requires = "extend_ends")]
+    pub mask_extensions: bool,
+
+    /// Instead of alignment files: short reads (FASTQ, first read in each pair), which
+    /// Polypolish aligns with bwa mem -a (bwa or bwa-mem2 must be on the PATH)
+    #[arg(long = "reads1",
+          conflicts_with_all = ["windowed", "batch_size", "paired", "samples", "weights",
+                                "load_pileup", "checkpoint", "target_depth",
+                                "merge_overlaps", "gap_patches", "fill_ns", "extend_ends"])]
+    pub reads1: Option<PathBuf>,
+
+    /// With --reads1: short reads (FASTQ) for the second read in each pair
+    #[arg(long = "reads2", requires = "reads1")]
+    pub reads2: Option<PathBuf>,
+
+    /// With --reads1: number of threads for bwa
+    #[arg(long = "threads", default_value = "8", requires = "reads1")]
+    pub threads: usize,
+
+    /// Assembly to polish (FASTA or GFA format, or - to read FASTA from stdin)
+    pub assembly: PathBuf,
+
+    /// Short read alignments (one or more files in SAM, BAM or PAF format, not needed with
+    /// --load-pileup or --reads1). PAF needs cg and cs tags, e.g. from minimap2 -c
+    /// --cs=long. Extra assembly FASTA files can come before these, e.g. chromosome.fasta
+    /// plasmids.fasta reads.sam
+    pub sam: Vec<PathBuf>,
+}
+
+
+#[derive(Args)]
+pub struct FilterPolishOptions {
+    /// Alignments for the first read in each pair (SAM, BAM or PAF format, grouped by read)
+    #[arg(long = "in1")]
+    pub in1: PathBuf,
+
+    /// Alignments for the second read in each pair (SAM, BAM or PAF format, grouped by read)
+    #[arg(long = "in2")]
+    pub in2: PathBuf,
+
+    /// Library type, which sets the defaults for --orientation, --low and --high: paired-end
+    /// (auto, 0.1, 99.9) or mate-pair for long-insert jumping libraries (rf,fr, 1, 99)
+    #[arg(long = "preset", default_value = "paired-end",
+          value_parser = ["paired-end", "mate-pair"])]
+    pub preset: String,
+
+    /// Expected pair orientation: auto, any, or one or more of fr, rf, ff and rr (e.g. fr,rf)
+    /// [default: from --preset]
+    #[arg(long = "orientation")]
+    pub orientation: Option<String>,
+
+    /// Low percentile threshold for insert sizes [default: from --preset]
+    #[arg(long = "low")]
+    pub low: Option<f64>,
+
+    /// High percentile threshold for insert sizes [default: from --preset]
+    #[arg(long = "high")]
+    pub high: Option<f64>,
+
+    /// A base must make up less than this fraction of the read depth to be considered invalid
+    #[arg(short = 'i', long = "fraction_invalid", default_value = "0.2")]
+    pub fraction_invalid: f64,
+
+    /// A base must make up at least this fraction of the read depth to be considered valid
+    #[arg(short = 'v', long = "fraction_valid", default_value = "0.5")]
+    pub fraction_valid: f64,
+
+    /// Ignore alignments with more than this many mismatches and indels
+    #[arg(short = 'm', long = "max_errors", default_value = "10")]
+    pub max_errors: u32,
+
+    /// A base must occur at least this many times in the pileup to be considered valid
+    #[arg(short = 'd', long = "min_depth", default_value = "5")]
+    pub min_depth: u32,
+
+    /// Ignore any reads with multiple alignments
+    #[arg(long = "careful")]
+    pub careful: bool,
+
+    /// Write the polished assembly to this file instead of stdout
+    #[arg(short = 'o', long = "output")]
+    pub output: Option<PathBuf>,
+
+    /// Assembly to polish (FASTA or GFA format)
+    pub assembly: PathBuf,
+}
+
+
+#[derive(Args)]
+pub struct VariantsOptions {
+    /// Report an allele if it makes up at least this fraction of the position's read
+    /// sequences
+    #[arg(long = "min-frequency", default_value = "0.1")]
+    pub min_frequency: f64,
+
+    /// Report an allele only if it occurs at least this many times in the pileup
+    #[arg(long = "min-count", default_value = "2")]
+    pub min_count: f64,
+
+    /// Ignore alignments with more than this many mismatches and indels
+    #[clap(short = 'm', long = "max_errors", default_value = "10")]
+    pub max_errors: u32,
+
+    /// Ignore alignments whose mismatches and indels make up more than this fraction of the
+    /// alignment length (used instead of --max_errors)
+    #[arg(long = "max-error-rate", conflicts_with = "max_errors")]
+    pub max_error_rate: Option<f64>,
+
+    /// Ignore alignments with an alignment score (AS tag) below this value
+    #[arg(long = "min-alignment-score", allow_negative_numbers = true)]
+    pub min_alignment_score: Option<i32>,
+
+    /// Ignore alignments whose alignment score (AS tag) is more than this far below the best
+    /// score for the same read
+    #[arg(long = "max-score-diff")]
+    pub max_score_diff: Option<i32>,
+
+    /// Ignore alignments whose identity (the fraction of the alignment length without a
+    /// mismatch or indel) is below this value (used instead of --max_errors)
+    #[arg(long = "min-identity", conflicts_with_all = ["max_errors", "max_error_rate"])]
+    pub min_identity: Option<f64>,
+
+    /// Ignore any reads with multiple alignments
+    #[arg(long = "careful")]
+    pub careful: bool,
+
+    /// Sort alignments by read name (using temporary files) if they aren't grouped by read
+    #[arg(long = "auto-sort")]
+    pub auto_sort: bool,
+
+    /// Ignore this many aligned bases at each end of each read
+    #[arg(long = "trim-ends", default_value = "0")]
+    pub trim_ends: usize,
+
+    /// When an alignment ends in a homopolymer, trim the homopolymer and this many more bases
+    /// from its end
+    #[arg(long = "homopolymer-trim", default_value = "1")]
+    pub homopolymer_trim: usize,
+
+    /// Don't trim alignment ends in homopolymers (e.g. for reads with few homopolymer errors)
+    #[arg(long = "no-homopolymer-trim", conflicts_with = "homopolymer_trim")]
+    pub no_homopolymer_trim: bool,
+
+    /// Skip malformed alignment records (and stop reading a truncated file at its last good
+    /// record) instead of stopping with an error
+    #[arg(long = "skip-bad-records")]
+    pub skip_bad_records: bool,
+
+    /// Skip up to this many malformed alignment records (logging each one), stopping with an
+    /// error if there are more
+    #[arg(long = "max-bad-records", conflicts_with = "skip_bad_records")]
+    pub max_bad_records: Option<usize>,
+
+    /// Assembly (FASTA or GFA format)
+    pub assembly: PathBuf,
+
+    /// Short read alignments (one or more files in SAM, BAM or PAF format). Extra assembly
+    /// FASTA files can come before these.
+    #[arg(required = true)]
+    pub sam: Vec<PathBuf>,
+}
@@ -0,0 +1,393 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The polished assembly goes to stdout or a file, and can be compressed with bgzip (BGZF: a series
+// of gzip blocks, each holding at most 64 KiB, as described in the SAM/BAM specification). A
+// samtools faidx index (.fai, plus .gzi for bgzip) can be made as the sequences are written, so
+// the output doesn't need to be read again. With --split-output, each sequence is also written to
+// its own file, named after the sequence.
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::misc;
+use crate::misc::{quit_with_error, ErrorType, FastHashSet};
+use crate::sink::SeqSink;
+
+
+/// The most uncompressed data put in one BGZF block (the same as htslib).
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// The empty block which marks the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06,
+                            0x00, 0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+
+/// Writes the polished sequences, keeping track of where each one starts for the .fai index.
+pub struct SeqWriter {
+    writer: OutputWriter,
+    filename: Option<PathBuf>,  // None for stdout
+    offset: u64,                // uncompressed bytes written so far
+    index: Option<Vec<FaiEntry>>,
+}
+
+struct FaiEntry {
+    name: String,
+    length: u64,
+    offset: u64,
+}
+
+enum OutputWriter {
+    Plain(BufWriter<Box<dyn Write>>),
+    Bgzip(BgzfWriter<BufWriter<Box<dyn Write>>>),
+}
+
+impl SeqWriter {
+    pub fn create(filename: &Option<PathBuf>, bgzip: bool, fai: bool) -> SeqWriter {
+        let inner: Box<dyn Write> = match filename {
+            Some(f) => match File::create(f) {
+                Ok(file) => Box::new(file),
+                Err(_)   => quit_with_error(ErrorType::Io, &format!("unable to create {:?}", f)),
+            },
+            None => Box::new(io::stdout()),
+        };
+        let inner = BufWriter::new(inner);
+        let writer = if bgzip { OutputWriter::Bgzip(BgzfWriter::new(inner)) }
+                     else { OutputWriter::Plain(inner) };
+        SeqWriter { writer, filename: filename.clone(), offset: 0,
+                    index: fai.then(Vec::new) }
+    }
+
+    /// Writes a sequence's header line. Everything written with write_seq after this is counted
+    /// as that sequence in the index.
+    pub fn write_header(&mut self, name: &str, header: &str) {
+        self.write_line(header);
+        let offset = self.offset;
+        if let Some(index) = &mut self.index {
+            index.push(FaiEntry { name: name.to_string(), length: 0, offset });
+        }
+    }
+
+    /// Writes all or part of a sequence.
+    pub fn write_seq(&mut self, seq: &str) {
+        self.write_str(seq);
+        if let Some(entry) = self.index.as_mut().and_then(|i| i.last_mut()) {
+            entry.length += seq.len() as u64;
+        }
+    }
+
+    /// Writes a line which isn't part of a sequence, e.g. FASTQ qualities.
+    pub fn write_line(&mut self, text: &str) {
+        self.write_str(text);
+        self.end_line();
+    }
+
+    pub fn end_line(&mut self) {
+        self.write_str("\n");
+    }
+
+    fn write_str(&mut self, text: &str) {
+        if self.write_all(text.as_bytes()).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to write to {}", self.destination()));
+        }
+    }
+
+    pub fn destination(&self) -> String {
+        match &self.filename {
+            Some(f) => f.display().to_string(),
+            None    => "stdout".to_string(),
+        }
+    }
+
+    /// Finishes the output (including the BGZF end-of-file marker) and writes the index files.
+    pub fn finish(self) {
+        let destination = self.destination();
+        let block_offsets = match self.writer {
+            OutputWriter::Plain(mut w) => w.flush().map(|_| None),
+            OutputWriter::Bgzip(w)     => w.finish().map(Some),
+        };
+        let Ok(block_offsets) = block_offsets else {
+            quit_with_error(ErrorType::Io, &format!("unable to write to {}", destination));
+        };
+        let (Some(index), Some(filename)) = (self.index, self.filename) else { return; };
+        write_index_file(&index_filename(&filename, "fai"), |w| write_fai(w, &index));
+        if let Some(block_offsets) = block_offsets {
+            write_index_file(&index_filename(&filename, "gzi"), |w| write_gzi(w, &block_offsets));
+        }
+    }
+}
+
+
+impl SeqSink for SeqWriter {
+    fn start_seq(&mut self, name: &str, header: &str) {
+        self.write_header(name, header);
+    }
+
+    fn write_seq(&mut self, seq: &str) {
+        SeqWriter::write_seq(self, seq);
+    }
+
+    /// Ends the sequence's line, followed by the separator and quality lines for FASTQ output.
+    fn finish_seq(&mut self, quals: Option<&str>) {
+        self.end_line();
+        if let Some(quals) = quals {
+            self.write_line("+");
+            self.write_line(quals);
+        }
+    }
+
+    fn finish(self: Box<Self>) {
+        SeqWriter::finish(*self);
+    }
+}
+
+
+/// Other output (e.g. a GFA graph) can be written directly, without being indexed.
+impl Write for SeqWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = match &mut self.writer {
+            OutputWriter::Plain(w) => w.write(buf)?,
+            OutputWriter::Bgzip(w) => w.write(buf)?,
+        };
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            OutputWriter::Plain(w) => w.flush(),
+            OutputWriter::Bgzip(w) => w.flush(),
+        }
+    }
+}
+
+
+/// Writes each polished sequence to its own file in a directory (--split-output), using the same
+/// compression and indexing as the combined output.
+pub struct SplitWriter {
+    dir: PathBuf,
+    bgzip: bool,
+    fai: bool,
+    fastq: bool,
+    filenames: FastHashSet<String>,
+    current: Option<SeqWriter>,
+}
+
+impl SplitWriter {
+    pub fn create(dir: &Path, bgzip: bool, fai: bool, fastq: bool) -> SplitWriter {
+        if fs::create_dir_all(dir).is_err() {
+            quit_with_error(ErrorType::Io, &format!("unable to create directory {:?}", dir));
+        }
+        SplitWriter { dir: dir.to_path_buf(), bgzip, fai, fastq,
+                      filenames: FastHashSet::default(), current: None }
+    }
+}
+
+impl SeqSink for SplitWriter {
+    /// Starts a new file for the sequence and writes its header line.
+    fn start_seq(&mut self, name: &str, header: &str) {
+        let filename = split_filename(name, self.fastq, self.bgzip);
+        if !self.filenames.insert(filename.clone()) {
+            quit_with_error(ErrorType::Input,
+                            &format!("more than one sequence would be written to {}", filename));
+        }
+        let mut writer = SeqWriter::create(&Some(self.dir.join(filename)), self.bgzip, self.fai);
+        writer.write_header(name, header);
+        self.current = Some(writer);
+    }
+
+    fn write_seq(&mut self, seq: &str) {
+        if let Some(writer) = &mut self.current {
+            SeqWriter::write_seq(writer, seq);
+        }
+    }
+
+    fn finish_seq(&mut self, quals: Option<&str>) {
+        if let Some(mut writer) = self.current.take() {
+            SeqSink::finish_seq(&mut writer, quals);
+            writer.finish();
+        }
+    }
+}
+
+
+fn split_filename(name: &str, fastq: bool, bgzip: bool) -> String {
+    format!("{}.{}{}", misc::safe_filename(name), if fastq { "fastq" } else { "fasta" },
+            if bgzip { ".gz" } else { "" })
+}
+
+
+/// Index files go alongside the output, e.g. assembly.fasta.gz.fai.
+pub fn index_filename(filename: &Path, extension: &str) -> PathBuf {
+    let mut name = filename.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+
+fn write_index_file(filename: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) {
+    let result = File::create(filename).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.flush()
+    });
+    if result.is_err() {
+        quit_with_error(ErrorType::Io, &format!("unable to write {:?}", filename));
+    }
+}
+
+
+/// Each sequence is on a single line, so its line length is the sequence length.
+fn write_fai(writer: &mut impl Write, index: &[FaiEntry]) -> io::Result<()> {
+    for e in index {
+        writeln!(writer, "{}\t{}\t{}\t{}\t{}", e.name, e.length, e.offset, e.length, e.length + 1)?;
+    }
+    Ok(())
+}
+
+
+/// The .gzi format: the number of entries, then compressed/uncompressed offset pairs for the start
+/// of each block after the first, all as little-endian 64-bit integers.
+fn write_gzi(writer: &mut impl Write, block_offsets: &[(u64, u64)]) -> io::Result<()> {
+    let offsets = &block_offsets[1.min(block_offsets.len())..];
+    writer.write_all(&(offsets.len() as u64).to_le_bytes())?;
+    for (compressed, uncompressed) in offsets {
+        writer.write_all(&compressed.to_le_bytes())?;
+        writer.write_all(&uncompressed.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+
+/// Compresses data into BGZF blocks, recording where each block starts.
+struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+    block_offsets: Vec<(u64, u64)>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter { inner, buffer: Vec::with_capacity(BGZF_BLOCK_SIZE), compressed_offset: 0,
+                     uncompressed_offset: 0, block_offsets: Vec::new() }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let block = compress_block(&self.buffer)?;
+        self.inner.write_all(&block)?;
+        self.block_offsets.push((self.compressed_offset, self.uncompressed_offset));
+        self.compressed_offset += block.len() as u64;
+        self.uncompressed_offset += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes any remaining data and the end-of-file marker, returning the block offsets.
+    fn finish(mut self) -> io::Result<Vec<(u64, u64)>> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(self.block_offsets)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..count]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// Makes one BGZF block: a gzip member whose header has a BC extra field giving the block size.
+fn compress_block(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    let mut crc = Crc::new();
+    crc.update(data);
+    let block_size = (18 + compressed.len() + 8 - 1) as u16;
+    let mut block = Vec::with_capacity(26 + compressed.len());
+    block.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06,
+                              0x00, 0x42, 0x43, 0x02, 0x00]);
+    block.extend_from_slice(&block_size.to_le_bytes());
+    block.extend_from_slice(&compressed);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::MultiGzDecoder;
+    use super::*;
+
+    #[test]
+    fn test_bgzf_round_trip() {
+        let data: Vec<u8> = (0..200000).map(|i| b"ACGT"[(i * 7 + i / 13) % 4]).collect();
+        let mut bytes = Vec::new();
+        let mut writer = BgzfWriter::new(&mut bytes);
+        writer.write_all(&data).unwrap();
+        let offsets = writer.finish().unwrap();
+        assert_eq!(offsets.len(), 4);
+        assert_eq!(offsets[0], (0, 0));
+        assert_eq!(offsets[1].1, BGZF_BLOCK_SIZE as u64);
+        let first_block_size = u16::from_le_bytes([bytes[16], bytes[17]]) as u64 + 1;
+        assert_eq!(offsets[1].0, first_block_size);
+        assert!(bytes.ends_with(&BGZF_EOF));
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&bytes[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_split_filename() {
+        assert_eq!(split_filename("chromosome", false, false), "chromosome.fasta");
+        assert_eq!(split_filename("plasmid 1", true, false), "plasmid_1.fastq");
+        assert_eq!(split_filename("contig/2", false, true), "contig_2.fasta.gz");
+    }
+
+    #[test]
+    fn test_write_fai() {
+        let index = vec![FaiEntry { name: "a".to_string(), length: 10, offset: 14 },
+                         FaiEntry { name: "b".to_string(), length: 5, offset: 40 }];
+        let mut out = Vec::new();
+        write_fai(&mut out, &index).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a\t10\t14\t10\t11\nb\t5\t40\t5\t6\n");
+    }
+
+    #[test]
+    fn test_index_filename() {
+        assert_eq!(index_filename(Path::new("out/polished.fasta.gz"), "gzi"),
+                   PathBuf::from("out/polished.fasta.gz.gzi"));
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// PAF input (e.g. from minimap2 -c). Like BAM, each record is converted to a SAM line, so the
+// rest of Polypolish only has to deal with SAM. PAF has no read sequence, so the aligned part of
+// each read is rebuilt from the long form of the cs tag (minimap2 --cs=long), and the CIGAR comes
+// from the cg tag. Clipped parts of the read are given as Ns. The format is described here:
+// https://github.com/lh3/miniasm/blob/master/PAF.md
+
+use flate2::read::GzDecoder;
+
+use std::fs::File;
+use std::io;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+
+use crate::misc::{is_file_gzipped, quit_with_error, ErrorType};
+
+
+/// Returns true if the file's first line looks like a PAF record: at least 12 tab-delimited
+/// columns, with the strand in the fifth column and numbers where PAF has them.
+pub fn is_paf(filename: &Path) -> bool {
+    let Ok(file) = File::open(filename) else { return false; };
+    let mut first_line = String::new();
+    let read_result = if is_file_gzipped(filename) {
+        BufReader::new(GzDecoder::new(file)).read_line(&mut first_line)
+    } else {
+        BufReader::new(file).read_line(&mut first_line)
+    };
+    read_result.is_ok() && looks_like_paf(first_line.trim_end())
+}
+
+
+fn looks_like_paf(line: &str) -> bool {
+    let parts: Vec<&str> = line.split('\t').collect();
+    parts.len() >= 12 && (parts[4] == "+" || parts[4] == "-") &&
+        [1, 2, 3, 6, 7, 8, 9, 10, 11].iter().all(|&i| parts[i].parse::<u64>().is_ok())
+}
+
+
+/// Iterates over the lines of a PAF file in SAM format, one line per PAF record.
+pub struct PafLines<I: Iterator<Item = io::Result<String>>> {
+    lines: I,
+}
+
+impl<I: Iterator<Item = io::Result<String>>> PafLines<I> {
+    pub fn new(lines: I) -> PafLines<I> {
+        PafLines { lines }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<String>>> Iterator for PafLines<I> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.lines.next()? {
+                Ok(line) if line.is_empty() => continue,
+                Ok(line) => return Some(paf_to_sam(&line)),
+                Err(e)   => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+
+/// Converts one PAF record to a SAM line. Records without a target (minimap2 --paf-no-hit)
+/// become unmapped reads.
+fn paf_to_sam(line: &str) -> io::Result<String> {
+    let parts: Vec<&str> = line.split('\t').collect();
+    if parts.len() < 12 {
+        return Err(invalid_data("PAF record has fewer than 12 columns"));
+    }
+    let read_name = parts[0];
+    if parts[5] == "*" {
+        return Ok(format!("{}\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*", read_name));
+    }
+    let number = |i: usize| parts[i].parse::<usize>()
+        .map_err(|_| invalid_data("PAF record has a non-numeric column"));
+    let (read_len, read_start, read_end) = (number(1)?, number(2)?, number(3)?);
+    let (ref_start, mapq) = (number(7)?, number(11)?);
+    if read_start > read_end || read_end > read_len {
+        return Err(invalid_data("PAF record has invalid read coordinates"));
+    }
+    let forward = match parts[4] {
+        "+" => true,
+        "-" => false,
+        _   => return Err(invalid_data("PAF record has an invalid strand")),
+    };
+
+    let (mut cg, mut cs, mut secondary, mut score) = (None, None, false, None);
+    for tag in &parts[12..] {
+        if let Some(value) = tag.strip_prefix("cg:Z:") {
+            cg = Some(value);
+        } else if let Some(value) = tag.strip_prefix("cs:Z:") {
+            cs = Some(value);
+        } else if let Some(value) = tag.strip_prefix("tp:A:") {
+            secondary = value != "P";
+        } else if tag.starts_with("AS:i:") {
+            score = Some(*tag);
+        }
+    }
+    let Some(cg) = cg else { missing_tags_error("cg") };
+    let Some(cs) = cs else { missing_tags_error("cs") };
+    let (aligned_seq, edit_distance) = parse_long_cs(cs)?;
+
+    // The cg and cs tags are in the reference's orientation, so for reverse-strand alignments the
+    // clip at the start of the read goes at the end.
+    let (start_clip, end_clip) = if forward { (read_start, read_len - read_end) }
+                                 else { (read_len - read_end, read_start) };
+    let mut cigar = String::new();
+    let mut seq = String::with_capacity(read_len);
+    if start_clip > 0 {
+        cigar.push_str(&format!("{}S", start_clip));
+        seq.push_str(&"N".repeat(start_clip));
+    }
+    cigar.push_str(cg);
+    seq.push_str(&aligned_seq);
+    if end_clip > 0 {
+        cigar.push_str(&format!("{}S", end_clip));
+        seq.push_str(&"N".repeat(end_clip));
+    }
+
+    let mut flag = if forward { 0 } else { 16 };
+    if secondary {
+        flag += 256;
+    }
+    let mut sam_line = format!("{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t*\tNM:i:{}", read_name, flag,
+                               parts[5], ref_start + 1, mapq, cigar, seq, edit_distance);
+    if let Some(score) = score {
+        sam_line.push_str(&format!("\t{}", score));
+    }
+    Ok(sam_line)
+}
+
+
+/// Rebuilds the aligned part of the read (in the reference's orientation) from a long-form cs tag
+/// and also returns the alignment's edit distance.
+fn parse_long_cs(cs: &str) -> io::Result<(String, u32)> {
+    let mut seq = String::new();
+    let mut edit_distance = 0;
+    let mut chars = cs.chars().peekable();
+    while let Some(op) = chars.next() {
+        let mut bases = String::new();
+        while let Some(&c) = chars.peek() {
+            if matches!(c, '=' | ':' | '*' | '+' | '-' | '~') {
+                break;
+            }
+            bases.push(c.to_ascii_uppercase());
+            chars.next();
+        }
+        match op {
+            '=' => seq.push_str(&bases),
+            '*' => {
+                if bases.len() != 2 {
+                    return Err(invalid_data("PAF record has an invalid cs tag substitution"));
+                }
+                seq.push_str(&bases[1..]);
+                edit_distance += 1;
+            },
+            '+' => {
+                edit_distance += bases.len() as u32;
+                seq.push_str(&bases);
+            },
+            '-' => edit_distance += bases.len() as u32,
+            ':' => missing_tags_error("long-form cs"),
+            _   => return Err(invalid_data("PAF record has an unsupported cs tag operation")),
+        }
+    }
+    Ok((seq, edit_distance))
+}
+
+
+fn missing_tags_error(tag: &str) -> ! {
+    quit_with_error(ErrorType::Input, &format!("PAF alignments need the {} tag, as PAF doesn't \
+                                                contain read sequences (align with minimap2 -c \
+                                                --cs=long)", tag));
+}
+
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_paf() {
+        assert!(looks_like_paf("r1\t10\t0\t10\t+\tctg\t100\t20\t30\t10\t10\t60\tcg:Z:10M"));
+        assert!(!looks_like_paf("r1\t10\t0\t10\t+\tctg\t100\t20\t30\t10\t10"));
+        assert!(!looks_like_paf("r1\t0\tctg\t21\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\tNM:i:0"));
+        assert!(!looks_like_paf("@HD\tVN:1.6\tSO:coordinate"));
+    }
+
+    #[test]
+    fn test_parse_long_cs() {
+        assert_eq!(parse_long_cs("=ACGT").unwrap(), ("ACGT".to_string(), 0));
+        assert_eq!(parse_long_cs("=AC*ga=T+tt=A-cc=G").unwrap(), ("ACATTTAG".to_string(), 5));
+        assert!(parse_long_cs("=AC*g=T").is_err());
+        assert!(parse_long_cs("=AC~gt10ag=T").is_err());
+    }
+
+    #[test]
+    fn test_paf_to_sam() {
+        let paf = "r1\t12\t1\t11\t+\tctg\t100\t20\t29\t8\t11\t60\ttp:A:P\tcg:Z:3M1I3M1D3M\t\
+                   cs:Z:=ACG+t=ACG-a=A*tc=A\tAS:i:5";
+        assert_eq!(paf_to_sam(paf).unwrap(),
+                   "r1\t0\tctg\t21\t60\t1S3M1I3M1D3M1S\t*\t0\t0\tNACGTACGACAN\t*\tNM:i:3\tAS:i:5");
+
+        let paf = "r1\t12\t0\t10\t-\tctg\t100\t20\t30\t10\t10\t0\ttp:A:S\tcg:Z:10M\t\
+                   cs:Z:=ACGTACGTAC";
+        assert_eq!(paf_to_sam(paf).unwrap(),
+                   "r1\t272\tctg\t21\t0\t2S10M\t*\t0\t0\tNNACGTACGTAC\t*\tNM:i:0");
+
+        assert_eq!(paf_to_sam("r2\t12\t0\t0\t*\t*\t0\t0\t0\t0\t0\t255").unwrap(),
+                   "r2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*");
+        assert!(paf_to_sam("r1\t12\t0\t10\t+\tctg").is_err());
+    }
+}
@@ -9,19 +9,172 @@
 // Public License for more details. You should have received a copy of the GNU General Public
 // License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::alignment::Alignment;
-use crate::misc::bankers_rounding;
+use crate::alignment::{trim_bases_for_homopolymers, Alignment};
+use crate::misc::{bankers_rounding, binomial_tail_phred, ln_gamma, median, FastHashMap};
 
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 
+use std::collections::VecDeque;
+use std::sync::Arc;
 
+
+/// Change confidences are capped at this value, as higher ones aren't meaningfully different.
+pub const MAX_CONFIDENCE: f64 = 60.0;
+
+/// Minimum depths scaled down for low-depth regions don't go below this value.
+pub const MIN_SCALED_DEPTH: u32 = 2;
+
+/// Long reads (--long-reads) only break ties between short-read sequences, so their own call must
+/// be clear: enough depth, most of their sequences agreeing and no other sequence close.
+const LONG_READ_MIN_DEPTH: u32 = 5;
+const LONG_READ_FRACTION_VALID: f64 = 0.7;
+const LONG_READ_FRACTION_INVALID: f64 = 0.3;
+
+/// The Bayesian model (--model bayes) treats base qualities above this as this (an error rate of
+/// 5%), as errors from other sources (e.g. mis-mapped reads) put a floor on the error rate.
+/// Sequences without a base quality (indels and reads without qualities) also get this value.
+const MAX_BAYES_QUAL: f32 = 13.0;
+
+/// The Bayesian model's prior probabilities (relative to the original sequence) for each other
+/// sequence and for a mixture of sequences (e.g. from repeat copies which differ), and the
+/// Dirichlet concentration used for a mixture's sequence frequencies.
+const CHANGE_PRIOR: f64 = 1e-3;
+const MIXTURE_PRIOR: f64 = 1e-6;
+const MIXTURE_CONCENTRATION: f64 = 5.0;
+
+
+/// The thresholds which decide a base's polished sequence. Indels (insertions and deletions) can
+/// have a stricter valid fraction and minimum depth than substitutions, as they are undercounted
+/// near homopolymers. Each type of change can also be disallowed entirely. With a minimum
+/// posterior (--model bayes), the Bayesian model is used instead of the depths and fractions.
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub min_depth: u32,
+    pub fraction_valid: f64,
+    pub fraction_invalid: f64,
+    pub indel_min_depth: u32,
+    pub indel_fraction_valid: f64,
+    pub allow_substitutions: bool,
+    pub allow_insertions: bool,
+    pub allow_deletions: bool,
+    pub low_complexity_fraction_valid: Option<f64>,  // stricter valid fraction, if given
+    pub min_samples: usize,  // samples which must each make a change on their own (0 to not check)
+    pub relative_min_depth: Option<f64>,  // minimum depth as a fraction of local depth, if given
+    pub min_posterior: Option<f64>,  // for the Bayesian model (None for depths and fractions)
+    pub joint_indels: bool,  // adjacent deleted bases are called as one event (--joint-indels)
+}
+
+impl Thresholds {
+    /// Thresholds which treat indels the same as substitutions.
+    pub fn new(min_depth: u32, fraction_valid: f64, fraction_invalid: f64) -> Thresholds {
+        Thresholds { min_depth, fraction_valid, fraction_invalid,
+                     indel_min_depth: min_depth, indel_fraction_valid: fraction_valid,
+                     allow_substitutions: true, allow_insertions: true, allow_deletions: true,
+                     low_complexity_fraction_valid: None, min_samples: 0,
+                     relative_min_depth: None, min_posterior: None, joint_indels: false }
+    }
+
+    /// Whether the thresholds allow a base to be changed to the given sequence. A multi-base
+    /// sequence is an insertion, and also a substitution if its first base differs.
+    fn allows_change(&self, original: &str, new_seq: &str) -> bool {
+        if new_seq == "-" {
+            return self.allow_deletions;
+        }
+        let substitution = !new_seq.starts_with(original);
+        let insertion = new_seq.len() > 1;
+        (!substitution || self.allow_substitutions) && (!insertion || self.allow_insertions)
+    }
+
+    /// Thresholds for a low-complexity region, which use the stricter valid fraction (if one was
+    /// given) for both substitutions and indels.
+    pub fn for_low_complexity(&self) -> Thresholds {
+        let Some(fraction_valid) = self.low_complexity_fraction_valid else { return *self; };
+        Thresholds { fraction_valid: self.fraction_valid.max(fraction_valid),
+                     indel_fraction_valid: self.indel_fraction_valid.max(fraction_valid),
+                     ..*self }
+    }
+
+    /// Thresholds for a region whose depth is lower than is typical for the assembly (e.g. due to
+    /// GC bias), with the minimum depths scaled down in proportion. They are never scaled up, and
+    /// not below MIN_SCALED_DEPTH. With a relative minimum depth, the typical depth isn't used:
+    /// the minimum depths follow the local depth, up or down.
+    pub fn scaled_for_depth(&self, local_depth: f64, typical_depth: f64) -> Thresholds {
+        if let Some(fraction) = self.relative_min_depth {
+            return self.relative_to_depth(local_depth, fraction);
+        }
+        if typical_depth <= 0.0 || local_depth >= typical_depth {
+            return *self;
+        }
+        let scale = local_depth / typical_depth;
+        let scale_depth = |depth: u32| {
+            bankers_rounding(depth as f64 * scale).max(MIN_SCALED_DEPTH.min(depth))
+        };
+        Thresholds { min_depth: scale_depth(self.min_depth),
+                     indel_min_depth: scale_depth(self.indel_min_depth), ..*self }
+    }
+
+    /// Thresholds with the minimum depth set to a fraction of the local depth, not below
+    /// MIN_SCALED_DEPTH. The indel minimum depth keeps its ratio to the minimum depth.
+    fn relative_to_depth(&self, local_depth: f64, fraction: f64) -> Thresholds {
+        let min_depth = bankers_rounding(local_depth * fraction)
+            .max(MIN_SCALED_DEPTH.min(self.min_depth));
+        let indel_ratio = self.indel_min_depth as f64 / self.min_depth.max(1) as f64;
+        let indel_min_depth = bankers_rounding(min_depth as f64 * indel_ratio)
+            .max(MIN_SCALED_DEPTH.min(self.indel_min_depth));
+        Thresholds { min_depth, indel_min_depth, ..*self }
+    }
+
+    /// Thresholds for a high-copy sequence (e.g. a small plasmid), with the minimum depths scaled
+    /// up by its copy number, so they stay in proportion to its read depth.
+    pub fn for_copy_number(&self, copy_number: f64) -> Thresholds {
+        if copy_number <= 1.0 {
+            return *self;
+        }
+        let scale_depth = |depth: u32| bankers_rounding(depth as f64 * copy_number);
+        Thresholds { min_depth: scale_depth(self.min_depth),
+                     indel_min_depth: scale_depth(self.indel_min_depth), ..*self }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
 pub enum BaseStatus {
     DepthTooLow,          // not enough read depth (not changed)
     NoValidOptions,       // no sequences pass the valid threshold (not changed)
     MultipleValidOptions, // multiple sequences pass the valid threshold (not changed)
     TooClose,             // there is one or more almost-valid sequences (not changed)
+    NotAllowed,           // one valid sequence but its type of change is disallowed (not changed)
+    TooFewSamples,        // one valid sequence but not enough samples agree on it (not changed)
     OriginalBaseKept,     // one valid sequence and it matches the original base
     Changed,              // one valid sequence and it differs from the original base
+    LongReadChanged,      // ambiguous in short reads, but changed to the long reads' choice
+}
+
+impl BaseStatus {
+    pub fn is_change(&self) -> bool {
+        matches!(self, BaseStatus::Changed | BaseStatus::LongReadChanged)
+    }
+
+    /// True if the short reads didn't clearly support one sequence (even if long reads did).
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self, BaseStatus::NoValidOptions | BaseStatus::MultipleValidOptions |
+                       BaseStatus::TooClose | BaseStatus::LongReadChanged)
+    }
+
+    /// The status's name, as used in the debug output (and by --debug-filter).
+    pub fn name(&self) -> &'static str {
+        match self {
+            BaseStatus::OriginalBaseKept     => "kept",
+            BaseStatus::Changed              => "changed",
+            BaseStatus::DepthTooLow          => "low_depth",
+            BaseStatus::NoValidOptions       => "none",
+            BaseStatus::MultipleValidOptions => "multiple",
+            BaseStatus::TooClose             => "too_close",
+            BaseStatus::NotAllowed           => "not_allowed",
+            BaseStatus::TooFewSamples        => "few_samples",
+            BaseStatus::LongReadChanged      => "long_read",
+        }
+    }
 }
 
 
@@ -30,80 +183,318 @@ pub struct PileupBase {
     original: char,
     pub depth: f64,
 
-    // A, C, G and T are the most common sequences, so we count them with integers (fast):
-    count_a: u32,
-    count_c: u32,
-    count_g: u32,
-    count_t: u32,
+    // Counts are weighted by their alignment file's --weights value (1 by default).
+
+    // A, C, G and T are the most common sequences, so we count them with their own fields (fast):
+    count_a: f64,
+    count_c: f64,
+    count_g: f64,
+    count_t: f64,
 
     // Everything else will be counted in a HashMap (slower but can handle any sequence):
-    counts: HashMap<String, u32>,
+    counts: FastHashMap<String, f64>,
+
+    // Base qualities (capped at MAX_BAYES_QUAL and weighted like the counts) summed for each of
+    // A, C, G and T, for the Bayesian model. They are f32 to save memory.
+    qual_sums: [f32; 4],
+
+    multi_mapped: f64,      // count of sequences from reads with multiple alignments
+    seq_count: u32,         // total number of sequences added
+    pub skipped_count: u32, // sequences not added because the base was at --max-depth
+
+    // The name of the read behind each added sequence (only kept for --debug-reads):
+    read_names: Option<Vec<(String, Arc<str>)>>,
 }
 
 impl PileupBase {
     fn new(original: char) -> PileupBase {
         PileupBase {
-            original: original,
+            original,
             depth: 0.0,
-            count_a: 0,
-            count_c: 0,
-            count_g: 0,
-            count_t: 0,
-            counts: HashMap::new(),
+            count_a: 0.0,
+            count_c: 0.0,
+            count_g: 0.0,
+            count_t: 0.0,
+            counts: FastHashMap::default(),
+            qual_sums: [0.0; 4],
+            multi_mapped: 0.0,
+            seq_count: 0,
+            skipped_count: 0,
+            read_names: None,
         }
     }
 
-    pub fn add_seq(&mut self, seq: &str, depth_contribution: f64) {
+    /// Adds a sequence, with its count and depth contribution scaled by the given weight.
+    pub fn add_seq(&mut self, seq: &str, depth_contribution: f64, weight: f64) {
+        self.add_seq_with_qual(seq, None, depth_contribution, weight);
+    }
+
+    /// Adds a sequence with its base quality (only used for single bases).
+    pub fn add_seq_with_qual(&mut self, seq: &str, qual: Option<u8>, depth_contribution: f64,
+                             weight: f64) {
+        let qual = qual.map_or(MAX_BAYES_QUAL, |q| (q as f32).min(MAX_BAYES_QUAL)) * weight as f32;
         match seq {
-            "A" => {self.count_a += 1},
-            "C" => {self.count_c += 1},
-            "G" => {self.count_g += 1},
-            "T" => {self.count_t += 1},
-             _  => {*self.counts.entry(seq.to_string()).or_insert(0) += 1},
+            "A" => {self.count_a += weight; self.qual_sums[0] += qual},
+            "C" => {self.count_c += weight; self.qual_sums[1] += qual},
+            "G" => {self.count_g += weight; self.qual_sums[2] += qual},
+            "T" => {self.count_t += weight; self.qual_sums[3] += qual},
+             _  => {*self.counts.entry(seq.to_string()).or_insert(0.0) += weight},
+        }
+        self.depth += depth_contribution * weight;
+        if depth_contribution < 1.0 {
+            self.multi_mapped += weight;
+        }
+        self.seq_count += 1;
+    }
+
+    /// Adds a sequence unless the base already has max_depth sequences, in which case it is only
+    /// counted as skipped. If a read name is given, it is kept with the sequence.
+    fn add_seq_capped(&mut self, seq: &str, qual: Option<u8>, depth_contribution: f64,
+                      weight: f64, max_depth: u32, read_name: Option<&Arc<str>>) {
+        if self.seq_count >= max_depth {
+            self.skipped_count += 1;
+            return;
+        }
+        self.add_seq_with_qual(seq, qual, depth_contribution, weight);
+        if let Some(read_name) = read_name {
+            self.read_names.get_or_insert_with(Vec::new).push((seq.to_string(),
+                                                               read_name.clone()));
         }
-        self.depth += depth_contribution;
     }
 
-    pub fn get_polished_seq(&self, min_depth: u32, fraction_valid: f64, fraction_invalid: f64,
-                            build_debug_line: bool) -> (String, BaseStatus, String) {
+    /// Adds all of another base's sequences (e.g. from another sample) to this one.
+    pub fn merge(&mut self, other: &PileupBase) {
+        self.count_a += other.count_a;
+        self.count_c += other.count_c;
+        self.count_g += other.count_g;
+        self.count_t += other.count_t;
+        for (seq, count) in &other.counts {
+            *self.counts.entry(seq.clone()).or_insert(0.0) += count;
+        }
+        for (qual_sum, other_qual_sum) in self.qual_sums.iter_mut().zip(other.qual_sums) {
+            *qual_sum += other_qual_sum;
+        }
+        self.depth += other.depth;
+        self.multi_mapped += other.multi_mapped;
+        self.seq_count += other.seq_count;
+        self.skipped_count += other.skipped_count;
+        if let Some(read_names) = &other.read_names {
+            self.read_names.get_or_insert_with(Vec::new).extend(read_names.iter().cloned());
+        }
+    }
+
+    pub fn original(&self) -> char {
+        self.original
+    }
+
+    /// Returns the names of the reads behind each sequence, e.g. "A:read_1,read_2;AT:read_3"
+    /// (used in the debug output).
+    pub fn get_read_names_str(&self) -> String {
+        let Some(read_names) = &self.read_names else { return String::new(); };
+        let mut seqs: Vec<&str> = read_names.iter().map(|(seq, _)| seq.as_str()).collect();
+        seqs.sort();
+        seqs.dedup();
+        seqs.iter().map(|seq| {
+            let names: Vec<&str> = read_names.iter().filter(|(s, _)| s == seq)
+                .map(|(_, name)| name.as_ref()).collect();
+            format!("{}:{}", seq, names.join(","))
+        }).collect::<Vec<_>>().join(";")
+    }
+
+    /// Returns the base's counts as one line of a saved pileup, e.g.
+    /// "A\t31.5\t0\tA:30,AT:2\t4\t390,0,0,0" for the original base, depth, skipped count, the count
+    /// of each sequence, the count from multi-mapped reads and the A/C/G/T base quality sums. Read
+    /// names are not included.
+    pub fn saved_line(&self) -> String {
+        let mut counts: Vec<(&str, f64)> = vec![("A", self.count_a), ("C", self.count_c),
+                                                ("G", self.count_g), ("T", self.count_t)];
+        counts.extend(self.counts.iter().map(|(seq, count)| (seq.as_str(), *count)));
+        counts.retain(|(_, count)| *count > 0.0);
+        counts.sort_by(|a, b| a.0.cmp(b.0));
+        let counts: Vec<String> = counts.iter()
+            .map(|(seq, count)| format!("{}:{}", seq, count)).collect();
+        let qual_sums: Vec<String> = self.qual_sums.iter().map(|q| q.to_string()).collect();
+        format!("{}\t{}\t{}\t{}\t{}\t{}", self.original, self.depth, self.skipped_count,
+                counts.join(","), self.multi_mapped, qual_sums.join(","))
+    }
+
+    /// Replaces the base's counts with those from a line made by saved_line. Returns false
+    /// (leaving the base unchanged) if the line is malformed or is for a different original base.
+    /// Lines saved before the multi-mapped count or base quality sums were added (without their
+    /// columns) are accepted, with each base given the maximum quality.
+    pub fn restore(&mut self, line: &str) -> bool {
+        let parts: Vec<&str> = line.split('\t').collect();
+        if !(4..=6).contains(&parts.len()) || !parts[0].chars().eq(std::iter::once(self.original)) {
+            return false;
+        }
+        let (Ok(depth), Ok(skipped_count)) = (parts[1].parse(), parts[2].parse()) else {
+            return false;
+        };
+        let mut restored = PileupBase::new(self.original);
+        restored.depth = depth;
+        restored.skipped_count = skipped_count;
+        if let Some(multi_mapped) = parts.get(4) {
+            let Ok(multi_mapped) = multi_mapped.parse() else { return false; };
+            restored.multi_mapped = multi_mapped;
+        }
+        for seq_count in parts[3].split(',').filter(|s| !s.is_empty()) {
+            let Some((seq, count)) = seq_count.split_once(':') else { return false; };
+            let Ok(count) = count.parse::<f64>() else { return false; };
+            match seq {
+                "A" => restored.count_a = count,
+                "C" => restored.count_c = count,
+                "G" => restored.count_g = count,
+                "T" => restored.count_t = count,
+                 _  => {restored.counts.insert(seq.to_string(), count);},
+            }
+            restored.seq_count += count.round() as u32;
+        }
+        match parts.get(5) {
+            Some(qual_sums) => {
+                let qual_sums: Vec<f32> = match qual_sums.split(',').map(|q| q.parse()).collect() {
+                    Ok(qual_sums) => qual_sums,
+                    Err(_)        => return false,
+                };
+                let Ok(qual_sums) = qual_sums.try_into() else { return false; };
+                restored.qual_sums = qual_sums;
+            },
+            None => {
+                let counts = [restored.count_a, restored.count_c, restored.count_g,
+                              restored.count_t];
+                restored.qual_sums = counts.map(|count| count as f32 * MAX_BAYES_QUAL);
+            },
+        }
+        *self = restored;
+        true
+    }
+
+    /// Returns the base's polished sequence, its status and (if requested) its summary. When
+    /// thresholds.min_samples is set, a change is only made if at least that many of the samples'
+    /// bases (at the same position) would make it on their own. If the short reads are ambiguous
+    /// and a long-read base is given, its sequence is used if the short reads also support it.
+    pub fn get_polished_seq(&self, thresholds: &Thresholds, samples: &[&PileupBase],
+                            long_read: Option<&PileupBase>,
+                            summarise: bool) -> (String, BaseStatus, Option<BaseSummary>) {
+        let (mut new_base, mut status, valid_threshold, invalid_threshold) =
+            self.call_seq(thresholds);
+        if let Some(long_read) = long_read {
+            if let Some(seq) = self.long_read_choice(long_read, thresholds, &status,
+                                                     invalid_threshold) {
+                new_base = seq;
+                status = BaseStatus::LongReadChanged;
+            }
+        }
+        if matches!(status, BaseStatus::Changed) && thresholds.min_samples > 0 {
+            let agreeing = samples.iter().filter(|s| {
+                let (seq, status, _, _) = s.call_seq(thresholds);
+                matches!(status, BaseStatus::Changed) && seq == new_base
+            }).count();
+            if agreeing < thresholds.min_samples {
+                new_base = self.original.to_string();
+                status = BaseStatus::TooFewSamples;
+            }
+        }
+        let summary = summarise.then(|| self.get_summary(valid_threshold, invalid_threshold,
+                                                         &status, &new_base));
+        (new_base, status, summary)
+    }
+
+    /// Replaces the base's call (from get_polished_seq) with its deletion event's: deleted if the
+    /// event was accepted, and otherwise not deleted (the original base is kept where the base
+    /// alone would have been deleted).
+    pub fn apply_deletion_event(&self, event: &DeletionEvent,
+                                call: (String, BaseStatus, Option<BaseSummary>))
+            -> (String, BaseStatus, Option<BaseSummary>) {
+        let (seq, status, summary) = call;
+        let (seq, status) = if event.accepted {
+            ("-".to_string(), BaseStatus::Changed)
+        } else if seq == "-" {
+            (self.original.to_string(), event.status)
+        } else {
+            return (seq, status, summary);
+        };
+        let summary = summary.map(|s| self.get_summary(s.valid, s.invalid, &status, &seq));
+        (seq, status, summary)
+    }
+
+    /// Whether enough of the base's reads delete it for it to be part of a deletion event: at least
+    /// the invalid fraction of its depth.
+    pub fn has_deletion_support(&self, thresholds: &Thresholds) -> bool {
+        let count = self.count_of("-");
+        count > 0.0 && count >= self.depth * thresholds.fraction_invalid
+    }
+
+    /// For a base whose short reads gave multiple valid sequences (or one with another too close),
+    /// returns the long reads' sequence if they clearly choose one which differs from the original
+    /// base and has at least the invalid threshold of short-read support.
+    fn long_read_choice(&self, long_read: &PileupBase, thresholds: &Thresholds, status: &BaseStatus,
+                        invalid_threshold: u32) -> Option<String> {
+        if !matches!(status, BaseStatus::MultipleValidOptions | BaseStatus::TooClose) {
+            return None;
+        }
+        let long_read_thresholds = Thresholds::new(LONG_READ_MIN_DEPTH, LONG_READ_FRACTION_VALID,
+                                                   LONG_READ_FRACTION_INVALID);
+        let (seq, long_read_status, _, _) = long_read.call_seq(&long_read_thresholds);
+        let original = self.original.to_string();
+        let chosen = matches!(long_read_status, BaseStatus::Changed | BaseStatus::OriginalBaseKept);
+        let support = self.count_of(&seq);
+        (chosen && seq != original && support > 0.0 && support >= invalid_threshold as f64 &&
+         thresholds.allows_change(&original, &seq)).then_some(seq)
+    }
+
+    /// Returns the base's polished sequence and status, along with the valid and invalid
+    /// thresholds used.
+    fn call_seq(&self, thresholds: &Thresholds) -> (String, BaseStatus, u32, u32) {
+        if let Some(min_posterior) = thresholds.min_posterior {
+            return self.call_seq_bayes(thresholds, min_posterior);
+        }
+        let Thresholds { min_depth, fraction_valid, fraction_invalid, indel_min_depth,
+                         indel_fraction_valid, .. } = *thresholds;
         let original = self.original.to_string();
         let valid_threshold = std::cmp::max(min_depth,
                                             bankers_rounding(self.depth * fraction_valid));
+        let indel_valid_threshold = std::cmp::max(indel_min_depth,
+                                                  bankers_rounding(self.depth *
+                                                                   indel_fraction_valid));
         let invalid_threshold = bankers_rounding(self.depth * fraction_invalid);
+        let (valid, indel_valid, invalid) = (valid_threshold as f64, indel_valid_threshold as f64,
+                                             invalid_threshold as f64);
 
         let mut valid_seqs = Vec::new();  // holds sequences above the valid threshold
         let mut intermediate_seqs = Vec::new();  // holds sequences between the two thresholds
 
-        if self.count_a >= valid_threshold {
+        if self.count_a >= valid {
             valid_seqs.push("A".to_string());
-        } else if self.count_a >= invalid_threshold {
+        } else if self.count_a >= invalid {
             intermediate_seqs.push("A".to_string());
         }
 
-        if self.count_c >= valid_threshold {
+        if self.count_c >= valid {
             valid_seqs.push("C".to_string());
-        } else if self.count_c >= invalid_threshold {
+        } else if self.count_c >= invalid {
             intermediate_seqs.push("C".to_string());
         }
 
-        if self.count_g >= valid_threshold {
+        if self.count_g >= valid {
             valid_seqs.push("G".to_string());
-        } else if self.count_g >= invalid_threshold {
+        } else if self.count_g >= invalid {
             intermediate_seqs.push("G".to_string());
         }
 
-        if self.count_t >= valid_threshold {
+        if self.count_t >= valid {
             valid_seqs.push("T".to_string());
-        } else if self.count_t >= invalid_threshold {
+        } else if self.count_t >= invalid {
             intermediate_seqs.push("T".to_string());
         }
 
         let mut all_counts = vec![self.count_a, self.count_c, self.count_g, self.count_t];
         for (seq, count) in &self.counts {
             all_counts.push(*count);
-            if count >= &valid_threshold {
+            let is_indel = seq == "-" || seq.len() > 1;
+            let valid = if is_indel { indel_valid } else { valid };
+            if *count >= valid {
                 valid_seqs.push(seq.clone());
-            } else if count >= &invalid_threshold {
+            } else if *count >= invalid {
                 intermediate_seqs.push(seq.clone());
             }
         }
@@ -114,90 +505,518 @@ impl PileupBase {
         if self.depth < min_depth as f64 {
             status = BaseStatus::DepthTooLow;
         } else if valid_seqs.len() == 1 {
-            if intermediate_seqs.len() > 0 {
+            if !intermediate_seqs.is_empty() {
                 status = BaseStatus::TooClose;
-            } else {
+            } else if valid_seqs[0] == original {
                 new_base = valid_seqs[0].clone();
-                if new_base != original {
-                    status = BaseStatus::Changed;
-                }
+            } else if thresholds.allows_change(&original, &valid_seqs[0]) {
+                new_base = valid_seqs[0].clone();
+                status = BaseStatus::Changed;
+            } else {
+                status = BaseStatus::NotAllowed;
             }
-        } else if valid_seqs.len() == 0 {
+        } else if valid_seqs.is_empty() {
             status = BaseStatus::NoValidOptions;
         } else {  // valid_seqs.len() > 1
             status = BaseStatus::MultipleValidOptions;
         }
+        (new_base, status, valid_threshold, invalid_threshold)
+    }
 
-        let debug_line = self.get_debug_line(build_debug_line, valid_threshold, invalid_threshold,
-                                             &status, &new_base);
-        (new_base, status, debug_line)
+    /// Returns the base's polished sequence and status from the Bayesian model: the sequence (or
+    /// mixture) with the highest posterior probability is used if that probability is at least the
+    /// minimum. There are no valid or invalid thresholds, so they are given as zero.
+    fn call_seq_bayes(&self, thresholds: &Thresholds,
+                      min_posterior: f64) -> (String, BaseStatus, u32, u32) {
+        let original = self.original.to_string();
+        if self.seq_counts().is_empty() {
+            return (original, BaseStatus::DepthTooLow, 0, 0);
+        }
+        let (posteriors, mixture) = self.posteriors();
+        let (best_seq, best) = posteriors.into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1)).unwrap();
+        let status = if mixture >= min_posterior {
+            BaseStatus::MultipleValidOptions
+        } else if best < min_posterior {
+            BaseStatus::TooClose
+        } else if best_seq == original {
+            BaseStatus::OriginalBaseKept
+        } else if thresholds.allows_change(&original, &best_seq) {
+            return (best_seq, BaseStatus::Changed, 0, 0);
+        } else {
+            BaseStatus::NotAllowed
+        };
+        (original, status, 0, 0)
+    }
+
+    /// Returns the posterior probability of each candidate sequence (the original and each
+    /// sequence in the pileup) and of a mixture. A candidate's likelihood comes from a simple error
+    /// model: each base which doesn't match it is an error with a probability from its base
+    /// quality, and each base which does is taken as certain. A mixture's likelihood doesn't depend
+    /// on errors, but its sequences' frequencies come from a Dirichlet prior.
+    pub fn posteriors(&self) -> (Vec<(String, f64)>, f64) {
+        let original = self.original.to_string();
+        let mut seqs = self.seq_counts();
+        let qual_of = |seq: &str, count: f64| match seq {
+            "A" => self.qual_sums[0] as f64,
+            "C" => self.qual_sums[1] as f64,
+            "G" => self.qual_sums[2] as f64,
+            "T" => self.qual_sums[3] as f64,
+            _   => count * MAX_BAYES_QUAL as f64,
+        };
+        let total_count: f64 = seqs.iter().map(|(_, count)| count).sum();
+        let total_qual: f64 = seqs.iter().map(|(seq, count)| qual_of(seq, *count)).sum();
+        let observed = seqs.len() as f64;
+        if !seqs.iter().any(|(seq, _)| *seq == original) {
+            seqs.push((original.clone(), 0.0));
+        }
+
+        // Log likelihoods (plus log priors) in natural log units.
+        let mut candidates: Vec<(String, f64)> = seqs.iter().map(|(seq, count)| {
+            let error_qual = total_qual - qual_of(seq, *count);
+            let error_count = total_count - count;
+            let prior = if *seq == original { 0.0 } else { CHANGE_PRIOR.ln() };
+            let likelihood = -error_qual * std::f64::consts::LN_10 / 10.0
+                - error_count * 3.0_f64.ln();
+            (seq.clone(), likelihood + prior)
+        }).collect();
+        let mixture = (observed >= 2.0).then(|| {
+            let a = MIXTURE_CONCENTRATION;
+            let seq_terms: f64 = seqs.iter().filter(|(_, count)| *count > 0.0)
+                .map(|(_, count)| ln_gamma(count + a) - ln_gamma(a)).sum();
+            ln_gamma(observed * a) - ln_gamma(total_count + observed * a) + seq_terms +
+                MIXTURE_PRIOR.ln()
+        });
+
+        let max = candidates.iter().map(|(_, p)| *p).chain(mixture)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let total: f64 = candidates.iter().map(|(_, p)| *p).chain(mixture)
+            .map(|p| (p - max).exp()).sum();
+        for (_, p) in &mut candidates {
+            *p = (*p - max).exp() / total;
+        }
+        (candidates, mixture.map_or(0.0, |p| (p - max).exp() / total))
+    }
+
+    /// Returns a Phred-scaled confidence for this base having the given sequence: how unlikely its
+    /// read support would be if it were no better than a coin flip at each read.
+    pub fn seq_confidence(&self, seq: &str) -> f64 {
+        let support = self.count_of(seq);
+        let total = self.count_a + self.count_c + self.count_g + self.count_t +
+                    self.counts.values().sum::<f64>();
+        binomial_tail_phred(bankers_rounding(support), bankers_rounding(total))
+            .min(MAX_CONFIDENCE)
+    }
+
+    /// Returns the probability (from the Bayesian model) that the base's true sequence is one
+    /// sequence other than the given one. A mixture isn't counted, as each of its sequences is
+    /// right for some of the reads.
+    pub fn error_probability(&self, seq: &str) -> f64 {
+        let (posteriors, _) = self.posteriors();
+        posteriors.iter().filter(|(s, _)| s != seq).map(|(_, p)| p).sum()
+    }
+
+    /// Returns each sequence in the pileup with its count, sorted by sequence.
+    pub fn seq_counts(&self) -> Vec<(String, f64)> {
+        let mut counts: Vec<(String, f64)> = [("A", self.count_a), ("C", self.count_c),
+                                              ("G", self.count_g), ("T", self.count_t)]
+            .into_iter().filter(|(_, count)| *count > 0.0)
+            .map(|(seq, count)| (seq.to_string(), count)).collect();
+        counts.extend(self.counts.iter().filter(|(_, count)| **count > 0.0)
+            .map(|(seq, count)| (seq.clone(), *count)));
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+        counts
+    }
+
+    /// The fraction of the base's sequences which came from reads with multiple alignments, or None
+    /// if the base has no sequences.
+    pub fn multi_mapped_fraction(&self) -> Option<f64> {
+        let total = self.count_a + self.count_c + self.count_g + self.count_t +
+                    self.counts.values().sum::<f64>();
+        (total > 0.0).then(|| self.multi_mapped / total)
+    }
+
+    fn count_of(&self, seq: &str) -> f64 {
+        match seq {
+            "A" => self.count_a,
+            "C" => self.count_c,
+            "G" => self.count_g,
+            "T" => self.count_t,
+            _   => *self.counts.get(seq).unwrap_or(&0.0),
+        }
     }
 
     /// Returns the sequence counts in string form (used in the debug output).
     fn get_count_str(&self) -> String {
         let mut counts = Vec::new();
-        if self.count_a > 0 {counts.push(format!("Ax{}", self.count_a));}
-        if self.count_c > 0 {counts.push(format!("Cx{}", self.count_c));}
-        if self.count_g > 0 {counts.push(format!("Gx{}", self.count_g));}
-        if self.count_t > 0 {counts.push(format!("Tx{}", self.count_t));}
+        if self.count_a > 0.0 {counts.push(format!("Ax{}", format_count(self.count_a)));}
+        if self.count_c > 0.0 {counts.push(format!("Cx{}", format_count(self.count_c)));}
+        if self.count_g > 0.0 {counts.push(format!("Gx{}", format_count(self.count_g)));}
+        if self.count_t > 0.0 {counts.push(format!("Tx{}", format_count(self.count_t)));}
         for (seq, count) in &self.counts {
-            counts.push(format!("{}x{}", seq, count));
+            counts.push(format!("{}x{}", seq, format_count(*count)));
         }
         counts.sort();
         counts.join(",")
     }
 
-    fn get_debug_line(&self, build_debug_line: bool, valid_threshold: u32, invalid_threshold: u32,
-                      status: &BaseStatus, new_base: &str) -> String {
-        if !build_debug_line {
-            return String::new();
-        }
+    fn get_summary(&self, valid_threshold: u32, invalid_threshold: u32, status: &BaseStatus,
+                   new_base: &str) -> BaseSummary {
+        let confidence = status.is_change().then(|| self.seq_confidence(new_base));
+        BaseSummary { base: self.original, depth: self.depth, invalid: invalid_threshold,
+                      valid: valid_threshold, pileup: self.get_count_str(),
+                      status: status.name().to_string(), new_base: new_base.to_string(),
+                      confidence }
+    }
+}
 
-        let status_str = match status {
-            BaseStatus::OriginalBaseKept     => "kept",
-            BaseStatus::Changed              => "changed",
-            BaseStatus::DepthTooLow          => "low_depth",
-            BaseStatus::NoValidOptions       => "none",
-            BaseStatus::MultipleValidOptions => "multiple",
-            BaseStatus::TooClose             => "too_close",
-        };
-        format!("{}\t{:.1}\t{}\t{}\t{}\t{}\t{}", self.original, self.depth, invalid_threshold,
-                valid_threshold, self.get_count_str(), status_str, new_base)
+
+/// How a base was polished: the values in the debug file (--debug), which are also available to
+/// library users and downstream tools as a serialisable type.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BaseSummary {
+    pub base: char,
+    pub depth: f64,
+    pub invalid: u32,      // the invalid threshold
+    pub valid: u32,        // the valid threshold
+    pub pileup: String,    // each sequence and its count, e.g. A:20,C:2
+    pub status: String,
+    pub new_base: String,
+    pub confidence: Option<f64>,  // only for changes
+}
+
+impl BaseSummary {
+    /// The summary's columns in the TSV debug file.
+    pub fn tsv_line(&self) -> String {
+        let confidence = self.confidence.map(|c| format!("{:.1}", c)).unwrap_or_default();
+        format!("{}\t{:.1}\t{}\t{}\t{}\t{}\t{}\t{}", self.base, self.depth, self.invalid,
+                self.valid, self.pileup, self.status, self.new_base, confidence)
+    }
+}
+
+
+/// A run of adjacent bases with deletion support, which --joint-indels accepts or rejects as one
+/// multi-base deletion, so a real deletion isn't partly made (a frameshift) when some of its bases
+/// fall short of the thresholds on their own. It is called from the mean counts over its bases:
+/// deletions against everything else. A multi-base insertion is already one sequence at one base,
+/// so insertions need no such handling.
+#[derive(Clone, Copy, Debug)]
+pub struct DeletionEvent {
+    pub start: usize,  // index of the first base (in those given to deletion_events)
+    pub end: usize,    // index after the last base
+    pub support: f64,  // mean deletion count
+    pub depth: f64,    // mean depth
+    pub accepted: bool,
+    pub status: BaseStatus,
+}
+
+impl DeletionEvent {
+    /// The event as given in the debug output, e.g. "120-123:del=17.3/20.0:changed".
+    pub fn description(&self) -> String {
+        format!("{}-{}:del={:.1}/{:.1}:{}", self.start, self.end, self.support, self.depth,
+                self.status.name())
     }
 }
 
 
+/// Finds the deletion events (runs of at least two adjacent bases with deletion support) and
+/// calls each with the thresholds for its first base.
+pub fn deletion_events(bases: &[&PileupBase],
+                       thresholds: impl Fn(usize) -> Thresholds) -> Vec<DeletionEvent> {
+    let mut events = Vec::new();
+    let mut i = 0;
+    while i < bases.len() {
+        let t = thresholds(i);
+        let run = bases[i..].iter().take_while(|b| b.has_deletion_support(&t)).count();
+        if run >= 2 {
+            events.push(call_deletion_event(&bases[i..i + run], i, &t));
+        }
+        i += run.max(1);
+    }
+    events
+}
+
+
+/// Calls a deletion event with a base made from the mean counts over its bases: the deletions
+/// and everything else (counted as the original base).
+fn call_deletion_event(bases: &[&PileupBase], start: usize,
+                       thresholds: &Thresholds) -> DeletionEvent {
+    let n = bases.len() as f64;
+    let support = bases.iter().map(|b| b.count_of("-")).sum::<f64>() / n;
+    let depth = bases.iter().map(|b| b.depth).sum::<f64>() / n;
+    let other = bases.iter().map(|b| b.seq_counts().iter().filter(|(seq, _)| seq != "-")
+                                      .map(|(_, count)| count).sum::<f64>()).sum::<f64>() / n;
+    let original = bases[0].original;
+    let mut event_base = PileupBase::new(original);
+    event_base.add_seq(&original.to_string(), 1.0, other);
+    event_base.add_seq("-", 1.0, support);
+    event_base.depth = depth;
+    let (seq, status, _, _) = event_base.call_seq(thresholds);
+    DeletionEvent { start, end: start + bases.len(), support, depth,
+                    accepted: seq == "-" && status.is_change(), status }
+}
+
+
+/// The deletion event (if any) containing a position.
+pub fn event_at(events: &[DeletionEvent], pos: usize) -> Option<&DeletionEvent> {
+    let i = events.partition_point(|e| e.end <= pos);
+    events.get(i).filter(|e| e.start <= pos)
+}
+
+
+
+
+/// How alignments are added to the pileups: the depth cap (--max-depth), the bases ignored at
+/// alignment ends (--trim-ends and --homopolymer-trim) and whether read names are recorded (for
+/// --debug-reads). The default values match polypolish polish's.
+#[derive(Clone, Copy, Debug)]
+pub struct PileupSettings {
+    pub max_depth: u32,
+    pub trim_ends: usize,
+    pub homopolymer_trim: Option<usize>,
+    pub record_reads: bool,
+}
+
+impl Default for PileupSettings {
+    fn default() -> PileupSettings {
+        PileupSettings { max_depth: u32::MAX, trim_ends: 0, homopolymer_trim: Some(1),
+                         record_reads: false }
+    }
+}
 
 
 #[derive(Debug)]
 pub struct Pileup {
     pub bases: Vec<PileupBase>,
+    max_depth: u32,
+    trim_ends: usize,
+    homopolymer_trim: Option<usize>,  // extra bases trimmed after a homopolymer (None to disable)
+    record_reads: bool,
+    pub homopolymer_trimmed: usize,    // alignment positions removed by homopolymer trimming
+    pub weight: f64,                   // weight of the alignments being added (--weights)
 }
 
 impl Pileup {
-    pub fn new(seq: &str) -> Pileup {
+    pub fn new(seq: &str, max_depth: u32, trim_ends: usize, homopolymer_trim: Option<usize>,
+               record_reads: bool) -> Pileup {
         let mut bases = Vec::new();
         for b in seq.chars() {
             bases.push(PileupBase::new(b));
         }
 
         Pileup {
-            bases: bases,
+            bases,
+            max_depth,
+            trim_ends,
+            homopolymer_trim,
+            record_reads,
+            homopolymer_trimmed: 0,
+            weight: 1.0,
+        }
+    }
+
+    /// Returns a pileup for the same sequence and with the same settings, but with no sequences
+    /// added yet.
+    pub fn empty_copy(&self) -> Pileup {
+        Pileup {
+            bases: self.bases.iter().map(|b| PileupBase::new(b.original)).collect(),
+            max_depth: self.max_depth,
+            trim_ends: self.trim_ends,
+            homopolymer_trim: self.homopolymer_trim,
+            record_reads: self.record_reads,
+            homopolymer_trimmed: 0,
+            weight: 1.0,
         }
     }
 
+    /// Adds all of another pileup's sequences (which must be for the same sequence) to this one.
+    pub fn merge(&mut self, other: &Pileup) {
+        for (b, other_b) in self.bases.iter_mut().zip(&other.bases) {
+            b.merge(other_b);
+        }
+        self.homopolymer_trimmed += other.homopolymer_trimmed;
+    }
+
+    /// Returns the assembly sequence of the pileup.
+    pub fn original_seq(&self) -> String {
+        self.bases.iter().map(|b| b.original).collect()
+    }
+
+    /// Returns the median depth of each window (of the given size) along the sequence.
+    pub fn window_median_depths(&self, window_size: usize) -> Vec<f64> {
+        self.bases.chunks(window_size).map(|window| {
+            median(&window.iter().map(|b| b.depth).collect::<Vec<_>>())
+        }).collect()
+    }
+
     pub fn add_alignment(&mut self, alignment: &Alignment, depth_contribution: f64) {
-        let read_bases = alignment.get_read_bases_for_each_target_base();
-        let mut i = alignment.ref_start;
-        for (start, end) in read_bases {
-            if start == end {
-                self.bases[i].add_seq("-", depth_contribution);
-            } else {
-                self.bases[i].add_seq(&alignment.read_seq[start..end], depth_contribution);
-            }
-            i += 1;
+        let (ref_start, read_bases) = trimmed_read_bases(alignment, self.trim_ends,
+                                                         self.homopolymer_trim,
+                                                         &mut self.homopolymer_trimmed);
+        let read_name = read_name_to_record(alignment, self.record_reads);
+        let weight = self.weight * alignment.count_weight();
+        for (i, (start, end)) in (ref_start..).zip(read_bases) {
+            let seq = if start == end { "-" } else { &alignment.read_seq[start..end] };
+            self.bases[i].add_seq_capped(seq, range_qual(alignment, (start, end)),
+                                         depth_contribution, weight, self.max_depth,
+                                         read_name.as_ref());
         }
     }
+
+    /// Adds the alignments of two overlapping mates. Positions covered by both mates come from
+    /// the same piece of DNA, so they are only counted once: with the mates' sequence if they
+    /// agree or the sequence with higher base quality if they don't (the first mate's on a tie).
+    /// Overlapping positions get the mean of the mates' depth contributions and the weight of the
+    /// mate whose sequence is used (the higher one if they agree).
+    pub fn add_overlapping_pair(&mut self, alignment_1: &Alignment, depth_contribution_1: f64,
+                                alignment_2: &Alignment, depth_contribution_2: f64) {
+        let (start_1, read_bases_1) = trimmed_read_bases(alignment_1, self.trim_ends,
+                                                         self.homopolymer_trim,
+                                                         &mut self.homopolymer_trimmed);
+        let (start_2, read_bases_2) = trimmed_read_bases(alignment_2, self.trim_ends,
+                                                         self.homopolymer_trim,
+                                                         &mut self.homopolymer_trimmed);
+        let start = start_1.min(start_2);
+        let end = (start_1 + read_bases_1.len()).max(start_2 + read_bases_2.len());
+        let read_name = read_name_to_record(alignment_1, self.record_reads);
+        let (weight_1, weight_2) = (self.weight * alignment_1.count_weight(),
+                                    self.weight * alignment_2.count_weight());
+        for i in start..end {
+            let bases_1 = i.checked_sub(start_1).and_then(|j| read_bases_1.get(j));
+            let bases_2 = i.checked_sub(start_2).and_then(|j| read_bases_2.get(j));
+            let (seq, qual, weight, depth_contribution) = match (bases_1, bases_2) {
+                (Some(&b_1), Some(&b_2)) => {
+                    let seq_1 = alignment_1.read_seq_for_range(b_1);
+                    let seq_2 = alignment_2.read_seq_for_range(b_2);
+                    let (qual_1, qual_2) = (range_qual(alignment_1, b_1),
+                                            range_qual(alignment_2, b_2));
+                    let (seq, qual, weight) = if seq_1 == seq_2 {
+                        (seq_1, qual_1.max(qual_2), weight_1.max(weight_2))
+                    } else if alignment_1.mean_qual_for_range(b_1) >=
+                              alignment_2.mean_qual_for_range(b_2) {
+                        (seq_1, qual_1, weight_1)
+                    } else {
+                        (seq_2, qual_2, weight_2)
+                    };
+                    (seq, qual, weight, (depth_contribution_1 + depth_contribution_2) / 2.0)
+                },
+                (Some(&b_1), None) => (alignment_1.read_seq_for_range(b_1),
+                                       range_qual(alignment_1, b_1), weight_1,
+                                       depth_contribution_1),
+                (None, Some(&b_2)) => (alignment_2.read_seq_for_range(b_2),
+                                       range_qual(alignment_2, b_2), weight_2,
+                                       depth_contribution_2),
+                (None, None)       => continue,
+            };
+            self.bases[i].add_seq_capped(seq, qual, depth_contribution, weight,
+                                         self.max_depth, read_name.as_ref());
+        }
+    }
+}
+
+
+/// A pileup covering only a sliding window of a sequence, for use with coordinate-sorted
+/// alignments. Bases are created as alignments reach them and removed once they're finished (i.e.
+/// no later alignment can reach them), so memory use depends on the window size, not the sequence
+/// length.
+pub struct PileupWindow<'a> {
+    seq: &'a [u8],
+    start: usize,  // sequence position of the first base in the window
+    bases: VecDeque<PileupBase>,
+    max_depth: u32,
+    trim_ends: usize,
+    homopolymer_trim: Option<usize>,
+    record_reads: bool,
+    pub homopolymer_trimmed: usize,
+    pub weight: f64,  // weight of the alignments being added (--weights)
+}
+
+impl<'a> PileupWindow<'a> {
+    pub fn new(seq: &'a str, max_depth: u32, trim_ends: usize, homopolymer_trim: Option<usize>,
+               record_reads: bool) -> PileupWindow<'a> {
+        PileupWindow { seq: seq.as_bytes(), start: 0, bases: VecDeque::new(), max_depth,
+                       trim_ends, homopolymer_trim, record_reads, homopolymer_trimmed: 0,
+                       weight: 1.0 }
+    }
+
+    /// Adds an alignment to the window. Alignments must be added in order of their start position.
+    pub fn add_alignment(&mut self, alignment: &Alignment, depth_contribution: f64) {
+        assert!(alignment.ref_start >= self.start);
+        let (ref_start, read_bases) = trimmed_read_bases(alignment, self.trim_ends,
+                                                         self.homopolymer_trim,
+                                                         &mut self.homopolymer_trimmed);
+        let end = (ref_start + read_bases.len()).min(self.seq.len());
+        let read_name = read_name_to_record(alignment, self.record_reads);
+        let weight = self.weight * alignment.count_weight();
+        while self.start + self.bases.len() < end {
+            let pos = self.start + self.bases.len();
+            self.bases.push_back(PileupBase::new(self.seq[pos] as char));
+        }
+        for (i, (start, end)) in (ref_start..self.seq.len()).zip(read_bases) {
+            let seq = if start == end { "-" } else { &alignment.read_seq[start..end] };
+            self.bases[i - self.start].add_seq_capped(seq, range_qual(alignment, (start, end)),
+                                                      depth_contribution, weight,
+                                                      self.max_depth, read_name.as_ref());
+        }
+    }
+
+    /// Removes and returns the finished bases: those before the given position (or to the end of
+    /// the sequence if None). Positions no alignment reached are returned as zero-depth bases.
+    pub fn take_finished(&mut self, before: Option<usize>) -> Vec<PileupBase> {
+        let end = before.unwrap_or(self.seq.len()).min(self.seq.len());
+        let mut finished = Vec::with_capacity(end.saturating_sub(self.start));
+        while self.start < end {
+            let b = self.bases.pop_front()
+                .unwrap_or_else(|| PileupBase::new(self.seq[self.start] as char));
+            finished.push(b);
+            self.start += 1;
+        }
+        finished
+    }
+}
+
+
+/// Counts are whole numbers unless --weights was used, so decimal places are only shown if needed.
+pub fn format_count(count: f64) -> String {
+    let formatted = format!("{:.2}", count);
+    match formatted.strip_suffix(".00") {
+        Some(whole) => whole.to_string(),
+        None        => formatted,
+    }
+}
+
+
+/// Returns the alignment's read name if read names are being recorded.
+fn read_name_to_record(alignment: &Alignment, record_reads: bool) -> Option<Arc<str>> {
+    record_reads.then(|| alignment.read_name.clone())
+}
+
+
+/// Returns the base quality for one of an alignment's read ranges, if it's a single base (indels
+/// don't have one).
+fn range_qual(alignment: &Alignment, (start, end): (usize, usize)) -> Option<u8> {
+    (end == start + 1).then(|| alignment.base_qual(start)).flatten()
+}
+
+
+/// Returns the read bases for each target base of an alignment (from
+/// get_read_bases_for_each_target_base) with trim_ends positions removed from each end, along with
+/// the reference position of the first one. Homopolymer trimming (if enabled) is done first, and
+/// the number of positions it removed is added to homopolymer_trimmed.
+fn trimmed_read_bases(alignment: &Alignment, trim_ends: usize, homopolymer_trim: Option<usize>,
+                      homopolymer_trimmed: &mut usize) -> (usize, Vec<(usize, usize)>) {
+    let mut read_bases = alignment.get_read_bases_for_each_target_base();
+    if let Some(extra_bases) = homopolymer_trim {
+        *homopolymer_trimmed += trim_bases_for_homopolymers(&mut read_bases, &alignment.read_seq,
+                                                            extra_bases);
+    }
+    if trim_ends == 0 {
+        return (alignment.ref_start, read_bases);
+    }
+    read_bases.truncate(read_bases.len().saturating_sub(trim_ends));
+    let front_trim = trim_ends.min(read_bases.len());
+    read_bases.drain(..front_trim);
+    (alignment.ref_start + front_trim, read_bases)
 }
 
 
@@ -208,9 +1027,10 @@ mod tests {
     #[test]
     fn test_pileupbase_01() {
         let mut b = PileupBase::new('A');
-        for _ in 0..50 {b.add_seq("A", 1.0);}
+        for _ in 0..50 {b.add_seq("A", 1.0, 1.0);}
         assert_eq!(b.get_count_str(), "Ax50");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.2, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "A");
         assert!(matches!(status, BaseStatus::OriginalBaseKept));
     }
@@ -218,11 +1038,12 @@ mod tests {
     #[test]
     fn test_pileupbase_02() {
         let mut b = PileupBase::new('G');
-        b.add_seq("A", 1.0);
-        b.add_seq("T", 1.0);
-        for _ in 0..50 {b.add_seq("G", 1.0);}
+        b.add_seq("A", 1.0, 1.0);
+        b.add_seq("T", 1.0, 1.0);
+        for _ in 0..50 {b.add_seq("G", 1.0, 1.0);}
         assert_eq!(b.get_count_str(), "Ax1,Gx50,Tx1");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.2, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "G");
         assert!(matches!(status, BaseStatus::OriginalBaseKept));
     }
@@ -230,10 +1051,11 @@ mod tests {
     #[test]
     fn test_pileupbase_03() {
         let mut b = PileupBase::new('T');
-        b.add_seq("C", 1.0);
-        for _ in 0..99 {b.add_seq("A", 1.0);}
+        b.add_seq("C", 1.0, 1.0);
+        for _ in 0..99 {b.add_seq("A", 1.0, 1.0);}
         assert_eq!(b.get_count_str(), "Ax99,Cx1");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.2, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "A");
         assert!(matches!(status, BaseStatus::Changed));
     }
@@ -241,11 +1063,12 @@ mod tests {
     #[test]
     fn test_pileupbase_04() {
         let mut b = PileupBase::new('A');
-        b.add_seq("T", 1.0);
-        b.add_seq("C", 1.0);
-        b.add_seq("G", 1.0);
+        b.add_seq("T", 1.0, 1.0);
+        b.add_seq("C", 1.0, 1.0);
+        b.add_seq("G", 1.0, 1.0);
         assert_eq!(b.get_count_str(), "Cx1,Gx1,Tx1");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.2, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "A");
         assert!(matches!(status, BaseStatus::DepthTooLow));
     }
@@ -253,10 +1076,11 @@ mod tests {
     #[test]
     fn test_pileupbase_05() {
         let mut b = PileupBase::new('C');
-        for _ in 0..123 {b.add_seq("A", 0.1);}
-        for _ in 0..321 {b.add_seq("T", 0.1);}
+        for _ in 0..123 {b.add_seq("A", 0.1, 1.0);}
+        for _ in 0..321 {b.add_seq("T", 0.1, 1.0);}
         assert_eq!(b.get_count_str(), "Ax123,Tx321");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.2, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "C");
         assert!(matches!(status, BaseStatus::MultipleValidOptions));
     }
@@ -264,10 +1088,11 @@ mod tests {
     #[test]
     fn test_pileupbase_06() {
         let mut b = PileupBase::new('T');
-        for _ in 0..6 { b.add_seq("A", 1.0); }
-        for _ in 0..4 { b.add_seq("C", 1.0); }
+        for _ in 0..6 { b.add_seq("A", 1.0, 1.0); }
+        for _ in 0..4 { b.add_seq("C", 1.0, 1.0); }
         assert_eq!(b.get_count_str(), "Ax6,Cx4");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.2, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "T");
         assert!(matches!(status, BaseStatus::TooClose));
     }
@@ -275,10 +1100,11 @@ mod tests {
     #[test]
     fn test_pileupbase_07() {
         let mut b = PileupBase::new('T');
-        for _ in 0..9 { b.add_seq("A", 1.0); }
-        b.add_seq("C", 1.0);
+        for _ in 0..9 { b.add_seq("A", 1.0, 1.0); }
+        b.add_seq("C", 1.0, 1.0);
         assert_eq!(b.get_count_str(), "Ax9,Cx1");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.1, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.1);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "T");
         assert!(matches!(status, BaseStatus::TooClose));
     }
@@ -286,11 +1112,494 @@ mod tests {
     #[test]
     fn test_pileupbase_08() {
         let mut b = PileupBase::new('T');
-        for _ in 0..19 { b.add_seq("A", 1.0); }
-        b.add_seq("C", 1.0);
+        for _ in 0..19 { b.add_seq("A", 1.0, 1.0); }
+        b.add_seq("C", 1.0, 1.0);
         assert_eq!(b.get_count_str(), "Ax19,Cx1");
-        let (polished, status, _) = b.get_polished_seq(5, 0.5, 0.1, false);
+        let thresholds = Thresholds::new(5, 0.5, 0.1);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
         assert_eq!(polished, "A");
         assert!(matches!(status, BaseStatus::Changed));
     }
+
+    #[test]
+    fn test_indel_thresholds() {
+        let mut thresholds = Thresholds::new(5, 0.5, 0.2);
+        thresholds.indel_fraction_valid = 0.95;
+
+        let mut b = PileupBase::new('T');
+        for _ in 0..8 { b.add_seq("TA", 1.0, 1.0); }
+        for _ in 0..1 { b.add_seq("T", 1.0, 1.0); }
+        assert_eq!(b.get_polished_seq(&Thresholds::new(5, 0.5, 0.2), &[], None, false).0, "TA");
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
+        assert_eq!(polished, "T");
+        assert!(matches!(status, BaseStatus::NoValidOptions));
+
+        let mut b = PileupBase::new('T');
+        for _ in 0..7 { b.add_seq("-", 1.0, 1.0); }
+        for _ in 0..1 { b.add_seq("T", 1.0, 1.0); }
+        assert_eq!(b.get_polished_seq(&thresholds, &[], None, false).0, "T");
+        thresholds.indel_min_depth = 7;
+        thresholds.indel_fraction_valid = 0.5;
+        assert_eq!(b.get_polished_seq(&thresholds, &[], None, false).0, "-");
+        thresholds.indel_min_depth = 8;
+        assert_eq!(b.get_polished_seq(&thresholds, &[], None, false).0, "T");
+
+        // Substitutions still use the ordinary thresholds.
+        let mut b = PileupBase::new('T');
+        for _ in 0..7 { b.add_seq("A", 1.0, 1.0); }
+        for _ in 0..1 { b.add_seq("T", 1.0, 1.0); }
+        assert_eq!(b.get_polished_seq(&thresholds, &[], None, false).0, "A");
+    }
+
+    #[test]
+    fn test_low_complexity_thresholds() {
+        let mut thresholds = Thresholds::new(5, 0.5, 0.2);
+        thresholds.indel_fraction_valid = 0.6;
+        let strict = thresholds.for_low_complexity();
+        assert_eq!((strict.fraction_valid, strict.indel_fraction_valid), (0.5, 0.6));
+        thresholds.low_complexity_fraction_valid = Some(0.8);
+        let strict = thresholds.for_low_complexity();
+        assert_eq!((strict.fraction_valid, strict.indel_fraction_valid), (0.8, 0.8));
+
+        let mut b = PileupBase::new('A');
+        for _ in 0..7 { b.add_seq("C", 1.0, 1.0); }
+        for s in ["A", "G", "T"] { b.add_seq(s, 1.0, 1.0); }
+        assert_eq!(b.get_polished_seq(&thresholds, &[], None, false).0, "C");
+        assert_eq!(b.get_polished_seq(&strict, &[], None, false).0, "A");
+    }
+
+    #[test]
+    fn test_scaled_thresholds() {
+        let mut thresholds = Thresholds::new(10, 0.5, 0.2);
+        thresholds.indel_min_depth = 20;
+        let scaled = thresholds.scaled_for_depth(50.0, 100.0);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (5, 10));
+        let scaled = thresholds.scaled_for_depth(150.0, 100.0);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (10, 20));
+        let scaled = thresholds.scaled_for_depth(1.0, 100.0);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (2, 2));
+        let scaled = Thresholds::new(1, 0.5, 0.2).scaled_for_depth(0.0, 100.0);
+        assert_eq!(scaled.min_depth, 1);
+
+        // A base with too little depth for the global threshold can be changed in a low-depth
+        // region.
+        let mut b = PileupBase::new('A');
+        for _ in 0..6 { b.add_seq("C", 1.0, 1.0); }
+        let (_, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
+        assert!(matches!(status, BaseStatus::DepthTooLow));
+        let scaled = thresholds.scaled_for_depth(30.0, 50.0);
+        assert_eq!(b.get_polished_seq(&scaled, &[], None, false).0, "C");
+    }
+
+    #[test]
+    fn test_relative_thresholds() {
+        let mut thresholds = Thresholds::new(5, 0.5, 0.2);
+        thresholds.indel_min_depth = 10;
+        thresholds.relative_min_depth = Some(0.1);
+        let scaled = thresholds.scaled_for_depth(500.0, 50.0);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (50, 100));
+        let scaled = thresholds.scaled_for_depth(35.0, 50.0);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (4, 8));
+        let scaled = thresholds.scaled_for_depth(5.0, 50.0);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (2, 4));
+    }
+
+    #[test]
+    fn test_copy_number_thresholds() {
+        let mut thresholds = Thresholds::new(5, 0.5, 0.2);
+        thresholds.indel_min_depth = 8;
+        let scaled = thresholds.for_copy_number(12.5);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (62, 100));
+        let scaled = thresholds.for_copy_number(0.5);
+        assert_eq!((scaled.min_depth, scaled.indel_min_depth), (5, 8));
+    }
+
+    #[test]
+    fn test_window_median_depths() {
+        let mut pileup = Pileup::new("ACGTACGTAC", u32::MAX, 0, Some(1), false);
+        for (i, b) in pileup.bases.iter_mut().enumerate() {
+            b.depth = i as f64;
+        }
+        assert_eq!(pileup.window_median_depths(4), vec![1.5, 5.5, 8.5]);
+        assert_eq!(pileup.window_median_depths(100), vec![4.5]);
+    }
+
+    #[test]
+    fn test_allowed_changes() {
+        let mut substitution = PileupBase::new('T');
+        for _ in 0..10 { substitution.add_seq("A", 1.0, 1.0); }
+        let mut insertion = PileupBase::new('T');
+        for _ in 0..10 { insertion.add_seq("TG", 1.0, 1.0); }
+        let mut deletion = PileupBase::new('T');
+        for _ in 0..10 { deletion.add_seq("-", 1.0, 1.0); }
+        let mut both = PileupBase::new('T');
+        for _ in 0..10 { both.add_seq("AG", 1.0, 1.0); }
+        let mut kept = PileupBase::new('T');
+        for _ in 0..10 { kept.add_seq("T", 1.0, 1.0); }
+
+        let mut thresholds = Thresholds::new(5, 0.5, 0.2);
+        thresholds.allow_substitutions = false;
+        thresholds.allow_deletions = false;
+        let (polished, status, _) = substitution.get_polished_seq(&thresholds, &[], None, false);
+        assert_eq!(polished, "T");
+        assert!(matches!(status, BaseStatus::NotAllowed));
+        assert_eq!(insertion.get_polished_seq(&thresholds, &[], None, false).0, "TG");
+        assert_eq!(deletion.get_polished_seq(&thresholds, &[], None, false).0, "T");
+        assert_eq!(both.get_polished_seq(&thresholds, &[], None, false).0, "T");
+        let (polished, status, _) = kept.get_polished_seq(&thresholds, &[], None, false);
+        assert_eq!(polished, "T");
+        assert!(matches!(status, BaseStatus::OriginalBaseKept));
+
+        thresholds.allow_substitutions = true;
+        assert_eq!(both.get_polished_seq(&thresholds, &[], None, false).0, "AG");
+        thresholds.allow_insertions = false;
+        thresholds.allow_deletions = true;
+        assert_eq!(substitution.get_polished_seq(&thresholds, &[], None, false).0, "A");
+        assert_eq!(insertion.get_polished_seq(&thresholds, &[], None, false).0, "T");
+        assert_eq!(deletion.get_polished_seq(&thresholds, &[], None, false).0, "-");
+    }
+
+    #[test]
+    fn test_seq_confidence() {
+        let mut b = PileupBase::new('T');
+        for _ in 0..6 { b.add_seq("A", 1.0, 1.0); }
+        for _ in 0..2 { b.add_seq("TG", 1.0, 1.0); }
+        assert!((b.seq_confidence("A") - 8.4004).abs() < 0.001);  // P(X >= 6) = 37/256
+        assert!(b.seq_confidence("TG") < b.seq_confidence("A"));
+        assert_eq!(b.seq_confidence("C"), 0.0);
+        for _ in 0..100 { b.add_seq("A", 1.0, 1.0); }
+        assert_eq!(b.seq_confidence("A"), MAX_CONFIDENCE);
+    }
+
+    #[test]
+    fn test_pileupbase_max_depth() {
+        let mut b = PileupBase::new('A');
+        for _ in 0..8 { b.add_seq_capped("A", None, 1.0, 1.0, 10, None); }
+        for _ in 0..5 { b.add_seq_capped("AC", None, 1.0, 1.0, 10, None); }
+        assert_eq!(b.get_count_str(), "ACx2,Ax8");
+        assert_eq!(b.depth, 10.0);
+        assert_eq!(b.skipped_count, 3);
+    }
+
+    #[test]
+    fn test_add_overlapping_pair() {
+        let seq = "ACGTACGTACGTACGTACGT";
+        // After trimming their last two bases, the mates overlap at positions 4-7. They agree at
+        // 4 and 5, and disagree at 6 (the second mate has the higher quality) and 7 (the first
+        // mate has the higher quality).
+        let a_1 = Alignment::new("r1\t0\tx\t1\t60\t10M\t*\t0\t0\tACGTACTAAC\tKKKKKK5KKK\t\
+                                  NM:i:1", &[]).unwrap();
+        let a_2 = Alignment::new("r1\t16\tx\t5\t60\t10M\t*\t0\t0\tACCGACGTAC\tKKK5KKKKKK\t\
+                                  NM:i:2", &[]).unwrap();
+        assert!(a_1.overlaps_mate(&a_2));
+        let mut pileup = Pileup::new(seq, u32::MAX, 0, Some(1), false);
+        pileup.add_overlapping_pair(&a_1, 1.0, &a_2, 0.5);
+        let counts: Vec<String> = pileup.bases.iter().map(|b| b.get_count_str()).collect();
+        assert_eq!(counts[3], "Tx1");
+        assert_eq!(counts[4], "Ax1");
+        assert_eq!(counts[5], "Cx1");
+        assert_eq!(counts[6], "Cx1");
+        assert_eq!(counts[7], "Ax1");
+        assert_eq!(counts[8], "Ax1");
+        assert_eq!(pileup.bases[3].depth, 1.0);
+        assert_eq!(pileup.bases[5].depth, 0.75);
+        assert_eq!(pileup.bases[8].depth, 0.5);
+    }
+
+    #[test]
+    fn test_trim_ends() {
+        let seq = "ACGTACGTACGTACGTACGT";
+        let a = Alignment::new("r1\t0\tx\t3\t60\t10M\t*\t0\t0\tGTACGTACGT\tKKKKKKKKKK\tNM:i:0", &[])
+            .unwrap();
+        let mut pileup = Pileup::new(seq, u32::MAX, 0, Some(1), false);
+        pileup.add_alignment(&a, 1.0);
+        let covered: Vec<usize> = (0..seq.len()).filter(|&i| pileup.bases[i].depth > 0.0).collect();
+        assert_eq!(covered, (2..10).collect::<Vec<usize>>());  // last two bases trimmed
+        assert_eq!(pileup.homopolymer_trimmed, 2);
+
+        let mut pileup = Pileup::new(seq, u32::MAX, 0, None, false);
+        pileup.add_alignment(&a, 1.0);
+        let covered: Vec<usize> = (0..seq.len()).filter(|&i| pileup.bases[i].depth > 0.0).collect();
+        assert_eq!(covered, (2..12).collect::<Vec<usize>>());
+        assert_eq!(pileup.homopolymer_trimmed, 0);
+
+        let mut pileup = Pileup::new(seq, u32::MAX, 3, Some(1), false);
+        pileup.add_alignment(&a, 1.0);
+        let covered: Vec<usize> = (0..seq.len()).filter(|&i| pileup.bases[i].depth > 0.0).collect();
+        assert_eq!(covered, (5..7).collect::<Vec<usize>>());
+
+        let mut window = PileupWindow::new(seq, u32::MAX, 3, Some(1), false);
+        window.add_alignment(&a, 1.0);
+        let bases = window.take_finished(None);
+        let covered: Vec<usize> = (0..seq.len()).filter(|&i| bases[i].depth > 0.0).collect();
+        assert_eq!(covered, (5..7).collect::<Vec<usize>>());
+
+        let mut pileup = Pileup::new(seq, u32::MAX, 5, Some(1), false);
+        pileup.add_alignment(&a, 1.0);
+        assert!(pileup.bases.iter().all(|b| b.depth == 0.0));
+    }
+
+    #[test]
+    fn test_read_names() {
+        let seq = "ACGTACGTACGTACGTACGT";
+        let a_1 = Alignment::new("r1\t0\tx\t3\t60\t6M\t*\t0\t0\tGTTCGT\tKKKKKK\tNM:i:1", &[])
+            .unwrap();
+        let a_2 = Alignment::new("r2\t0\tx\t6\t60\t3M1D4M\t*\t0\t0\tCGTCGTA\tKKKKKKK\tNM:i:1", &[])
+            .unwrap();
+        let a_3 = Alignment::new("r3\t0\tx\t6\t60\t8M\t*\t0\t0\tCGTACGTA\tKKKKKKKK\tNM:i:0", &[])
+            .unwrap();
+        let mut pileup = Pileup::new(seq, u32::MAX, 0, Some(1), true);
+        pileup.add_alignment(&a_1, 1.0);
+        pileup.add_alignment(&a_2, 1.0);
+        pileup.add_alignment(&a_3, 1.0);
+        let read_names: Vec<String> = pileup.bases.iter().map(|b| b.get_read_names_str())
+            .collect();
+        assert_eq!(read_names[0], "");
+        assert_eq!(read_names[4], "T:r1");
+        assert_eq!(read_names[5], "C:r1,r2,r3");
+        assert_eq!(read_names[8], "-:r2;A:r3");
+
+        let mut window = PileupWindow::new(seq, u32::MAX, 0, Some(1), true);
+        window.add_alignment(&a_1, 1.0);
+        window.add_alignment(&a_2, 1.0);
+        window.add_alignment(&a_3, 1.0);
+        let bases = window.take_finished(None);
+        assert!(bases.iter().map(|b| b.get_read_names_str()).eq(read_names));
+
+        let mut pileup = Pileup::new(seq, u32::MAX, 0, Some(1), false);
+        pileup.add_alignment(&a_1, 1.0);
+        assert!(pileup.bases.iter().all(|b| b.get_read_names_str().is_empty()));
+    }
+
+    #[test]
+    fn test_pileup_window() {
+        let seq = "ACGTACGTACGTACGTACGT";
+        let a_1 = Alignment::new("r1\t0\tx\t3\t60\t6M\t*\t0\t0\tGTTCGT\tKKKKKK\tNM:i:1", &[])
+            .unwrap();
+        let a_2 = Alignment::new("r2\t0\tx\t6\t60\t3M1D4M\t*\t0\t0\tCGTCGTA\tKKKKKKK\tNM:i:1", &[])
+            .unwrap();
+        let mut pileup = Pileup::new(seq, u32::MAX, 0, Some(1), false);
+        pileup.add_alignment(&a_1, 1.0);
+        pileup.add_alignment(&a_2, 0.5);
+
+        let mut window = PileupWindow::new(seq, u32::MAX, 0, Some(1), false);
+        window.add_alignment(&a_1, 1.0);
+        let mut bases = window.take_finished(Some(5));
+        window.add_alignment(&a_2, 0.5);
+        bases.extend(window.take_finished(None));
+
+        assert_eq!(bases.len(), seq.len());
+        for (a, b) in pileup.bases.iter().zip(&bases) {
+            assert_eq!(a.original, b.original);
+            assert_eq!(a.depth, b.depth);
+            assert_eq!(a.get_count_str(), b.get_count_str());
+        }
+        assert_eq!(bases[8].get_count_str(), "-x1");
+    }
+
+    #[test]
+    fn test_saved_line() {
+        let mut b = PileupBase::new('G');
+        for _ in 0..3 {b.add_seq("G", 0.5, 1.0);}
+        b.add_seq("GT", 1.0, 1.0);
+        b.add_seq("-", 1.0, 1.0);
+        b.skipped_count = 2;
+        let line = b.saved_line();
+        assert_eq!(line, "G\t3.5\t2\t-:1,G:3,GT:1\t3\t0,0,39,0");
+
+        let mut restored = PileupBase::new('G');
+        assert!(restored.restore(&line));
+        assert_eq!(restored.saved_line(), line);
+        assert_eq!(restored.get_count_str(), b.get_count_str());
+        assert_eq!(restored.seq_count, b.seq_count);
+        assert!(restored.restore("G\t3.5\t2\t-:1,G:3,GT:1"));
+        assert_eq!(restored.multi_mapped_fraction(), Some(0.0));
+        assert!(restored.restore("G\t3.5\t2\t-:1,G:3,GT:1\t3"));
+        assert_eq!(restored.saved_line(), line);
+        assert!(!restored.restore("G\t3.5\t2\t-:1,G:3,GT:1\t3\t0,0,39"));
+        assert!(restored.restore(&line));
+
+        assert!(!PileupBase::new('A').restore(&line));  // different original base
+        assert!(!restored.restore("G\t3.5\t2"));
+        assert!(!restored.restore("G\tx\t2\tG:3"));
+        assert_eq!(restored.saved_line(), line);
+    }
+
+    #[test]
+    fn test_bayes_model() {
+        let mut thresholds = Thresholds::new(5, 0.5, 0.2);
+        thresholds.min_posterior = Some(0.99);
+        let call = |b: &PileupBase| {
+            let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
+            (polished, status)
+        };
+
+        // A few unanimous reads are enough for a change, below --min_depth.
+        let mut b = PileupBase::new('A');
+        for _ in 0..3 {b.add_seq_with_qual("C", Some(30), 1.0, 1.0);}
+        let (polished, status) = call(&b);
+        assert_eq!(polished, "C");
+        assert!(matches!(status, BaseStatus::Changed));
+
+        // But not if their bases are low quality.
+        let mut b = PileupBase::new('A');
+        for _ in 0..3 {b.add_seq_with_qual("C", Some(2), 1.0, 1.0);}
+        let (polished, status) = call(&b);
+        assert_eq!(polished, "A");
+        assert!(matches!(status, BaseStatus::TooClose));
+
+        // A few errors don't prevent a change.
+        let mut b = PileupBase::new('A');
+        for _ in 0..8 {b.add_seq("C", 1.0, 1.0);}
+        for _ in 0..2 {b.add_seq("A", 1.0, 1.0);}
+        assert_eq!(call(&b).0, "C");
+
+        // An even split is a mixture, and a less even one is ambiguous.
+        let mut b = PileupBase::new('A');
+        for _ in 0..12 {b.add_seq("C", 1.0, 1.0);}
+        for _ in 0..8 {b.add_seq("A", 1.0, 1.0);}
+        let (polished, status) = call(&b);
+        assert_eq!(polished, "A");
+        assert!(matches!(status, BaseStatus::MultipleValidOptions));
+        let (posteriors, mixture) = b.posteriors();
+        assert!(mixture > 0.99);
+        assert!(posteriors.iter().all(|(_, p)| *p < 0.01));
+
+        let mut b = PileupBase::new('A');
+        assert!(matches!(call(&b).1, BaseStatus::DepthTooLow));
+        for _ in 0..15 {b.add_seq("C", 1.0, 1.0);}
+        for _ in 0..5 {b.add_seq("A", 1.0, 1.0);}
+        let (polished, status) = call(&b);
+        assert_eq!(polished, "A");
+        assert!(matches!(status, BaseStatus::TooClose));
+    }
+
+    #[test]
+    fn test_deletion_events() {
+        let base = |original: char, deleted: usize| {
+            let mut b = PileupBase::new(original);
+            for _ in 0..deleted {b.add_seq("-", 1.0, 1.0);}
+            for _ in deleted..20 {b.add_seq(&original.to_string(), 1.0, 1.0);}
+            b
+        };
+        let thresholds = Thresholds::new(5, 0.5, 0.2);
+        let call = |b: &PileupBase, events: &[DeletionEvent], pos: usize| {
+            let call = b.get_polished_seq(&thresholds, &[], None, false);
+            match event_at(events, pos) {
+                Some(event) => b.apply_deletion_event(event, call).0,
+                None        => call.0,
+            }
+        };
+
+        // The third base is too close on its own, but the deletion is made as a whole.
+        let bases = [base('A', 0), base('C', 19), base('G', 19), base('T', 14), base('A', 2)];
+        let refs: Vec<&PileupBase> = bases.iter().collect();
+        assert_eq!(call(&bases[3], &[], 3), "T");
+        let events = deletion_events(&refs, |_| thresholds);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].start, events[0].end), (1, 4));
+        assert!(events[0].accepted);
+        assert_eq!(events[0].description(), "1-4:del=17.3/20.0:changed");
+        let polished: Vec<String> = bases.iter().enumerate()
+            .map(|(i, b)| call(b, &events, i)).collect();
+        assert_eq!(polished, vec!["A", "-", "-", "-", "A"]);
+
+        // Here the event is too close, so none of its bases are deleted.
+        let bases = [base('C', 18), base('G', 18), base('T', 9)];
+        let refs: Vec<&PileupBase> = bases.iter().collect();
+        assert_eq!(call(&bases[0], &[], 0), "-");
+        let events = deletion_events(&refs, |_| thresholds);
+        assert!(!events[0].accepted);
+        let polished: Vec<String> = bases.iter().enumerate()
+            .map(|(i, b)| call(b, &events, i)).collect();
+        assert_eq!(polished, vec!["C", "G", "T"]);
+
+        // A single deleted base isn't an event.
+        let bases = [base('C', 0), base('G', 18), base('T', 0)];
+        let refs: Vec<&PileupBase> = bases.iter().collect();
+        assert!(deletion_events(&refs, |_| thresholds).is_empty());
+    }
+
+    #[test]
+    fn test_multi_mapped_fraction() {
+        let mut b = PileupBase::new('A');
+        assert_eq!(b.multi_mapped_fraction(), None);
+        b.add_seq("A", 1.0, 1.0);
+        b.add_seq("A", 0.5, 1.0);
+        b.add_seq("C", 0.25, 2.0);
+        b.add_seq("AT", 1.0, 1.0);
+        assert_eq!(b.multi_mapped_fraction(), Some(0.6));
+    }
+
+    #[test]
+    fn test_sample_agreement() {
+        let sample = |a: usize, c: usize| {
+            let mut b = PileupBase::new('A');
+            for _ in 0..a {b.add_seq("A", 1.0, 1.0);}
+            for _ in 0..c {b.add_seq("C", 1.0, 1.0);}
+            b
+        };
+        let samples = [sample(0, 10), sample(0, 10), sample(10, 0)];
+        let mut pooled = PileupBase::new('A');
+        for s in &samples {
+            pooled.merge(s);
+        }
+        assert_eq!(pooled.get_count_str(), "Ax10,Cx20");
+        let samples: Vec<&PileupBase> = samples.iter().collect();
+        let mut thresholds = Thresholds::new(5, 0.5, 0.4);
+        assert_eq!(pooled.get_polished_seq(&thresholds, &samples, None, false).0, "C");
+        thresholds.min_samples = 2;
+        assert_eq!(pooled.get_polished_seq(&thresholds, &samples, None, false).0, "C");
+        thresholds.min_samples = 3;
+        let (polished, status, _) = pooled.get_polished_seq(&thresholds, &samples, None, false);
+        assert_eq!(polished, "A");
+        assert!(matches!(status, BaseStatus::TooFewSamples));
+    }
+
+    #[test]
+    fn test_weighted_seqs() {
+        let mut b = PileupBase::new('A');
+        for _ in 0..6 {b.add_seq("C", 1.0, 0.5);}
+        for _ in 0..4 {b.add_seq("A", 1.0, 1.0);}
+        assert_eq!(b.depth, 7.0);
+        assert_eq!(b.get_count_str(), "Ax4,Cx3");
+        let thresholds = Thresholds::new(2, 0.5, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
+        assert_eq!(polished, "A");
+        assert!(matches!(status, BaseStatus::TooClose));
+        b.add_seq("C", 1.0, 0.25);
+        assert_eq!(b.get_count_str(), "Ax4,Cx3.25");
+        assert_eq!(b.seq_count, 11);
+    }
+
+    #[test]
+    fn test_long_read_tie_breaker() {
+        let mut b = PileupBase::new('A');
+        for _ in 0..10 {b.add_seq("A", 1.0, 1.0);}
+        for _ in 0..10 {b.add_seq("AT", 1.0, 1.0);}
+        let thresholds = Thresholds::new(5, 0.4, 0.2);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], None, false);
+        assert_eq!(polished, "A");
+        assert!(matches!(status, BaseStatus::MultipleValidOptions));
+
+        let mut long_read = PileupBase::new('A');
+        for _ in 0..9 {long_read.add_seq("AT", 1.0, 1.0);}
+        long_read.add_seq("A", 1.0, 1.0);
+        let (polished, status, _) = b.get_polished_seq(&thresholds, &[], Some(&long_read), false);
+        assert_eq!(polished, "AT");
+        assert!(matches!(status, BaseStatus::LongReadChanged));
+
+        // Long reads can't choose a sequence without short-read support.
+        let mut long_read = PileupBase::new('A');
+        for _ in 0..10 {long_read.add_seq("C", 1.0, 1.0);}
+        let (polished, _, _) = b.get_polished_seq(&thresholds, &[], Some(&long_read), false);
+        assert_eq!(polished, "A");
+
+        // Or break a tie if they're split themselves.
+        let mut long_read = PileupBase::new('A');
+        for _ in 0..5 {long_read.add_seq("AT", 1.0, 1.0);}
+        for _ in 0..5 {long_read.add_seq("A", 1.0, 1.0);}
+        let (polished, _, _) = b.get_polished_seq(&thresholds, &[], Some(&long_read), false);
+        assert_eq!(polished, "A");
+    }
 }
@@ -0,0 +1,168 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// A read with multiple usable alignments adds one read's worth of depth to the pileups, shared
+// between its alignments (placements). By default, each gets an equal share (1/n). With
+// --placement-weights, clearly better placements get more: by MAPQ, the read's highest-MAPQ
+// placements get the probability that they're correct and the others share the rest, and by
+// alignment score, each 10 points of AS below the read's best divide a placement's share by 10.
+// This helps at the edges of imperfect repeats, where one copy is the read's true source. Scores
+// are treated like MAPQ (on a log scale) instead of letting any better placement take all of the
+// depth, as an assembly error in the read's true copy also lowers its score there. Reads lacking
+// the needed values (MAPQ 255 or no AS tag) are shared equally.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PlacementWeights {
+    #[default]
+    Equal,
+    Mapq,
+    Score,
+}
+
+impl PlacementWeights {
+    pub fn from_name(name: &str) -> PlacementWeights {
+        match name {
+            "mapq"  => PlacementWeights::Mapq,
+            "score" => PlacementWeights::Score,
+            _       => PlacementWeights::Equal,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlacementWeights::Equal => "equal",
+            PlacementWeights::Mapq  => "mapq",
+            PlacementWeights::Score => "score",
+        }
+    }
+}
+
+
+/// The MAPQ and alignment score of one of a read's alignments.
+pub type Placement = (Option<u8>, Option<i32>);
+
+
+/// Returns each placement's share of its read's depth, which sum to one. Placements with the same
+/// MAPQ and alignment score always get the same share.
+pub fn shares(weights: PlacementWeights, placements: &[Placement]) -> Vec<f64> {
+    let n = placements.len();
+    let equal = vec![1.0 / n as f64; n];
+    match weights {
+        PlacementWeights::Equal => equal,
+        PlacementWeights::Mapq  => {
+            let Some(mapqs) = placements.iter().map(|p| p.0).collect::<Option<Vec<u8>>>() else {
+                return equal;
+            };
+            let top = *mapqs.iter().max().unwrap_or(&0);
+            let top_count = mapqs.iter().filter(|&&q| q == top).count();
+            if top_count == n {
+                return equal;
+            }
+            // MAPQ is -10 log10 of the chance the placement is wrong, but the top placements
+            // never get less than their equal share (e.g. for MAPQ 0).
+            let top_share = (1.0 - 10.0_f64.powf(-(top as f64) / 10.0))
+                .max(top_count as f64 / n as f64);
+            mapqs.iter().map(|&q| {
+                if q == top { top_share / top_count as f64 }
+                else { (1.0 - top_share) / (n - top_count) as f64 }
+            }).collect()
+        },
+        PlacementWeights::Score => {
+            let Some(scores) = placements.iter().map(|p| p.1).collect::<Option<Vec<i32>>>() else {
+                return equal;
+            };
+            let best = *scores.iter().max().unwrap_or(&0);
+            let weights: Vec<f64> = scores.iter()
+                .map(|&s| 10.0_f64.powf((s - best) as f64 / 10.0)).collect();
+            let total: f64 = weights.iter().sum();
+            weights.iter().map(|w| w / total).collect()
+        },
+    }
+}
+
+
+/// The shares of a read's distinct placements, for when its alignments are used one at a time
+/// (e.g. in --windowed mode) instead of all together.
+#[derive(Clone, Debug, Default)]
+pub struct PlacementShares(Vec<(Placement, f64)>);
+
+impl PlacementShares {
+    pub fn new(weights: PlacementWeights, placements: &[Placement]) -> PlacementShares {
+        let mut distinct = Vec::new();
+        for (&p, share) in placements.iter().zip(shares(weights, placements)) {
+            if !distinct.iter().any(|&(d, _)| d == p) {
+                distinct.push((p, share));
+            }
+        }
+        PlacementShares(distinct)
+    }
+
+    pub fn get(&self, placement: Placement) -> Option<f64> {
+        self.0.iter().find(|&&(p, _)| p == placement).map(|&(_, share)| share)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_shares(weights: PlacementWeights, placements: &[Placement], expected: &[f64]) {
+        let s = shares(weights, placements);
+        assert_eq!(s.len(), expected.len());
+        for (a, b) in s.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-9, "{:?} != {:?}", s, expected);
+        }
+    }
+
+    #[test]
+    fn test_equal_shares() {
+        let placements = [(Some(60), Some(100)), (Some(0), Some(90))];
+        assert_shares(PlacementWeights::Equal, &placements, &[0.5, 0.5]);
+        assert_shares(PlacementWeights::Equal, &placements[..1], &[1.0]);
+        assert!(shares(PlacementWeights::Score, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_mapq_shares() {
+        let placements = [(Some(20), None), (Some(0), None), (Some(0), None)];
+        assert_shares(PlacementWeights::Mapq, &placements, &[0.99, 0.005, 0.005]);
+        let placements = [(Some(3), None), (Some(0), None), (Some(0), None)];
+        assert_shares(PlacementWeights::Mapq, &placements,
+                      &[1.0 - 10.0_f64.powf(-0.3), 10.0_f64.powf(-0.3) / 2.0,
+                        10.0_f64.powf(-0.3) / 2.0]);
+        let placements = [(Some(1), None), (Some(0), None), (Some(0), None), (Some(0), None)];
+        assert_shares(PlacementWeights::Mapq, &placements, &[0.25, 0.25, 0.25, 0.25]);
+        let placements = [(Some(0), None), (Some(0), None)];
+        assert_shares(PlacementWeights::Mapq, &placements, &[0.5, 0.5]);
+        let placements = [(Some(60), None), (None, None)];
+        assert_shares(PlacementWeights::Mapq, &placements, &[0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_score_shares() {
+        let placements = [(None, Some(100)), (None, Some(90)), (None, Some(100))];
+        assert_shares(PlacementWeights::Score, &placements, &[1.0 / 2.1, 0.1 / 2.1, 1.0 / 2.1]);
+        let placements = [(None, Some(100)), (None, Some(-100_000))];
+        assert_shares(PlacementWeights::Score, &placements, &[1.0, 0.0]);
+        let placements = [(None, Some(100)), (None, None)];
+        assert_shares(PlacementWeights::Score, &placements, &[0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_placement_shares() {
+        let placements = [(None, Some(100)), (None, Some(80)), (None, Some(100)),
+                          (None, Some(80))];
+        let s = PlacementShares::new(PlacementWeights::Score, &placements);
+        assert!((s.get((None, Some(80))).unwrap() - 0.01 / 2.02).abs() < 1e-9);
+        assert_eq!(s.get((None, Some(90))), None);
+    }
+}
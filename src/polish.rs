@@ -9,294 +9,2252 @@
 // Public License for more details. You should have received a copy of the GNU General Public
 // License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
 
-use std::path::PathBuf;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::prelude::*;
 use clap::crate_version;
+use indicatif::HumanBytes;
 use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
+use crate::aligner;
+#[cfg(feature = "arrow")]
+use crate::arrow_debug::ArrowDebugFile;
 use crate::alignment;
+use crate::alignment::{AlignmentFilter, DiscardCounts, DiscardReason, SamCounts};
+use crate::batched;
+use crate::bed::BedWriter;
+use crate::bwa::BwaIndex;
+use crate::changes::ChangesFile;
+use crate::checkpoint;
+use crate::checkpoint::{Checkpoint, LoadState};
+use crate::complexity;
+use crate::downsample;
+use crate::downsample::Downsampler;
+use crate::filter;
+use crate::filter::PairSettings;
+use crate::gaps;
+use crate::gfa;
+use crate::gfa::GraphOutput;
+use crate::gvcf::GvcfFile;
 use crate::log;
+use crate::long_reads;
+use crate::memory;
 use crate::misc;
+use crate::misc::{ErrorType, Instant};
+use crate::mixture::MixtureReport;
+use crate::normalise::LeftNormaliser;
+use crate::options::PolishOptions;
+use crate::output;
+use crate::output::{SeqWriter, SplitWriter};
 use crate::pileup;
+use crate::pileup::{BaseSummary, DeletionEvent, PileupSettings, Thresholds};
+use crate::placement::PlacementWeights;
+use crate::progress::{Progress, Unit};
+use crate::report::{HtmlReport, SvgPlot};
+use crate::residual;
+use crate::rotate;
+use crate::rotate::Rotation;
+use crate::run::RunContext;
+use crate::sink::{ChangeSink, SeqSink};
+use crate::split;
+use crate::stats;
+use crate::stats::AssemblyStats;
+use crate::windowed;
 
 
-pub fn polish(debug: Option<PathBuf>, fraction_invalid: f64, fraction_valid: f64, max_errors: u32,
-              min_depth: u32, careful: bool, assembly: PathBuf, sam: Vec<PathBuf>) {
+/// Files with at least this many reads are expected to have some reads with multiple alignments.
+const MULTI_ALIGNMENT_CHECK_MIN_READS: usize = 1000;
+
+/// If fewer than this fraction of reads have multiple alignments, the aligner probably only gave
+/// each read's best alignment.
+const MIN_MULTI_ALIGNED_FRACTION: f64 = 0.001;
+
+/// A warning is given if less than this fraction of alignments are kept (not counting those
+/// discarded by --careful or --max-hits).
+const LOW_KEPT_FRACTION: f64 = 0.5;
+
+/// With --plasmid-aware, sequences shorter than this are treated as high-copy plasmids if their
+/// mean depth is at least HIGH_COPY_MIN_RATIO times the depth of the longer sequences.
+const PLASMID_MAX_LENGTH: usize = 50_000;
+const HIGH_COPY_MIN_RATIO: f64 = 3.0;
+
+
+pub fn polish(options: &PolishOptions) {
     let start_time = Instant::now();
-    check_option_values(fraction_invalid, fraction_valid);
-    check_inputs_exist(&assembly, &sam);
-    starting_message(&debug, fraction_invalid, fraction_valid, max_errors, min_depth,
-                     careful, &assembly, &sam);
-    let (seq_names, mut pileups) = load_assembly(&assembly);
-    load_alignments(max_errors, careful, &sam, &mut pileups);
-    let new_lengths = polish_sequences(&debug, fraction_invalid, fraction_valid, min_depth,
-                                       &seq_names, &mut pileups);
-    finished_message(&debug, new_lengths, start_time);
+    let replaces_max_errors = options.max_error_rate.is_some() || options.min_identity.is_some();
+    let mut filter = AlignmentFilter {
+        max_errors: (!replaces_max_errors).then_some(options.max_errors),
+        max_error_rate: options.max_error_rate, min_identity: options.min_identity,
+        min_alignment_score: options.min_alignment_score, max_score_diff: options.max_score_diff,
+        careful: options.careful, exclude_qcfail: options.exclude_qcfail,
+        exclude_alt_hits: options.exclude_xa, max_hits: options.max_hits,
+        placement_weights: PlacementWeights::from_name(&options.placement_weights),
+        identity_weighting: options.identity_weighting,
+        fail_tags: options.fail_tags.clone(),
+        run: RunContext::new(options.skip_bad_records, options.max_bad_records,
+                             options.validate_nm, options.mmap),
+        ..Default::default()
+    };
+    let mut thresholds = Thresholds::new(options.min_depth, options.fraction_valid,
+                                         options.fraction_invalid);
+    thresholds.indel_min_depth = options.indel_min_depth.unwrap_or(options.min_depth);
+    thresholds.indel_fraction_valid = options.indel_fraction_valid
+        .unwrap_or(options.fraction_valid);
+    thresholds.joint_indels = options.joint_indels;
+    thresholds.low_complexity_fraction_valid = options.low_complexity_fraction_valid;
+    thresholds.min_posterior = (options.model == "bayes").then_some(options.min_posterior);
+    thresholds.relative_min_depth = options.relative_min_depth;
+    if !options.samples.is_empty() {
+        thresholds.min_samples = options.min_samples;
+    }
+    if options.only_substitutions || options.only_insertions || options.only_deletions {
+        thresholds.allow_substitutions = options.only_substitutions;
+        thresholds.allow_insertions = options.only_insertions;
+        thresholds.allow_deletions = options.only_deletions;
+    }
+    check_option_values(&thresholds, &filter, options.target_depth, options.max_depth,
+                        options.local_depth_window, options.require_mean_depth);
+    let pair = filter::apply_preset(&options.preset, options.orientation.clone(), options.low,
+                                    options.high);
+    let (assemblies, mut sam) = split_inputs(options.assembly.clone(), options.sam.clone());
+    if options.paired {
+        if !sam.is_empty() {
+            misc::quit_with_error(ErrorType::Args, "with --paired, the alignment files are given \
+                                                    with --in1 and --in2")
+        }
+        filter::check_orientation(&pair.orientation);
+        filter::check_percentiles(pair.low, pair.high);
+        sam = vec![options.in1.clone().unwrap(), options.in2.clone().unwrap()];
+    }
+    let reads: Vec<PathBuf> = options.reads1.iter().chain(&options.reads2).cloned().collect();
+    if !reads.is_empty() && !sam.is_empty() {
+        misc::quit_with_error(ErrorType::Args, "alignment files can't be given with --reads1")
+    }
+    if options.threads == 0 {
+        misc::quit_with_error(ErrorType::Args, "--threads must be greater than 0")
+    }
+    if sam.is_empty() && reads.is_empty() && options.load_pileup.is_none() {
+        misc::quit_with_error(ErrorType::Args, "no alignment files were given")
+    }
+    if !sam.is_empty() && options.load_pileup.is_some() {
+        misc::quit_with_error(ErrorType::Args,
+                              "alignment files can't be given with --load-pileup")
+    }
+    for tag in &options.fail_tags {
+        filter::check_fail_tag(tag);
+    }
+    check_inputs_exist(&assemblies, &sam);
+    filter.skippable_seqs = split::shard_header_seqs(&sam, &filter.run);
+    if let Some(filename) = &options.long_reads {
+        misc::check_if_file_exists(filename);
+    }
+    for filename in reads.iter().chain(&options.rotated_sam) {
+        misc::check_if_file_exists(filename);
+    }
+    let assembly_is_gfa = assemblies.iter().any(|a| gfa::is_gfa(a));
+    if assembly_is_gfa && assemblies.len() > 1 {
+        misc::quit_with_error(ErrorType::Args,
+                              "a GFA assembly can't be combined with other assembly files")
+    }
+    if assembly_is_gfa && options.output_fastq {
+        misc::quit_with_error(ErrorType::Args, "--output-fastq can't be used with a GFA assembly")
+    }
+    if assembly_is_gfa && options.annotate_headers {
+        misc::quit_with_error(ErrorType::Args,
+                              "--annotate-headers can't be used with a GFA assembly")
+    }
+    if assembly_is_gfa && options.fai {
+        misc::quit_with_error(ErrorType::Args, "--fai can't be used with a GFA assembly")
+    }
+    if assembly_is_gfa && options.rotate.is_some() {
+        misc::quit_with_error(ErrorType::Args, "--rotate can't be used with a GFA assembly")
+    }
+    if assembly_is_gfa && !options.rotated_sam.is_empty() {
+        misc::quit_with_error(ErrorType::Args, "--rotated-sam can't be used with a GFA assembly")
+    }
+    if options.debug_format == "arrow" && !cfg!(feature = "arrow") {
+        misc::quit_with_error(ErrorType::Args, "--debug-format arrow needs Polypolish to be built \
+                                                with the arrow feature (cargo build --release \
+                                                --features arrow)")
+    }
+    if assembly_is_gfa && options.split_output.is_some() {
+        misc::quit_with_error(ErrorType::Args, "--split-output can't be used with a GFA assembly")
+    }
+    if assembly_is_gfa && options.extend_ends.is_some() {
+        misc::quit_with_error(ErrorType::Args, "--extend-ends can't be used with a GFA assembly")
+    }
+    if options.extend_ends == Some(0) {
+        misc::quit_with_error(ErrorType::Args, "--extend-ends must be greater than 0")
+    }
+    if options.batch_size == Some(0) {
+        misc::quit_with_error(ErrorType::Args, "--batch-size must be greater than 0")
+    }
+    let rotation = options.rotate.as_deref().map(Rotation::parse);
+    if options.merge_overlaps && sam.len() != 2 {
+        misc::quit_with_error(ErrorType::Args,
+                              "--merge-overlaps requires exactly two alignment files (one for \
+                               each read of the pair)")
+    }
+    check_samples(&options.samples, options.min_samples, &sam);
+    check_weights(&options.weights, &sam);
+    let weights = if options.weights.is_empty() { vec![1.0; sam.len()] }
+                  else { options.weights.clone() };
+    starting_message(options, &thresholds, &filter, &pair, &assemblies, &sam, &reads);
+    let max_depth = options.max_depth.unwrap_or(u32::MAX);
+    let (mut fasta, graph) = load_assembly(&assemblies, assembly_is_gfa);
+    let contigs: Vec<(String, usize)> = fasta.iter()
+        .map(|(name, _, seq)| (name.clone(), seq.len())).collect();
+    let original_stats = AssemblyStats::new(&fasta.iter()
+        .map(|(_, _, seq)| (seq.len(), stats::gc_count(seq))).collect::<Vec<_>>());
+    let assembly_length = fasta.iter().map(|(_, _, seq)| seq.len()).sum();
+    let downsampler = downsample::make_downsampler(options.target_depth, options.seed,
+                                                   &sam, assembly_length, &filter.run);
+    let inputs = AlignmentInputs { sam: &sam, weights: &weights, filter: &filter,
+                                   downsampler: &downsampler, auto_sort: options.auto_sort,
+                                   merge_overlaps: options.merge_overlaps,
+                                   strict: options.strict };
+
+    // With --rotate, the alignments are to the rotated assembly, so the pileups are made from
+    // rotated sequences. Polishing rotates them back.
+    let offsets = match rotation {
+        Some(rotation) => {
+            let offsets = rotate::rotate_circular_seqs(&mut fasta, rotation);
+            rotate::log_rotations(&fasta, &offsets);
+            offsets
+        },
+        None => misc::FastHashMap::default(),
+    };
+    let index = (!reads.is_empty()).then(|| BwaIndex::build(&fasta, options.threads));
+
+    let homopolymer_trim = (!options.no_homopolymer_trim).then_some(options.homopolymer_trim);
+    let pileup_settings = PileupSettings { max_depth, trim_ends: options.trim_ends,
+                                           homopolymer_trim, record_reads: options.debug_reads };
+
+    // Outside of windowed and batched modes, all alignments are loaded before any output is
+    // made, so the run can stop without output if the read depth is too low.
+    let (fasta, loaded) = if options.windowed || options.batch_size.is_some() {
+        (fasta, None)
+    } else {
+        let rotated_fasta = (!options.rotated_sam.is_empty()).then(|| fasta.clone());
+        let (seq_names, mut pileups) = make_pileups(fasta, pileup_settings);
+        let mut extras = ExtraInputs { offsets, ..Default::default() };
+        if !options.samples.is_empty() {
+            extras.samples = load_sample_alignments(&inputs, &options.samples, &mut pileups);
+        } else if let Some(filename) = &options.load_pileup {
+            load_saved_pileup(filename, &filter, &mut pileups);
+        } else {
+            let settings = json!({"filter": format!("{:?}", filter), "careful": options.careful,
+                                  "target_depth": options.target_depth, "seed": options.seed,
+                                  "max_depth": max_depth,
+                                  "merge_overlaps": options.merge_overlaps,
+                                  "trim_ends": options.trim_ends,
+                                  "homopolymer_trim": homopolymer_trim,
+                                  "skip_bad_records": options.skip_bad_records,
+                                  "max_bad_records": options.max_bad_records,
+                                  "weights": weights, "rotate": options.rotate,
+                                  "fail_tags": options.fail_tags,
+                                  "paired": options.paired.then_some((&pair.orientation,
+                                                                      pair.low, pair.high))});
+            let files: Vec<&PathBuf> = assemblies.iter().chain(&sam).chain(&reads).collect();
+            let fingerprint = checkpoint::fingerprint(settings, &files);
+            let checkpoint = options.checkpoint.as_ref()
+                .map(|dir| Checkpoint::new(dir, fingerprint.clone()));
+            let state = if options.paired {
+                load_paired_alignments(&inputs, &pair, &mut pileups)
+            } else if let Some(index) = &index {
+                load_read_alignments(index, &reads, &filter, &mut pileups)
+            } else {
+                load_alignments(&inputs, checkpoint.as_ref(), &mut pileups)
+            };
+            if let Some(filename) = &options.save_pileup {
+                checkpoint::save_pileup(filename, &fingerprint, &state, &pileups);
+            }
+        }
+        check_mean_depth(options.require_mean_depth, &pileups);
+        extras.long_reads = options.long_reads.as_ref()
+            .map(|filename| long_reads::load_long_reads(filename, &pileups, &filter.run));
+        if let Some(fasta) = rotated_fasta {
+            let rotated_inputs = AlignmentInputs { sam: &options.rotated_sam, ..inputs };
+            extras.rotated = load_rotated_alignments(fasta, &rotated_inputs, pileup_settings);
+        }
+        if options.fill_ns {
+            extras.fills = gaps::fill_n_runs(&seq_names, &pileups, &sam, &filter.run);
+        }
+        if let Some(max_length) = options.extend_ends {
+            extras.extensions = gaps::extend_ends(&seq_names, &pileups, &sam, &filter.run,
+                                                  max_length, options.mask_extensions);
+        }
+        (Vec::new(), Some((seq_names, pileups, extras)))
+    };
+    let paths = OutputPaths::from_options(options);
+    let mut outputs = OutputFiles {
+        debug: create_debug_file(&paths.debug, options.debug_filter.clone(), options.debug_reads,
+                                 options.low_complexity_fraction_valid.is_some(),
+                                 options.joint_indels, &options.debug_format),
+        depth: paths.depth_out.as_ref().map(|f| BedWriter::create(f)),
+        uncovered: paths.uncovered_bed.as_ref().map(|f| BedWriter::create(f)),
+        multi_mapped: paths.multi_mapped_out.as_ref().map(|f| BedWriter::create(f)),
+        seqs: seq_sinks(graph, &paths, options.output_fastq),
+        html: paths.html.as_ref().map(|f| HtmlReport::new(f)),
+        plot: paths.plot.as_ref().map(|f| SvgPlot::new(f)),
+        changes: paths.changes.as_ref().map(|f| -> Box<dyn ChangeSink> {
+            Box::new(LeftNormaliser::new(Box::new(ChangesFile::create(f))))
+        }),
+        mixtures: paths.mixtures.as_ref().map(|f| MixtureReport::new(f)),
+        gvcf: paths.gvcf.as_ref().map(|f| GvcfFile::create(f, &contigs)),
+        fastq: options.output_fastq,
+        annotate_headers: options.annotate_headers,
+        run: filter.run.clone(),
+    };
+    let polished_seqs = match loaded {
+        Some((seq_names, pileups, extras)) => {
+            let polished_seqs = polish_sequences(&mut outputs, &thresholds,
+                                                 options.local_depth_window,
+                                                 options.plasmid_aware, &seq_names, &pileups,
+                                                 &extras);
+            if let Some(filename) = &options.gap_patches {
+                gaps::write_gap_patches(filename, &seq_names, &pileups, &sam, &filter.run);
+            }
+            polished_seqs
+        },
+        None => match options.batch_size {
+            Some(size) => {
+                batched::polish_batched(&mut outputs, &thresholds, &inputs, pileup_settings, fasta,
+                                        size)
+            },
+            None => {
+                windowed::polish_windowed(options, &thresholds, &inputs, &fasta, &mut outputs)
+            },
+        },
+    };
+    outputs.finish(&polished_seqs);
+    finished_message(&paths, &original_stats, polished_seqs, assembly_is_gfa, &filter.run,
+                     start_time);
 }
 
 
-fn starting_message(debug: &Option<PathBuf>, fraction_invalid: f64, fraction_valid: f64,
-                    max_errors: u32, min_depth: u32, careful: bool, assembly: &PathBuf,
-                    sam: &Vec<PathBuf>) {
+fn starting_message(options: &PolishOptions, thresholds: &Thresholds, filter: &AlignmentFilter,
+                    pair: &PairSettings, assemblies: &[PathBuf], sam: &[PathBuf],
+                    reads: &[PathBuf]) {
+    let &PolishOptions { ref debug, careful, windowed, batch_size, auto_sort, target_depth, seed,
+                         max_depth, merge_overlaps, trim_ends, homopolymer_trim,
+                         no_homopolymer_trim, output_fastq, annotate_headers, ref output, bgzip,
+                         fai, ref split_output, split_only, skip_bad_records, max_bad_records,
+                         validate_nm, mmap: use_mmap, require_mean_depth, strict, ref checkpoint,
+                         ref save_pileup, ref load_pileup, ref debug_filter, debug_reads,
+                         ref debug_format, ref depth_out, ref uncovered_bed, ref multi_mapped_out,
+                         ref html, ref plot, ref changes, ref mixtures, ref gvcf, ref samples,
+                         ref weights, ref long_reads, plasmid_aware, ref rotate, ref rotated_sam,
+                         paired, local_depth_window, ref fail_tags, ref gap_patches, fill_ns,
+                         extend_ends, mask_extensions, threads, .. } = options;
+    let PairSettings { orientation, low, high, .. } = pair;
+    let homopolymer_trim = (!no_homopolymer_trim).then_some(homopolymer_trim);
     log::section_header("Starting Polypolish polish");
     log::explanation("Polypolish is a tool for polishing genome assemblies with short reads. \
                       Unlike other tools in this category, Polypolish uses SAM files where each \
                       read has been aligned to all possible locations (not just a single best \
                       location). This allows it to repair errors in repeat regions that other \
                       alignment-based polishers cannot fix.");
-    eprintln!("Polypolish version: {}", crate_version!());
-    eprintln!();
-    eprintln!("Input assembly:");
-    eprintln!("  {}", assembly.display());
-    eprintln!();
-    eprintln!("Input short-read alignments:");
-    for s in sam {
-        eprintln!("  {}", s.display());
-    }
-    eprintln!();
-    eprintln!("Settings:");
-    eprintln!("  --fraction_invalid {}", fraction_invalid);
-    eprintln!("  --fraction_valid {}", fraction_valid);
-    eprintln!("  --max_errors {}", max_errors);
-    eprintln!("  --min_depth {}", min_depth);
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input assembly:");
+    for a in assemblies {
+        log::text!("  {}", a.display());
+    }
+    log::text!();
+    if let Some(filename) = long_reads {
+        log::text!("Input long-read alignments:");
+        log::text!("  {}", filename.display());
+        log::text!();
+    }
+    if !rotated_sam.is_empty() {
+        log::text!("Input alignments to the rotated assembly:");
+        for s in rotated_sam {
+            log::text!("  {}", s.display());
+        }
+        log::text!();
+    }
+    if let Some(filename) = load_pileup {
+        log::text!("Input pileup:");
+        log::text!("  {}", filename.display());
+    } else if !reads.is_empty() {
+        log::text!("Input short reads (to be aligned with bwa mem -a):");
+        for r in reads {
+            log::text!("  {}", r.display());
+        }
+    } else {
+        log::text!("Input short-read alignments:");
+        for (i, s) in sam.iter().enumerate() {
+            let mut labels = Vec::new();
+            if let Some(sample) = samples.get(i) {
+                labels.push(format!("sample {}", sample));
+            }
+            if let Some(weight) = weights.get(i).filter(|w| **w != 1.0) {
+                labels.push(format!("weight {}", weight));
+            }
+            if labels.is_empty() {
+                log::text!("  {}", s.display());
+            } else {
+                log::text!("  {} ({})", s.display(), labels.join(", "));
+            }
+        }
+    }
+    log::text!();
+    let Thresholds { min_depth, fraction_valid, fraction_invalid, indel_min_depth,
+                     indel_fraction_valid, allow_substitutions, allow_insertions,
+                     allow_deletions, low_complexity_fraction_valid, min_samples,
+                     relative_min_depth, min_posterior, joint_indels } = *thresholds;
+    let AlignmentFilter { max_errors, max_error_rate, min_identity, min_alignment_score,
+                          max_score_diff, exclude_qcfail, exclude_alt_hits, max_hits,
+                          placement_weights, identity_weighting, .. } = *filter;
+    let all_allowed = allow_substitutions && allow_insertions && allow_deletions;
+    log::text!("Settings:");
+    match min_posterior {
+        Some(min_posterior) => log::text!("  --model bayes --min-posterior {}", min_posterior),
+        None                => {
+            log::text!("  --fraction_invalid {}", fraction_invalid);
+            log::text!("  --fraction_valid {}", fraction_valid);
+        },
+    }
+    log_filter_settings(filter);
+    if min_posterior.is_none() {
+        log::text!("  --min_depth {}", min_depth);
+    }
+    if indel_fraction_valid != fraction_valid {
+        log::text!("  --indel-fraction-valid {}", indel_fraction_valid);
+    }
+    if indel_min_depth != min_depth {
+        log::text!("  --indel-min-depth {}", indel_min_depth);
+    }
+    if joint_indels {
+        log::text!("  --joint-indels");
+    }
+    if !all_allowed && allow_substitutions {
+        log::text!("  --only-substitutions");
+    }
+    if !all_allowed && allow_insertions {
+        log::text!("  --only-insertions");
+    }
+    if !all_allowed && allow_deletions {
+        log::text!("  --only-deletions");
+    }
+    if let Some(window) = local_depth_window {
+        log::text!("  --local-depth-window {}", window);
+    }
+    if let Some(fraction) = relative_min_depth {
+        log::text!("  --relative-min-depth {}", fraction);
+    }
+    if let Some(fraction) = low_complexity_fraction_valid {
+        log::text!("  --low-complexity-fraction-valid {}", fraction);
+    }
+    if min_samples > 0 {
+        log::text!("  --min-samples {}", min_samples);
+    }
+    if plasmid_aware {
+        log::text!("  --plasmid-aware");
+    }
+    if let Some(rotate) = rotate {
+        log::text!("  --rotate {}", rotate);
+    }
+    if paired {
+        log::text!("  --paired --orientation {} --low {} --high {}", orientation, low, high);
+    }
+    if *fail_tags != [alignment::DEFAULT_FAIL_TAG] {
+        log::text!("  --fail-tag {}", fail_tags.join(","));
+    }
     if careful {
-        eprintln!("  --careful");
+        log::text!("  --careful");
+    }
+    if windowed {
+        log::text!("  --windowed");
+    }
+    if auto_sort {
+        log::text!("  --auto-sort");
+    }
+    if let Some(target_depth) = target_depth {
+        log::text!("  --target-depth {}", target_depth);
+        log::text!("  --seed {}", seed);
+    }
+    if let Some(max_depth) = max_depth {
+        log::text!("  --max-depth {}", max_depth);
+    }
+    if merge_overlaps {
+        log::text!("  --merge-overlaps");
+    }
+    if trim_ends > 0 {
+        log::text!("  --trim-ends {}", trim_ends);
+    }
+    match homopolymer_trim {
+        Some(1)     => {},
+        Some(extra) => log::text!("  --homopolymer-trim {}", extra),
+        None        => log::text!("  --no-homopolymer-trim"),
+    }
+    if output_fastq {
+        log::text!("  --output-fastq");
+    }
+    if annotate_headers {
+        log::text!("  --annotate-headers");
+    }
+    if let Some(filename) = output {
+        log::text!("  --output {}", filename.display());
+    }
+    if bgzip {
+        log::text!("  --bgzip");
+    }
+    if fai {
+        log::text!("  --fai");
+    }
+    if let Some(dir) = split_output {
+        log::text!("  --split-output {}", dir.display());
+    }
+    if split_only {
+        log::text!("  --split-only");
+    }
+    if skip_bad_records {
+        log::text!("  --skip-bad-records");
+    }
+    if let Some(max) = max_bad_records {
+        log::text!("  --max-bad-records {}", max);
+    }
+    if validate_nm {
+        log::text!("  --validate-nm");
+    }
+    if let Some(depth) = require_mean_depth {
+        log::text!("  --require-mean-depth {}", depth);
+    }
+    if strict {
+        log::text!("  --strict");
+    }
+    if let Some(dir) = checkpoint {
+        log::text!("  --checkpoint {}", dir.display());
+    }
+    if let Some(filename) = save_pileup {
+        log::text!("  --save-pileup {}", filename.display());
     }
     match debug {
-        Some(filename) => eprintln!("  --debug {}", filename.display()),
-        None           => eprintln!("  not logging debugging information"),
+        Some(filename) => log::text!("  --debug {}", filename.display()),
+        None           => log::text!("  not logging debugging information"),
+    }
+    if !debug_filter.is_empty() {
+        log::text!("  --debug-filter {}", debug_filter.join(","));
+    }
+    if debug_reads {
+        log::text!("  --debug-reads");
+    }
+    if debug.is_some() && debug_format != "tsv" {
+        log::text!("  --debug-format {}", debug_format);
+    }
+    if let Some(filename) = depth_out {
+        log::text!("  --depth-out {}", filename.display());
+    }
+    if let Some(filename) = uncovered_bed {
+        log::text!("  --uncovered-bed {}", filename.display());
+    }
+    if let Some(filename) = multi_mapped_out {
+        log::text!("  --multi-mapped-out {}", filename.display());
+    }
+    if let Some(filename) = html {
+        log::text!("  --html {}", filename.display());
+    }
+    if let Some(filename) = plot {
+        log::text!("  --plot {}", filename.display());
+    }
+    if let Some(filename) = changes {
+        log::text!("  --changes {}", filename.display());
+    }
+    if let Some(filename) = mixtures {
+        log::text!("  --mixtures {}", filename.display());
+    }
+    if let Some(filename) = gvcf {
+        log::text!("  --gvcf {}", filename.display());
+    }
+    if let Some(filename) = gap_patches {
+        log::text!("  --gap-patches {}", filename.display());
+    }
+    if fill_ns {
+        log::text!("  --fill-ns");
+    }
+    if let Some(max_length) = extend_ends {
+        log::text!("  --extend-ends {}", max_length);
+    }
+    if mask_extensions {
+        log::text!("  --mask-extensions");
+    }
+    if use_mmap {
+        log::text!("  --mmap");
+    }
+    if let Some(size) = batch_size {
+        log::text!("  --batch-size {}", size);
+    }
+    if !reads.is_empty() {
+        log::text!("  --threads {}", threads);
+    }
+    log::text!();
+    log::event("run_started", json!({"command": "polish", "version": crate_version!(),
+                                     "assembly": assemblies, "sam": sam, "reads": reads,
+                                     "threads": (!reads.is_empty()).then_some(threads),
+                                     "fraction_invalid": fraction_invalid,
+                                     "fraction_valid": fraction_valid,
+                                     "model": if min_posterior.is_some() { "bayes" }
+                                              else { "fractions" },
+                                     "min_posterior": min_posterior, "max_errors": max_errors,
+                                     "max_error_rate": max_error_rate,
+                                     "min_identity": min_identity,
+                                     "min_alignment_score": min_alignment_score,
+                                     "max_score_diff": max_score_diff,
+                                     "exclude_qcfail": exclude_qcfail,
+                                     "exclude_xa": exclude_alt_hits,
+                                     "min_depth": min_depth, "careful": careful,
+                                     "max_hits": max_hits,
+                                     "placement_weights": placement_weights.name(),
+                                     "identity_weighting": identity_weighting,
+                                     "windowed": windowed,
+                                     "auto_sort": auto_sort,
+                                     "target_depth": target_depth, "seed": seed,
+                                     "max_depth": max_depth, "merge_overlaps": merge_overlaps,
+                                     "trim_ends": trim_ends,
+                                     "homopolymer_trim": homopolymer_trim,
+                                     "indel_fraction_valid": indel_fraction_valid,
+                                     "indel_min_depth": indel_min_depth,
+                                     "joint_indels": joint_indels,
+                                     "local_depth_window": local_depth_window,
+                                     "relative_min_depth": relative_min_depth,
+                                     "low_complexity_fraction_valid":
+                                         low_complexity_fraction_valid,
+                                     "allow_substitutions": allow_substitutions,
+                                     "allow_insertions": allow_insertions,
+                                     "allow_deletions": allow_deletions,
+                                     "output_fastq": output_fastq,
+                                     "annotate_headers": annotate_headers, "output": output,
+                                     "bgzip": bgzip, "fai": fai, "split_output": split_output,
+                                     "split_only": split_only,
+                                     "skip_bad_records": skip_bad_records,
+                                     "max_bad_records": max_bad_records,
+                                     "validate_nm": validate_nm,
+                                     "require_mean_depth": require_mean_depth,
+                                     "strict": strict, "debug": debug,
+                                     "debug_filter": debug_filter, "debug_reads": debug_reads,
+                                     "debug_format": debug_format,
+                                     "depth_out": depth_out, "uncovered_bed": uncovered_bed,
+                                     "multi_mapped_out": multi_mapped_out,
+                                     "fail_tags": fail_tags, "gap_patches": gap_patches,
+                                     "fill_ns": fill_ns, "extend_ends": extend_ends,
+                                     "mask_extensions": mask_extensions, "mmap": use_mmap,
+                                     "batch_size": batch_size}));
+}
+
+
+pub fn log_filter_settings(filter: &AlignmentFilter) {
+    let AlignmentFilter { max_errors, max_error_rate, min_identity, min_alignment_score,
+                          max_score_diff, exclude_qcfail, exclude_alt_hits, max_hits,
+                          placement_weights, identity_weighting, .. } = *filter;
+    if let Some(max_errors) = max_errors {
+        log::text!("  --max_errors {}", max_errors);
+    }
+    if let Some(max_error_rate) = max_error_rate {
+        log::text!("  --max-error-rate {}", max_error_rate);
+    }
+    if let Some(min_identity) = min_identity {
+        log::text!("  --min-identity {}", min_identity);
+    }
+    if let Some(min_alignment_score) = min_alignment_score {
+        log::text!("  --min-alignment-score {}", min_alignment_score);
+    }
+    if let Some(max_score_diff) = max_score_diff {
+        log::text!("  --max-score-diff {}", max_score_diff);
+    }
+    if exclude_qcfail {
+        log::text!("  --exclude-qcfail");
+    }
+    if exclude_alt_hits {
+        log::text!("  --exclude-xa");
+    }
+    if let Some(max_hits) = max_hits {
+        log::text!("  --max-hits {}", max_hits);
+    }
+    if placement_weights != PlacementWeights::Equal {
+        log::text!("  --placement-weights {}", placement_weights.name());
+    }
+    if identity_weighting {
+        log::text!("  --identity-weighting");
     }
-    eprintln!();
 }
 
 
-fn finished_message(debug: &Option<PathBuf>, new_lengths: Vec<(String, usize)>,
-                    start_time: Instant) {
+pub fn finished_message(paths: &OutputPaths, original_stats: &AssemblyStats,
+                        polished_seqs: Vec<PolishedSeq>, graph: bool, run: &RunContext,
+                        start_time: Instant) {
+    let &OutputPaths { ref output, bgzip, fai, ref split_output, split_only, ref debug,
+                       ref depth_out, ref uncovered_bed, ref multi_mapped_out, ref html, ref plot,
+                       ref changes, ref mixtures, ref gvcf } = paths;
     log::section_header("Finished!");
-    eprintln!("Polished sequence (to stdout):");
-    for (new_name, new_length) in new_lengths {
-        eprintln!("  {}_polypolish ({} bp)", new_name, new_length.to_formatted_string(&Locale::en));
+    let mut destination = match output {
+        Some(filename) => filename.display().to_string(),
+        None           => "stdout".to_string(),
+    };
+    if let Some(dir) = split_output {
+        let split = format!("one file per sequence in {}", dir.display());
+        destination = if split_only { split } else { format!("{} and {}", destination, split) };
     }
-    eprintln!();
-    match debug {
-        Some(filename) => eprintln!("Per-base debugging info written to {}", filename.display()),
-        None           => {},
+    if graph {
+        log::text!("Polished graph (to {}):", destination);
+    } else {
+        log::text!("Polished sequence (to {}):", destination);
+    }
+    for s in polished_seqs.iter().filter(|s| s.copy_number.is_none()) {
+        log::text!("  {} ({} bp)", s.name, s.length.to_formatted_string(&Locale::en));
+    }
+    log::text!();
+    if polished_seqs.iter().any(|s| s.copy_number.is_some()) {
+        log::text!("High-copy plasmids (minimum depths scaled by copy number):");
+        for s in &polished_seqs {
+            if let Some(copy_number) = s.copy_number {
+                log::text!("  {} ({} bp, copy number {:.1}, {} changes)", s.name,
+                           s.length.to_formatted_string(&Locale::en), copy_number,
+                           s.changed_count.to_formatted_string(&Locale::en));
+            }
+        }
+        log::text!();
+    }
+    let polished_stats = AssemblyStats::new(&polished_seqs.iter()
+        .map(|s| (s.length, s.gc_count)).collect::<Vec<_>>());
+    print_assembly_stats(original_stats, &polished_stats);
+    let changed_total: usize = polished_seqs.iter().map(|s| s.changed_count).sum();
+    let estimated_accuracy = estimated_accuracy(changed_total, original_stats.total_length);
+    log::text!("Estimated pre-polishing assembly accuracy: {:.4}% ({})", estimated_accuracy,
+               qscore(estimated_accuracy));
+    log::text!();
+    let error_rate = residual::draft_error_rate(&polished_seqs);
+    let residual_errors = residual::estimate(&polished_seqs, error_rate);
+    let polished_accuracy = residual_errors.accuracy(polished_stats.total_length);
+    log::text!("Estimated remaining errors (with 95% interval), at positions with too little depth \
+                or ambiguous read support:");
+    for s in &polished_seqs {
+        let r = residual::estimate([s], error_rate);
+        log::text!("  {}: {:.1} ({}-{})", s.name, r.expected, r.low, r.high);
+    }
+    log::text!("  total: {:.1} ({}-{})", residual_errors.expected, residual_errors.low,
+               residual_errors.high);
+    log::text!("Estimated post-polishing assembly accuracy: {:.4}% ({})", polished_accuracy,
+               qscore(polished_accuracy));
+    log::text!();
+    run.report();
+    if let Some(filename) = debug {
+        log::text!("Per-base debugging info written to {}", filename.display());
+    }
+    if let Some(filename) = depth_out {
+        log::text!("Per-base read depth written to {}", filename.display());
+    }
+    if let Some(filename) = uncovered_bed {
+        log::text!("Zero-depth intervals written to {}", filename.display());
+    }
+    if let Some(filename) = multi_mapped_out {
+        log::text!("Per-base multi-mapped read fraction written to {}", filename.display());
+    }
+    if let Some(filename) = html {
+        log::text!("HTML report written to {}", filename.display());
+    }
+    if let Some(filename) = plot {
+        log::text!("Depth and change plots written to {}", filename.display());
+    }
+    if let Some(filename) = changes {
+        log::text!("Changes with flanking sequence written to {}", filename.display());
+    }
+    if let Some(filename) = mixtures {
+        log::text!("Mixed-population report written to {}", filename.display());
     }
-    eprintln!("Time to run: {}", misc::format_duration(start_time.elapsed()));
-    eprintln!();
+    if let Some(filename) = gvcf {
+        log::text!("gVCF written to {}", filename.display());
+    }
+    if let (true, Some(filename)) = (fai, output) {
+        log::text!("Sequence index written to {}",
+                   output::index_filename(filename, "fai").display());
+        if bgzip {
+            log::text!("Compressed sequence index written to {}",
+                       output::index_filename(filename, "gzi").display());
+        }
+    }
+    log::text!("Time to run: {}", misc::format_duration(start_time.elapsed()));
+    let peak_memory = memory::peak_memory();
+    if let Some(peak) = peak_memory {
+        log::text!("Peak memory usage: {}", HumanBytes(peak));
+    }
+    log::text!();
+    let sequences: Vec<_> = polished_seqs.iter()
+        .map(|s| json!({"name": s.name, "length": s.length, "changed": s.changed_count,
+                        "copy_number": s.copy_number,
+                        "estimated_accuracy": s.estimated_accuracy(),
+                        "estimated_qscore": qscore_value(s.estimated_accuracy()),
+                        "residual_errors": residual::estimate([s], error_rate)}))
+        .collect();
+    log::event("finished", json!({"sequences": sequences, "output": output, "debug": debug,
+                                  "depth_out": depth_out,
+                                  "uncovered_bed": uncovered_bed,
+                                  "multi_mapped_out": multi_mapped_out, "html": html,
+                                  "plot": plot,
+                                  "changes": changes, "mixtures": mixtures, "gvcf": gvcf,
+                                  "assembly_stats": {"before": original_stats,
+                                                     "after": polished_stats},
+                                  "changed": changed_total,
+                                  "bad_records": run.bad_record_count(),
+                                  "warnings": run.warnings(),
+                                  "estimated_accuracy": estimated_accuracy,
+                                  "estimated_qscore": qscore_value(estimated_accuracy),
+                                  "residual_errors": residual_errors,
+                                  "estimated_polished_accuracy": polished_accuracy,
+                                  "estimated_polished_qscore": qscore_value(polished_accuracy),
+                                  "seconds": start_time.elapsed().as_secs_f64(),
+                                  "peak_memory_bytes": peak_memory}));
+    log::end_stage();
+}
+
+
+fn print_assembly_stats(before: &AssemblyStats, after: &AssemblyStats) {
+    let f = |n: usize| n.to_formatted_string(&Locale::en);
+    log::text!("Assembly statistics (before -> after polishing):");
+    log::text!("  sequences:      {} -> {}", f(before.count), f(after.count));
+    log::text!("  total length:   {} -> {} bp", f(before.total_length), f(after.total_length));
+    log::text!("  largest:        {} -> {} bp", f(before.largest), f(after.largest));
+    log::text!("  N50:            {} -> {} bp", f(before.n50), f(after.n50));
+    log::text!("  GC content:     {:.3}% -> {:.3}%", before.gc_percent, after.gc_percent);
+    log::text!();
 }
 
 
-fn load_assembly(assembly_filename: &PathBuf) -> (Vec<(String, String)>,
-                                                  HashMap<String, pileup::Pileup>) {
+/// Loads the assembly from one or more FASTA files (in order) or a single GFA file. For GFA, the
+/// graph is also returned (with its segment sequences taken out) so the polished sequences can
+/// be put back into it.
+pub fn load_assembly(assemblies: &[PathBuf],
+                 is_gfa: bool) -> (Vec<(String, String, String)>, Option<gfa::Gfa>) {
     log::section_header("Loading assembly");
-    let fasta = misc::load_fasta(assembly_filename);
+    let (fasta, graph) = if is_gfa {
+        let mut graph = gfa::load_gfa(&assemblies[0]);
+        (graph.take_seqs(), Some(graph))
+    } else {
+        let fasta: Vec<_> = assemblies.iter().flat_map(|a| misc::load_fasta(a)).collect();
+        let names: HashSet<&str> = fasta.iter().map(|(name, _, _)| name.as_str()).collect();
+        if names.len() < fasta.len() {
+            misc::quit_with_error(ErrorType::Input,
+                                  "the assembly files have a duplicated sequence name")
+        }
+        (fasta, None)
+    };
+    for (name, _, sequence) in &fasta {
+        log::text!("{} ({} bp)", name, sequence.len().to_formatted_string(&Locale::en));
+        log::event("sequence_loaded", json!({"name": name, "length": sequence.len()}));
+    }
+    log::text!();
+    (fasta, graph)
+}
+
+
+pub fn make_pileups(fasta: Vec<(String, String, String)>, settings: PileupSettings)
+        -> (Vec<(String, String)>, misc::FastHashMap<String, pileup::Pileup>) {
+    let PileupSettings { max_depth, trim_ends, homopolymer_trim, record_reads } = settings;
     let mut seq_names = Vec::new();
-    let mut pileups = HashMap::new();
-    for (name, description, sequence) in &fasta {
-        eprintln!("{} ({} bp)", name, sequence.len().to_formatted_string(&Locale::en));
-        seq_names.push((name.clone(), description.clone()));
-        pileups.insert(name.clone(), pileup::Pileup::new(sequence));
+    let mut pileups = misc::FastHashMap::default();
+    for (name, description, sequence) in fasta {
+        pileups.insert(name.clone(), pileup::Pileup::new(&sequence, max_depth, trim_ends,
+                                                         homopolymer_trim, record_reads));
+        seq_names.push((name, description));
     }
-    eprintln!();
     (seq_names, pileups)
 }
 
 
-fn load_alignments(max_errors: u32, careful: bool, sam: &Vec<PathBuf>,
-                   pileups: &mut HashMap<String, pileup::Pileup>) {
+/// The alignment files (with their --weights) and how their alignments are read and filtered,
+/// shared by the different ways of loading them.
+#[derive(Clone, Copy)]
+pub struct AlignmentInputs<'a> {
+    pub sam: &'a [PathBuf],
+    pub weights: &'a [f64],
+    pub filter: &'a AlignmentFilter,
+    pub downsampler: &'a Downsampler,
+    pub auto_sort: bool,
+    pub merge_overlaps: bool,
+    pub strict: bool,
+}
+
+
+/// Loads each alignment file into the pileups. With a checkpoint, files loaded by an earlier run
+/// are skipped and the checkpoint is saved after each newly loaded file (or pair of files, with
+/// --merge-overlaps).
+pub fn load_alignments(inputs: &AlignmentInputs, checkpoint: Option<&Checkpoint>,
+                       pileups: &mut misc::FastHashMap<String, pileup::Pileup>) -> LoadState {
+    let AlignmentInputs { sam, weights, filter, downsampler, auto_sort, merge_overlaps,
+                          strict } = *inputs;
+    log::section_header("Loading alignments");
+    let mut state = checkpoint.and_then(|c| c.resume(pileups)).unwrap_or_default();
+    for (s, counts) in sam.iter().zip(&state.file_counts) {
+        log_sam_counts(s, counts, strict, &filter.run);
+    }
+    if merge_overlaps && state.file_counts.is_empty() {
+        let (counts, overlap_count) = alignment::process_sam_pair([&sam[0], &sam[1]], pileups,
+                                                                  filter, auto_sort,
+                                                                  downsampler,
+                                                                  &mut state.discarded);
+        for (s, counts) in sam.iter().zip(&counts) {
+            log_sam_counts(s, counts, strict, &filter.run);
+        }
+        state.file_counts = counts.to_vec();
+        state.overlap_count = overlap_count;
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.save(&state, pileups);
+        }
+    }
+    for (s, weight) in sam.iter().zip(weights).skip(state.file_counts.len()) {
+        set_weight(pileups, *weight);
+        let counts = alignment::process_sam(s, pileups, filter, auto_sort, downsampler,
+                                            &mut state.discarded);
+        log_sam_counts(s, &counts, strict, &filter.run);
+        state.file_counts.push(counts);
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.save(&state, pileups);
+        }
+    }
+    if merge_overlaps {
+        log::text!("{} read pairs with overlapping mates",
+                   state.overlap_count.to_formatted_string(&Locale::en));
+        log::event("overlapping_pairs", json!({"pairs": state.overlap_count}));
+    }
+    log::text!();
+    print_alignment_filtering(filter.careful, &state.file_counts, &state.discarded, &filter.run);
+    state
+}
+
+
+/// Aligns each reads file with bwa (--reads1/--reads2) and loads the alignments into the pileups
+/// as bwa makes them. bwa is always run with -a, so the alignments aren't checked for that.
+fn load_read_alignments(index: &BwaIndex, reads: &[PathBuf], filter: &AlignmentFilter,
+                        pileups: &mut misc::FastHashMap<String, pileup::Pileup>) -> LoadState {
+    log::section_header("Loading alignments");
+    let mut state = LoadState::default();
+    for r in reads {
+        let source = index.align(r, &filter.run).with_fail_tags(&filter.fail_tags);
+        let counts = match alignment::add_to_pileup(source, pileups, filter,
+                                                    &mut state.discarded) {
+            Ok(counts) => counts,
+            Err(e) if e.error_type == ErrorType::Io => {
+                misc::quit_with_error(ErrorType::Io,
+                                      &format!("unable to load alignments of {:?}", r))
+            },
+            Err(e)     => e.quit(),
+        };
+        log_counts(r, &counts);
+        state.file_counts.push(counts);
+    }
+    log::text!();
+    print_alignment_filtering(filter.careful, &state.file_counts, &state.discarded, &filter.run);
+    state
+}
+
+
+/// Loads the two alignment files of a read pair (--paired), leaving out alignments which fail the
+/// read pair filter as they are loaded. This gives the same result as polishing with the output of
+/// polypolish filter, without writing those files.
+fn load_paired_alignments(inputs: &AlignmentInputs, pair: &PairSettings,
+                          pileups: &mut misc::FastHashMap<String, pileup::Pileup>) -> LoadState {
+    let AlignmentInputs { sam, weights, filter, downsampler, strict, .. } = *inputs;
+    log::section_header("Filtering read pairs");
+    log::explanation("Alignments which are not part of a good read pair (correct orientation and \
+                      insert size) are found using temporary files sorted by read name, and they \
+                      are excluded when the alignments are loaded.");
+    for s in sam {
+        if alignment::is_sorted_by_position(s, &filter.run).unwrap_or(false) {
+            misc::quit_with_error(ErrorType::Input,
+                                  &format!("--paired needs alignments grouped by read, but {:?} \
+                                            is sorted by position", s))
+        }
+    }
+    let (failed, _, _) = filter::find_failures(&sam[0], &sam[1], pair, 0, None, &filter.run);
+    log::section_header("Loading alignments");
+    let mut state = LoadState::default();
+    for ((s, weight), failed) in sam.iter().zip(weights).zip(failed) {
+        set_weight(pileups, *weight);
+        let counts = alignment::process_paired_sam(s, failed, pileups, filter, downsampler,
+                                                   &mut state.discarded);
+        log_sam_counts(s, &counts, strict, &filter.run);
+        state.file_counts.push(counts);
+    }
+    log::text!();
+    print_alignment_filtering(filter.careful, &state.file_counts, &state.discarded, &filter.run);
+    state
+}
+
+
+/// Loads each sample's alignment files into its own pileups (for --samples), so each change can
+/// be checked for agreement between samples, and pools all samples into the main pileups.
+fn load_sample_alignments(inputs: &AlignmentInputs, samples: &[String],
+                          pileups: &mut misc::FastHashMap<String, pileup::Pileup>)
+                          -> Vec<misc::FastHashMap<String, pileup::Pileup>> {
+    let AlignmentInputs { sam, weights, filter, downsampler, auto_sort, strict,
+                          .. } = *inputs;
     log::section_header("Loading alignments");
-    let mut alignment_total: usize = 0;
-    let mut used_total: usize = 0;
+    log::explanation("Each sample's alignments are loaded separately, so Polypolish can check \
+                      how many samples support each change.");
+    let mut state = LoadState::default();
+    let mut sample_pileups = Vec::new();
+    for name in sample_names(samples) {
+        log::text!("Sample {}:", name);
+        let mut sample: misc::FastHashMap<String, pileup::Pileup> = pileups.iter()
+            .map(|(seq_name, p)| (seq_name.clone(), p.empty_copy())).collect();
+        for ((s, weight), _) in sam.iter().zip(weights).zip(samples)
+                .filter(|(_, sample_name)| *sample_name == name) {
+            set_weight(&mut sample, *weight);
+            let counts = alignment::process_sam(s, &mut sample, filter, auto_sort,
+                                                downsampler, &mut state.discarded);
+            log_sam_counts(s, &counts, strict, &filter.run);
+            state.file_counts.push(counts);
+        }
+        for (seq_name, p) in &sample {
+            pileups.get_mut(seq_name).unwrap().merge(p);
+        }
+        sample_pileups.push(sample);
+        log::text!();
+    }
+    print_alignment_filtering(filter.careful, &state.file_counts, &state.discarded, &filter.run);
+    sample_pileups
+}
+
+
+/// Loads the alignments to the rotated assembly (--rotated-sam) into pileups of the rotated
+/// circular sequences. Each is returned with its rotation offset.
+fn load_rotated_alignments(mut fasta: Vec<(String, String, String)>, inputs: &AlignmentInputs,
+                           pileup_settings: PileupSettings)
+                           -> misc::FastHashMap<String, (pileup::Pileup, usize)> {
+    let AlignmentInputs { sam, filter, downsampler, auto_sort, strict, .. } = *inputs;
+    log::section_header("Loading rotated alignments");
+    log::explanation("Circular sequences are polished a second time using alignments to the \
+                      assembly with its circular sequences rotated to their midpoints. Bases \
+                      closer to a sequence's start/end junction than to its middle use this \
+                      second pass, where they are far from the junction.");
+    let offsets = rotate::rotate_circular_seqs(&mut fasta, Rotation::Midpoint);
+    let settings = PileupSettings { record_reads: false, ..pileup_settings };
+    let (_, mut pileups) = make_pileups(fasta, settings);
+    let mut discarded = DiscardCounts::default();
     for s in sam {
-        let (alignment_count, used_count,
-             read_count) = alignment::process_sam(&s, pileups, max_errors, careful);
-        eprintln!("{}: {} alignments from {} reads", s.display(),
-                  alignment_count.to_formatted_string(&Locale::en),
-                  read_count.to_formatted_string(&Locale::en));
-        alignment_total += alignment_count;
-        used_total += used_count;
+        let counts = alignment::process_sam(s, &mut pileups, filter, auto_sort,
+                                            downsampler, &mut discarded);
+        log_sam_counts(s, &counts, strict, &filter.run);
+    }
+    if offsets.is_empty() {
+        log::text!("No circular sequences found (circular=true in their header)");
+    }
+    log::text!();
+    pileups.into_iter()
+        .filter_map(|(name, p)| offsets.get(&name).map(|offset| (name, (p, *offset))))
+        .collect()
+}
+
+
+/// Sets the weight for alignments added to the pileups from the next alignment file (--weights).
+fn set_weight(pileups: &mut misc::FastHashMap<String, pileup::Pileup>, weight: f64) {
+    for pileup in pileups.values_mut() {
+        pileup.weight = weight;
+    }
+}
+
+
+/// The distinct sample names, in the order they were first given.
+fn sample_names(samples: &[String]) -> Vec<&str> {
+    let mut names = Vec::new();
+    for s in samples {
+        if !names.contains(&s.as_str()) {
+            names.push(s.as_str());
+        }
+    }
+    names
+}
+
+
+/// Loads pileups saved by an earlier run with --save-pileup, instead of loading alignments.
+fn load_saved_pileup(filename: &Path, filter: &AlignmentFilter,
+                     pileups: &mut misc::FastHashMap<String, pileup::Pileup>) {
+    log::section_header("Loading pileup");
+    log::explanation("The pileup was saved by an earlier run, so its alignments were filtered \
+                      with that run's settings.");
+    let state = checkpoint::load_pileup(filename, pileups);
+    log::text!();
+    print_alignment_filtering(filter.careful, &state.file_counts, &state.discarded, &filter.run);
+}
+
+
+pub fn log_sam_counts(filename: &Path, counts: &SamCounts, strict: bool, run: &RunContext) {
+    log_counts(filename, counts);
+    check_alignment_file(filename, counts.reads, counts.multi_aligned_reads, strict, run);
+}
+
+
+/// Logs the counts for one alignment file (or one reads file, with --reads1/--reads2).
+fn log_counts(filename: &Path, counts: &SamCounts) {
+    log::text!("{}: {} alignments from {} reads ({} with multiple alignments)",
+               filename.display(), counts.alignments.to_formatted_string(&Locale::en),
+               counts.reads.to_formatted_string(&Locale::en),
+               counts.multi_aligned_reads.to_formatted_string(&Locale::en));
+    if counts.unmapped > 0 {
+        log::text!("  {} unmapped records skipped",
+                   counts.unmapped.to_formatted_string(&Locale::en));
+    }
+    log::event("sam_loaded", json!({"file": filename, "alignments": counts.alignments,
+                                    "reads": counts.reads,
+                                    "multi_aligned_reads": counts.multi_aligned_reads,
+                                    "unmapped": counts.unmapped}));
+}
+
+
+/// Quits with an error if the mean depth of the loaded alignments is below --require-mean-depth,
+/// as polishing would then leave the assembly mostly unchanged.
+fn check_mean_depth(require_mean_depth: Option<f64>,
+                    pileups: &misc::FastHashMap<String, pileup::Pileup>) {
+    let Some(required) = require_mean_depth else { return; };
+    let total_length: usize = pileups.values().map(|p| p.bases.len()).sum();
+    let total_depth: f64 = pileups.values().flat_map(|p| &p.bases).map(|b| b.depth).sum();
+    let mean_depth = total_depth / total_length as f64;
+    if mean_depth < required {
+        misc::quit_with_error(ErrorType::LowDepth,
+                              &format!("mean read depth ({:.1}x) is below --require-mean-depth \
+                                        ({}x)", mean_depth, required));
     }
+}
+
+
+/// Logs how many alignments were kept and why the others were discarded. Unmapped records aren't
+/// alignments, so they're only listed here, not counted as discarded.
+pub fn print_alignment_filtering(careful: bool, file_counts: &[SamCounts],
+                                 discarded: &DiscardCounts, run: &RunContext) {
+    let alignment_total: usize = file_counts.iter().map(|c| c.alignments).sum();
+    let used_total: usize = file_counts.iter().map(|c| c.used).sum();
+    let unmapped_total: usize = file_counts.iter().map(|c| c.unmapped).sum();
     let discarded_count = alignment_total - used_total;
-    eprintln!();
     if careful {
-        eprintln!("Filtering for high-quality end-to-end alignments from reads with only one \
-                   alignment:");
+        log::text!("Filtering for high-quality end-to-end alignments from reads with only one \
+                    alignment:");
+    } else {
+        log::text!("Filtering for high-quality end-to-end alignments:");
+    }
+    log::text!("  {} alignments kept", used_total.to_formatted_string(&Locale::en));
+    log::text!("  {} alignments discarded", discarded_count.to_formatted_string(&Locale::en));
+    let mut reasons = serde_json::Map::new();
+    for reason in DiscardReason::ALL {
+        let count = discarded.get(reason);
+        if count > 0 {
+            log::text!("    {}: {}", reason.description(), count.to_formatted_string(&Locale::en));
+        }
+        reasons.insert(reason.name().to_string(), json!(count));
+    }
+    if unmapped_total > 0 {
+        log::text!("  {} unmapped records skipped",
+                   unmapped_total.to_formatted_string(&Locale::en));
+    }
+    log::text!();
+    log::event("alignments_filtered", json!({"kept": used_total, "discarded": discarded_count,
+                                             "discard_reasons": reasons,
+                                             "unmapped": unmapped_total}));
+    check_kept_fraction(alignment_total, used_total, discarded, run);
+}
+
+
+/// Polypolish needs all of a read's alignments to polish repeats, so this warns (or quits, with
+/// --strict) if the aligner seems to have only given each read's best alignment, e.g. bwa mem
+/// without -a. When the file's @PG header names the aligner, its command is checked directly and
+/// the advice is specific to that aligner.
+pub fn check_alignment_file(filename: &Path, read_count: usize, multi_aligned_count: usize,
+                            strict: bool, run: &RunContext) {
+    let f = |n: usize| n.to_formatted_string(&Locale::en);
+    let program = aligner::detect_aligner(filename, run).unwrap_or(None);
+    if let Some(program) = &program {
+        log::text!("  made by {} {}", program.aligner.name(),
+                   program.version.as_deref().unwrap_or("(unknown version)"));
+        log::event("aligner_detected", json!({"file": filename, "aligner": program.aligner.name(),
+                                              "version": program.version,
+                                              "command_line": program.command_line}));
+        for problem in program.other_problems() {
+            run.warn(format!("{}: {}", filename.display(), problem));
+        }
+    }
+    let (alt_hit_count, checked_count) = alignment::count_alt_hit_tags(filename, run)
+        .unwrap_or((0, 0));
+    let single_best = program.as_ref().and_then(|p| p.single_best_reason());
+    let problem = if let Some(reason) = single_best {
+        format!("{} for {}", reason, filename.display())
+    } else if alt_hit_count > 0 {
+        format!("{} of the first {} alignments in {} have an XA tag, so some alignments can't be \
+                 used", f(alt_hit_count), f(checked_count), filename.display())
+    } else if read_count >= MULTI_ALIGNMENT_CHECK_MIN_READS &&
+            (multi_aligned_count as f64) < MIN_MULTI_ALIGNED_FRACTION * read_count as f64 {
+        format!("only {} of {} reads in {} have more than one alignment",
+                f(multi_aligned_count), f(read_count), filename.display())
+    } else {
+        return;
+    };
+    let message = match &program {
+        Some(p) => format!("{} - Polypolish needs all alignments for each read, so {}", problem,
+                           p.aligner.all_alignments_hint()),
+        None    => format!("{} - Polypolish needs all alignments for each read, so the aligner \
+                            may have been run incorrectly (for bwa, use mem -a, e.g. bwa mem -a \
+                            draft.fasta reads_1.fastq.gz > alignments_1.sam)", problem),
+    };
+    if strict {
+        misc::quit_with_error(ErrorType::Input, &message);
+    }
+    run.warn(message);
+}
+
+
+/// Warns if few alignments were kept, as the assembly may then be left mostly unpolished. This is
+/// usually due to the aligner, e.g. one which clips reads instead of aligning them end-to-end, so
+/// the warning names the most common reason when it accounts for most discarded alignments.
+fn check_kept_fraction(alignment_total: usize, used_total: usize, discarded: &DiscardCounts,
+                       run: &RunContext) {
+    let chosen = [DiscardReason::Careful, DiscardReason::MaxHits];
+    let considered = alignment_total - chosen.iter().map(|&r| discarded.get(r)).sum::<usize>();
+    if considered == 0 {
+        return;
+    }
+    let most_common = DiscardReason::ALL.into_iter().filter(|r| !chosen.contains(r))
+        .max_by_key(|&r| discarded.get(r)).unwrap();
+    let reason = if 2 * discarded.get(most_common) > considered - used_total {
+        format!(" (most common reason: {})", most_common.description())
     } else {
-        eprintln!("Filtering for high-quality end-to-end alignments:");
+        String::new()
+    };
+    if used_total == 0 {
+        run.warn(format!("no alignments were kept for polishing{}", reason));
+    } else if (used_total as f64) < LOW_KEPT_FRACTION * considered as f64 {
+        run.warn(format!("only {:.1}% of alignments were kept for polishing{}",
+                         100.0 * used_total as f64 / considered as f64, reason));
     }
-    eprintln!("  {} alignments kept", used_total.to_formatted_string(&Locale::en));
-    eprintln!("  {} alignments discarded", discarded_count.to_formatted_string(&Locale::en));
-    eprintln!();
 }
 
 
-fn polish_sequences(debug: &Option<PathBuf>, fraction_invalid: f64, fraction_valid: f64,
-                    min_depth: u32, seq_names: &Vec<(String, String)>,
-                    pileups: &HashMap<String, pileup::Pileup>) -> Vec<(String, usize)>{
+pub fn polishing_header() {
     log::section_header("Polishing assembly sequences");
     log::explanation("For each position in the assembly, Polypolish determines the read \
                      depth at that position and collects all aligned bases. It then polishes the \
                      assembly by looking for positions where the pileup unambiguously supports a \
                      different sequence than the assembly.");
-    let mut debug_file = create_debug_file(&debug);
-    let mut new_lengths = Vec::new();
+}
+
+
+/// Everything besides the main pileups which polishing can use, each only for some runs: each
+/// sample's pileups (--samples), the long-read pileups (--long-reads), each sequence's rotation
+/// (--rotate), the pileups of the rotated sequences (--rotated-sam), the N-run fills (--fill-ns)
+/// and the end extensions (--extend-ends).
+#[derive(Default)]
+pub struct ExtraInputs {
+    pub samples: Vec<misc::FastHashMap<String, pileup::Pileup>>,
+    pub long_reads: Option<misc::FastHashMap<String, pileup::Pileup>>,
+    pub offsets: misc::FastHashMap<String, usize>,
+    pub rotated: misc::FastHashMap<String, (pileup::Pileup, usize)>,
+    pub fills: misc::FastHashMap<String, Vec<gaps::Fill>>,
+    pub extensions: misc::FastHashMap<String, gaps::Extension>,
+}
+
+impl ExtraInputs {
+    fn for_seq(&self, name: &str) -> SeqExtras<'_> {
+        SeqExtras {
+            samples: self.samples.iter().map(|s| s.get(name).unwrap()).collect(),
+            long_reads: self.long_reads.as_ref().map(|p| p.get(name).unwrap()),
+            offset: self.offsets.get(name).copied().unwrap_or(0),
+            rotated: self.rotated.get(name).map(|(p, offset)| (p, *offset)),
+            fills: self.fills.get(name).map_or(&[][..], |f| f.as_slice()),
+            extension: self.extensions.get(name),
+        }
+    }
+}
+
+
+/// One sequence's share of the ExtraInputs.
+struct SeqExtras<'a> {
+    samples: Vec<&'a pileup::Pileup>,
+    long_reads: Option<&'a pileup::Pileup>,
+    offset: usize,
+    rotated: Option<(&'a pileup::Pileup, usize)>,
+    fills: &'a [gaps::Fill],
+    extension: Option<&'a gaps::Extension>,
+}
+
+
+pub fn polish_sequences(outputs: &mut OutputFiles, thresholds: &Thresholds,
+                        local_depth_window: Option<usize>, plasmid_aware: bool,
+                        seq_names: &[(String, String)],
+                        pileups: &misc::FastHashMap<String, pileup::Pileup>,
+                        extras: &ExtraInputs) -> Vec<PolishedSeq> {
+    let copy_numbers = if plasmid_aware { find_high_copy_seqs(seq_names, pileups) }
+                       else { misc::FastHashMap::default() };
+    polishing_header();
+    let local_depth = local_depth_window.map(|window| {
+        (window, typical_window_depth(window, seq_names, pileups))
+    });
+    let mut polished_seqs = Vec::new();
     for (name, description) in seq_names {
         let pileup = pileups.get(name).unwrap();
-        let new_length = polish_one_sequence(&debug, fraction_invalid, fraction_valid, min_depth,
-                                             name, description, pileup, &mut debug_file);
-        new_lengths.push((name.clone(), new_length));
+        let copy_number = copy_numbers.get(name).copied();
+        let seq_thresholds = copy_number.map_or(*thresholds, |c| thresholds.for_copy_number(c));
+        let mut polished_seq = polish_one_sequence(&seq_thresholds, local_depth, name,
+                                                   description, pileup, &extras.for_seq(name),
+                                                   outputs);
+        polished_seq.copy_number = copy_number;
+        polished_seqs.push(polished_seq);
     }
-    new_lengths
+    polished_seqs
 }
 
 
-fn polish_one_sequence(debug: &Option<PathBuf>, fraction_invalid: f64, fraction_valid: f64,
-                       min_depth: u32, name: &str, description: &str, pileup: &pileup::Pileup,
-                       debug_file: &mut Option<File>) -> usize {
+/// Finds short sequences with a much higher depth than the long sequences (for --plasmid-aware)
+/// and returns their copy numbers: their depth relative to the long sequences.
+fn find_high_copy_seqs(seq_names: &[(String, String)],
+                       pileups: &misc::FastHashMap<String, pileup::Pileup>)
+                       -> misc::FastHashMap<String, f64> {
+    log::section_header("Finding high-copy plasmids");
+    log::explanation(&format!("Sequences shorter than {} bp with at least {}x the read depth of \
+                               the longer sequences are treated as high-copy plasmids. Their \
+                               minimum depths are scaled up by their copy number.",
+                              PLASMID_MAX_LENGTH.to_formatted_string(&Locale::en),
+                              HIGH_COPY_MIN_RATIO));
+    let mean_depths: Vec<(&str, usize, f64)> = seq_names.iter().map(|(name, _)| {
+        let bases = &pileups.get(name).unwrap().bases;
+        let total: f64 = bases.iter().map(|b| b.depth).sum();
+        let mean = if bases.is_empty() { 0.0 } else { total / bases.len() as f64 };
+        (name.as_str(), bases.len(), mean)
+    }).collect();
+    let copy_numbers = high_copy_numbers(&mean_depths);
+    for (name, length, depth) in &mean_depths {
+        if let Some(copy_number) = copy_numbers.get(*name) {
+            log::text!("{} ({} bp): {:.1}x depth, copy number {:.1}", name,
+                       length.to_formatted_string(&Locale::en), depth, copy_number);
+        }
+    }
+    if copy_numbers.is_empty() {
+        log::text!("No high-copy plasmids found");
+    }
+    log::text!();
+    log::event("high_copy_sequences", json!({"sequences": copy_numbers}));
+    copy_numbers
+}
+
+
+/// Copy numbers are relative to the long sequences' length-weighted mean depth, so without any
+/// long sequences, nothing is treated as high-copy.
+fn high_copy_numbers(mean_depths: &[(&str, usize, f64)]) -> misc::FastHashMap<String, f64> {
+    let mut copy_numbers = misc::FastHashMap::default();
+    let long: Vec<_> = mean_depths.iter().filter(|(_, len, _)| *len >= PLASMID_MAX_LENGTH)
+        .collect();
+    let long_length: usize = long.iter().map(|(_, len, _)| len).sum();
+    if long_length == 0 {
+        return copy_numbers;
+    }
+    let long_depth = long.iter().map(|(_, len, depth)| *len as f64 * depth).sum::<f64>()
+                     / long_length as f64;
+    if long_depth <= 0.0 {
+        return copy_numbers;
+    }
+    for (name, length, depth) in mean_depths {
+        let copy_number = depth / long_depth;
+        if *length < PLASMID_MAX_LENGTH && copy_number >= HIGH_COPY_MIN_RATIO {
+            copy_numbers.insert(name.to_string(), copy_number);
+        }
+    }
+    copy_numbers
+}
+
+
+/// The median of the window depths over all sequences, used as the expected depth when scaling
+/// thresholds for --local-depth-window.
+fn typical_window_depth(window: usize, seq_names: &[(String, String)],
+                        pileups: &misc::FastHashMap<String, pileup::Pileup>) -> f64 {
+    let depths: Vec<f64> = seq_names.iter()
+        .flat_map(|(name, _)| pileups.get(name).unwrap().window_median_depths(window)).collect();
+    let typical_depth = misc::median(&depths);
+    log::text!("Median depth of {} bp windows: {:.1}x", window, typical_depth);
+    log::text!();
+    log::event("local_depth", json!({"window": window, "typical_depth": typical_depth}));
+    typical_depth
+}
+
+
+fn polish_one_sequence(thresholds: &Thresholds, local_depth: Option<(usize, f64)>, name: &str,
+                       description: &str, pileup: &pileup::Pileup, extras: &SeqExtras,
+                       outputs: &mut OutputFiles) -> PolishedSeq {
+    let SeqExtras { ref samples, long_reads, offset, rotated, fills, extension } = *extras;
     let seq_len = pileup.bases.len();
-    eprintln!("Polishing {} ({} bp):", name, seq_len.to_formatted_string(&Locale::en));
+    log::text!("Polishing {} ({} bp):", name, seq_len.to_formatted_string(&Locale::en));
+
+    // With --local-depth-window, each window gets its own thresholds, scaled by its depth (or set
+    // relative to it, with --relative-min-depth).
+    let (window, window_thresholds) = match local_depth {
+        Some((window, typical_depth)) => {
+            (window, pileup.window_median_depths(window).iter()
+                .map(|depth| thresholds.scaled_for_depth(*depth, typical_depth)).collect())
+        },
+        None => (usize::MAX, vec![*thresholds]),
+    };
+    let low_complexity = find_low_complexity(&pileup.original_seq(), thresholds);
+
+    // With --joint-indels, each run of adjacent deleted bases is called as one event.
+    let events = if thresholds.joint_indels {
+        let index = |pos: usize| (pos + seq_len - offset) % seq_len;
+        let bases: Vec<&pileup::PileupBase> = (0..seq_len).map(|pos| &pileup.bases[index(pos)])
+            .collect();
+        pileup::deletion_events(&bases, |pos| {
+            let i = index(pos);
+            let t = window_thresholds[i / window];
+            if low_complexity[i] { t.for_low_complexity() } else { t }
+        })
+    } else {
+        Vec::new()
+    };
 
     let mut polished_seq: String = String::with_capacity(seq_len);
-    let mut total_depth = 0.0;
-    let mut zero_depth_count: usize = 0;
-    let mut changed_count: usize = 0;
-    let mut pos: usize = 0;
-    let build_debug_str = match debug_file {Some(_) => true, None => false};
-
-    for b in &pileup.bases {
-        let (seq, status, debug_line) = b.get_polished_seq(min_depth, fraction_valid,
-                                                           fraction_invalid, build_debug_str);
-        match status {
-            pileup::BaseStatus::Changed => {changed_count += 1}
-            _                           => {}
-        }
-        total_depth += b.depth;
-        if b.depth == 0.0 {
-            zero_depth_count += 1;
+    let mut state = PolishState::new(name, seq_len, outputs.fastq);
+    state.totals.homopolymer_trimmed = pileup.homopolymer_trimmed;
+    let mut progress = Progress::new(&format!("Polishing {}", name), Unit::Bases, seq_len as u64,
+                                     "changes");
+
+    // With --fill-ns, the polished sequence's span for each filled N-run is noted here.
+    let mut fill_start = None;
+    let mut fill_spans = Vec::new();
+
+    // For a rotated sequence (--rotate), the pileup starts at the offset, so the bases are taken
+    // from the pileup in the original sequence's order.
+    for pos in 0..seq_len {
+        progress.update(pos as u64, state.totals.changed_count as u64);
+        let i = (pos + seq_len - offset) % seq_len;
+        let mut b = &pileup.bases[i];
+        let base_thresholds = &window_thresholds[i / window];
+        if base_thresholds.min_depth < thresholds.min_depth {
+            state.totals.scaled_depth_count += 1;
+        } else if base_thresholds.min_depth > thresholds.min_depth {
+            state.totals.raised_depth_count += 1;
+        }
+        let sample_bases: Vec<&pileup::PileupBase> = samples.iter().map(|s| &s.bases[i])
+            .collect();
+        let long_read_base = long_reads.map(|p| &p.bases[i]);
+
+        // With --rotated-sam, the bases of a circular sequence which are closer to its start/end
+        // junction than to its middle are polished with the rotated pass instead.
+        let mut from_rotated = false;
+        if let Some((rotated_pileup, rotated_offset)) = rotated {
+            let r = (pos + seq_len - rotated_offset) % seq_len;
+            let rotated_b = &rotated_pileup.bases[r];
+            let t = if low_complexity[i] { base_thresholds.for_low_complexity() }
+                    else { *base_thresholds };
+            let (seq, _, _) = b.get_polished_seq(&t, &sample_bases, long_read_base, false);
+            let (rotated_seq, _, _) = rotated_b.get_polished_seq(&t, &sample_bases,
+                                                                 long_read_base, false);
+            if seq != rotated_seq {
+                state.totals.conflict_count += 1;
+            }
+            if end_distance(r, seq_len) > end_distance(i, seq_len) {
+                b = rotated_b;
+                from_rotated = true;
+                state.totals.origin_count += 1;
+            }
+        }
+        let changed_before = state.totals.changed_count;
+        if fills.iter().any(|f| f.start == i) {
+            fill_start = Some(polished_seq.len());
         }
-        match debug_file {
-            Some(file) => write_debug_line(file, name, pos, &debug_line, &debug),
-            None       => {},
+        let support = BaseSupport { samples: &sample_bases, long_read: long_read_base,
+                                    event: pileup::event_at(&events, pos) };
+        polished_seq.push_str(&polish_base(b, &support, pos, base_thresholds, low_complexity[i],
+                                           &mut state, outputs));
+        if from_rotated && state.totals.changed_count > changed_before {
+            state.totals.origin_changed_count += 1;
         }
-        polished_seq.push_str(&seq);
-        pos += 1;
+        if let Some(fill) = fills.iter().find(|f| f.end == i + 1) {
+            fill_spans.push((fill_start.take().unwrap(), polished_seq.len(), fill));
+        }
+    }
+    progress.finish();
+
+    // The N-runs are replaced (and the ends extended) after polishing, so the per-base outputs
+    // still describe the assembly's positions. Read-derived bases have no per-base confidence,
+    // so they get the lowest quality.
+    for (start, end, fill) in fill_spans.into_iter().rev() {
+        state.totals.gc_count = state.totals.gc_count + stats::gc_count(&fill.seq)
+                                - stats::gc_count(&polished_seq[start..end]);
+        polished_seq.replace_range(start..end, &fill.seq);
+        if let Some(quals) = &mut state.quals {
+            quals.replace_range(start..end, &qual_char(0.0).to_string().repeat(fill.seq.len()));
+        }
+        state.totals.filled_count += 1;
+        state.totals.filled_bases += end - start;
+    }
+    if let Some(extension) = extension {
+        polished_seq.insert_str(0, &extension.start);
+        polished_seq.push_str(&extension.end);
+        if let Some(quals) = &mut state.quals {
+            let qual = qual_char(0.0).to_string();
+            quals.insert_str(0, &qual.repeat(extension.start.len()));
+            quals.push_str(&qual.repeat(extension.end.len()));
+        }
+        state.totals.gc_count += stats::gc_count(&extension.start)
+                                 + stats::gc_count(&extension.end);
+        state.totals.extended_bases = extension.start.len() + extension.end.len();
+    }
+    let annotation = outputs.annotate_headers.then(|| header_annotation(seq_len, &state.totals));
+    outputs.start_seq(name, description, annotation.as_deref());
+    outputs.write_seq(&polished_seq);
+    outputs.finish_seq(&state.quals);
+    print_polishing_info(name, seq_len, polished_seq.len(), &state.totals, &outputs.run);
+
+    PolishedSeq::new(name, seq_len, polished_seq.len(), &state.totals)
+}
+
+
+/// A summary of one polished sequence, used in the final report.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolishedSeq {
+    pub name: String,
+    pub original_length: usize,
+    pub length: usize,
+    pub gc_count: usize,
+    pub changed_count: usize,
+    pub copy_number: Option<f64>,  // for high-copy plasmids (--plasmid-aware)
+    pub checked_count: usize,
+    pub zero_depth_count: usize,
+    pub ambiguous_errors: f64,
+}
+
+impl PolishedSeq {
+    pub fn new(name: &str, original_length: usize, length: usize,
+               totals: &PolishTotals) -> PolishedSeq {
+        PolishedSeq { name: name.to_string(), original_length, length,
+                      gc_count: totals.gc_count, changed_count: totals.changed_count,
+                      copy_number: None, checked_count: totals.checked_count,
+                      zero_depth_count: totals.zero_depth_count,
+                      ambiguous_errors: totals.ambiguous_errors }
+    }
+
+    pub fn estimated_accuracy(&self) -> f64 {
+        estimated_accuracy(self.changed_count, self.original_length)
     }
-    polished_seq = polished_seq.replace("-", "");
-    print_seq_to_stdout(name, description, &polished_seq);
-    print_polishing_info(seq_len, total_depth, zero_depth_count, changed_count);
+}
 
-    polished_seq.len()
+
+/// Running totals for a sequence as it is polished, used for the summary printed afterwards.
+#[derive(Default)]
+pub struct PolishTotals {
+    pub total_depth: f64,
+    pub zero_depth_count: usize,
+    pub changed_count: usize,
+    pub capped_count: usize,          // bases which reached --max-depth
+    pub skipped_count: usize,         // read sequences not added to bases at --max-depth
+    pub not_allowed_count: usize,     // changes not made due to --only-* options
+    pub few_samples_count: usize,     // changes not made due to --min-samples
+    pub long_read_count: usize,       // changes made by using long reads to break a tie
+    pub confidence_total: f64,        // summed over changed bases
+    pub min_confidence: Option<f64>,  // lowest of any changed base
+    pub gc_count: usize,              // G and C bases in the polished sequence
+    pub scaled_depth_count: usize,    // bases with a lowered minimum depth (--local-depth-window)
+    pub raised_depth_count: usize,    // bases with a raised minimum depth (--relative-min-depth)
+    pub low_complexity_count: usize,  // bases in low-complexity regions (stricter thresholds)
+    pub homopolymer_trimmed: usize,   // alignment positions removed by homopolymer trimming
+    pub origin_count: usize,          // bases polished with the rotated pass (--rotated-sam)
+    pub origin_changed_count: usize,  // changes made by the rotated pass
+    pub conflict_count: usize,        // bases where the two passes disagree
+    pub filled_count: usize,          // N-runs replaced by a read-derived fill (--fill-ns)
+    pub filled_bases: usize,          // polished bases replaced by those fills
+    pub extended_bases: usize,        // bases added to the sequence ends (--extend-ends)
+    pub checked_count: usize,         // bases kept or changed with clear read support
+    pub ambiguous_errors: f64,        // expected errors at the other bases (see residual.rs)
 }
 
 
-fn print_seq_to_stdout(name: &str, description: &str, seq: &str) {
-    print!(">{}", name);
-    if description.len() > 0 {
-        print!(" {}", description);
+/// The distance from a position to the nearest end of its sequence.
+fn end_distance(pos: usize, seq_len: usize) -> usize {
+    pos.min(seq_len - 1 - pos)
+}
+
+
+/// A sequence as it is polished: its name, its running totals and (for FASTQ output) the
+/// qualities of its polished bases so far.
+pub struct PolishState<'a> {
+    pub name: &'a str,
+    pub totals: PolishTotals,
+    pub quals: Option<String>,
+}
+
+impl PolishState<'_> {
+    pub fn new(name: &str, seq_len: usize, fastq: bool) -> PolishState<'_> {
+        PolishState { name, totals: PolishTotals::default(),
+                      quals: fastq.then(|| String::with_capacity(seq_len)) }
     }
-    println!(" polypolish");
-    println!("{}", seq);
 }
 
 
-fn print_polishing_info(seq_len: usize, total_depth: f64, zero_depth_count: usize,
-                        changed_count: usize) {
+/// What else is known about a base, beyond its own pileup: the same position's base in each
+/// sample (--samples) and in the long-read pileup (--long-reads), and the deletion event it is
+/// part of (--joint-indels).
+#[derive(Default)]
+pub struct BaseSupport<'a> {
+    pub samples: &'a [&'a pileup::PileupBase],
+    pub long_read: Option<&'a pileup::PileupBase>,
+    pub event: Option<&'a DeletionEvent>,
+}
+
+
+/// Polishes one base of the pileup, returning its new sequence (which can be empty for a deletion
+/// or multiple bases for an insertion). If qualities are being collected (for FASTQ output), the
+/// new sequence's confidence is added for each of its bases. With --samples, each sample's base at
+/// this position is given, to check their agreement on a change. With --long-reads, the long-read
+/// base at this position is given, to break ties. With --joint-indels, a base in a deletion event
+/// takes the event's call.
+pub fn polish_base(b: &pileup::PileupBase, support: &BaseSupport, pos: usize,
+                   thresholds: &Thresholds, low_complexity: bool, state: &mut PolishState,
+                   outputs: &mut OutputFiles) -> String {
+    let BaseSupport { samples, long_read, event } = *support;
+    let PolishState { name, ref mut totals, ref mut quals } = *state;
+    let base_thresholds = if low_complexity {
+        totals.low_complexity_count += 1;
+        thresholds.for_low_complexity()
+    } else {
+        *thresholds
+    };
+    let mut call = b.get_polished_seq(&base_thresholds, samples, long_read,
+                                      outputs.debug.is_some());
+    if let Some(event) = event {
+        call = b.apply_deletion_event(event, call);
+    }
+    let (seq, status, summary) = call;
+    if status.is_change() {
+        let confidence = b.seq_confidence(&seq);
+        totals.changed_count += 1;
+        totals.confidence_total += confidence;
+        totals.min_confidence = Some(totals.min_confidence.map_or(confidence,
+                                                                  |c| c.min(confidence)));
+        log::callback_event("change_applied", || {
+            json!({"name": name, "pos": pos, "base": b.original().to_string(), "new_base": seq,
+                   "confidence": confidence, "status": status.name()})
+        });
+    }
+    match status {
+        pileup::BaseStatus::NotAllowed      => totals.not_allowed_count += 1,
+        pileup::BaseStatus::TooFewSamples   => totals.few_samples_count += 1,
+        pileup::BaseStatus::LongReadChanged => totals.long_read_count += 1,
+        _                                   => (),
+    }
+    match status {
+        pileup::BaseStatus::OriginalBaseKept | pileup::BaseStatus::Changed => {
+            totals.checked_count += 1;
+        },
+        _ => totals.ambiguous_errors += b.error_probability(&seq),
+    }
+    totals.total_depth += b.depth;
+    if b.depth == 0.0 {
+        totals.zero_depth_count += 1;
+    }
+    if b.skipped_count > 0 {
+        totals.capped_count += 1;
+        totals.skipped_count += b.skipped_count as usize;
+    }
+    if let (Some(file), Some(summary)) = (&mut outputs.debug, &summary) {
+        let event = event.map(DeletionEvent::description);
+        file.write_line(name, pos, b, summary, low_complexity, event.as_deref());
+    }
+    if let Some(file) = &mut outputs.depth {
+        file.add(name, pos, &format_depth(b.depth));
+    }
+    if let Some(file) = &mut outputs.multi_mapped {
+        if let Some(fraction) = b.multi_mapped_fraction() {
+            file.add(name, pos, &format_depth(fraction));
+        }
+    }
+    if let Some(file) = &mut outputs.uncovered {
+        if b.depth == 0.0 {
+            file.add(name, pos, "");
+        }
+    }
+    let changed_to = status.is_change().then_some(seq.as_str());
+    if let Some(report) = &mut outputs.html {
+        report.add_base(name, pos, b.depth, b.original(), changed_to);
+    }
+    if let Some(plot) = &mut outputs.plot {
+        plot.add_base(name, pos, b.depth, b.original(), changed_to);
+    }
+    let polished_seq = seq.replace("-", "");
+    if let Some(file) = &mut outputs.changes {
+        let change = status.is_change().then(|| (seq.as_str(), b.seq_confidence(&seq)));
+        file.add_base(name, pos, b.original(), &polished_seq, change);
+    }
+    if let Some(report) = &mut outputs.mixtures {
+        report.add_base(name, b, &status, base_thresholds.min_depth);
+    }
+    if let Some(file) = &mut outputs.gvcf {
+        file.add_base(name, pos, b, &status, &seq);
+    }
+    totals.gc_count += stats::gc_count(&polished_seq);
+    if let Some(quals) = quals {
+        let qual = qual_char(b.seq_confidence(&seq));
+        quals.extend(std::iter::repeat(qual).take(polished_seq.len()));
+    }
+    polished_seq
+}
+
+
+/// Returns whether each position of the sequence is in a low-complexity region. This is only
+/// needed when low-complexity regions have their own thresholds, so otherwise all are false.
+pub fn find_low_complexity(seq: &str, thresholds: &Thresholds) -> Vec<bool> {
+    match thresholds.low_complexity_fraction_valid {
+        Some(_) => complexity::low_complexity_mask(seq),
+        None    => vec![false; seq.len()],
+    }
+}
+
+
+/// Formats a read depth for the depth bedGraph, with up to two decimal places.
+fn format_depth(depth: f64) -> String {
+    let formatted = format!("{:.2}", depth);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+
+/// Converts a Phred-scaled confidence to a FASTQ quality character.
+fn qual_char(confidence: f64) -> char {
+    (33 + confidence.round() as u8) as char
+}
+
+
+/// A sequence's header line: its name and original description, then either a plain
+/// "polypolish" or (with --annotate-headers) a summary of its polishing.
+fn seq_header(name: &str, description: &str, annotation: Option<&str>, fastq: bool) -> String {
+    let mut header = format!("{}{}", if fastq {'@'} else {'>'}, name);
+    if !description.is_empty() {
+        header.push(' ');
+        header.push_str(description);
+    }
+    header.push(' ');
+    header.push_str(annotation.unwrap_or("polypolish"));
+    header
+}
+
+
+/// The summary added to a sequence's header with --annotate-headers.
+fn header_annotation(seq_len: usize, totals: &PolishTotals) -> String {
+    format!("polypolish={}changes depth={}x", totals.changed_count,
+            format_depth(totals.total_depth / seq_len as f64))
+}
+
+
+
+pub fn print_polishing_info(name: &str, seq_len: usize, polished_len: usize,
+                            totals: &PolishTotals, run: &RunContext) {
+    let PolishTotals { total_depth, zero_depth_count, changed_count, capped_count,
+                       skipped_count, not_allowed_count, few_samples_count, long_read_count,
+                       confidence_total,
+                       min_confidence, scaled_depth_count, raised_depth_count,
+                       low_complexity_count, homopolymer_trimmed, origin_count,
+                       origin_changed_count, conflict_count, filled_count, filled_bases,
+                       extended_bases, .. } = *totals;
     let seq_len_f64 = seq_len as f64;
     let mean_depth = total_depth / seq_len_f64;
-    eprintln!("  mean read depth: {:.1}x", mean_depth);
+    log::text!("  mean read depth: {:.1}x", mean_depth);
 
     let have = if zero_depth_count == 1 {"has"} else {"have"};
     let covered = seq_len - zero_depth_count;
     let coverage = 100.0 * (covered as f64) / seq_len_f64;
-    eprintln!("  {} bp {} a depth of zero ({:.4}% coverage)",
-              zero_depth_count.to_formatted_string(&Locale::en), have, coverage);
+    log::text!("  {} bp {} a depth of zero ({:.4}% coverage)",
+               zero_depth_count.to_formatted_string(&Locale::en), have, coverage);
+    if homopolymer_trimmed > 0 {
+        log::text!("  {} aligned bases trimmed from read ends in homopolymers",
+                   homopolymer_trimmed.to_formatted_string(&Locale::en));
+    }
+    if scaled_depth_count > 0 {
+        log::text!("  {} bp had a lowered minimum depth due to low local depth",
+                   scaled_depth_count.to_formatted_string(&Locale::en));
+    }
+    if raised_depth_count > 0 {
+        log::text!("  {} bp had a raised minimum depth due to high local depth",
+                   raised_depth_count.to_formatted_string(&Locale::en));
+    }
+    if low_complexity_count > 0 {
+        log::text!("  {} bp in low-complexity regions",
+                   low_complexity_count.to_formatted_string(&Locale::en));
+    }
+    if capped_count > 0 {
+        log::text!("  {} bp reached the maximum depth ({} read sequences skipped)",
+                   capped_count.to_formatted_string(&Locale::en),
+                   skipped_count.to_formatted_string(&Locale::en));
+    }
 
     let changed_percent = 100.0 * (changed_count as f64) / seq_len_f64;
-    let estimated_accuracy = 100.0 - changed_percent;
+    let estimated_accuracy = estimated_accuracy(changed_count, seq_len);
     let estimated_qscore = qscore(estimated_accuracy);
     let positions = if changed_count == 1 {"position"} else {"positions"};
-    eprintln!("  {} {} changed ({:.4}% of total positions)",
-              changed_count.to_formatted_string(&Locale::en), positions, changed_percent);
-    eprintln!("  estimated pre-polishing sequence accuracy: {:.4}% ({})",
-              estimated_accuracy, estimated_qscore);
-    eprintln!();
+    log::text!("  {} {} changed ({:.4}% of total positions)",
+               changed_count.to_formatted_string(&Locale::en), positions, changed_percent);
+    let mean_confidence = (changed_count > 0).then(|| confidence_total / changed_count as f64);
+    if let (Some(mean), Some(min)) = (mean_confidence, min_confidence) {
+        log::text!("  change confidence: mean Q{:.1}, lowest Q{:.1}", mean, min);
+    }
+    if not_allowed_count > 0 {
+        let positions = if not_allowed_count == 1 {"position"} else {"positions"};
+        log::text!("  {} {} not changed due to the type of change",
+                   not_allowed_count.to_formatted_string(&Locale::en), positions);
+    }
+    if long_read_count > 0 {
+        let positions = if long_read_count == 1 {"position"} else {"positions"};
+        log::text!("  {} {} changed using long reads to break a tie",
+                   long_read_count.to_formatted_string(&Locale::en), positions);
+    }
+    if few_samples_count > 0 {
+        let positions = if few_samples_count == 1 {"position"} else {"positions"};
+        log::text!("  {} {} not changed due to too few agreeing samples",
+                   few_samples_count.to_formatted_string(&Locale::en), positions);
+    }
+    if origin_count > 0 {
+        log::text!("  {} bp near the start/end junction polished with rotated alignments ({} \
+                    changed)", origin_count.to_formatted_string(&Locale::en),
+                   origin_changed_count.to_formatted_string(&Locale::en));
+        log::text!("  {} bp where the two passes disagree",
+                   conflict_count.to_formatted_string(&Locale::en));
+    }
+    if filled_count > 0 {
+        let runs = if filled_count == 1 {"N-run"} else {"N-runs"};
+        log::text!("  {} {} ({} bp) filled from reads",
+                   filled_count.to_formatted_string(&Locale::en), runs,
+                   filled_bases.to_formatted_string(&Locale::en));
+    }
+    if extended_bases > 0 {
+        log::text!("  {} bp added to the sequence ends from reads",
+                   extended_bases.to_formatted_string(&Locale::en));
+    }
+    log::text!("  estimated pre-polishing sequence accuracy: {:.4}% ({})",
+               estimated_accuracy, estimated_qscore);
+    if seq_len > 0 && zero_depth_count == seq_len {
+        run.warn(format!("{} has no read coverage, so it was not polished", name));
+    }
+    log::text!();
+    log::event("contig_polished", json!({"name": name, "length": seq_len,
+                                         "polished_length": polished_len,
+                                         "mean_depth": mean_depth,
+                                         "zero_depth_bases": zero_depth_count,
+                                         "coverage": coverage, "changed": changed_count,
+                                         "max_depth_bases": capped_count,
+                                         "max_depth_skipped": skipped_count,
+                                         "lowered_min_depth_bases": scaled_depth_count,
+                                         "raised_min_depth_bases": raised_depth_count,
+                                         "low_complexity_bases": low_complexity_count,
+                                         "homopolymer_trimmed": homopolymer_trimmed,
+                                         "not_allowed": not_allowed_count,
+                                         "too_few_samples": few_samples_count,
+                                         "long_read_changes": long_read_count,
+                                         "origin_bases": origin_count,
+                                         "origin_changes": origin_changed_count,
+                                         "origin_conflicts": conflict_count,
+                                         "filled_n_runs": filled_count,
+                                         "extended_bases": extended_bases,
+                                         "mean_change_confidence": mean_confidence,
+                                         "min_change_confidence": min_confidence,
+                                         "estimated_accuracy": estimated_accuracy,
+                                         "estimated_qscore": qscore_value(estimated_accuracy)}));
 }
 
 
-fn create_debug_file(debug: &Option<PathBuf>) -> Option<File> {
+/// Where the polished sequences and the optional per-base files are written. filter-polish only
+/// has --output, so it leaves the others empty.
+#[derive(Default)]
+pub struct OutputPaths {
+    pub output: Option<PathBuf>,
+    pub bgzip: bool,
+    pub fai: bool,
+    pub split_output: Option<PathBuf>,
+    pub split_only: bool,
+    pub debug: Option<PathBuf>,
+    pub depth_out: Option<PathBuf>,
+    pub uncovered_bed: Option<PathBuf>,
+    pub multi_mapped_out: Option<PathBuf>,
+    pub html: Option<PathBuf>,
+    pub plot: Option<PathBuf>,
+    pub changes: Option<PathBuf>,
+    pub mixtures: Option<PathBuf>,
+    pub gvcf: Option<PathBuf>,
+}
+
+impl OutputPaths {
+    pub fn from_options(options: &PolishOptions) -> OutputPaths {
+        OutputPaths { output: options.output.clone(), bgzip: options.bgzip, fai: options.fai,
+                      split_output: options.split_output.clone(), split_only: options.split_only,
+                      debug: options.debug.clone(), depth_out: options.depth_out.clone(),
+                      uncovered_bed: options.uncovered_bed.clone(),
+                      multi_mapped_out: options.multi_mapped_out.clone(),
+                      html: options.html.clone(), plot: options.plot.clone(),
+                      changes: options.changes.clone(), mixtures: options.mixtures.clone(),
+                      gvcf: options.gvcf.clone() }
+    }
+}
+
+
+/// The polished sequence sinks and the optional files which get information about each base as
+/// it's polished. Polished sequences are given to the sinks as they're made (see sink.rs).
+pub struct OutputFiles {
+    pub debug: Option<DebugFile>,
+    pub depth: Option<BedWriter>,
+    pub uncovered: Option<BedWriter>,
+    pub multi_mapped: Option<BedWriter>,
+    pub seqs: Vec<Box<dyn SeqSink>>,
+    pub html: Option<HtmlReport>,
+    pub plot: Option<SvgPlot>,
+    pub changes: Option<Box<dyn ChangeSink>>,
+    pub mixtures: Option<MixtureReport>,
+    pub fastq: bool,              // headers start with '@' and qualities follow each sequence
+    pub annotate_headers: bool,   // headers summarise each sequence's polishing
+    pub gvcf: Option<GvcfFile>,
+    pub run: RunContext,          // gets the warnings made while polishing
+}
+
+impl OutputFiles {
+    pub fn start_seq(&mut self, name: &str, description: &str, annotation: Option<&str>) {
+        let header = seq_header(name, description, annotation, self.fastq);
+        for sink in &mut self.seqs {
+            sink.start_seq(name, &header);
+        }
+    }
+
+    /// Adds polished sequence, which may be all of the sequence or just the next part of it.
+    pub fn write_seq(&mut self, seq: &str) {
+        for sink in &mut self.seqs {
+            sink.write_seq(seq);
+        }
+    }
+
+    pub fn finish_seq(&mut self, quals: &Option<String>) {
+        for sink in &mut self.seqs {
+            sink.finish_seq(quals.as_deref());
+        }
+    }
+
+    pub fn finish(self, polished_seqs: &[PolishedSeq]) {
+        if let Some(file) = self.debug {
+            file.finish();
+        }
+        for file in [self.depth, self.uncovered, self.multi_mapped].into_iter().flatten() {
+            file.finish();
+        }
+        if let Some(file) = self.changes {
+            file.finish();
+        }
+        if let Some(report) = self.mixtures {
+            report.finish();
+        }
+        if let Some(file) = self.gvcf {
+            file.finish();
+        }
+        if let Some(report) = self.html {
+            report.write(polished_seqs, &self.run.warnings());
+        }
+        if let Some(plot) = self.plot {
+            plot.write(polished_seqs);
+        }
+        for sink in self.seqs {
+            sink.finish();
+        }
+    }
+}
+
+
+/// The polished sequences go to the output (or into the graph, which is written to the output at
+/// the end) and to a file per sequence with --split-output.
+pub fn seq_sinks(graph: Option<gfa::Gfa>, paths: &OutputPaths,
+                 fastq: bool) -> Vec<Box<dyn SeqSink>> {
+    let &OutputPaths { ref output, bgzip, fai, ref split_output, split_only, .. } = paths;
+    let mut sinks: Vec<Box<dyn SeqSink>> = Vec::new();
+    if let Some(graph) = graph {
+        sinks.push(Box::new(GraphOutput::new(graph, SeqWriter::create(output, bgzip, fai))));
+    } else if !split_only {
+        sinks.push(Box::new(SeqWriter::create(output, bgzip, fai)));
+    }
+    if let Some(dir) = split_output {
+        sinks.push(Box::new(SplitWriter::create(dir, bgzip, fai, fastq)));
+    }
+    sinks
+}
+
+
+/// The per-base debugging file. If any statuses are given, only bases with those statuses are
+/// written to it. With read_names, it has an extra column naming the reads behind each sequence
+/// (for bases which weren't simply kept).
+pub struct DebugFile {
+    output: DebugOutput,
+    filename: PathBuf,
+    statuses: Vec<String>,
+    pub read_names: bool,
+    low_complexity: bool,   // whether there is a column for low-complexity positions
+    deletion_events: bool,  // whether there is a column for deletion events (--joint-indels)
+}
+
+enum DebugOutput {
+    Tsv(File),
+    #[cfg(feature = "arrow")]
+    Arrow(Box<ArrowDebugFile>),
+}
+
+impl DebugFile {
+    fn write_line(&mut self, name: &str, pos: usize, b: &pileup::PileupBase,
+                  summary: &BaseSummary, low_complexity: bool, event: Option<&str>) {
+        if !self.statuses.is_empty() && !self.statuses.contains(&summary.status) {
+            return;
+        }
+        let kept = summary.status == pileup::BaseStatus::OriginalBaseKept.name();
+        let reads = if self.read_names && !kept {
+            b.get_read_names_str()
+        } else {
+            String::new()
+        };
+        match &mut self.output {
+            DebugOutput::Tsv(file) => {
+                let mut line = summary.tsv_line();
+                if self.low_complexity {
+                    line.push_str(if low_complexity { "\tyes" } else { "\tno" });
+                }
+                if self.deletion_events {
+                    line.push('\t');
+                    line.push_str(event.unwrap_or_default());
+                }
+                if self.read_names {
+                    line.push('\t');
+                    line.push_str(&reads);
+                }
+                write_debug_line(file, name, pos, &line, &self.filename);
+            },
+            #[cfg(feature = "arrow")]
+            DebugOutput::Arrow(file) => file.add(name, pos, summary, low_complexity, event,
+                                                 &reads),
+        }
+    }
+
+    fn finish(self) {
+        #[cfg(feature = "arrow")]
+        if let DebugOutput::Arrow(file) = self.output {
+            file.finish();
+        }
+    }
+}
+
+
+fn create_debug_file(debug: &Option<PathBuf>, statuses: Vec<String>, read_names: bool,
+                     low_complexity: bool, deletion_events: bool,
+                     format: &str) -> Option<DebugFile> {
     match debug {
         Some(_) => {},
         None    => {return None;},
     }
     let filename = debug.as_ref().unwrap();
+    if format == "arrow" {
+        #[cfg(feature = "arrow")]
+        return Some(DebugFile {
+            output: DebugOutput::Arrow(Box::new(ArrowDebugFile::create(filename, read_names,
+                                                                       low_complexity,
+                                                                       deletion_events))),
+            filename: filename.clone(), statuses, read_names, low_complexity, deletion_events,
+        });
+        #[cfg(not(feature = "arrow"))]
+        unreachable!("--debug-format arrow is rejected without the arrow feature");
+    }
     let create_result = File::create(filename);
     match create_result {
         Ok(_)  => (),
-        Err(_) => misc::quit_with_error(&format!("unable to create {:?}", filename)),
+        Err(_) => misc::quit_with_error(ErrorType::Io, &format!("unable to create {:?}", filename)),
     }
     let mut file = create_result.unwrap();
-    write_debug_header(&mut file, filename);
-    Some(file)
+    write_debug_header(&mut file, filename, read_names, low_complexity, deletion_events);
+    Some(DebugFile { output: DebugOutput::Tsv(file), filename: filename.clone(), statuses,
+                     read_names, low_complexity, deletion_events })
 }
 
 
-fn write_debug_header(file: &mut File, filename: &PathBuf) {
-    let header = "name\tpos\tbase\tdepth\tinvalid\tvalid\tpileup\tstatus\tnew_base\n";
+fn write_debug_header(file: &mut File, filename: &Path, read_names: bool, low_complexity: bool,
+                      deletion_events: bool) {
+    let mut header = "name\tpos\tbase\tdepth\tinvalid\tvalid\tpileup\tstatus\tnew_base\tconfidence"
+        .to_string();
+    if low_complexity {
+        header.push_str("\tlow_complexity");
+    }
+    if deletion_events {
+        header.push_str("\tdeletion_event");
+    }
+    if read_names {
+        header.push_str("\treads");
+    }
+    header.push('\n');
     let result = file.write_all(header.as_bytes());
     match result {
         Ok(_)  => (),
-        Err(_) => misc::quit_with_error(&format!("unable to write to file {:?}", filename)),
+        Err(_) => misc::quit_with_error(ErrorType::Io,
+                                        &format!("unable to write to file {:?}", filename)),
     }
 }
 
 
 fn write_debug_line(file: &mut File, name: &str, pos: usize, debug_line: &str,
-                    debug: &Option<PathBuf>) {
+                    filename: &Path) {
     let debug_line: String = format!("{}\t{}\t{}\n", name, pos, debug_line);
     let result = file.write_all(debug_line.as_bytes());
     match result {
         Ok(_)  => (),
-        Err(_) => misc::quit_with_error(&format!("unable to write to file {:?}",
-                                                 debug.as_ref().unwrap())),
+        Err(_) => misc::quit_with_error(ErrorType::Io,
+                                        &format!("unable to write to file {:?}", filename)),
     }
 }
 
 
-fn check_inputs_exist(assembly: &PathBuf, sam: &Vec<PathBuf>) {
-    misc::check_if_file_exists(&assembly);
-    for s in sam {
-        misc::check_if_file_exists(&s);
+pub fn check_inputs_exist(assemblies: &[PathBuf], sam: &[PathBuf]) {
+    for f in assemblies.iter().chain(sam) {
+        if !misc::is_stdin(f) {
+            misc::check_if_file_exists(f);
+        }
+    }
+}
+
+
+/// The first input is always an assembly, and any FASTA files directly after it are more of the
+/// assembly (e.g. chromosome.fasta plasmids.fasta). The rest are alignment files.
+pub fn split_inputs(assembly: PathBuf, sam: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let extra_count = sam.iter().take_while(|s| misc::is_fasta(s)).count();
+    let mut assemblies = vec![assembly];
+    let mut sam = sam;
+    assemblies.extend(sam.drain(..extra_count));
+    (assemblies, sam)
+}
+
+
+/// With --samples, there must be one sample name for each alignment file, and --min-samples can't
+/// be more than the number of samples.
+fn check_samples(samples: &[String], min_samples: usize, sam: &[PathBuf]) {
+    if samples.is_empty() {
+        return;
+    }
+    if samples.len() != sam.len() {
+        misc::quit_with_error(ErrorType::Args,
+                              &format!("--samples has {} names but there are {} alignment files",
+                                       samples.len(), sam.len()))
+    }
+    let sample_count = sample_names(samples).len();
+    if min_samples == 0 || min_samples > sample_count {
+        misc::quit_with_error(ErrorType::Args,
+                              &format!("--min-samples must be between 1 and the number of \
+                                        samples ({})", sample_count))
+    }
+}
+
+
+/// With --weights, there must be one positive weight for each alignment file.
+fn check_weights(weights: &[f64], sam: &[PathBuf]) {
+    if weights.is_empty() {
+        return;
+    }
+    if weights.len() != sam.len() {
+        misc::quit_with_error(ErrorType::Args,
+                              &format!("--weights has {} values but there are {} alignment files",
+                                       weights.len(), sam.len()))
+    }
+    if weights.iter().any(|w| *w <= 0.0 || !w.is_finite()) {
+        misc::quit_with_error(ErrorType::Args, "--weights must all be greater than 0")
     }
 }
 
 
-fn check_option_values(fraction_invalid: f64, fraction_valid: f64) {
+pub fn check_option_values(thresholds: &Thresholds, filter: &AlignmentFilter,
+                           target_depth: Option<f64>, max_depth: Option<u32>,
+                           local_depth_window: Option<usize>, require_mean_depth: Option<f64>) {
+    let Thresholds { fraction_valid, fraction_invalid, indel_fraction_valid, .. } = *thresholds;
     if fraction_valid <= 0.0 || fraction_valid >= 1.0 {
-        misc::quit_with_error("--fraction_valid must be between 0 and 1 (exclusive)")
+        misc::quit_with_error(ErrorType::Args,
+                              "--fraction_valid must be between 0 and 1 (exclusive)")
     }
     if fraction_invalid <= 0.0 || fraction_invalid >= 1.0 {
-        misc::quit_with_error("--fraction_invalid must be between 0 and 1 (exclusive)")
+        misc::quit_with_error(ErrorType::Args,
+                              "--fraction_invalid must be between 0 and 1 (exclusive)")
     }
     if fraction_invalid >= fraction_valid {
-        misc::quit_with_error("--fraction_invalid must be less than --fraction_valid")
+        misc::quit_with_error(ErrorType::Args,
+                              "--fraction_invalid must be less than --fraction_valid")
+    }
+    if indel_fraction_valid <= fraction_invalid || indel_fraction_valid >= 1.0 {
+        misc::quit_with_error(ErrorType::Args,
+                              "--indel-fraction-valid must be between --fraction_invalid and 1 \
+                               (exclusive)")
+    }
+    check_filter_values(filter);
+    if target_depth.is_some_and(|d| d <= 0.0) {
+        misc::quit_with_error(ErrorType::Args, "--target-depth must be greater than 0")
+    }
+    if max_depth == Some(0) {
+        misc::quit_with_error(ErrorType::Args, "--max-depth must be greater than 0")
+    }
+    if thresholds.low_complexity_fraction_valid.is_some_and(|f| f < fraction_valid || f >= 1.0) {
+        misc::quit_with_error(ErrorType::Args,
+                              "--low-complexity-fraction-valid must be between --fraction_valid \
+                               and 1")
+    }
+    if local_depth_window == Some(0) {
+        misc::quit_with_error(ErrorType::Args, "--local-depth-window must be greater than 0")
+    }
+    if thresholds.min_posterior.is_some_and(|p| p <= 0.0 || p >= 1.0) {
+        misc::quit_with_error(ErrorType::Args,
+                              "--min-posterior must be between 0 and 1 (exclusive)")
+    }
+    if thresholds.relative_min_depth.is_some_and(|f| f <= 0.0 || f >= 1.0) {
+        misc::quit_with_error(ErrorType::Args,
+                              "--relative-min-depth must be between 0 and 1 (exclusive)")
+    }
+    if require_mean_depth.is_some_and(|d| d <= 0.0) {
+        misc::quit_with_error(ErrorType::Args, "--require-mean-depth must be greater than 0")
+    }
+}
+
+
+pub fn check_filter_values(filter: &AlignmentFilter) {
+    if filter.max_error_rate.is_some_and(|r| !(0.0..1.0).contains(&r)) {
+        misc::quit_with_error(ErrorType::Args,
+                              "--max-error-rate must be at least 0 and less than 1")
+    }
+    if filter.min_identity.is_some_and(|i| !(0.0..=1.0).contains(&i)) {
+        misc::quit_with_error(ErrorType::Args, "--min-identity must be between 0 and 1 (inclusive)")
+    }
+    if filter.max_score_diff.is_some_and(|d| d < 0) {
+        misc::quit_with_error(ErrorType::Args, "--max-score-diff must be at least 0")
+    }
+    if filter.max_hits == Some(0) {
+        misc::quit_with_error(ErrorType::Args, "--max-hits must be at least 1")
+    }
+}
+
+
+/// The pre-polishing accuracy (as a percentage) is estimated by assuming each change fixed one
+/// error.
+fn estimated_accuracy(changed_count: usize, seq_len: usize) -> f64 {
+    if seq_len == 0 {
+        return 100.0;
+    }
+    100.0 - 100.0 * (changed_count as f64) / (seq_len as f64)
+}
+
+
+pub fn qscore(identity: f64) -> String {
+    match qscore_value(identity) {
+        None                       => "Q∞".to_string(),
+        Some(_) if identity <= 0.0 => "Q0".to_string(),
+        Some(qscore)               => format!("Q{:.2}", qscore),
     }
 }
 
 
-fn qscore(identity: f64) -> String {
+/// Returns the Q-score for an identity (as a percentage), or None for 100% identity (Q∞). This is
+/// used for machine-readable output, where None becomes null.
+pub fn qscore_value(identity: f64) -> Option<f64> {
     if identity >= 100.0 {
-        return "Q∞".to_string();
+        return None;
     }
     if identity <= 0.0 {
-        return "Q0".to_string();
+        return Some(0.0);
     }
     let errors = 1.0 - (identity / 100.0);
-    let qscore = -10.0 * errors.log10();
-    format!("Q{:.2}", qscore)
+    Some(-10.0 * errors.log10())
 }
 
 
@@ -316,4 +2274,101 @@ mod tests {
         assert_eq!(qscore(100.0), "Q∞");
         assert_eq!(qscore(0.0), "Q0");
     }
+
+    #[test]
+    fn test_qscore_value() {
+        assert!((qscore_value(99.9).unwrap() - 30.0).abs() < 1e-9);
+        assert_eq!(qscore_value(100.0), None);
+        assert_eq!(qscore_value(0.0), Some(0.0));
+        assert_eq!(estimated_accuracy(0, 1000), 100.0);
+        assert_eq!(estimated_accuracy(1, 1000), 99.9);
+        assert_eq!(estimated_accuracy(0, 0), 100.0);
+    }
+
+    #[test]
+    fn test_qual_char() {
+        assert_eq!(qual_char(0.0), '!');
+        assert_eq!(qual_char(8.4), ')');
+        assert_eq!(qual_char(30.103), '?');
+        assert_eq!(qual_char(pileup::MAX_CONFIDENCE), ']');
+    }
+
+    #[test]
+    fn test_format_depth() {
+        assert_eq!(format_depth(0.0), "0");
+        assert_eq!(format_depth(10.0), "10");
+        assert_eq!(format_depth(2.5), "2.5");
+        assert_eq!(format_depth(1.0 / 3.0), "0.33");
+        assert_eq!(format_depth(29.999), "30");
+    }
+
+    #[test]
+    fn test_header_annotation() {
+        let totals = PolishTotals { changed_count: 3, total_depth: 1425.0, ..Default::default() };
+        assert_eq!(header_annotation(10, &totals), "polypolish=3changes depth=142.5x");
+    }
+
+    #[test]
+    fn test_debug_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("debug.tsv");
+        let statuses = vec!["changed".to_string(), "none".to_string()];
+        let mut file = create_debug_file(&Some(filename.clone()), statuses, false, false, false,
+                                         "tsv").unwrap();
+        let b = pileup::Pileup::new("A", u32::MAX, 0, Some(1), false).bases.remove(0);
+        let kept = test_summary("kept", None);
+        let changed = test_summary("changed", Some(25.0));
+        let none = test_summary("none", None);
+        let too_close = test_summary("too_close", None);
+        file.write_line("a", 0, &b, &kept, false, None);
+        file.write_line("a", 1, &b, &changed, false, None);
+        file.write_line("a", 2, &b, &none, false, None);
+        file.write_line("a", 3, &b, &too_close, false, None);
+        drop(file);
+        let contents = std::fs::read_to_string(&filename).unwrap();
+        let lines: Vec<&str> = contents.lines().skip(1).collect();
+        assert_eq!(lines, vec!["a\t1\tA\t10.0\t2\t5\tA:10\tchanged\tA\t25.0",
+                               "a\t2\tA\t10.0\t2\t5\tA:10\tnone\tA\t"]);
+    }
+
+    #[test]
+    fn test_debug_low_complexity() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("debug.tsv");
+        let mut file = create_debug_file(&Some(filename.clone()), Vec::new(), false, true, false,
+                                         "tsv").unwrap();
+        let b = pileup::Pileup::new("A", u32::MAX, 0, Some(1), false).bases.remove(0);
+        let kept = test_summary("kept", None);
+        file.write_line("a", 0, &b, &kept, false, None);
+        file.write_line("a", 1, &b, &kept, true, None);
+        drop(file);
+        let contents = std::fs::read_to_string(&filename).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].ends_with("\tconfidence\tlow_complexity"));
+        assert_eq!(&lines[1..], vec!["a\t0\tA\t10.0\t2\t5\tA:10\tkept\tA\t\tno",
+                                     "a\t1\tA\t10.0\t2\t5\tA:10\tkept\tA\t\tyes"]);
+    }
+
+    fn test_summary(status: &str, confidence: Option<f64>) -> BaseSummary {
+        BaseSummary { base: 'A', depth: 10.0, invalid: 2, valid: 5, pileup: "A:10".to_string(),
+                      status: status.to_string(), new_base: "A".to_string(), confidence }
+    }
+
+    #[test]
+    fn test_high_copy_numbers() {
+        let depths = [("chromosome", 4_000_000, 40.0), ("big_plasmid", 120_000, 200.0),
+                      ("small_plasmid", 5_000, 400.0), ("low_copy", 10_000, 80.0)];
+        let copy_numbers = high_copy_numbers(&depths);
+        assert_eq!(copy_numbers.len(), 1);
+        assert!((copy_numbers["small_plasmid"] - 400.0 / 44.6602).abs() < 0.001);
+        assert!(high_copy_numbers(&[("a", 5_000, 10.0), ("b", 3_000, 100.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_end_distance() {
+        assert_eq!(end_distance(0, 10), 0);
+        assert_eq!(end_distance(9, 10), 0);
+        assert_eq!(end_distance(3, 10), 3);
+        assert_eq!(end_distance(7, 10), 2);
+    }
 }
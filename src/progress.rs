@@ -0,0 +1,193 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::io::IsTerminal;
+use std::time::Duration;
+
+use crate::log;
+use crate::misc::Instant;
+
+
+/// When stderr isn't a terminal, a plain-text progress line is written this often.
+const PLAIN_TEXT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// With an event callback (see log.rs), it's given a progress event this often.
+const CALLBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+
+#[derive(Clone, Copy)]
+pub enum Unit {
+    Bytes,  // e.g. reading through a SAM file
+    Bases,  // e.g. polishing through a contig
+}
+
+
+/// Progress reporting for long-running stages. On an interactive terminal this draws a progress
+/// bar with an ETA. Otherwise (e.g. a cluster job's log file) it periodically writes a plain line
+/// of text, or a progress event when using JSON logging.
+pub struct Progress {
+    label: String,
+    unit: Unit,
+    total: u64,
+    position: u64,
+    count: u64,
+    count_name: &'static str,
+    bar: Option<ProgressBar>,
+    start_time: Instant,
+    last_report: Instant,
+    last_callback: Instant,
+    calls: u32,
+}
+
+impl Progress {
+    /// Creates a new progress reporter. The optional count (with its name, e.g. "alignments") is
+    /// a secondary counter which is displayed alongside the main progress.
+    pub fn new(label: &str, unit: Unit, total: u64, count_name: &'static str) -> Progress {
+        let bar = if !log::is_json() && std::io::stderr().is_terminal() {
+            Some(make_bar(label, unit, total))
+        } else {
+            None
+        };
+        Progress {
+            label: label.to_string(),
+            unit,
+            total,
+            position: 0,
+            count: 0,
+            count_name,
+            bar,
+            start_time: Instant::now(),
+            last_report: Instant::now(),
+            last_callback: Instant::now(),
+            calls: 0,
+        }
+    }
+
+    pub fn update(&mut self, position: u64, count: u64) {
+        self.position = position;
+        self.count = count;
+
+        // Checking the time on every call would be wasteful, as this is called very often.
+        self.calls += 1;
+        if self.calls < 4096 {
+            return;
+        }
+        self.calls = 0;
+
+        if log::has_event_callback() && self.last_callback.elapsed() >= CALLBACK_INTERVAL {
+            self.last_callback = Instant::now();
+            log::callback_event("progress", || self.event_fields());
+        }
+
+        if let Some(bar) = &self.bar {
+            bar.set_position(position);
+            if !self.count_name.is_empty() {
+                bar.set_message(format!("{} {}", count.to_formatted_string(&Locale::en),
+                                        self.count_name));
+            }
+        } else if self.last_report.elapsed() >= PLAIN_TEXT_INTERVAL {
+            self.last_report = Instant::now();
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        if log::is_json() {
+            log::event("progress", self.event_fields());
+            return;
+        }
+        let fraction = self.fraction();
+        let eta = estimate_remaining(self.start_time.elapsed(), fraction);
+        let mut text = format!("{}: {:.1}% ({} / {})", self.label, 100.0 * fraction,
+                               format_amount(self.position, self.unit),
+                               format_amount(self.total, self.unit));
+        if !self.count_name.is_empty() {
+            text.push_str(&format!(", {} {}", self.count.to_formatted_string(&Locale::en),
+                                   self.count_name));
+        }
+        if let Some(eta) = eta {
+            text.push_str(&format!(", ETA {}", HumanDuration(eta)));
+        }
+        eprintln!("{}", text);
+    }
+
+    fn fraction(&self) -> f64 {
+        if self.total == 0 { 0.0 } else { self.position as f64 / self.total as f64 }
+    }
+
+    fn event_fields(&self) -> serde_json::Value {
+        let eta = estimate_remaining(self.start_time.elapsed(), self.fraction());
+        json!({"stage": self.label, "position": self.position, "total": self.total,
+               "count": self.count, "eta_seconds": eta.map(|d| d.as_secs_f64())})
+    }
+
+    /// Removes the progress bar (if any). This should be called before the stage prints its
+    /// results, so they don't get tangled up with the bar.
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+
+fn make_bar(label: &str, unit: Unit, total: u64) -> ProgressBar {
+    let template = match unit {
+        Unit::Bytes => "{prefix} [{bar:30}] {bytes}/{total_bytes} (ETA {eta}) {msg}",
+        Unit::Bases => "{prefix} [{bar:30}] {human_pos}/{human_len} bp (ETA {eta}) {msg}",
+    };
+    let bar = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stderr());
+    bar.set_style(ProgressStyle::with_template(template).unwrap().progress_chars("=> "));
+    bar.set_prefix(label.to_string());
+    bar
+}
+
+
+fn format_amount(amount: u64, unit: Unit) -> String {
+    match unit {
+        Unit::Bytes => HumanBytes(amount).to_string(),
+        Unit::Bases => format!("{} bp", amount.to_formatted_string(&Locale::en)),
+    }
+}
+
+
+/// Estimates the time remaining based on the time taken so far and the fraction complete.
+fn estimate_remaining(elapsed: Duration, fraction: f64) -> Option<Duration> {
+    if fraction <= 0.0 || fraction > 1.0 {
+        return None;
+    }
+    Some(elapsed.mul_f64((1.0 - fraction) / fraction))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_remaining() {
+        assert_eq!(estimate_remaining(Duration::from_secs(10), 0.0), None);
+        assert_eq!(estimate_remaining(Duration::from_secs(10), 0.5), Some(Duration::from_secs(10)));
+        assert_eq!(estimate_remaining(Duration::from_secs(10), 0.25),
+                   Some(Duration::from_secs(30)));
+        assert_eq!(estimate_remaining(Duration::from_secs(10), 1.0), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(1234567, Unit::Bases), "1,234,567 bp");
+        assert_eq!(format_amount(2048, Unit::Bytes), "2.00 KiB");
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The HTML report (--html) is a single file with no external resources, so it can be emailed or
+// archived alongside the assembly. It has a summary table and, for each sequence, a plot of read
+// depth with the positions of changes marked. Depths are gathered in small bins as bases are
+// polished, so the report works in windowed mode without holding per-base depths. The same plots
+// can be written on their own as an SVG image (--plot), one panel per sequence.
+
+use clap::crate_version;
+use num_format::{Locale, ToFormattedString};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::misc::{quit_with_error, ErrorType};
+use crate::polish::{qscore, PolishedSeq};
+
+
+/// Depths are gathered in bins of this many bases.
+const DEPTH_BIN_SIZE: usize = 100;
+
+/// Bins are merged so that each depth plot has at most this many points.
+const MAX_PLOT_POINTS: usize = 1000;
+
+/// Only this many changes are listed for each sequence (all are still marked on its plot).
+const MAX_LISTED_CHANGES: usize = 1000;
+
+const PLOT_WIDTH: f64 = 900.0;
+const PLOT_HEIGHT: f64 = 160.0;
+const PLOT_LEFT: f64 = 50.0;    // space for depth labels
+const PLOT_BOTTOM: f64 = 20.0;  // space for position labels
+const PLOT_TITLE: f64 = 24.0;   // space above each plot for its sequence's name (--plot)
+
+
+pub struct HtmlReport {
+    filename: PathBuf,
+    seqs: Vec<SeqReport>,
+}
+
+#[derive(Default)]
+struct SeqReport {
+    name: String,
+    length: usize,
+    depth_bins: Vec<f64>,                 // summed depth of each bin
+    zero_depth_count: usize,
+    changes: Vec<(usize, char, String)>,  // position, original base and new sequence
+}
+
+impl HtmlReport {
+    pub fn new(filename: &Path) -> HtmlReport {
+        HtmlReport { filename: filename.to_path_buf(), seqs: Vec::new() }
+    }
+
+    /// Records one polished base. Bases must be added in order, one sequence after another.
+    pub fn add_base(&mut self, name: &str, pos: usize, depth: f64, original: char,
+                    changed_to: Option<&str>) {
+        add_base(&mut self.seqs, name, pos, depth, original, changed_to);
+    }
+
+    pub fn write(&self, polished_seqs: &[PolishedSeq], warnings: &[String]) {
+        let html = self.render(polished_seqs, warnings);
+        if let Err(e) = fs::write(&self.filename, html) {
+            quit_with_error(ErrorType::Io,
+                            &format!("unable to write {:?}: {}", self.filename, e));
+        }
+    }
+
+    fn render(&self, polished_seqs: &[PolishedSeq], warnings: &[String]) -> String {
+        let f = |n: usize| n.to_formatted_string(&Locale::en);
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str("<title>Polypolish report</title>\n");
+        html.push_str("<style>\n");
+        html.push_str(PAGE_STYLE);
+        html.push_str(PLOT_STYLE);
+        html.push_str("</style>\n");
+        html.push_str("</head>\n<body>\n");
+        html.push_str(&format!("<h1>Polypolish report</h1>\n<p>Polypolish v{}</p>\n",
+                               crate_version!()));
+
+        if !warnings.is_empty() {
+            html.push_str("<h2>Warnings</h2>\n<ul class=\"warnings\">\n");
+            for warning in warnings {
+                html.push_str(&format!("<li>{}</li>\n", escape(warning)));
+            }
+            html.push_str("</ul>\n");
+        }
+
+        let empty = SeqReport::default();
+        let seqs = in_order(&self.seqs, polished_seqs, &empty);
+
+        html.push_str("<h2>Summary</h2>\n<table>\n<tr><th>Sequence</th><th>Length</th>\
+                       <th>Polished length</th><th>Changes</th><th>Mean depth</th>\
+                       <th>Zero-depth bases</th><th>Estimated accuracy</th></tr>\n");
+        for (i, (seq, polished)) in seqs.iter().zip(polished_seqs).enumerate() {
+            let accuracy = polished.estimated_accuracy();
+            html.push_str(&format!("<tr><td><a href=\"#seq{}\">{}</a></td><td>{}</td><td>{}</td>\
+                                    <td>{}</td><td>{:.1}x</td><td>{}</td><td>{:.4}% ({})</td>\
+                                    </tr>\n",
+                                   i, escape(&polished.name), f(polished.original_length),
+                                   f(polished.length), f(seq.changes.len()), seq.mean_depth(),
+                                   f(seq.zero_depth_count), accuracy, qscore(accuracy)));
+        }
+        html.push_str("</table>\n");
+
+        for (i, (seq, polished)) in seqs.iter().zip(polished_seqs).enumerate() {
+            html.push_str(&format!("<h2 id=\"seq{}\">{}</h2>\n", i, escape(&polished.name)));
+            html.push_str(&format!("<p>{} bp, {} change{}, {:.1}x mean depth</p>\n",
+                                   f(seq.length), f(seq.changes.len()),
+                                   if seq.changes.len() == 1 { "" } else { "s" },
+                                   seq.mean_depth()));
+            html.push_str(&seq.depth_plot());
+            html.push_str(&seq.change_table());
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+
+/// The depth plots on their own, as one SVG image with a panel for each sequence.
+pub struct SvgPlot {
+    filename: PathBuf,
+    seqs: Vec<SeqReport>,
+}
+
+impl SvgPlot {
+    pub fn new(filename: &Path) -> SvgPlot {
+        SvgPlot { filename: filename.to_path_buf(), seqs: Vec::new() }
+    }
+
+    /// Records one polished base. Bases must be added in order, one sequence after another.
+    pub fn add_base(&mut self, name: &str, pos: usize, depth: f64, original: char,
+                    changed_to: Option<&str>) {
+        add_base(&mut self.seqs, name, pos, depth, original, changed_to);
+    }
+
+    pub fn write(&self, polished_seqs: &[PolishedSeq]) {
+        let svg = self.render(polished_seqs);
+        if let Err(e) = fs::write(&self.filename, svg) {
+            quit_with_error(ErrorType::Io,
+                            &format!("unable to write {:?}: {}", self.filename, e));
+        }
+    }
+
+    fn render(&self, polished_seqs: &[PolishedSeq]) -> String {
+        let f = |n: usize| n.to_formatted_string(&Locale::en);
+        let empty = SeqReport::default();
+        let seqs = in_order(&self.seqs, polished_seqs, &empty);
+        let panel_height = PLOT_TITLE + PLOT_HEIGHT;
+        let mut svg = String::new();
+        svg.push_str(&format!("<svg width=\"{}\" height=\"{}\" \
+                               xmlns=\"http://www.w3.org/2000/svg\">\n", PLOT_WIDTH,
+                              panel_height * seqs.len() as f64));
+        svg.push_str("<style>\n");
+        svg.push_str(PLOT_STYLE);
+        svg.push_str("</style>\n");
+        svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n");
+        for (i, (seq, polished)) in seqs.iter().zip(polished_seqs).enumerate() {
+            let top = panel_height * i as f64;
+            svg.push_str(&format!("<text class=\"title\" x=\"{}\" y=\"{}\">{} ({} bp, {} \
+                                   change{}, {:.1}x mean depth)</text>\n",
+                                  PLOT_LEFT, top + PLOT_TITLE - 8.0, escape(&polished.name),
+                                  f(seq.length), f(seq.changes.len()),
+                                  if seq.changes.len() == 1 { "" } else { "s" },
+                                  seq.mean_depth()));
+            svg.push_str(&format!("<g transform=\"translate(0,{})\">\n", top + PLOT_TITLE));
+            svg.push_str(&seq.depth_plot());
+            svg.push_str("</g>\n");
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+
+/// Adds one base to the last sequence, or starts a new one.
+fn add_base(seqs: &mut Vec<SeqReport>, name: &str, pos: usize, depth: f64, original: char,
+            changed_to: Option<&str>) {
+    if seqs.last().map_or(true, |s| s.name != name) {
+        seqs.push(SeqReport { name: name.to_string(), ..Default::default() });
+    }
+    let seq = seqs.last_mut().unwrap();
+    seq.length += 1;
+    if pos % DEPTH_BIN_SIZE == 0 {
+        seq.depth_bins.push(0.0);
+    }
+    *seq.depth_bins.last_mut().unwrap() += depth;
+    if depth == 0.0 {
+        seq.zero_depth_count += 1;
+    }
+    if let Some(new_seq) = changed_to {
+        seq.changes.push((pos, original, new_seq.to_string()));
+    }
+}
+
+
+/// The sequences in the order of the polished sequences. Sequences with no bases never reached
+/// add_base, so they get an empty report.
+fn in_order<'a>(seqs: &'a [SeqReport], polished_seqs: &[PolishedSeq],
+                empty: &'a SeqReport) -> Vec<&'a SeqReport> {
+    polished_seqs.iter()
+        .map(|p| seqs.iter().find(|s| s.name == p.name).unwrap_or(empty)).collect()
+}
+
+
+impl SeqReport {
+    fn mean_depth(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.depth_bins.iter().sum::<f64>() / self.length as f64
+    }
+
+    /// Mean depths for the plot, with bins merged so there are at most MAX_PLOT_POINTS. Each is
+    /// given with the number of bases it covers.
+    fn plot_depths(&self) -> Vec<(f64, usize)> {
+        let bins_per_point = self.depth_bins.len().div_ceil(MAX_PLOT_POINTS).max(1);
+        let bases_per_point = bins_per_point * DEPTH_BIN_SIZE;
+        self.depth_bins.chunks(bins_per_point).enumerate().map(|(i, chunk)| {
+            let bases = bases_per_point.min(self.length - i * bases_per_point);
+            (chunk.iter().sum::<f64>() / bases as f64, bases)
+        }).collect()
+    }
+
+    /// An SVG plot of read depth along the sequence, with a red line at each change.
+    fn depth_plot(&self) -> String {
+        let plot_width = PLOT_WIDTH - PLOT_LEFT;
+        let plot_height = PLOT_HEIGHT - PLOT_BOTTOM;
+        let depths = self.plot_depths();
+        let max_depth = depths.iter().map(|d| d.0).fold(0.0, f64::max).max(1.0);
+        let x = |pos: usize| PLOT_LEFT + plot_width * pos as f64 / self.length.max(1) as f64;
+        let y = |depth: f64| plot_height * (1.0 - depth / max_depth);
+
+        let mut svg = String::new();
+        svg.push_str(&format!("<svg width=\"{}\" height=\"{}\" \
+                               xmlns=\"http://www.w3.org/2000/svg\">\n", PLOT_WIDTH, PLOT_HEIGHT));
+        let mut path = format!("M{:.1},{:.1}", x(0), y(0.0));
+        let mut pos = 0;
+        for (depth, bases) in &depths {
+            path.push_str(&format!(" L{:.1},{:.1} L{:.1},{:.1}", x(pos), y(*depth),
+                                   x(pos + bases), y(*depth)));
+            pos += bases;
+        }
+        path.push_str(&format!(" L{:.1},{:.1} Z", x(self.length), y(0.0)));
+        svg.push_str(&format!("<path class=\"depth\" d=\"{}\"/>\n", path));
+        for (pos, original, new_seq) in &self.changes {
+            svg.push_str(&format!("<line class=\"change\" x1=\"{0:.1}\" y1=\"0\" x2=\"{0:.1}\" \
+                                   y2=\"{1:.1}\"><title>{2}: {3} → {4}</title></line>\n",
+                                  x(*pos), plot_height, pos + 1, original, escape(new_seq)));
+        }
+        svg.push_str(&format!("<line class=\"axis\" x1=\"{0}\" y1=\"{1}\" x2=\"{2}\" \
+                               y2=\"{1}\"/>\n", PLOT_LEFT, plot_height, PLOT_WIDTH));
+        svg.push_str(&format!("<line class=\"axis\" x1=\"{0}\" y1=\"0\" x2=\"{0}\" \
+                               y2=\"{1}\"/>\n", PLOT_LEFT, plot_height));
+        svg.push_str(&format!("<text x=\"{}\" y=\"12\" text-anchor=\"end\">{:.0}x</text>\n",
+                              PLOT_LEFT - 4.0, max_depth));
+        svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"end\">0x</text>\n",
+                              PLOT_LEFT - 4.0, plot_height));
+        svg.push_str(&format!("<text x=\"{}\" y=\"{}\">1</text>\n", PLOT_LEFT, PLOT_HEIGHT - 4.0));
+        svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{} bp</text>\n",
+                              PLOT_WIDTH, PLOT_HEIGHT - 4.0,
+                              self.length.to_formatted_string(&Locale::en)));
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    fn change_table(&self) -> String {
+        if self.changes.is_empty() {
+            return String::new();
+        }
+        let mut html = String::new();
+        html.push_str("<details>\n");
+        if self.changes.len() > MAX_LISTED_CHANGES {
+            html.push_str(&format!("<summary>First {} changes</summary>\n", MAX_LISTED_CHANGES));
+        } else {
+            html.push_str("<summary>Changes</summary>\n");
+        }
+        html.push_str("<table>\n<tr><th>Position</th><th>Original</th><th>Polished</th></tr>\n");
+        for (pos, original, new_seq) in self.changes.iter().take(MAX_LISTED_CHANGES) {
+            html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                                   (pos + 1).to_formatted_string(&Locale::en), original,
+                                   escape(new_seq)));
+        }
+        html.push_str("</table>\n</details>\n");
+        html
+    }
+}
+
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+
+const PAGE_STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+.warnings { color: #b00; }
+";
+
+const PLOT_STYLE: &str = "svg text { font-family: sans-serif; font-size: 11px; fill: #444; }
+svg text.title { font-size: 13px; fill: #222; }
+.depth { fill: #9ecae1; stroke: #3182bd; stroke-width: 1; }
+.change { stroke: #d62728; stroke-width: 1; stroke-opacity: 0.7; }
+.axis { stroke: #444; stroke-width: 1; }
+";
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polish::PolishTotals;
+
+    #[test]
+    fn test_add_base() {
+        let mut report = HtmlReport::new(Path::new("report.html"));
+        for pos in 0..250 {
+            let depth = if pos < 200 { 10.0 } else { 0.0 };
+            let changed_to = (pos == 5).then_some("AT");
+            report.add_base("seq<1>", pos, depth, 'A', changed_to);
+        }
+        report.add_base("seq_2", 0, 4.0, 'C', None);
+        assert_eq!(report.seqs.len(), 2);
+        let seq = &report.seqs[0];
+        assert_eq!(seq.length, 250);
+        assert_eq!(seq.depth_bins, vec![1000.0, 1000.0, 0.0]);
+        assert_eq!(seq.zero_depth_count, 50);
+        assert_eq!(seq.changes, vec![(5, 'A', "AT".to_string())]);
+        assert_eq!(seq.mean_depth(), 8.0);
+        assert_eq!(seq.plot_depths(), vec![(10.0, 100), (10.0, 100), (0.0, 50)]);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut report = HtmlReport::new(Path::new("report.html"));
+        for pos in 0..10 {
+            report.add_base("seq<1>", pos, 5.0, 'G', (pos == 3).then_some("-"));
+        }
+        let totals = PolishTotals { changed_count: 1, ..Default::default() };
+        let polished_seqs = vec![PolishedSeq::new("seq<1>", 10, 9, &totals),
+                                 PolishedSeq::new("empty", 0, 0, &PolishTotals::default())];
+        let html = report.render(&polished_seqs, &[]);
+        assert!(html.contains("<a href=\"#seq0\">seq&lt;1&gt;</a>"));
+        assert!(html.contains("<h2 id=\"seq1\">empty</h2>"));
+        assert!(html.contains("<title>4: G → -</title>"));
+        assert_eq!(html.matches("<svg ").count(), 2);
+    }
+
+    #[test]
+    fn test_svg_plot() {
+        let mut plot = SvgPlot::new(Path::new("plot.svg"));
+        for pos in 0..10 {
+            plot.add_base("seq<1>", pos, 5.0, 'G', (pos == 3).then_some("-"));
+        }
+        let totals = PolishTotals { changed_count: 1, ..Default::default() };
+        let polished_seqs = vec![PolishedSeq::new("seq<1>", 10, 9, &totals),
+                                 PolishedSeq::new("empty", 0, 0, &PolishTotals::default())];
+        let svg = plot.render(&polished_seqs);
+        assert!(svg.starts_with("<svg width=\"900\" height=\"368\" "));
+        assert!(svg.contains(">seq&lt;1&gt; (10 bp, 1 change, 5.0x mean depth)</text>"));
+        assert!(svg.contains("<g transform=\"translate(0,208)\">"));
+        assert_eq!(svg.matches("<line class=\"change\"").count(), 1);
+        assert_eq!(svg.matches("<svg ").count(), 3);
+    }
+}
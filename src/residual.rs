@@ -0,0 +1,163 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The pre-polishing accuracy estimate counts the errors polishing fixed, but says nothing about
+// the errors left behind. These can only be at positions polishing couldn't settle:
+//   * Ambiguous and low-depth positions keep their original base, which is wrong with the
+//     probability the Bayesian model (--model bayes) gives to the other sequences. The fraction of
+//     disagreeing reads would be far too pessimistic at low depth, where one read error among a
+//     few reads is enough to make a position ambiguous.
+//   * Positions with no reads at all are assumed to have the draft's error rate, as measured at
+//     the checked positions (changes per checked base). The rate is uncertain when few bases were
+//     checked, so it has a Gamma posterior (with a weak prior of one change per thousand bases),
+//     making these errors negative binomial.
+// Bases which were checked and kept (or changed) are counted as correct. The other positions'
+// errors are taken as Poisson (slightly conservative for positions with high error
+// probabilities), and the 95% interval comes from the sum of the two distributions.
+
+use serde::Serialize;
+
+use crate::polish::PolishedSeq;
+
+
+/// The interval uses a normal approximation above this mean.
+const MAX_EXACT_MEAN: f64 = 500.0;
+
+/// The draft error rate's prior: this rate, worth this many checked bases.
+const PRIOR_RATE: f64 = 1e-3;
+const PRIOR_BASES: f64 = 1000.0;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ResidualErrors {
+    pub expected: f64,
+    pub low: u64,   // 95% interval
+    pub high: u64,
+}
+
+impl ResidualErrors {
+    /// The estimated accuracy (as a percentage) of a polished sequence with these errors.
+    pub fn accuracy(&self, length: usize) -> f64 {
+        if length == 0 {
+            return 100.0;
+        }
+        (100.0 - 100.0 * self.expected / length as f64).max(0.0)
+    }
+}
+
+
+/// The posterior of the draft's error rate at the positions polishing could check, over all
+/// sequences: a Gamma distribution with this shape and rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DraftErrorRate {
+    pub shape: f64,
+    pub rate: f64,
+}
+
+impl DraftErrorRate {
+    pub fn mean(&self) -> f64 {
+        self.shape / self.rate
+    }
+}
+
+/// The draft error rate's posterior, from the changes made at the checked positions.
+pub fn draft_error_rate(seqs: &[PolishedSeq]) -> DraftErrorRate {
+    let changed: usize = seqs.iter().map(|s| s.changed_count).sum();
+    let checked: usize = seqs.iter().map(|s| s.checked_count).sum();
+    DraftErrorRate { shape: changed as f64 + PRIOR_RATE * PRIOR_BASES,
+                     rate: checked as f64 + PRIOR_BASES }
+}
+
+
+/// Estimates the errors remaining in the given polished sequences.
+pub fn estimate<'a>(seqs: impl IntoIterator<Item = &'a PolishedSeq>,
+                    draft_error_rate: DraftErrorRate) -> ResidualErrors {
+    let (ambiguous, zero_depth) = seqs.into_iter()
+        .fold((0.0, 0), |(a, n), s| (a + s.ambiguous_errors, n + s.zero_depth_count));
+    let expected = ambiguous + zero_depth as f64 * draft_error_rate.mean();
+    let (low, high) = interval(ambiguous, zero_depth, draft_error_rate);
+    ResidualErrors { expected, low, high }
+}
+
+
+/// The central 95% interval of the remaining errors: a Poisson distribution (for the ambiguous
+/// positions) plus a negative binomial (for the zero-depth positions, from a Poisson whose rate
+/// has the draft error rate's Gamma posterior).
+fn interval(ambiguous: f64, zero_depth: usize, draft_error_rate: DraftErrorRate) -> (u64, u64) {
+    let r = draft_error_rate.shape;
+    let q = draft_error_rate.rate / (draft_error_rate.rate + zero_depth as f64);
+    let nb_mean = r * (1.0 - q) / q;
+    let mean = ambiguous + nb_mean;
+    if mean <= 0.0 {
+        return (0, 0);
+    }
+    if mean > MAX_EXACT_MEAN {
+        let margin = 1.96 * (ambiguous + nb_mean + nb_mean * nb_mean / r).sqrt();
+        return ((mean - margin).floor().max(0.0) as u64, (mean + margin).ceil() as u64);
+    }
+    let mut poisson = vec![(-ambiguous).exp()];
+    let mut negative_binomial = vec![q.powf(r)];
+    let mut cumulative = 0.0;
+    let mut low = None;
+    let mut k = 0;
+    loop {
+        cumulative += (0..=k).map(|j| poisson[j] * negative_binomial[k - j]).sum::<f64>();
+        if low.is_none() && cumulative >= 0.025 {
+            low = Some(k as u64);
+        }
+        if cumulative >= 0.975 {
+            return (low.unwrap(), k as u64);
+        }
+        k += 1;
+        poisson.push(poisson[k - 1] * ambiguous / k as f64);
+        negative_binomial.push(negative_binomial[k - 1] * (k as f64 - 1.0 + r) / k as f64
+                               * (1.0 - q));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polish::PolishTotals;
+
+    #[test]
+    fn test_interval() {
+        let certain = DraftErrorRate { shape: 1e9, rate: 1e12 };
+        assert_eq!(interval(0.0, 0, certain), (0, 0));
+        assert_eq!(interval(0.01, 0, certain), (0, 0));
+        assert_eq!(interval(1.0, 0, certain), (0, 3));
+        assert_eq!(interval(10.0, 0, certain), (4, 17));
+        assert_eq!(interval(10000.0, 0, certain), (9804, 10196));
+        assert_eq!(interval(0.0, 10000, certain), (4, 17));
+
+        // An uncertain rate (nothing changed in 1000 checked bases) gives a wider interval.
+        let uncertain = DraftErrorRate { shape: 0.5, rate: 1001.0 };
+        let (low, high) = interval(0.0, 10000, uncertain);
+        assert_eq!(low, 0);
+        assert!(high > 17);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let totals = PolishTotals { changed_count: 9, checked_count: 1000, zero_depth_count: 100,
+                                    ambiguous_errors: 1.5, ..Default::default() };
+        let seqs = vec![PolishedSeq::new("a", 1100, 1100, &totals),
+                        PolishedSeq::new("b", 0, 0, &PolishTotals::default())];
+        let rate = draft_error_rate(&seqs);
+        assert_eq!(rate.mean(), 0.005);
+        let residual = estimate(&seqs, rate);
+        assert!((residual.expected - 2.0).abs() < 1e-9);
+        assert_eq!((residual.low, residual.high), (0, 5));
+        assert!((residual.accuracy(1100) - (100.0 - 200.0 / 1100.0)).abs() < 1e-9);
+        assert_eq!(estimate(&seqs[1..], rate).accuracy(0), 100.0);
+    }
+}
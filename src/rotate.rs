@@ -0,0 +1,184 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Reads can't align across the start/end junction of a circular sequence, so the bases near it
+// have low depth and often go unpolished. Rotating circular sequences (the rotate subcommand) and
+// aligning reads to the rotated assembly moves the junction away, and polish --rotate then applies
+// the same rotation to the original assembly and rotates the polished sequences back, so output
+// keeps the original starting positions.
+
+use clap::crate_version;
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::path::{Path, PathBuf};
+
+use crate::log;
+use crate::misc;
+use crate::misc::{quit_with_error, ErrorType};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Bases(usize),  // move this many bases from the start of each sequence to its end
+    Midpoint,      // move the junction to the middle of each sequence
+}
+
+impl Rotation {
+    /// Parses a rotation from the command line: a number of bases or "mid".
+    pub fn parse(text: &str) -> Rotation {
+        if text.eq_ignore_ascii_case("mid") {
+            return Rotation::Midpoint;
+        }
+        match text.parse::<usize>() {
+            Ok(bases) => Rotation::Bases(bases),
+            Err(_)    => quit_with_error(ErrorType::Args, &format!("invalid rotation {:?} (must \
+                                                                    be a number of bases or \
+                                                                    mid)", text)),
+        }
+    }
+
+    /// The position (in the original sequence) which becomes the start of the rotated sequence.
+    pub fn offset(&self, seq_len: usize) -> usize {
+        if seq_len == 0 {
+            return 0;
+        }
+        match self {
+            Rotation::Bases(bases) => bases % seq_len,
+            Rotation::Midpoint     => seq_len / 2,
+        }
+    }
+}
+
+
+/// Sequences are treated as circular if their header says so, e.g. circular=true from Unicycler
+/// or Trycycler.
+pub fn is_circular(description: &str) -> bool {
+    description.to_ascii_lowercase().split_whitespace().any(|w| w == "circular=true")
+}
+
+
+pub fn rotate_seq(seq: &str, offset: usize) -> String {
+    format!("{}{}", &seq[offset..], &seq[..offset])
+}
+
+
+/// Rotates the circular sequences in place and returns each rotated sequence's offset.
+pub fn rotate_circular_seqs(fasta: &mut [(String, String, String)],
+                            rotation: Rotation) -> misc::FastHashMap<String, usize> {
+    let mut offsets = misc::FastHashMap::default();
+    for (name, description, seq) in fasta.iter_mut() {
+        if !is_circular(description) {
+            continue;
+        }
+        let offset = rotation.offset(seq.len());
+        if offset > 0 {
+            *seq = rotate_seq(seq, offset);
+            offsets.insert(name.clone(), offset);
+        }
+    }
+    offsets
+}
+
+
+/// Logs which sequences were rotated, for both the rotate subcommand and polish --rotate.
+pub fn log_rotations(fasta: &[(String, String, String)],
+                     offsets: &misc::FastHashMap<String, usize>) {
+    log::section_header("Rotating circular sequences");
+    log::explanation("Sequences with circular=true in their header are rotated so their start/end \
+                      junction is away from the sequence ends.");
+    for (name, _, seq) in fasta {
+        if let Some(offset) = offsets.get(name) {
+            log::text!("{} ({} bp): starts at position {}", name,
+                       seq.len().to_formatted_string(&Locale::en),
+                       (offset + 1).to_formatted_string(&Locale::en));
+        }
+    }
+    if offsets.is_empty() {
+        log::text!("No circular sequences found");
+    }
+    log::text!();
+    log::event("sequences_rotated", json!({"offsets": offsets}));
+}
+
+
+pub fn rotate(by: String, assembly: PathBuf) {
+    let rotation = Rotation::parse(&by);
+    misc::check_if_file_exists(&assembly);
+    starting_message(&by, &assembly);
+    let mut fasta = misc::load_fasta(&assembly);
+    let offsets = rotate_circular_seqs(&mut fasta, rotation);
+    log_rotations(&fasta, &offsets);
+    for (name, description, seq) in &fasta {
+        if description.is_empty() {
+            println!(">{}", name);
+        } else {
+            println!(">{} {}", name, description);
+        }
+        println!("{}", seq);
+    }
+    log::section_header("Finished!");
+    log::text!("Align reads to the rotated assembly, then polish the original assembly with \
+                --rotate {}", by);
+    log::text!();
+    log::event("finished", json!({"rotated": offsets.len()}));
+}
+
+
+fn starting_message(by: &str, assembly: &Path) {
+    log::section_header("Starting Polypolish rotate");
+    log::explanation("This rotates the circular sequences in an assembly, so reads can be aligned \
+                      across their start/end junctions for polish --rotate.");
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input assembly:");
+    log::text!("  {}", assembly.display());
+    log::text!();
+    log::text!("Settings:");
+    log::text!("  --by {}", by);
+    log::text!();
+    log::event("run_started", json!({"command": "rotate", "version": crate_version!(),
+                                     "assembly": assembly, "by": by}));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        assert_eq!(Rotation::parse("mid"), Rotation::Midpoint);
+        assert_eq!(Rotation::parse("MID"), Rotation::Midpoint);
+        assert_eq!(Rotation::parse("100"), Rotation::Bases(100));
+        assert_eq!(Rotation::Midpoint.offset(11), 5);
+        assert_eq!(Rotation::Bases(3).offset(10), 3);
+        assert_eq!(Rotation::Bases(13).offset(10), 3);
+        assert_eq!(Rotation::Bases(3).offset(0), 0);
+    }
+
+    #[test]
+    fn test_rotate_seq() {
+        assert_eq!(rotate_seq("ACGTACC", 3), "TACCACG");
+        assert_eq!(rotate_seq("ACGTACC", 0), "ACGTACC");
+        assert!(is_circular("length=5386 depth=1.00x circular=true"));
+        assert!(is_circular("Circular=True"));
+        assert!(!is_circular("length=5386 circular=false"));
+        assert!(!is_circular(""));
+        let mut fasta = vec![("a".to_string(), "circular=true".to_string(), "ACGTAC".to_string()),
+                             ("b".to_string(), String::new(), "ACGTAC".to_string())];
+        let offsets = rotate_circular_seqs(&mut fasta, Rotation::Midpoint);
+        assert_eq!(fasta[0].2, "TACACG");
+        assert_eq!(fasta[1].2, "ACGTAC");
+        assert_eq!(offsets.get("a"), Some(&3));
+        assert_eq!(offsets.get("b"), None);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// A run's context is what everything that reads its alignment files shares: how the files are
+// read (--mmap), how bad records are handled (--skip-bad-records and --max-bad-records), whether
+// NM tags are checked (--validate-nm) and what is collected along the way to be reported at the
+// end (skipped records, NM checks and warnings). Each run makes its own, so nothing carries over
+// from one run to the next when the library polishes more than once in the same process.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::alignment::Alignment;
+use crate::bad_records::BadRecords;
+use crate::misc::{Error, FastHashMap};
+use crate::nm_check::NmChecks;
+use crate::pileup::Pileup;
+use crate::warnings::Warnings;
+
+
+/// Clones share the same records, so a context can be handed to each file reader of the run.
+#[derive(Clone, Default)]
+pub struct RunContext {
+    pub use_mmap: bool,
+    bad_records: Arc<Mutex<BadRecords>>,
+    nm_checks: Option<Arc<Mutex<NmChecks>>>,  // only with --validate-nm
+    warnings: Arc<Mutex<Warnings>>,
+}
+
+impl RunContext {
+    pub fn new(skip_bad_records: bool, max_bad_records: Option<usize>, validate_nm: bool,
+               use_mmap: bool) -> RunContext {
+        RunContext { use_mmap,
+                     bad_records: Arc::new(Mutex::new(BadRecords::new(skip_bad_records,
+                                                                      max_bad_records))),
+                     nm_checks: validate_nm.then(Arc::default),
+                     warnings: Arc::default() }
+    }
+
+    /// Returns a context with the same settings but nothing recorded, for a new run.
+    pub fn fresh(&self) -> RunContext {
+        RunContext { use_mmap: self.use_mmap,
+                     bad_records: Arc::new(Mutex::new(self.bad_records.lock().unwrap().fresh())),
+                     nm_checks: self.nm_checks.as_ref().map(|_| Arc::default()),
+                     warnings: Arc::default() }
+    }
+
+    pub fn skipping_bad_records(&self) -> bool {
+        self.bad_records.lock().unwrap().skipping()
+    }
+
+    /// Handles a record which couldn't be used (see BadRecords::found), quitting if it can't be
+    /// skipped.
+    pub fn bad_record(&self, error: &str, filename: &Path, line_number: usize, record: &str) {
+        if let Err(e) = self.skip_bad_record(error, filename, line_number, record) {
+            e.quit();
+        }
+    }
+
+    /// Handles a record which couldn't be used, returning the error if it can't be skipped.
+    pub fn skip_bad_record(&self, error: &str, filename: &Path, line_number: usize,
+                           record: &str) -> Result<(), Error> {
+        self.bad_records.lock().unwrap().found(error, filename, line_number, record)
+    }
+
+    pub fn read_has_bad_record(&self, read_name: &str) -> bool {
+        self.bad_records.lock().unwrap().read_has_bad_record(read_name)
+    }
+
+    pub fn bad_record_count(&self) -> usize {
+        self.bad_records.lock().unwrap().count()
+    }
+
+    pub fn nm_header_line(&self, filename: &Path, line: &str) {
+        if let Some(checks) = &self.nm_checks {
+            checks.lock().unwrap().header_line(filename, line);
+        }
+    }
+
+    pub fn check_nm(&self, filename: &Path, alignment: &Alignment,
+                    pileups: &FastHashMap<String, Pileup>) {
+        if let Some(checks) = &self.nm_checks {
+            checks.lock().unwrap().check(filename, alignment, pileups);
+        }
+    }
+
+    pub fn warn(&self, message: String) {
+        self.warnings.lock().unwrap().add(message);
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().all().to_vec()
+    }
+
+    /// Logs the skipped records, the NM checks and then all of the warnings (including any from
+    /// the NM checks).
+    pub fn report(&self) {
+        self.bad_records.lock().unwrap().report();
+        let mut warnings = self.warnings.lock().unwrap();
+        if let Some(checks) = &self.nm_checks {
+            checks.lock().unwrap().report(&mut warnings);
+        }
+        warnings.report();
+    }
+}
+
+/// Nothing is shown, as the records change during the run and a filter's Debug output is part of
+/// the checkpoint fingerprint (the settings are in the fingerprint separately).
+impl fmt::Debug for RunContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunContext").finish_non_exhaustive()
+    }
+}
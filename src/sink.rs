@@ -0,0 +1,172 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Polished sequences and changes are given to sinks as they're made. The command-line tool's
+// sinks write files (the polished assembly, --split-output, --changes, etc.), and library users
+// can capture them in memory with SeqCollector and ChangeCollector, or implement the traits for
+// their own formats. A collector is a handle to shared storage, so a clone of it can be kept to
+// get the results after the sink has been given to the polishing.
+
+use serde::{Deserialize, Serialize};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+
+/// Receives the polished sequences, one at a time.
+pub trait SeqSink {
+    /// Starts a sequence. The header is its whole FASTA or FASTQ header line (with > or @).
+    fn start_seq(&mut self, name: &str, header: &str);
+
+    /// Adds polished sequence, which may be all of the sequence or just the next part of it.
+    fn write_seq(&mut self, seq: &str);
+
+    /// Ends the sequence, with its qualities for FASTQ output.
+    fn finish_seq(&mut self, quals: Option<&str>);
+
+    /// Called once after the last sequence, e.g. to flush a file.
+    fn finish(self: Box<Self>) {}
+}
+
+
+/// Receives every polished base, so changes can be reported with their surrounding sequence.
+pub trait ChangeSink {
+    /// Adds one base: its original base, its polished sequence (empty for a deletion) and, if it
+    /// was changed, the new base (- for a deletion) and the change's confidence.
+    fn add_base(&mut self, name: &str, pos: usize, base: char, polished: &str,
+                change: Option<(&str, f64)>);
+
+    /// Called once after the last base, e.g. to flush a file.
+    fn finish(self: Box<Self>) {}
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PolishedContig {
+    pub name: String,
+    pub header: String,
+    pub seq: String,
+    pub quals: Option<String>,  // only for FASTQ output
+}
+
+
+/// Keeps the polished sequences in memory.
+#[derive(Clone, Default)]
+pub struct SeqCollector(Rc<RefCell<Vec<PolishedContig>>>);
+
+impl SeqCollector {
+    pub fn new() -> SeqCollector {
+        SeqCollector::default()
+    }
+
+    /// Removes and returns the sequences collected so far.
+    pub fn take(&self) -> Vec<PolishedContig> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl SeqSink for SeqCollector {
+    fn start_seq(&mut self, name: &str, header: &str) {
+        self.0.borrow_mut().push(PolishedContig { name: name.to_string(),
+                                                  header: header.to_string(), seq: String::new(),
+                                                  quals: None });
+    }
+
+    fn write_seq(&mut self, seq: &str) {
+        if let Some(contig) = self.0.borrow_mut().last_mut() {
+            contig.seq.push_str(seq);
+        }
+    }
+
+    fn finish_seq(&mut self, quals: Option<&str>) {
+        if let Some(contig) = self.0.borrow_mut().last_mut() {
+            contig.quals = quals.map(|q| q.to_string());
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub name: String,
+    pub pos: usize,
+    pub base: char,
+    pub new_seq: String,  // - for a deletion
+    pub confidence: f64,
+}
+
+
+/// Keeps the changes in memory (without their surrounding sequence).
+#[derive(Clone, Default)]
+pub struct ChangeCollector(Rc<RefCell<Vec<Change>>>);
+
+impl ChangeCollector {
+    pub fn new() -> ChangeCollector {
+        ChangeCollector::default()
+    }
+
+    /// Removes and returns the changes collected so far.
+    pub fn take(&self) -> Vec<Change> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl ChangeSink for ChangeCollector {
+    fn add_base(&mut self, name: &str, pos: usize, base: char, _polished: &str,
+                change: Option<(&str, f64)>) {
+        if let Some((new_seq, confidence)) = change {
+            self.0.borrow_mut().push(Change { name: name.to_string(), pos, base,
+                                              new_seq: new_seq.to_string(), confidence });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seq_collector() {
+        let collector = SeqCollector::new();
+        let mut sink: Box<dyn SeqSink> = Box::new(collector.clone());
+        sink.start_seq("a", ">a polypolish");
+        sink.write_seq("ACGT");
+        sink.write_seq("AC");
+        sink.finish_seq(None);
+        sink.start_seq("b", "@b polypolish");
+        sink.write_seq("GG");
+        sink.finish_seq(Some("II"));
+        sink.finish();
+        let contigs = collector.take();
+        assert_eq!(contigs.len(), 2);
+        assert_eq!(contigs[0].seq, "ACGTAC");
+        assert_eq!(contigs[0].quals, None);
+        assert_eq!(contigs[1].header, "@b polypolish");
+        assert_eq!(contigs[1].quals.as_deref(), Some("II"));
+        assert!(collector.take().is_empty());
+    }
+
+    #[test]
+    fn test_change_collector() {
+        let collector = ChangeCollector::new();
+        let mut sink: Box<dyn ChangeSink> = Box::new(collector.clone());
+        sink.add_base("a", 0, 'A', "A", None);
+        sink.add_base("a", 1, 'C', "", Some(("-", 20.0)));
+        sink.add_base("a", 2, 'G', "GT", Some(("GT", 30.5)));
+        sink.finish();
+        let changes = collector.take();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], Change { name: "a".to_string(), pos: 1, base: 'C',
+                                        new_seq: "-".to_string(), confidence: 20.0 });
+        assert_eq!(changes[1].new_seq, "GT");
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The pileups are built from an AlignmentSource: an iterator of alignments which also has a name
+// (for log and error messages) and can report how far through it is. Alignment files are read
+// with SamSource, which can also read SAM, BAM or PAF from any stream, and library users can
+// implement AlignmentSource for their own readers (e.g. htslib or a network stream), or wrap an
+// iterator with IterSource, instead of writing a temporary file. Like an alignment file, a source
+// must give each read's alignments together (as bwa mem outputs them).
+
+use std::io;
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
+
+use crate::alignment::{read_name_key, Alignment, SamLines};
+use crate::bam::BamLines;
+use crate::decompress::ThreadedGzReader;
+use crate::downsample::Downsampler;
+use crate::paf::PafLines;
+use crate::run::RunContext;
+
+
+pub trait AlignmentSource: Iterator<Item = io::Result<Alignment>> {
+    /// A name for the source (e.g. its filename), used in log and error messages.
+    fn name(&self) -> &Path;
+
+    /// The source's total size and how much of it has been read, in bytes, for progress
+    /// reporting. Sources which don't know their size can leave these as zero.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn position(&self) -> u64 {
+        0
+    }
+}
+
+
+/// Alignments from SAM lines, which can come from an alignment file (SAM, BAM or PAF, possibly
+/// gzipped) or a stream. Lines which can't be parsed are bad records (see bad_records.rs), and one
+/// which can't be skipped is given as an io::Error holding its misc::Error.
+pub struct SamSource {
+    lines: SamLines,
+    name: PathBuf,
+    line_count: usize,
+    downsampler: Downsampler,
+    fail_tags: Vec<String>,
+}
+
+impl SamSource {
+    pub fn new(name: &Path, lines: SamLines) -> SamSource {
+        SamSource { lines, name: name.to_path_buf(), line_count: 0,
+                    downsampler: Downsampler::keep_all(), fail_tags: Vec::new() }
+    }
+
+    pub fn open(filename: &Path, run: &RunContext) -> io::Result<SamSource> {
+        Ok(SamSource::new(filename, SamLines::open(filename, run)?))
+    }
+
+    /// Reads uncompressed SAM from a stream.
+    pub fn from_sam(name: &str, reader: impl BufRead + 'static) -> SamSource {
+        SamSource::from_lines(name, Box::new(reader.lines()))
+    }
+
+    /// Reads BAM from a stream.
+    pub fn from_bam(name: &str, reader: impl Read + Send + 'static) -> io::Result<SamSource> {
+        let lines = BamLines::new(ThreadedGzReader::new(reader))?;
+        Ok(SamSource::from_lines(name, Box::new(lines)))
+    }
+
+    /// Reads uncompressed PAF (with cs tags) from a stream.
+    pub fn from_paf(name: &str, reader: impl BufRead + 'static) -> SamSource {
+        SamSource::from_lines(name, Box::new(PafLines::new(reader.lines())))
+    }
+
+    fn from_lines(name: &str, lines: Box<dyn Iterator<Item = io::Result<String>>>) -> SamSource {
+        let name = Path::new(name);
+        SamSource::new(name, SamLines::from_lines(name, lines, &RunContext::default()))
+    }
+
+    /// Reports bad records (and checks NM tags) in the given run, instead of a run of its own.
+    /// Sources made from streams need this to be part of a polishing run.
+    pub fn in_run(mut self, run: &RunContext) -> SamSource {
+        self.lines.run = run.clone();
+        self
+    }
+
+    /// Marks the alignments with any of these fail tags as failed (see AlignmentFilter), instead
+    /// of those with DEFAULT_FAIL_TAG. They are matched as each line is parsed, so a source must
+    /// be given its filter's tags.
+    pub fn with_fail_tags(mut self, fail_tags: &[String]) -> SamSource {
+        self.fail_tags = fail_tags.to_vec();
+        self
+    }
+
+    /// Only gives the alignments of reads the downsampler keeps. Other reads' lines are skipped
+    /// without being parsed.
+    pub fn downsampled(mut self, downsampler: Downsampler) -> SamSource {
+        self.downsampler = downsampler;
+        self
+    }
+}
+
+impl Iterator for SamSource {
+    type Item = io::Result<Alignment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = self.lines.next_line()?;
+            self.line_count += 1;
+            let sam_line = match line {
+                Ok(sam_line) => sam_line,
+                Err(e)       => return Some(Err(e)),
+            };
+            if sam_line.starts_with('@') {
+                let header_line = sam_line.to_string();
+                self.lines.run.nm_header_line(&self.name, &header_line);
+                continue;
+            }
+            if sam_line.is_empty() || !self.downsampler.keeps(read_name_key(sam_line)) {
+                continue;
+            }
+            match Alignment::new(sam_line, &self.fail_tags) {
+                Ok(alignment) => return Some(Ok(alignment)),
+                Err(e)        => {
+                    let record = sam_line.to_string();
+                    let run = &self.lines.run;
+                    if let Err(e) = run.skip_bad_record(e, &self.name, self.line_count, &record) {
+                        return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl AlignmentSource for SamSource {
+    fn name(&self) -> &Path {
+        &self.name
+    }
+
+    fn size(&self) -> u64 {
+        self.lines.file_size
+    }
+
+    fn position(&self) -> u64 {
+        self.lines.bytes_read()
+    }
+}
+
+
+/// Makes an AlignmentSource from any iterator of alignments.
+pub struct IterSource<I: Iterator<Item = io::Result<Alignment>>> {
+    alignments: I,
+    name: PathBuf,
+}
+
+impl<I: Iterator<Item = io::Result<Alignment>>> IterSource<I> {
+    pub fn new(name: &str, alignments: I) -> IterSource<I> {
+        IterSource { alignments, name: PathBuf::from(name) }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Alignment>>> Iterator for IterSource<I> {
+    type Item = io::Result<Alignment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.alignments.next()
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Alignment>>> AlignmentSource for IterSource<I> {
+    fn name(&self) -> &Path {
+        &self.name
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAM: &str = "@SQ\tSN:chrom\tLN:100\n\
+                       r1\t0\tchrom\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tNM:i:0\n\
+                       \n\
+                       r2\t16\tchrom\t11\t60\t4M\t*\t0\t0\tTTTT\tFFFF\tNM:i:1\n";
+
+    #[test]
+    fn test_sam_source_from_stream() {
+        let source = SamSource::from_sam("stream", io::Cursor::new(SAM));
+        assert_eq!(source.name(), Path::new("stream"));
+        let alignments: Vec<Alignment> = source.map(|a| a.unwrap()).collect();
+        assert_eq!(alignments.len(), 2);
+        assert_eq!(&*alignments[0].read_name, "r1");
+        assert_eq!(alignments[1].ref_start, 10);
+    }
+
+    #[test]
+    fn test_sam_source_downsampled() {
+        let source = SamSource::from_sam("stream", io::Cursor::new(SAM))
+            .downsampled(Downsampler::new(0.0, 0));
+        assert_eq!(source.count(), 0);
+    }
+
+    #[test]
+    fn test_iter_source() {
+        let lines = SAM.lines().skip(1).filter(|l| !l.is_empty());
+        let source = IterSource::new("iter", lines.map(|l| Ok(Alignment::new(l, &[]).unwrap())));
+        assert_eq!(source.name(), Path::new("iter"));
+        assert_eq!(source.size(), 0);
+        assert_eq!(source.count(), 2);
+    }
+}
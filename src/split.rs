@@ -0,0 +1,266 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The split subcommand shards an alignment file by reference sequence, so each sequence can be
+// polished by a separate job. A read's depth contribution depends on how many places it aligns, so
+// each shard keeps all the alignments of every read which touches its sequence, including those to
+// other sequences. Each shard's header marks it as a shard, and when polish is given a shard, it
+// skips the alignments to the other sequences in the shard's @SQ header lines instead of stopping
+// with an error. The input must be grouped by read (as bwa mem outputs it).
+
+use clap::crate_version;
+use indicatif::HumanBytes;
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::alignment::{read_name_key, Alignment, SamLines};
+use crate::log;
+use crate::memory;
+use crate::misc;
+use crate::misc::{quit_with_error, ErrorType, FastHashMap, FastHashSet, Instant};
+use crate::progress::{Progress, Unit};
+use crate::run::RunContext;
+
+
+/// The start of the header line which marks a shard, followed by the shard's sequence name.
+const SHARD_COMMENT: &str = "@CO\tpolypolish split shard for ";
+
+
+struct Shard {
+    writer: BufWriter<File>,
+    read_count: usize,
+    alignment_count: usize,
+}
+
+
+pub fn split(input: PathBuf, outdir: PathBuf) {
+    let start_time = Instant::now();
+    misc::check_if_file_exists(&input);
+    starting_message(&input, &outdir);
+    if fs::create_dir_all(&outdir).is_err() {
+        quit_with_error(ErrorType::Io, &format!("unable to create directory {:?}", outdir))
+    }
+    let (shard_count, alignment_count) = match split_alignments(&input, &outdir) {
+        Ok(counts) => counts,
+        Err(_) => quit_with_error(ErrorType::Io, &format!("unable to split {:?} into {:?}",
+                                                          input, outdir)),
+    };
+    finished_message(start_time, &outdir, shard_count, alignment_count);
+}
+
+
+fn starting_message(input: &Path, outdir: &Path) {
+    log::section_header("Starting Polypolish split");
+    log::explanation("This splits an alignment file into one file per reference sequence, keeping \
+                      all alignments of each read which aligns to that sequence, so the sequences \
+                      can be polished in separate jobs.");
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input alignments:");
+    log::text!("  {}", input.display());
+    log::text!();
+    log::text!("Output directory:");
+    log::text!("  {}", outdir.display());
+    log::text!();
+    log::event("run_started", json!({"command": "split", "version": crate_version!(),
+                                     "input": input, "outdir": outdir}));
+}
+
+
+/// Writes each read's alignments to the shard of every sequence they touch. Sequences in the
+/// header without any alignments get a shard with only the header. Returns the number of shards
+/// and the number of alignments in the input.
+fn split_alignments(input: &Path, outdir: &Path) -> io::Result<(usize, usize)> {
+    log::section_header("Splitting alignments");
+    let run = RunContext::default();
+    let mut lines = SamLines::open(input, &run)?;
+    let mut progress = Progress::new(&format!("Splitting {}", input.display()), Unit::Bytes,
+                                     lines.file_size, "alignments");
+    let mut header = Vec::new();
+    let mut shards: FastHashMap<String, Shard> = FastHashMap::default();
+    let mut shard_order = Vec::new();
+    let mut filenames = FastHashSet::default();
+    let mut read_lines: Vec<(String, Alignment)> = Vec::new();
+    let mut line_count = 0;
+    let mut alignment_count = 0;
+    while let Some(line) = lines.next() {
+        line_count += 1;
+        let sam_line = line?;
+        if sam_line.is_empty() {
+            continue;
+        }
+        if sam_line.starts_with('@') {
+            header.push(sam_line);
+            continue;
+        }
+        let alignment = match Alignment::new(&sam_line, &[]) {
+            Ok(alignment) => alignment,
+            Err(e)        => { run.bad_record(e, input, line_count, &sam_line); continue; },
+        };
+        if !alignment.is_aligned() {
+            continue;
+        }
+        alignment_count += 1;
+        progress.update(lines.bytes_read(), alignment_count as u64);
+        if read_lines.last().is_some_and(|(l, _)| read_name_key(l) != read_name_key(&sam_line)) {
+            write_read(&std::mem::take(&mut read_lines), input, outdir, &header, &mut shards,
+                       &mut shard_order, &mut filenames)?;
+        }
+        read_lines.push((sam_line, alignment));
+    }
+    write_read(&read_lines, input, outdir, &header, &mut shards, &mut shard_order,
+               &mut filenames)?;
+    progress.finish();
+
+    for name in header.iter().filter_map(|l| header_seq_name(l)) {
+        if !shards.contains_key(name) {
+            let shard = create_shard(name, outdir, &header, &mut filenames)?;
+            shards.insert(name.to_string(), shard);
+            shard_order.push(name.to_string());
+        }
+    }
+    for name in &shard_order {
+        let mut shard = shards.remove(name).unwrap();
+        shard.writer.flush()?;
+        log::text!("{}: {} alignments from {} reads", name,
+                   shard.alignment_count.to_formatted_string(&Locale::en),
+                   shard.read_count.to_formatted_string(&Locale::en));
+        log::event("shard_written", json!({"name": name, "reads": shard.read_count,
+                                           "alignments": shard.alignment_count}));
+    }
+    log::text!();
+    Ok((shard_order.len(), alignment_count))
+}
+
+
+/// Writes one read's alignments to the shards of the sequences they touch. Every read has exactly
+/// one primary alignment, so a group without one means the file isn't grouped by read.
+fn write_read(read_lines: &[(String, Alignment)], input: &Path, outdir: &Path, header: &[String],
+              shards: &mut FastHashMap<String, Shard>, shard_order: &mut Vec<String>,
+              filenames: &mut FastHashSet<String>) -> io::Result<()> {
+    if read_lines.is_empty() {
+        return Ok(());
+    }
+    if !read_lines.iter().any(|(_, a)| a.is_primary()) {
+        quit_with_error(ErrorType::Input,
+                        &format!("the alignments for read {} are not together in {:?} - please \
+                                  sort the file by read name (e.g. samtools sort -n)",
+                                 read_lines[0].1.read_name, input))
+    }
+    let mut seq_names: Vec<&str> = read_lines.iter().map(|(_, a)| a.ref_name.as_str()).collect();
+    seq_names.sort_unstable();
+    seq_names.dedup();
+    for name in seq_names {
+        if !shards.contains_key(name) {
+            let shard = create_shard(name, outdir, header, filenames)?;
+            shards.insert(name.to_string(), shard);
+            shard_order.push(name.to_string());
+        }
+        let shard = shards.get_mut(name).unwrap();
+        for (line, _) in read_lines {
+            writeln!(shard.writer, "{}", line)?;
+        }
+        shard.read_count += 1;
+        shard.alignment_count += read_lines.iter().filter(|(_, a)| a.ref_name == name).count();
+    }
+    Ok(())
+}
+
+
+/// Creates a shard's file (named after its sequence) and writes the input's header to it, followed
+/// by the line which marks it as a shard.
+fn create_shard(name: &str, outdir: &Path, header: &[String],
+                filenames: &mut FastHashSet<String>) -> io::Result<Shard> {
+    let filename = format!("{}.sam", misc::safe_filename(name));
+    if !filenames.insert(filename.clone()) {
+        quit_with_error(ErrorType::Input,
+                        &format!("more than one sequence would be written to {}", filename))
+    }
+    let path = outdir.join(&filename);
+    let file = File::create(&path).unwrap_or_else(|_| {
+        quit_with_error(ErrorType::Io,
+                        &format!("unable to create {:?} (with many sequences, the limit on open \
+                                  files may need raising, e.g. with ulimit -n)", path))
+    });
+    let mut writer = BufWriter::new(file);
+    for line in header {
+        writeln!(writer, "{}", line)?;
+    }
+    writeln!(writer, "{}{}", SHARD_COMMENT, name)?;
+    Ok(Shard { writer, read_count: 0, alignment_count: 0 })
+}
+
+
+/// Returns the SN value of an @SQ header line.
+fn header_seq_name(header_line: &str) -> Option<&str> {
+    let mut parts = header_line.split('\t');
+    if parts.next()? != "@SQ" {
+        return None;
+    }
+    parts.find_map(|p| p.strip_prefix("SN:"))
+}
+
+
+/// Returns the sequences in the @SQ header lines of any shards (from split) among the alignment
+/// files. Alignments to these sequences can be skipped when they aren't in the assembly.
+pub fn shard_header_seqs(sam: &[PathBuf], run: &RunContext) -> FastHashSet<String> {
+    let mut seq_names = FastHashSet::default();
+    for filename in sam {
+        let Ok(lines) = SamLines::open(filename, run) else { continue; };
+        let header: Vec<String> = lines.map_while(Result::ok)
+            .take_while(|line| line.starts_with('@')).collect();
+        if header.iter().any(|line| line.starts_with(SHARD_COMMENT)) {
+            seq_names.extend(header.iter().filter_map(|l| header_seq_name(l))
+                             .map(|name| name.to_string()));
+        }
+    }
+    seq_names
+}
+
+
+fn finished_message(start_time: Instant, outdir: &Path, shard_count: usize,
+                    alignment_count: usize) {
+    log::section_header("Finished!");
+    log::text!("Alignments: {}", alignment_count.to_formatted_string(&Locale::en));
+    log::text!("Shards written to {}: {}", outdir.display(),
+               shard_count.to_formatted_string(&Locale::en));
+    log::text!();
+    log::text!("Time to run: {}", misc::format_duration(start_time.elapsed()));
+    let peak_memory = memory::peak_memory();
+    if let Some(peak) = peak_memory {
+        log::text!("Peak memory usage: {}", HumanBytes(peak));
+    }
+    log::text!();
+    log::event("finished", json!({"alignments": alignment_count, "shards": shard_count,
+                                  "seconds": start_time.elapsed().as_secs_f64(),
+                                  "peak_memory_bytes": peak_memory}));
+    log::end_stage();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_seq_name() {
+        assert_eq!(header_seq_name("@SQ\tSN:chrom\tLN:45011"), Some("chrom"));
+        assert_eq!(header_seq_name("@SQ\tLN:4000\tSN:plasmid"), Some("plasmid"));
+        assert_eq!(header_seq_name("@SQ\tLN:4000"), None);
+        assert_eq!(header_seq_name("@PG\tID:bwa\tSN:x"), None);
+    }
+}
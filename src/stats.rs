@@ -0,0 +1,82 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+
+
+/// Basic statistics for an assembly, made from each sequence's length and GC count.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AssemblyStats {
+    #[serde(rename = "sequences")]
+    pub count: usize,
+    pub total_length: usize,
+    pub largest: usize,
+    pub n50: usize,
+    pub gc_percent: f64,
+}
+
+impl AssemblyStats {
+    pub fn new(seqs: &[(usize, usize)]) -> AssemblyStats {
+        let mut lengths: Vec<usize> = seqs.iter().map(|(length, _)| *length).collect();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        let total_length: usize = lengths.iter().sum();
+        let gc_total: usize = seqs.iter().map(|(_, gc)| *gc).sum();
+        let mut n50 = 0;
+        let mut running_total = 0;
+        for length in &lengths {
+            running_total += length;
+            if running_total * 2 >= total_length {
+                n50 = *length;
+                break;
+            }
+        }
+        let largest = lengths.first().copied().unwrap_or(0);
+        let gc_percent = if total_length == 0 { 0.0 }
+                         else { 100.0 * gc_total as f64 / total_length as f64 };
+        AssemblyStats { count: lengths.len(), total_length, largest, n50, gc_percent }
+    }
+}
+
+
+/// Returns the number of G and C bases in a sequence.
+pub fn gc_count(seq: &str) -> usize {
+    seq.bytes().filter(|b| matches!(b, b'G' | b'C' | b'g' | b'c')).count()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gc_count() {
+        assert_eq!(gc_count(""), 0);
+        assert_eq!(gc_count("ACGT"), 2);
+        assert_eq!(gc_count("ggccNNat"), 4);
+    }
+
+    #[test]
+    fn test_assembly_stats() {
+        let stats = AssemblyStats::new(&[(100, 40), (500, 250), (300, 150), (100, 60)]);
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.total_length, 1000);
+        assert_eq!(stats.largest, 500);
+        assert_eq!(stats.n50, 500);
+        assert_eq!(stats.gc_percent, 50.0);
+
+        let stats = AssemblyStats::new(&[(100, 0), (200, 0), (300, 0), (400, 0)]);
+        assert_eq!(stats.n50, 300);
+
+        let stats = AssemblyStats::new(&[]);
+        assert_eq!(stats, AssemblyStats { count: 0, total_length: 0, largest: 0, n50: 0,
+                                          gc_percent: 0.0 });
+    }
+}
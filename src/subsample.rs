@@ -0,0 +1,189 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// The subsample subcommand writes a smaller alignment file, using the same read choice as polish
+// --target-depth. General-purpose tools downsample alignments one at a time, which breaks up the
+// all-alignments-per-read structure Polypolish needs (e.g. from bwa mem -a), but here a read's
+// alignments are always kept or discarded together. Mates share a read name, so subsampling both
+// files of a pair with the same seed keeps the same pairs.
+
+use clap::crate_version;
+use indicatif::HumanBytes;
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::alignment::SamLines;
+use crate::downsample;
+use crate::downsample::Downsampler;
+use crate::log;
+use crate::memory;
+use crate::misc;
+use crate::misc::{quit_with_error, ErrorType, Instant};
+use crate::progress::{Progress, Unit};
+use crate::run::RunContext;
+
+
+pub fn subsample(depth: f64, seed: u64, assembly: Option<PathBuf>, input: PathBuf,
+                 output: PathBuf) {
+    let start_time = Instant::now();
+    if depth <= 0.0 {
+        quit_with_error(ErrorType::Args, "--depth must be greater than 0")
+    }
+    misc::check_if_file_exists(&input);
+    if let Some(filename) = &assembly {
+        misc::check_if_file_exists(filename);
+    }
+    if input == output {
+        quit_with_error(ErrorType::Args, "the input and output files must be different")
+    }
+    starting_message(depth, seed, &assembly, &input, &output);
+    let assembly_length = match &assembly {
+        Some(filename) => misc::load_fasta(filename).iter().map(|(_, _, seq)| seq.len()).sum(),
+        None           => header_length(&input),
+    };
+    let downsampler = downsample::make_downsampler(Some(depth), seed,
+                                                   std::slice::from_ref(&input), assembly_length,
+                                                   &RunContext::default());
+    let (before_count, after_count) = match write_subsample(&input, &output, &downsampler) {
+        Ok(counts) => counts,
+        Err(_) => quit_with_error(ErrorType::Io, &format!("unable to subsample {:?} to {:?}",
+                                                          input, output)),
+    };
+    finished_message(start_time, before_count, after_count);
+}
+
+
+fn starting_message(depth: f64, seed: u64, assembly: &Option<PathBuf>, input: &Path,
+                    output: &Path) {
+    log::section_header("Starting Polypolish subsample");
+    log::explanation("This randomly discards reads from an alignment file to bring its depth down \
+                      to a target, keeping all of each read's alignments together.");
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input alignments:");
+    log::text!("  {}", input.display());
+    log::text!();
+    log::text!("Output alignments:");
+    log::text!("  {}", output.display());
+    log::text!();
+    log::text!("Settings:");
+    log::text!("  --depth {}", depth);
+    log::text!("  --seed {}", seed);
+    if let Some(filename) = assembly {
+        log::text!("  --assembly {}", filename.display());
+    }
+    log::text!();
+    log::event("run_started", json!({"command": "subsample", "version": crate_version!(),
+                                     "input": input, "output": output, "depth": depth,
+                                     "seed": seed, "assembly": assembly}));
+}
+
+
+/// Without --assembly, the assembly length is the total of the reference sequence lengths in the
+/// file's header (@SQ lines).
+fn header_length(filename: &Path) -> usize {
+    let error = || quit_with_error(ErrorType::Input,
+                                   &format!("{:?} has no @SQ header lines, so the assembly must be \
+                                             given with --assembly", filename));
+    let Ok(lines) = SamLines::open(filename, &RunContext::default()) else { error() };
+    let mut length = 0;
+    for line in lines.map_while(Result::ok).take_while(|line| line.starts_with('@')) {
+        length += sequence_length(&line).unwrap_or(0);
+    }
+    if length == 0 {
+        error();
+    }
+    length
+}
+
+
+/// Returns the LN value of an @SQ header line.
+fn sequence_length(header_line: &str) -> Option<usize> {
+    let mut parts = header_line.split('\t');
+    if parts.next()? != "@SQ" {
+        return None;
+    }
+    parts.find_map(|p| p.strip_prefix("LN:")).and_then(|ln| ln.parse().ok())
+}
+
+
+/// Writes the header and the kept reads' alignments, returning the number of alignments before
+/// and after subsampling.
+fn write_subsample(input: &Path, output: &Path,
+                   downsampler: &Downsampler) -> io::Result<(usize, usize)> {
+    log::section_header("Subsampling alignments");
+    let mut lines = SamLines::open(input, &RunContext::default())?;
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut progress = Progress::new(&format!("Subsampling {}", input.display()), Unit::Bytes,
+                                     lines.file_size, "alignments");
+    let (mut before_count, mut after_count) = (0, 0);
+    while let Some(line) = lines.next() {
+        let sam_line = line?;
+        if sam_line.is_empty() {
+            continue;
+        }
+        if !sam_line.starts_with('@') {
+            before_count += 1;
+            progress.update(lines.bytes_read(), before_count as u64);
+            if !downsampler.keeps(sam_line.split('\t').next().unwrap_or_default()) {
+                continue;
+            }
+            after_count += 1;
+        }
+        writeln!(writer, "{}", sam_line)?;
+    }
+    writer.flush()?;
+    progress.finish();
+    log::text!("{}: {} of {} alignments kept", output.display(),
+               after_count.to_formatted_string(&Locale::en),
+               before_count.to_formatted_string(&Locale::en));
+    log::text!();
+    Ok((before_count, after_count))
+}
+
+
+fn finished_message(start_time: Instant, before_count: usize, after_count: usize) {
+    log::section_header("Finished!");
+    log::text!("Alignments before subsampling: {}",
+               before_count.to_formatted_string(&Locale::en));
+    log::text!("Alignments after subsampling:  {}", after_count.to_formatted_string(&Locale::en));
+    log::text!();
+    log::text!("Time to run: {}", misc::format_duration(start_time.elapsed()));
+    let peak_memory = memory::peak_memory();
+    if let Some(peak) = peak_memory {
+        log::text!("Peak memory usage: {}", HumanBytes(peak));
+    }
+    log::text!();
+    log::event("finished", json!({"alignments_before": before_count,
+                                  "alignments_after": after_count,
+                                  "seconds": start_time.elapsed().as_secs_f64(),
+                                  "peak_memory_bytes": peak_memory}));
+    log::end_stage();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_length() {
+        assert_eq!(sequence_length("@SQ\tSN:chrom\tLN:45011"), Some(45011));
+        assert_eq!(sequence_length("@SQ\tLN:4000\tSN:plasmid"), Some(4000));
+        assert_eq!(sequence_length("@SQ\tSN:chrom"), None);
+        assert_eq!(sequence_length("@PG\tID:bwa\tLN:5"), None);
+    }
+}
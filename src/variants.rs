@@ -0,0 +1,266 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Variant reporting builds the same pileup as polishing (with the same alignment filtering), but
+// instead of choosing a sequence for each base, it reports every non-reference sequence above a
+// frequency cutoff. This shows heterogeneity in the reads (e.g. a mixed colony) which polishing
+// would leave alone.
+
+use clap::crate_version;
+use indicatif::HumanBytes;
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::alignment::AlignmentFilter;
+use crate::downsample::Downsampler;
+use crate::gfa;
+use crate::log;
+use crate::memory;
+use crate::misc;
+use crate::misc::{ErrorType, Instant};
+use crate::normalise::{left_shift_deletion, left_shift_insertion};
+use crate::options::VariantsOptions;
+use crate::pileup::{format_count, Pileup, PileupSettings};
+use crate::polish;
+use crate::polish::AlignmentInputs;
+use crate::run::RunContext;
+
+
+pub fn variants(options: &VariantsOptions) {
+    let start_time = Instant::now();
+    let replaces_max_errors = options.max_error_rate.is_some() || options.min_identity.is_some();
+    let filter = AlignmentFilter {
+        max_errors: (!replaces_max_errors).then_some(options.max_errors),
+        max_error_rate: options.max_error_rate, min_identity: options.min_identity,
+        min_alignment_score: options.min_alignment_score, max_score_diff: options.max_score_diff,
+        careful: options.careful,
+        run: RunContext::new(options.skip_bad_records, options.max_bad_records, false, false),
+        ..Default::default()
+    };
+    let homopolymer_trim = (!options.no_homopolymer_trim).then_some(options.homopolymer_trim);
+    let &VariantsOptions { min_frequency, min_count, .. } = options;
+    polish::check_filter_values(&filter);
+    if min_frequency <= 0.0 || min_frequency > 1.0 {
+        misc::quit_with_error(ErrorType::Args, "--min-frequency must be greater than 0 and at \
+                                                most 1")
+    }
+    let (assemblies, sam) = polish::split_inputs(options.assembly.clone(), options.sam.clone());
+    if sam.is_empty() {
+        misc::quit_with_error(ErrorType::Args, "no alignment files were given")
+    }
+    polish::check_inputs_exist(&assemblies, &sam);
+    let assembly_is_gfa = assemblies.iter().any(|a| gfa::is_gfa(a));
+    if assembly_is_gfa && assemblies.len() > 1 {
+        misc::quit_with_error(ErrorType::Args,
+                              "a GFA assembly can't be combined with other assembly files")
+    }
+    starting_message(&filter, min_frequency, min_count, &assemblies, &sam);
+
+    let (fasta, _) = polish::load_assembly(&assemblies, assembly_is_gfa);
+    let settings = PileupSettings { trim_ends: options.trim_ends, homopolymer_trim,
+                                    ..Default::default() };
+    let (seq_names, mut pileups) = polish::make_pileups(fasta, settings);
+    let inputs = AlignmentInputs { sam: &sam, weights: &vec![1.0; sam.len()], filter: &filter,
+                                   downsampler: &Downsampler::keep_all(),
+                                   auto_sort: options.auto_sort, merge_overlaps: false,
+                                   strict: false };
+    polish::load_alignments(&inputs, None, &mut pileups);
+
+    log::section_header("Finding variants");
+    log::explanation("Each non-reference sequence in the pileup is reported if its fraction of \
+                      the position's sequences is at least --min-frequency, whether or not \
+                      polishing would change the base.");
+    println!("##fileformat=VCFv4.2");
+    println!("##source=Polypolish v{}", crate_version!());
+    for (name, _) in &seq_names {
+        println!("##contig=<ID={},length={}>", name, pileups[name].bases.len());
+    }
+    println!("##INFO=<ID=DP,Number=1,Type=Float,Description=\"Read depth (reads with multiple \
+              alignments contribute a fraction to each)\">");
+    println!("##INFO=<ID=AC,Number=A,Type=Float,Description=\"Read count for the allele\">");
+    println!("##INFO=<ID=AF,Number=A,Type=Float,Description=\"Fraction of the position's read \
+              sequences with the allele\">");
+    println!("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO");
+    let mut variant_count = 0;
+    for (name, _) in &seq_names {
+        let lines = variant_lines(name, &pileups[name], min_frequency, min_count);
+        log::text!("{}: {} variants", name, lines.len().to_formatted_string(&Locale::en));
+        log::event("sequence_variants", json!({"name": name, "variants": lines.len()}));
+        for line in &lines {
+            println!("{}", line);
+        }
+        variant_count += lines.len();
+    }
+    log::text!();
+    finished_message(variant_count, start_time);
+}
+
+
+fn starting_message(filter: &AlignmentFilter, min_frequency: f64, min_count: f64,
+                    assemblies: &[PathBuf], sam: &[PathBuf]) {
+    log::section_header("Starting Polypolish variants");
+    log::explanation("This finds positions where the short-read pileup contains a non-reference \
+                      sequence, using the same alignment filtering as polishing. It is useful \
+                      for finding heterogeneity in the reads, e.g. a mixed population.");
+    log::text!("Polypolish version: {}", crate_version!());
+    log::text!();
+    log::text!("Input assembly:");
+    for a in assemblies {
+        log::text!("  {}", a.display());
+    }
+    log::text!();
+    log::text!("Input short-read alignments:");
+    for s in sam {
+        log::text!("  {}", s.display());
+    }
+    log::text!();
+    log::text!("Settings:");
+    polish::log_filter_settings(filter);
+    log::text!("  --min-frequency {}", min_frequency);
+    log::text!("  --min-count {}", min_count);
+    if filter.careful {
+        log::text!("  --careful");
+    }
+    log::text!();
+    log::event("run_started", json!({"command": "variants", "version": crate_version!(),
+                                     "assembly": assemblies, "alignments": sam,
+                                     "filter": format!("{:?}", filter), "careful": filter.careful,
+                                     "min_frequency": min_frequency, "min_count": min_count}));
+}
+
+
+fn finished_message(variant_count: usize, start_time: Instant) {
+    log::section_header("Finished!");
+    log::text!("Variants: {}", variant_count.to_formatted_string(&Locale::en));
+    log::text!();
+    log::text!("Time to run: {}", misc::format_duration(start_time.elapsed()));
+    let peak_memory = memory::peak_memory();
+    if let Some(peak) = peak_memory {
+        log::text!("Peak memory usage: {}", HumanBytes(peak));
+    }
+    log::text!();
+    log::event("finished", json!({"variants": variant_count,
+                                  "seconds": start_time.elapsed().as_secs_f64(),
+                                  "peak_memory_bytes": peak_memory}));
+}
+
+
+/// Returns a VCF line for each non-reference sequence in the pileup which passes the cutoffs. A
+/// sequence with more than one base is an insertion after the base (REF A, ALT AT), and a
+/// deletion is given with the base before it (REF AT, ALT A), as VCF requires. Indels are moved
+/// to their left-most position (see normalise.rs), and when reads put the same indel at different
+/// positions (e.g. in a homopolymer), their counts are combined, using the largest depth of those
+/// positions.
+fn variant_lines(name: &str, pileup: &Pileup, min_frequency: f64, min_count: f64) -> Vec<String> {
+    let original = |i: usize| pileup.bases[i].original();
+    let mut variants: Vec<Variant> = Vec::new();
+    let mut indices: HashMap<(usize, String, String), usize> = HashMap::new();
+    for (pos, base) in pileup.bases.iter().enumerate() {
+        let counts = base.seq_counts();
+        let total: f64 = counts.iter().map(|(_, count)| count).sum();
+        for (seq, count) in counts {
+            if seq == base.original().to_string() {
+                continue;
+            }
+            let (vcf_pos, ref_allele, alt_allele) = if seq == "-" {
+                let pos = left_shift_deletion(original, |_| true, pos, pos + 1, 0);
+                if pos > 0 {
+                    let before = original(pos - 1);
+                    (pos, format!("{}{}", before, original(pos)), before.to_string())
+                } else if pileup.bases.len() > 1 {
+                    (1, format!("{}{}", original(0), original(1)), original(1).to_string())
+                } else {
+                    continue;
+                }
+            } else if let Some(inserted) = seq.strip_prefix(base.original())
+                    .filter(|s| !s.is_empty() && !s.contains('-')) {
+                let (pos, inserted) = left_shift_insertion(original, |_| true, pos, inserted, 0);
+                (pos + 1, original(pos).to_string(), format!("{}{}", original(pos), inserted))
+            } else {
+                (pos + 1, base.original().to_string(), seq)
+            };
+            let key = (vcf_pos, ref_allele, alt_allele);
+            if let Some(&i) = indices.get(&key) {
+                let v = &mut variants[i];
+                v.count += count;
+                v.total = v.total.max(total);
+                v.depth = v.depth.max(base.depth);
+            } else {
+                indices.insert(key.clone(), variants.len());
+                variants.push(Variant { pos: key.0, ref_allele: key.1, alt_allele: key.2, count,
+                                        total, depth: base.depth });
+            }
+        }
+    }
+    variants.sort_by_key(|v| v.pos);
+    variants.into_iter().filter(|v| {
+        v.count >= min_count && v.count / v.total >= min_frequency
+    }).map(|v| {
+        format!("{}\t{}\t.\t{}\t{}\t.\tPASS\tDP={:.1};AC={};AF={:.4}", name, v.pos, v.ref_allele,
+                v.alt_allele, v.depth, format_count(v.count), v.count / v.total)
+    }).collect()
+}
+
+
+struct Variant {
+    pos: usize,  // 1-based, as in the VCF
+    ref_allele: String,
+    alt_allele: String,
+    count: f64,
+    total: f64,
+    depth: f64,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variant_lines() {
+        let mut pileup = Pileup::new("ACGTA", u32::MAX, 0, None, false);
+        for _ in 0..8 {
+            pileup.bases[1].add_seq("C", 1.0, 1.0);
+            pileup.bases[2].add_seq("G", 1.0, 1.0);
+        }
+        for _ in 0..2 {
+            pileup.bases[1].add_seq("T", 1.0, 1.0);
+            pileup.bases[2].add_seq("-", 1.0, 1.0);
+        }
+        pileup.bases[3].add_seq("TAA", 1.0, 1.0);
+        pileup.bases[3].add_seq("T", 1.0, 1.0);
+        pileup.bases[4].add_seq("G", 0.5, 1.0);
+        assert_eq!(variant_lines("a", &pileup, 0.1, 2.0),
+                   vec!["a\t2\t.\tC\tT\t.\tPASS\tDP=10.0;AC=2;AF=0.2000",
+                        "a\t2\t.\tCG\tC\t.\tPASS\tDP=10.0;AC=2;AF=0.2000"]);
+        assert_eq!(variant_lines("a", &pileup, 0.25, 1.0),
+                   vec!["a\t4\t.\tT\tTAA\t.\tPASS\tDP=2.0;AC=1;AF=0.5000",
+                        "a\t5\t.\tA\tG\t.\tPASS\tDP=0.5;AC=1;AF=1.0000"]);
+    }
+
+    #[test]
+    fn test_variant_lines_left_normalised() {
+        let mut pileup = Pileup::new("GAAACAC", u32::MAX, 0, None, false);
+        for pos in 2..4 {
+            for _ in 0..3 {
+                pileup.bases[pos].add_seq("A", 1.0, 1.0);
+            }
+            pileup.bases[pos].add_seq("-", 1.0, 1.0);
+        }
+        pileup.bases[6].add_seq("CAC", 1.0, 1.0);
+        assert_eq!(variant_lines("a", &pileup, 0.1, 1.0),
+                   vec!["a\t1\t.\tGA\tG\t.\tPASS\tDP=4.0;AC=2;AF=0.5000",
+                        "a\t3\t.\tA\tAAC\t.\tPASS\tDP=1.0;AC=1;AF=1.0000"]);
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Some problems with the inputs don't stop Polypolish, but can make its results much less useful
+// (e.g. alignments which are almost all discarded, leaving the assembly unchanged). These are
+// collected during the run and repeated together at the end, so they aren't lost in the middle of
+// a long log.
+
+use colored::Colorize;
+use serde_json::json;
+
+use crate::log;
+
+
+#[derive(Default)]
+pub struct Warnings(Vec<String>);
+
+impl Warnings {
+    /// Records a warning, which is also logged straight away so it appears where the problem was
+    /// found.
+    pub fn add(&mut self, message: String) {
+        log::text!("{}", format!("Warning: {}", message).bright_red());
+        log::event("warning", json!({"message": message}));
+        self.0.push(message);
+    }
+
+    pub fn all(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Logs all of the run's warnings (if there were any) in one section.
+    pub fn report(&self) {
+        if self.0.is_empty() {
+            return;
+        }
+        log::text!("{}", format!("WARNINGS ({}):", self.0.len()).bold().bright_red());
+        for warning in &self.0 {
+            log::text!("  - {}", warning);
+        }
+        log::text!();
+    }
+}
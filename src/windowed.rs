@@ -0,0 +1,292 @@
+// Copyright 2021 Ryan Wick (rrwick@gmail.com)
+// https://github.com/rrwick/Polypolish
+
+// This file is part of Polypolish. Polypolish is free software: you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version. Polypolish
+// is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the
+// implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General
+// Public License for more details. You should have received a copy of the GNU General Public
+// License along with Polypolish. If not, see <http://www.gnu.org/licenses/>.
+
+// Windowed polishing works through coordinate-sorted alignments one sequence at a time, keeping a
+// pileup for only the region the current alignments overlap. Finished bases are polished and
+// written out straight away, so memory use doesn't grow with the assembly size.
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::json;
+
+use std::path::{Path, PathBuf};
+
+use crate::alignment::{find_multi_aligned_reads, is_sorted_by_position, prepare_alignment,
+                       read_name_key, Alignment, AlignmentFilter, DiscardCounts,
+                       MultiAlignedRead, SamCounts, SamLines};
+use crate::downsample::Downsampler;
+use crate::log;
+use crate::misc::{quit_with_error, ErrorType, FastHashMap};
+use crate::options::PolishOptions;
+use crate::pileup;
+use crate::pileup::{PileupBase, PileupWindow, Thresholds};
+use crate::polish;
+use crate::polish::{AlignmentInputs, BaseSupport, OutputFiles, PolishState, PolishedSeq};
+use crate::progress::{Progress, Unit};
+use crate::run::RunContext;
+
+
+pub fn polish_windowed(options: &PolishOptions, thresholds: &Thresholds,
+                       inputs: &AlignmentInputs, fasta: &[(String, String, String)],
+                       outputs: &mut OutputFiles) -> Vec<PolishedSeq> {
+    let AlignmentInputs { sam, filter, downsampler, strict, .. } = *inputs;
+    log::section_header("Scanning alignments");
+    log::explanation("In windowed mode, alignments must be sorted by position. Each file is first \
+                      scanned to find reads with multiple alignments, as their alignments may not \
+                      be near each other.");
+    let seq_indices: FastHashMap<&str, usize> = fasta.iter().enumerate()
+        .map(|(i, (name, _, _))| (name.as_str(), i)).collect();
+    let mut files = Vec::new();
+    for s in sam {
+        let multi_aligned = scan_alignments(s, filter, strict, downsampler);
+        files.push(SortedAlignments::open(s, multi_aligned, *downsampler, &seq_indices,
+                                          &filter.fail_tags, &filter.run));
+    }
+    log::text!();
+
+    polish::polishing_header();
+    let mut polished_seqs = Vec::new();
+    for seq in fasta {
+        polished_seqs.push(polish_one_sequence(options, thresholds, inputs, seq, &mut files,
+                                               &seq_indices, outputs));
+    }
+
+    let counts: Vec<SamCounts> = files.iter()
+        .map(|f| SamCounts { alignments: f.alignment_count, used: f.used_count,
+                             unmapped: f.unmapped_count, ..Default::default() }).collect();
+    let mut discarded = DiscardCounts::default();
+    for f in &files {
+        discarded.merge(&f.discarded);
+    }
+    polish::print_alignment_filtering(filter.careful, &counts, &discarded, &filter.run);
+    polished_seqs
+}
+
+
+fn polish_one_sequence(options: &PolishOptions, thresholds: &Thresholds,
+                       inputs: &AlignmentInputs,
+                       (name, description, seq): &(String, String, String),
+                       files: &mut [SortedAlignments], seq_indices: &FastHashMap<&str, usize>,
+                       outputs: &mut OutputFiles) -> PolishedSeq {
+    let PolishOptions { max_depth, trim_ends, .. } = options;
+    let AlignmentInputs { filter, .. } = *inputs;
+    let seq_index = seq_indices[name.as_str()];
+    let seq_len = seq.len();
+    log::text!("Polishing {} ({} bp):", name, seq_len.to_formatted_string(&Locale::en));
+    outputs.start_seq(name, description, None);
+    let record_reads = outputs.debug.as_ref().is_some_and(|f| f.read_names);
+    let homopolymer_trim = (!options.no_homopolymer_trim).then_some(options.homopolymer_trim);
+    let mut window = PileupWindow::new(seq, max_depth.unwrap_or(u32::MAX), *trim_ends,
+                                       homopolymer_trim, record_reads);
+    let low_complexity = polish::find_low_complexity(seq, thresholds);
+    let mut state = PolishState::new(name, seq_len, outputs.fastq);
+    let mut pos = 0;
+    let mut polished_len = 0;
+    let mut pending = Vec::new();  // finished bases not yet polished
+    let mut progress = Progress::new(&format!("Polishing {}", name), Unit::Bases, seq_len as u64,
+                                     "changes");
+    loop {
+        // The next alignment is the one (from any of the files) with the lowest start position on
+        // this sequence.
+        let next = files.iter().enumerate()
+            .filter_map(|(j, f)| match &f.next {
+                Some((i, a)) if *i == seq_index => Some((a.ref_start, j)),
+                _                               => None,
+            }).min();
+        let Some((start, j)) = next else { break; };
+
+        // No later alignment can reach the bases before this one's start, so they are finished.
+        pending.extend(window.take_finished(Some(start)));
+        polished_len += polish_bases(&mut pending, false, &mut pos, thresholds, &low_complexity,
+                                     &mut state, outputs);
+        progress.update(pos as u64, state.totals.changed_count as u64);
+
+        let alignment = files[j].take_next(seq_indices);
+        if let Some((alignment, depth_contribution)) = files[j].prepare(alignment, filter) {
+            window.weight = inputs.weights[j];
+            window.add_alignment(&alignment, depth_contribution);
+        }
+    }
+    pending.extend(window.take_finished(None));
+    polished_len += polish_bases(&mut pending, true, &mut pos, thresholds, &low_complexity,
+                                 &mut state, outputs);
+    state.totals.homopolymer_trimmed = window.homopolymer_trimmed;
+    progress.finish();
+    outputs.finish_seq(&state.quals);
+    polish::print_polishing_info(name, seq_len, polished_len, &state.totals, &outputs.run);
+    PolishedSeq::new(name, seq_len, polished_len, &state.totals)
+}
+
+
+/// Polishes finished bases (removing them from the vector) and writes their sequence to stdout,
+/// returning its length. For FASTQ output, their qualities are held until the sequence is
+/// finished. With --joint-indels, bases at the end which may be part of a deletion event are left
+/// until the event's end is known, unless they are the sequence's last.
+fn polish_bases(bases: &mut Vec<PileupBase>, last: bool, pos: &mut usize,
+                thresholds: &Thresholds, low_complexity: &[bool], state: &mut PolishState,
+                outputs: &mut OutputFiles) -> usize {
+    let first = *pos;
+    let base_thresholds = |p: usize| {
+        if low_complexity[p] { thresholds.for_low_complexity() } else { *thresholds }
+    };
+    let mut ready = bases.len();
+    if thresholds.joint_indels && !last {
+        ready -= bases.iter().enumerate().rev()
+            .take_while(|(j, b)| b.has_deletion_support(&base_thresholds(first + j))).count();
+    }
+    let events = if thresholds.joint_indels {
+        let ready_bases: Vec<&PileupBase> = bases[..ready].iter().collect();
+        let mut events = pileup::deletion_events(&ready_bases, |j| base_thresholds(first + j));
+        for e in &mut events {
+            e.start += first;
+            e.end += first;
+        }
+        events
+    } else {
+        Vec::new()
+    };
+    let mut polished_seq = String::with_capacity(ready);
+    for b in bases.drain(..ready) {
+        let support = BaseSupport { event: pileup::event_at(&events, *pos),
+                                    ..Default::default() };
+        polished_seq.push_str(&polish::polish_base(&b, &support, *pos, thresholds,
+                                                   low_complexity[*pos], state, outputs));
+        *pos += 1;
+    }
+    outputs.write_seq(&polished_seq);
+    polished_seq.len()
+}
+
+
+/// One coordinate-sorted alignment file, read one alignment at a time.
+struct SortedAlignments {
+    filename: PathBuf,
+    lines: SamLines,
+    line_count: usize,
+    multi_aligned: FastHashMap<String, MultiAlignedRead>,
+    downsampler: Downsampler,
+    fail_tags: Vec<String>,
+    next: Option<(usize, Alignment)>,  // sequence index and the next alignment
+    previous: Option<(usize, usize)>,  // sequence index and start of the previous alignment
+    alignment_count: usize,
+    used_count: usize,
+    unmapped_count: usize,
+    discarded: DiscardCounts,
+}
+
+impl SortedAlignments {
+    fn open(filename: &Path, multi_aligned: FastHashMap<String, MultiAlignedRead>,
+            downsampler: Downsampler, seq_indices: &FastHashMap<&str, usize>,
+            fail_tags: &[String], run: &RunContext) -> SortedAlignments {
+        let lines = match SamLines::open(filename, run) {
+            Ok(lines) => lines,
+            Err(_)    => quit_with_error(ErrorType::Io,
+                                         &format!("unable to load alignments from {:?}",
+                                                  filename)),
+        };
+        let mut sorted = SortedAlignments {
+            filename: filename.to_path_buf(), lines, line_count: 0, multi_aligned, downsampler,
+            fail_tags: fail_tags.to_vec(), next: None, previous: None, alignment_count: 0,
+            used_count: 0, unmapped_count: 0, discarded: DiscardCounts::default(),
+        };
+        sorted.advance(seq_indices);
+        sorted
+    }
+
+    /// Takes the next alignment and reads in the one after it.
+    fn take_next(&mut self, seq_indices: &FastHashMap<&str, usize>) -> Alignment {
+        let (seq_index, alignment) = self.next.take().unwrap();
+        self.previous = Some((seq_index, alignment.ref_start));
+        self.advance(seq_indices);
+        alignment
+    }
+
+    fn advance(&mut self, seq_indices: &FastHashMap<&str, usize>) {
+        while let Some(line) = self.lines.next_line() {
+            self.line_count += 1;
+            let sam_line = match line {
+                Ok(sam_line) => sam_line,
+                Err(_)       => quit_with_error(ErrorType::Io,
+                                                &format!("unable to load alignments from {:?}",
+                                                         self.filename)),
+            };
+            if sam_line.is_empty() || sam_line.starts_with('@') {continue;}
+            if !self.downsampler.keeps(read_name_key(sam_line)) {continue;}
+            let alignment = match Alignment::new(sam_line, &self.fail_tags) {
+                Ok(alignment) => alignment,
+                Err(e)        => {
+                    let record = sam_line.to_string();
+                    self.lines.run.bad_record(e, &self.filename, self.line_count, &record);
+                    continue;
+                },
+            };
+            if !alignment.is_aligned() {
+                self.unmapped_count += 1;
+                continue;
+            }
+            let Some(&seq_index) = seq_indices.get(alignment.ref_name.as_str()) else {
+                quit_with_error(ErrorType::Input,
+                                &format!("query name {} in SAM but not in assembly",
+                                         alignment.ref_name));
+            };
+            if self.previous.is_some_and(|p| (seq_index, alignment.ref_start) < p) {
+                quit_with_error(ErrorType::Input,
+                                &format!("{:?} is not sorted by position (line {}) - --windowed \
+                                          requires alignments sorted by position, with sequences \
+                                          in the same order as the assembly",
+                                         self.filename, self.line_count));
+            }
+            self.alignment_count += 1;
+            self.next = Some((seq_index, alignment));
+            return;
+        }
+    }
+
+    /// Decides whether an alignment is used for polishing. If so, it is returned (with its read
+    /// sequence, if that was missing) along with its depth contribution.
+    fn prepare(&mut self, alignment: Alignment,
+               filter: &AlignmentFilter) -> Option<(Alignment, f64)> {
+        let prepared = prepare_alignment(alignment, &self.multi_aligned, filter,
+                                         &mut self.discarded);
+        if prepared.is_some() {
+            self.used_count += 1;
+        }
+        prepared
+    }
+}
+
+
+fn scan_alignments(filename: &Path, filter: &AlignmentFilter, strict: bool,
+                   downsampler: &Downsampler) -> FastHashMap<String, MultiAlignedRead> {
+    match is_sorted_by_position(filename, &filter.run) {
+        Ok(true)  => (),
+        Ok(false) => quit_with_error(ErrorType::Input,
+                                     &format!("{:?} is not sorted by position - --windowed \
+                                               requires alignments sorted by position (e.g. \
+                                               samtools sort)", filename)),
+        Err(_)    => quit_with_error(ErrorType::Io,
+                                     &format!("unable to load alignments from {:?}", filename)),
+    }
+    let (reads, alignment_count,
+         read_count) = match find_multi_aligned_reads(filename, filter, !filter.careful,
+                                                         downsampler) {
+        Ok(scanned) => scanned,
+        Err(_) => quit_with_error(ErrorType::Io,
+                                  &format!("unable to load alignments from {:?}", filename)),
+    };
+    log::text!("{}: {} alignments from {} reads ({} with multiple alignments)",
+               filename.display(), alignment_count.to_formatted_string(&Locale::en),
+               read_count.to_formatted_string(&Locale::en),
+               reads.len().to_formatted_string(&Locale::en));
+    log::event("sam_loaded", json!({"file": filename, "alignments": alignment_count,
+                                    "reads": read_count, "multi_aligned_reads": reads.len()}));
+    polish::check_alignment_file(filename, read_count, reads.len(), strict, &filter.run);
+    reads
+}